@@ -0,0 +1,6 @@
+# same as program.json
+let x = v1
+let y = v2
+v3 = x + y
+v4 = x - y
+v5 = inv v4
@@ -3,18 +3,27 @@ use std::task::Poll;
 use futures::{pin_mut, Future};
 use libp2p::PeerId;
 use rust_hashgraph::algorithm::event::Hash;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     channel_log_send,
-    consensus::Transaction,
-    data_memory, instruction_storage,
+    consensus::{
+        validation::{Accepted, Evidence},
+        Transaction,
+    },
+    data_memory::{self, placement::Placement},
+    encoding::reed_solomon,
+    instruction_storage,
     logging_helpers::Targets,
     processor::{Instructions, Program, ProgramIdentifier},
-    types::{Sid, Vid},
+    protocol,
+    types::{self, Sid, Vid},
 };
 
-use super::Behaviour;
+use super::{
+    snapshot::{FinalizedTx, StateSnapshot},
+    Behaviour,
+};
 
 pub enum HandleResult {
     Ok,
@@ -26,13 +35,14 @@ impl Behaviour {
         &mut self,
         cx: &mut std::task::Context<'_>,
         data_id: Vid,
+        checksums: Vec<(Sid, types::Hash)>,
         from: PeerId,
     ) -> HandleResult {
         debug!(
             target: Targets::DataDistribution.into_str(),
             "Recognized finalized storage request for {:?}", data_id
         );
-        let event = data_memory::InEvent::StorageRequestTx(data_id, from);
+        let event = data_memory::InEvent::StorageRequestTx(data_id, from, checksums);
         let send_future = self.data_memory.input.send(event.clone());
         pin_mut!(send_future);
         match send_future.poll(cx) {
@@ -51,11 +61,33 @@ impl Behaviour {
         }
     }
 
+    fn handle_delete_tx(&mut self, cx: &mut std::task::Context<'_>, data_id: Vid) -> HandleResult {
+        debug!("Recognized finalized deletion of {:?}", data_id);
+        let event = data_memory::InEvent::DeleteTx(data_id);
+        let send_future = self.data_memory.input.send(event.clone());
+        pin_mut!(send_future);
+        match send_future.poll(cx) {
+            Poll::Ready(Ok(_)) => {
+                channel_log_send!("data_memory.input", format!("{:?}", event));
+                HandleResult::Ok
+            }
+            Poll::Ready(Err(_e)) => {
+                error!("other half of `data_memory.input` was closed. cannot operate without this module.");
+                HandleResult::Abort
+            }
+            Poll::Pending => {
+                error!("`data_memory.input` queue is full. continuing will keep deleted data.");
+                HandleResult::Abort
+            }
+        }
+    }
+
     fn handle_stored_ts(
         &mut self,
         cx: &mut std::task::Context<'_>,
         data_id: Vid,
         shard_id: Sid,
+        checksum: types::Hash,
         from: PeerId,
     ) -> HandleResult {
         debug!(
@@ -65,6 +97,7 @@ impl Behaviour {
         let event = data_memory::InEvent::StoreConfirmed {
             full_shard_id: (data_id, shard_id),
             location: from,
+            checksum,
         };
         let send_future = self.data_memory.input.send(event.clone());
         pin_mut!(send_future);
@@ -89,6 +122,7 @@ impl Behaviour {
         cx: &mut std::task::Context<'_>,
         event_hash: Hash,
         instructions: Instructions,
+        author: PeerId,
     ) -> HandleResult {
         let program = match Program::new(instructions, event_hash.into()) {
             Ok(p) => p,
@@ -101,7 +135,7 @@ impl Behaviour {
         let send_future = self
             .instruction_memory
             .input
-            .send(instruction_storage::InEvent::FinalizedProgram(program));
+            .send(instruction_storage::InEvent::FinalizedProgram { program, author });
         pin_mut!(send_future);
         match send_future.poll(cx) {
             Poll::Ready(Ok(_)) => {
@@ -128,15 +162,51 @@ impl Behaviour {
         }
     }
 
+    fn handle_cancel_tx(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        program_id: ProgramIdentifier,
+        from: PeerId,
+    ) -> HandleResult {
+        let event = instruction_storage::InEvent::Cancelled {
+            program_id,
+            by: from,
+        };
+        let send_future = self.instruction_memory.input.send(event.clone());
+        pin_mut!(send_future);
+        match send_future.poll(cx) {
+            Poll::Ready(Ok(_)) => {
+                channel_log_send!("instruction_memory.input", format!("{:?}", event));
+                HandleResult::Ok
+            }
+            Poll::Ready(Err(_e)) => {
+                error!(
+                    "other half of `instruction_memory.input` was closed. \
+                        cannot operate without this module."
+                );
+                HandleResult::Abort
+            }
+            Poll::Pending => {
+                error!(
+                    "`instruction_memory.input` queue is full. \
+                    continue will skip a transaction, which is unacceptable."
+                );
+                HandleResult::Abort
+            }
+        }
+    }
+
     fn handle_executed_tx(
         &mut self,
         cx: &mut std::task::Context<'_>,
         from: PeerId,
         program_id: ProgramIdentifier,
+        updated_shards: Vec<((Vid, Sid), types::Hash)>,
     ) -> HandleResult {
         let event = instruction_storage::InEvent::ExecutedProgram {
             peer: from,
             program_id,
+            updated_shards,
         };
         let send_future = self.instruction_memory.input.send(event.clone());
         pin_mut!(send_future);
@@ -166,7 +236,9 @@ impl Behaviour {
     fn handle_initialize_storage_tx(
         &mut self,
         cx: &mut std::task::Context<'_>,
-        distribution: Vec<(PeerId, Sid)>,
+        members: Vec<PeerId>,
+        placement: Placement,
+        encoding: reed_solomon::Settings,
     ) -> HandleResult {
         debug!(
             target: Targets::StorageInitialization.into_str(),
@@ -175,7 +247,11 @@ impl Behaviour {
         let send_future = self
             .data_memory
             .input
-            .send(data_memory::InEvent::Initialize { distribution });
+            .send(data_memory::InEvent::Initialize {
+                members,
+                placement,
+                encoding,
+            });
         pin_mut!(send_future);
         match send_future.poll(cx) {
             Poll::Ready(Ok(_)) => {
@@ -199,6 +275,179 @@ impl Behaviour {
         }
     }
 
+    /// Templates are kept by instruction memory, it also queues programs
+    /// instantiated from them
+    fn handle_template_tx(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        event: instruction_storage::InEvent,
+    ) -> HandleResult {
+        let send_future = self.instruction_memory.input.send(event.clone());
+        pin_mut!(send_future);
+        match send_future.poll(cx) {
+            Poll::Ready(Ok(_)) => {
+                channel_log_send!("instruction_memory.input", format!("{:?}", event));
+                HandleResult::Ok
+            }
+            Poll::Ready(Err(_e)) => {
+                error!(
+                    "other half of `instruction_memory.input` was closed. \
+                        cannot operate without this module."
+                );
+                HandleResult::Abort
+            }
+            Poll::Pending => {
+                error!(
+                    "`instruction_memory.input` queue is full. \
+                    continue will skip a transaction, which is unacceptable."
+                );
+                HandleResult::Abort
+            }
+        }
+    }
+
+    /// Membership changes and shard migration are handled by data memory,
+    /// in the order of finalization
+    fn handle_membership_tx(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        event: data_memory::InEvent,
+    ) -> HandleResult {
+        debug!("Recognized membership change: {:?}", event);
+        let send_future = self.data_memory.input.send(event.clone());
+        pin_mut!(send_future);
+        match send_future.poll(cx) {
+            Poll::Ready(Ok(_)) => {
+                channel_log_send!("data_memory.input", format!("{:?}", event));
+                HandleResult::Ok
+            }
+            Poll::Ready(Err(_e)) => {
+                error!("other half of `data_memory.input` was closed. cannot operate without this module.");
+                HandleResult::Abort
+            }
+            Poll::Pending => {
+                error!("`data_memory.input` queue is full. continuing will lose track of stored shards.");
+                HandleResult::Abort
+            }
+        }
+    }
+
+    /// Give state from the snapshot to the memory modules, then apply
+    /// transactions finalized after it
+    pub(super) fn install_snapshot(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        snapshot: StateSnapshot,
+        buffered: impl Iterator<Item = FinalizedTx>,
+    ) -> HandleResult {
+        info!(
+            "Installing snapshot of generation {} by {:?}",
+            snapshot.generation, snapshot.pubkey
+        );
+        self.tx_validator.install(snapshot.validator);
+        // sends are finished before the buffered transactions are handled
+        {
+            let send_future = self
+                .data_memory
+                .input
+                .send(data_memory::InEvent::InstallSnapshot(snapshot.data));
+            pin_mut!(send_future);
+            match send_future.poll(cx) {
+                Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", "InstallSnapshot(_)"),
+                Poll::Ready(Err(_e)) => {
+                    error!("other half of `data_memory.input` was closed. cannot operate without this module.");
+                    return HandleResult::Abort;
+                }
+                Poll::Pending => {
+                    error!("`data_memory.input` queue is full. continuing will lose the state of the cluster.");
+                    return HandleResult::Abort;
+                }
+            }
+            let send_future =
+                self.instruction_memory
+                    .input
+                    .send(instruction_storage::InEvent::InstallSnapshot(
+                        snapshot.instructions,
+                    ));
+            pin_mut!(send_future);
+            match send_future.poll(cx) {
+                Poll::Ready(Ok(_)) => {
+                    channel_log_send!("instruction_memory.input", "InstallSnapshot(_)")
+                }
+                Poll::Ready(Err(_e)) => {
+                    error!(
+                        "other half of `instruction_memory.input` was closed. \
+                        cannot operate without this module."
+                    );
+                    return HandleResult::Abort;
+                }
+                Poll::Pending => {
+                    error!(
+                        "`instruction_memory.input` queue is full. \
+                        continuing will lose the state of the cluster."
+                    );
+                    return HandleResult::Abort;
+                }
+            }
+            let send_future = self
+                .user_interaction
+                .output
+                .send(super::module::OutEvent::SnapshotInstalled);
+            pin_mut!(send_future);
+            match send_future.poll(cx) {
+                Poll::Ready(Ok(_)) => {
+                    channel_log_send!("user_interaction.input", "SnapshotInstalled")
+                }
+                Poll::Ready(Err(_e)) => {
+                    error!("other half of `user_interaction.output` was closed. cannot operate without this module.");
+                    return HandleResult::Abort;
+                }
+                Poll::Pending => {
+                    error!("`user_interaction.output` queue is full. continuing will leave the user uninformed.");
+                    return HandleResult::Abort;
+                }
+            }
+        }
+        for (from, tx, event_hash) in buffered {
+            if let HandleResult::Abort = self.handle_tx(cx, from, tx, event_hash) {
+                return HandleResult::Abort;
+            }
+        }
+        info!("Fast sync is finished");
+        HandleResult::Ok
+    }
+
+    /// Respond to peers waiting for a snapshot with the one of the current
+    /// generation
+    pub(super) fn respond_snapshots(&mut self, cx: &mut std::task::Context<'_>) -> HandleResult {
+        let request = protocol::Request::Snapshot;
+        let response = protocol::Response::Snapshot(self.generation_snapshots.latest());
+        let waiting_for_response = self.processed_requests.remove(&request).unwrap_or_default();
+        for (request_id, channel) in waiting_for_response {
+            let send_future =
+                self.request_response
+                    .input
+                    .send(crate::request_response::InEvent::Respond {
+                        request_id,
+                        channel,
+                        response: response.clone(),
+                    });
+            pin_mut!(send_future);
+            match send_future.poll(cx) {
+                Poll::Ready(Ok(_)) => channel_log_send!("network.response", "Snapshot(_)"),
+                Poll::Ready(Err(_e)) => {
+                    error!("other half of `network.response` was closed. cannot operate without this module.");
+                    return HandleResult::Abort;
+                }
+                Poll::Pending => {
+                    error!("`network.response` queue is full. continuing will ignore someone's request.");
+                    return HandleResult::Abort;
+                }
+            }
+        }
+        HandleResult::Ok
+    }
+
     pub(super) fn handle_tx(
         &mut self,
         cx: &mut std::task::Context<'_>,
@@ -206,22 +455,94 @@ impl Behaviour {
         tx: Transaction<Vid, Sid, PeerId>,
         event_hash: Hash,
     ) -> HandleResult {
+        match self
+            .tx_validator
+            .validate(&from, &tx, &event_hash.clone().into())
+        {
+            Ok(Accepted::Apply) => (),
+            Ok(Accepted::Proposed) => {
+                info!(
+                    "{:?} proposed {}, waiting for other members",
+                    from,
+                    tx.variant_short_string()
+                );
+                return HandleResult::Ok;
+            }
+            Err(reason) => {
+                warn!(
+                    "Rejected {} by {:?}: {}",
+                    tx.variant_short_string(),
+                    from,
+                    reason
+                );
+                let evidence = Evidence {
+                    event_hash: event_hash.into(),
+                    tx: tx.variant_short_string(),
+                    reason,
+                };
+                self.metrics.record_rejected_tx(from, evidence);
+                return HandleResult::Ok;
+            }
+        }
         match tx {
             // track data locations, pull assigned shards
-            Transaction::StorageRequest { data_id } => {
-                self.handle_storage_request_tx(cx, data_id, from)
+            Transaction::StorageRequest { data_id, checksums } => {
+                self.handle_storage_request_tx(cx, data_id, checksums, from)
             }
             // take a note that `(data_id, shard_id)` is stored at `location`
-            Transaction::Stored(data_id, shard_id) => {
-                self.handle_stored_ts(cx, data_id, shard_id, from)
+            Transaction::Stored(data_id, shard_id, checksum) => {
+                self.handle_stored_ts(cx, data_id, shard_id, checksum, from)
             }
+            // forget the data everywhere
+            Transaction::Delete(data_id) => self.handle_delete_tx(cx, data_id),
             Transaction::Execute(instructions) => {
-                self.handle_execute_tx(cx, event_hash, instructions)
+                self.handle_execute_tx(cx, event_hash, instructions, from)
+            }
+            Transaction::RegisterTemplate(template) => self.handle_template_tx(
+                cx,
+                instruction_storage::InEvent::FinalizedTemplate(template),
+            ),
+            Transaction::ExecuteTemplate {
+                template_hash,
+                bindings,
+            } => self.handle_template_tx(
+                cx,
+                instruction_storage::InEvent::FinalizedTemplateCall {
+                    template_hash,
+                    bindings,
+                    event_hash: event_hash.into(),
+                    author: from,
+                },
+            ),
+            Transaction::Cancel(program_id) => self.handle_cancel_tx(cx, program_id, from),
+            Transaction::Executed(program_id, updated_shards) => {
+                self.handle_executed_tx(cx, from, program_id, updated_shards)
+            }
+            Transaction::InitializeStorage {
+                members,
+                placement,
+                encoding,
+            } => self.handle_initialize_storage_tx(cx, members, placement, encoding),
+            Transaction::Join { capacity } => self.handle_membership_tx(
+                cx,
+                data_memory::InEvent::PeerJoined {
+                    peer: from,
+                    capacity,
+                },
+            ),
+            Transaction::Leave => {
+                self.handle_membership_tx(cx, data_memory::InEvent::PeerLeft(from))
             }
-            Transaction::Executed(program_id) => self.handle_executed_tx(cx, from, program_id),
-            Transaction::InitializeStorage { distribution } => {
-                self.handle_initialize_storage_tx(cx, distribution)
+            Transaction::Evict(peer) => {
+                self.handle_membership_tx(cx, data_memory::InEvent::PeerEvicted { peer, by: from })
             }
+            Transaction::ShardsRepaired { shards } => self.handle_membership_tx(
+                cx,
+                data_memory::InEvent::ShardsRepaired {
+                    holder: from,
+                    shards,
+                },
+            ),
         }
     }
 }
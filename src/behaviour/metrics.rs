@@ -1,8 +1,17 @@
-use std::time::Instant;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
 
+use libp2p::PeerId;
 use tokio::sync::mpsc;
 use tracing::warn;
 
+use crate::consensus::validation::Evidence;
+
+/// Number of the latest rejected transactions kept for each peer
+const EVIDENCE_KEPT: usize = 100;
+
 #[derive(Debug, Clone)]
 enum EventStatus {
     /// (start time)
@@ -132,10 +141,35 @@ where
     }
 }
 
+/// Storage audits of a peer performed by this node
+#[derive(Debug, Clone, Default)]
+pub struct AuditResults {
+    pub passed: u64,
+    pub failed: u64,
+}
+
+/// Checks of program results of a peer performed by this node
+#[derive(Debug, Clone, Default)]
+pub struct ResultCheckResults {
+    pub agreed: u64,
+    pub disagreed: u64,
+}
+
+/// Transactions of a peer rejected by validation
+#[derive(Debug, Clone, Default)]
+pub struct Misbehaviour {
+    pub rejected: u64,
+    /// The latest ones, oldest first
+    pub evidence: VecDeque<Evidence>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Metrics {
     pub sync: PeriodicEvent,
     pub consensus_queue_size: Gauge<usize>,
+    pub audits: HashMap<PeerId, AuditResults>,
+    pub result_checks: HashMap<PeerId, ResultCheckResults>,
+    pub misbehaviour: HashMap<PeerId, Misbehaviour>,
 }
 
 impl Metrics {
@@ -143,7 +177,37 @@ impl Metrics {
         Self {
             sync: PeriodicEvent::new(),
             consensus_queue_size: Gauge::new(),
+            audits: HashMap::new(),
+            result_checks: HashMap::new(),
+            misbehaviour: HashMap::new(),
+        }
+    }
+
+    pub fn record_audit(&mut self, peer: PeerId, passed: bool) {
+        let results = self.audits.entry(peer).or_default();
+        if passed {
+            results.passed += 1;
+        } else {
+            results.failed += 1;
+        }
+    }
+
+    pub fn record_result_check(&mut self, peer: PeerId, agreed: bool) {
+        let results = self.result_checks.entry(peer).or_default();
+        if agreed {
+            results.agreed += 1;
+        } else {
+            results.disagreed += 1;
+        }
+    }
+
+    pub fn record_rejected_tx(&mut self, peer: PeerId, evidence: Evidence) {
+        let misbehaviour = self.misbehaviour.entry(peer).or_default();
+        misbehaviour.rejected += 1;
+        if misbehaviour.evidence.len() == EVIDENCE_KEPT {
+            misbehaviour.evidence.pop_front();
         }
+        misbehaviour.evidence.push_back(evidence);
     }
 
     pub fn update_queue_size<T>(queue: &mpsc::Sender<T>, metric: &mut Gauge<usize>) {
@@ -13,6 +13,7 @@ use libp2p::{
         dial_opts::{DialOpts, PeerCondition},
         ConnectionClosed, FromSwarm, NetworkBehaviour, NotifyHandler, ToSwarm,
     },
+    identity::ed25519,
     PeerId,
 };
 use libp2p_request_response::RequestId;
@@ -25,26 +26,34 @@ use tracing::{debug, error, info, trace, warn};
 use crate::{
     channel_log_recv, channel_log_send,
     consensus::{self, Transaction},
-    data_memory, instruction_storage,
+    data_memory::{
+        self,
+        placement::{Placement, DEFAULT_CAPACITY},
+    },
+    encoding::reed_solomon,
+    instruction_storage,
     logging_helpers::Targets,
-    processor::single_threaded::{self},
+    processor::{single_threaded::{self}, validation, Instructions},
     protocol::{
         self,
         one_shot::{InnerMessage, SimpleMessage, SwarmOneShot},
         Request,
     },
+    types::{Hash, Vid},
 };
-use crate::{
-    module::{ModuleChannelClient, ModuleChannelServer},
-    types::Sid,
-};
+use crate::module::{ModuleChannelClient, ModuleChannelServer};
 pub use module::{InEvent, Module, OutEvent};
 
-use self::{gossip_timer::DynamicTimer, metrics::Metrics};
+use self::{
+    gossip_timer::DynamicTimer,
+    metrics::Metrics,
+    snapshot::{FastSync, GenerationSnapshots, SkipVotes},
+};
 
 mod gossip_timer;
 mod handlers;
 pub mod metrics;
+pub mod snapshot;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -61,8 +70,10 @@ mod module {
 
     use crate::{
         data_memory,
-        processor::{Instructions, ProgramIdentifier},
-        types::{Data, Sid, Vid},
+        encoding::reed_solomon,
+        instruction_storage::ProgramStatus,
+        processor::{template::Template, validation, Instructions, ProgramIdentifier},
+        types::{Data, Hash, Sid, Vid},
     };
 
     use super::metrics::Metrics;
@@ -79,10 +90,26 @@ mod module {
     pub enum InEvent {
         // schedule program, collect data, distribute data
         ScheduleProgram(Instructions),
+        /// Programs whose identifier starts with the prefix (all for empty one)
+        ListPrograms(String),
+        /// Cancel the program with this prefix of identifier if it's not
+        /// started yet
+        Cancel(String),
+        /// Publish the template so that it can be invoked later
+        RegisterTemplate(Template),
+        /// Templates whose hash starts with the prefix (all for empty one)
+        ListTemplates(String),
+        /// Invoke the template with this prefix of hash on the data
+        ScheduleTemplate { prefix: String, bindings: Vec<Vid> },
         Get(Vid),
         Put(Vid, Data),
+        Delete(Vid),
         ListStored,
         InitializeStorage,
+        // membership
+        Join,
+        Leave,
+        Evict(PeerId),
         GetMetrics,
     }
 
@@ -90,11 +117,31 @@ mod module {
     pub enum OutEvent {
         // TODO: add hash?
         ScheduleOk,
+        ScheduleRejected(validation::Error),
         ProgramExecuted(ProgramIdentifier),
+        Programs(Vec<(ProgramIdentifier, ProgramStatus)>),
+        CancelScheduled(ProgramIdentifier),
+        CancelRejected { prefix: String, reason: String },
+        TemplateRegistrationScheduled(Hash),
+        TemplateRegistrationRejected(validation::Error),
+        Templates(Vec<(Hash, Template)>),
+        TemplateRejected { prefix: String, reason: String },
         GetResponse(Result<(Vid, Data), data_memory::RecollectionError>),
         PutConfirmed(Vid),
+        PutRejected(Vid, reed_solomon::Error),
+        Deleted(Vid),
         ListStoredResponse(Vec<(Vid, HashMap<Sid, PeerId>)>),
         StorageInitialized,
+        StorageInitializationRejected {
+            local: reed_solomon::Settings,
+            cluster: reed_solomon::Settings,
+        },
+        DistributionChanged(u64),
+        /// This peer is too far behind to sync by gossip, so it skipped to
+        /// the generation and takes the state from a snapshot
+        FastSyncStarted { generation: u64 },
+        /// State of the cluster is installed from a snapshot
+        SnapshotInstalled,
         GetMetricsResponse(Metrics),
     }
 }
@@ -104,6 +151,18 @@ pub type ToSwarmEvent = Result<Event, Error>;
 #[derive(Debug)]
 pub enum Event {}
 
+/// Who asked data memory for the list of distributed data
+enum ListRequest {
+    User,
+    /// Program is validated against the list before it is scheduled
+    Validate {
+        instructions: Instructions,
+        /// The program is an instance of the template, so the invocation is
+        /// scheduled instead of the instructions
+        template_call: Option<(Hash, Vec<Vid>)>,
+    },
+}
+
 struct ConnectionEventWrapper<E> {
     peer_id: libp2p::PeerId,
     _connection: libp2p::swarm::ConnectionId,
@@ -115,16 +174,25 @@ pub struct Behaviour {
     #[allow(unused)]
     local_peer_id: PeerId,
     discovered_peers: VecDeque<PeerId>,
+    expired_peers: VecDeque<PeerId>,
 
     user_interaction: ModuleChannelServer<module::Module>,
     // connections to other system components (run as separate async tasks)
     // todo: do some wrapper that'll check for timeouts and stuff. maybe also match request-response
-    consensus: ModuleChannelClient<consensus::graph::Module>,
+    consensus: ModuleChannelClient<consensus::Module>,
     instruction_memory: ModuleChannelClient<instruction_storage::Module>,
     data_memory: ModuleChannelClient<data_memory::Module>,
     processor: ModuleChannelClient<single_threaded::Module>,
     request_response: ModuleChannelClient<crate::request_response::Module>,
 
+    // proposed to other peers on storage initialization
+    encoding_settings: reed_solomon::Settings,
+    placement: Placement,
+    // announced to other members when joining the storage
+    capacity: u64,
+    // answered by data memory in the same order
+    list_requests: VecDeque<ListRequest>,
+
     // random gossip
     connected_peers: HashSet<PeerId>,
     rng: rand::rngs::ThreadRng,
@@ -141,6 +209,20 @@ pub struct Behaviour {
         )>,
     >,
 
+    // state snapshots
+    generation_snapshots: GenerationSnapshots,
+    // generation of the transactions finalized now
+    generation: u64,
+    // generation and event of the last finalized transaction, named by snapshots
+    last_finalized: Option<(u64, Hash)>,
+    // `Some` after skipping generations, until the snapshot is installed
+    fast_sync: Option<FastSync>,
+    // peers that told this one it's too far behind
+    skip_votes: SkipVotes,
+
+    // rules transactions are checked against before applying
+    tx_validator: consensus::validation::Validator,
+
     // notification to poll() to wake up and try to do some progress
     state_updated: Arc<Notify>,
 
@@ -154,21 +236,30 @@ impl Behaviour {
         consensus_gossip_min_timeout: Duration,
         consensus_gossip_max_timeout: Duration,
         user_interaction: ModuleChannelServer<module::Module>,
-        consensus: ModuleChannelClient<consensus::graph::Module>,
+        consensus: ModuleChannelClient<consensus::Module>,
         instruction_memory: ModuleChannelClient<instruction_storage::Module>,
         data_memory: ModuleChannelClient<data_memory::Module>,
         processor: ModuleChannelClient<single_threaded::Module>,
         request_response: ModuleChannelClient<crate::request_response::Module>,
+        encoding_settings: reed_solomon::Settings,
+        placement: Placement,
+        capacity: u64,
+        keypair: ed25519::Keypair,
     ) -> Self {
         Self {
             local_peer_id,
             discovered_peers: VecDeque::new(),
+            expired_peers: VecDeque::new(),
             user_interaction,
             consensus,
             instruction_memory,
             data_memory,
             processor,
             request_response,
+            encoding_settings,
+            placement,
+            capacity,
+            list_requests: VecDeque::new(),
             connected_peers: HashSet::new(),
             rng: rand::thread_rng(),
             consensus_gossip_timer: DynamicTimer::new(
@@ -178,6 +269,12 @@ impl Behaviour {
             oneshot_messages: VecDeque::new(),
             pending_response: HashMap::new(),
             processed_requests: HashMap::new(),
+            generation_snapshots: GenerationSnapshots::new(keypair),
+            generation: 0,
+            last_finalized: None,
+            fast_sync: None,
+            skip_votes: SkipVotes::default(),
+            tx_validator: consensus::validation::Validator::new(),
             state_updated: Arc::new(Notify::new()),
             metrics: Metrics::new(),
         }
@@ -190,8 +287,10 @@ impl Behaviour {
     }
 
     /// Notify behaviour that peer not discoverable and is expired according to MDNS
-    pub fn inject_peer_expired(&mut self, _peer: &PeerId) {
-        // Maybe add some logic later
+    pub fn inject_peer_expired(&mut self, peer: &PeerId) {
+        debug!("Peer {} expired", peer);
+        self.expired_peers.push_front(*peer);
+        self.state_updated.notify_one();
     }
 }
 
@@ -358,6 +457,18 @@ impl NetworkBehaviour for Behaviour {
             None => trace!("No new peers found"),
         }
 
+        trace!("Checking expired peers");
+        while let Some(peer) = self.expired_peers.pop_back() {
+            let event = data_memory::InEvent::PeerLost(peer);
+            let send_future = self.data_memory.input.send(event.clone());
+            pin_mut!(send_future);
+            match send_future.poll(cx) {
+                Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("{:?}", event)),
+                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will leave shards of the lost peer unrepaired. for now fail fast to see this."),
+            }
+        }
+
         // todo: reconsider ordering
         trace!("Checking incoming simple messages");
         loop {
@@ -377,7 +488,7 @@ impl NetworkBehaviour for Behaviour {
                         let send_future =
                             self.consensus
                                 .input
-                                .send(consensus::graph::InEvent::ApplySync {
+                                .send(consensus::InEvent::ApplySync {
                                     from: s.peer_id,
                                     sync,
                                 });
@@ -392,6 +503,71 @@ impl NetworkBehaviour for Behaviour {
                             &mut self.metrics.consensus_queue_size,
                         );
                     }
+                    SimpleMessage(protocol::Simple::TooFarBehind { generation }) => {
+                        channel_log_recv!(
+                            "network.simple",
+                            format!("TooFarBehind(from: {:?}, generation: {})", &s.peer_id, generation)
+                        );
+                        // a single peer could make this one drop its graph, so most
+                        // members have to report it. a new peer knows only connected ones.
+                        let members: HashSet<PeerId> = match self.tx_validator.known_distribution() {
+                            Some(distribution) => distribution.members().filter(|peer| **peer != self.local_peer_id).cloned().collect(),
+                            None => self.connected_peers.clone(),
+                        };
+                        let Some(generation) = self.skip_votes.report(s.peer_id, generation, &members) else {
+                            continue;
+                        };
+                        // consensus skips to the generation if it's really behind,
+                        // fast sync starts once it does
+                        let event = consensus::InEvent::SkipToGeneration(generation);
+                        let send_future = self.consensus.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will leave this peer behind. for now fail fast to see this."),
+                        }
+                        Metrics::update_queue_size(
+                            &self.consensus.input,
+                            &mut self.metrics.consensus_queue_size,
+                        );
+                    }
+                    SimpleMessage(protocol::Simple::PlaintextOperands(step, operands)) => {
+                        channel_log_recv!(
+                            "network.simple",
+                            format!("PlaintextOperands(from: {:?})", &s.peer_id)
+                        );
+                        let event = data_memory::InEvent::PlaintextOperands {
+                            from: s.peer_id,
+                            step,
+                            operands,
+                        };
+                        let send_future = self.data_memory.input.send(event);
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("PlaintextOperands(from: {})", s.peer_id)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will lose operands of non-linear instruction. for now fail fast to see this."),
+                        }
+                    }
+                    SimpleMessage(protocol::Simple::PlaintextResult(step, shards)) => {
+                        channel_log_recv!(
+                            "network.simple",
+                            format!("PlaintextResult(from: {:?})", &s.peer_id)
+                        );
+                        let event = data_memory::InEvent::PlaintextResult {
+                            from: s.peer_id,
+                            step,
+                            shards,
+                        };
+                        let send_future = self.data_memory.input.send(event);
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("PlaintextResult(from: {})", s.peer_id)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will lose result of non-linear instruction. for now fail fast to see this."),
+                        }
+                    }
                 }
                 continue;
             }
@@ -451,12 +627,12 @@ impl NetworkBehaviour for Behaviour {
                             }
                         }
                     },
-                    data_memory::OutEvent::AssignedStoreSuccess(full_shard_id) => {
+                    data_memory::OutEvent::AssignedStoreSuccess(full_shard_id, checksum) => {
                         debug!(
                             target: Targets::DataDistribution.into_str(),
                             "Notifying other nodes that we store shard {:?} via consensus tx", full_shard_id
                         );
-                        let event = consensus::graph::InEvent::ScheduleTx(Transaction::Stored(full_shard_id.0, full_shard_id.1));
+                        let event = consensus::InEvent::ScheduleTx(Transaction::Stored(full_shard_id.0, full_shard_id.1, checksum));
                         let send_future = self.consensus.input.send(event.clone());
                         pin_mut!(send_future);
                         match send_future.poll(cx) {
@@ -488,6 +664,79 @@ impl NetworkBehaviour for Behaviour {
                             }
                         }
                     },
+                    data_memory::OutEvent::AuditRequest(full_shard_id, challenge, holder) => {
+                        let request = protocol::Request::Audit(full_shard_id, challenge);
+                        let send_future = self.request_response
+                            .input
+                            .send(crate::request_response::InEvent::MakeRequest{
+                                request: request.clone(),
+                                to: holder,
+                            });
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("network.request", format!("{:?}", request)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `network.request` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`network.request` queue is full. continuing will drop our request. for now fail fast to see this."),
+                        }
+                    },
+                    data_memory::OutEvent::AuditResponse(full_shard_id, challenge, answer) => {
+                        let request = protocol::Request::Audit(full_shard_id, challenge);
+                        let response = protocol::Response::Audit(answer);
+                        let waiting_for_response = self.processed_requests.remove(&request).unwrap_or_default();
+                        for (request_id, sender) in waiting_for_response {
+                            let send_future = self.request_response
+                                .input
+                                .send(crate::request_response::InEvent::Respond {
+                                    request_id,
+                                    channel: sender,
+                                    response: response.clone(),
+                                });
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("network.response", format!("{:?}", response)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `network.response` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`network.response` queue is full. continuing will ignore someone's request. for now fail fast to see this."),
+                            }
+                        }
+                    },
+                    data_memory::OutEvent::AuditFinished { peer, passed } => {
+                        self.metrics.record_audit(peer, passed);
+                    },
+                    data_memory::OutEvent::ResultChecked { data_id, verdict } => {
+                        debug!(
+                            target: Targets::ProgramExecution.into_str(),
+                            "Checked result {:?}: {} holder(s) agreed, {} disagreed",
+                            data_id, verdict.agreed.len(), verdict.disagreed.len()
+                        );
+                        for peer in verdict.agreed {
+                            self.metrics.record_result_check(peer, true);
+                        }
+                        for peer in verdict.disagreed {
+                            self.metrics.record_result_check(peer, false);
+                        }
+                    },
+                    data_memory::OutEvent::PlaintextOperands { step, operands, coordinator } => {
+                        debug!(
+                            target: Targets::ProgramExecution.into_str(),
+                            "Sending operands of {:?} to {:?}", step, coordinator
+                        );
+                        return Poll::Ready(ToSwarm::NotifyHandler {
+                            peer_id: coordinator,
+                            handler: NotifyHandler::Any,
+                            event: protocol::Simple::PlaintextOperands(step, operands).into(),
+                        });
+                    },
+                    data_memory::OutEvent::PlaintextResult { step, shards, holder } => {
+                        debug!(
+                            target: Targets::ProgramExecution.into_str(),
+                            "Sending result of {:?} to {:?}", step, holder
+                        );
+                        return Poll::Ready(ToSwarm::NotifyHandler {
+                            peer_id: holder,
+                            handler: NotifyHandler::Any,
+                            event: protocol::Simple::PlaintextResult(step, shards).into(),
+                        });
+                    },
                     data_memory::OutEvent::DistributionSufficient(_data_id) => {
                         // todo: inform somehow
                         // let event = module::OutEvent::PutConfirmed(data_id);
@@ -509,23 +758,60 @@ impl NetworkBehaviour for Behaviour {
                             Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
                         }
                     },
-                    data_memory::OutEvent::ListDistributed(list) => {
-                        let send_future = self.user_interaction.output.send(
-                            module::OutEvent::ListStoredResponse(list)
-                        );
-                        pin_mut!(send_future);
-                        match send_future.poll(cx) {
-                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", "ListStoredResponse"),
-                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
-                            Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
-                        }
+                    data_memory::OutEvent::ListDistributed(list) => match self.list_requests.pop_front() {
+                        Some(ListRequest::User) => {
+                            let send_future = self.user_interaction.output.send(
+                                module::OutEvent::ListStoredResponse(list)
+                            );
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", "ListStoredResponse"),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                            }
+                        },
+                        Some(ListRequest::Validate { instructions, template_call }) => {
+                            let stored = list.into_iter().map(|(data_id, _)| data_id).collect();
+                            let response = match validation::validate(&instructions, &stored) {
+                                Ok(()) => {
+                                    let tx = match template_call {
+                                        Some((template_hash, bindings)) => Transaction::ExecuteTemplate { template_hash, bindings },
+                                        None => Transaction::Execute(instructions),
+                                    };
+                                    let send_future = self.consensus.input.send(
+                                        consensus::InEvent::ScheduleTx(tx)
+                                    );
+                                    pin_mut!(send_future);
+                                    match send_future.poll(cx) {
+                                        Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", "ScheduleTx(_)"),
+                                        Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                        Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                                    }
+                                    Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                                    self.consensus_gossip_timer.reset_full();
+                                    module::OutEvent::ScheduleOk
+                                },
+                                Err(e) => {
+                                    warn!("Rejecting program: {}", e);
+                                    module::OutEvent::ScheduleRejected(e)
+                                },
+                            };
+                            let send_future = self.user_interaction.output.send(response.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", response)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                            }
+                        },
+                        None => warn!("Received list of distributed data that was not requested, ignoring"),
                     },
-                    data_memory::OutEvent::PreparedServiceResponse(data_id) => {
+                    data_memory::OutEvent::PreparedServiceResponse(data_id, checksums) => {
                         debug!(
                             target: Targets::DataDistribution.into_str(),
                             "Placing storage request for {:?} onto consensus to notify peers", data_id
                         );
-                        let event = consensus::graph::InEvent::ScheduleTx(Transaction::StorageRequest { data_id });
+                        let event = consensus::InEvent::ScheduleTx(Transaction::StorageRequest { data_id, checksums });
                         let send_future = self.consensus.input.send(event.clone());
                         pin_mut!(send_future);
                         match send_future.poll(cx) {
@@ -536,6 +822,16 @@ impl NetworkBehaviour for Behaviour {
                         Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
                         self.consensus_gossip_timer.reset_full();
                     },
+                    data_memory::OutEvent::PrepareServiceFailed(data_id, e) => {
+                        let event = module::OutEvent::PutRejected(data_id, e);
+                        let send_future = self.user_interaction.output.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                        }
+                    },
                     data_memory::OutEvent::AssignedRequest(full_shard_id, location) => {
                         let request = protocol::Request::GetShard(full_shard_id);
                         channel_log_send!("network.request", format!("{:?}", request));
@@ -574,6 +870,83 @@ impl NetworkBehaviour for Behaviour {
                             Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
                             Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
                         }
+                        // initial members are assumed to have default capacity
+                        if self.capacity != DEFAULT_CAPACITY {
+                            debug!("Announcing capacity {}", self.capacity);
+                            let event = consensus::InEvent::ScheduleTx(Transaction::Join { capacity: self.capacity });
+                            let send_future = self.consensus.input.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will keep default capacity of this peer. for now fail fast to see this."),
+                            }
+                            Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                            self.consensus_gossip_timer.reset_full();
+                        }
+                    },
+                    data_memory::OutEvent::RepairFinished { shards } => {
+                        debug!("Announcing {} restored shards", shards.len());
+                        let event = consensus::InEvent::ScheduleTx(Transaction::ShardsRepaired { shards });
+                        let send_future = self.consensus.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will hide repaired shards from other peers. for now fail fast to see this."),
+                        }
+                        Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                        self.consensus_gossip_timer.reset_full();
+                    },
+                    data_memory::OutEvent::Deleted(data_id) => {
+                        let send_future = self.user_interaction.output.send(
+                            module::OutEvent::Deleted(data_id.clone())
+                        );
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("Deleted({:?})", data_id)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                        }
+                    },
+                    data_memory::OutEvent::EvictionRequest(peer) => {
+                        debug!("Proposing eviction of lost peer {:?}", peer);
+                        let event = consensus::InEvent::ScheduleTx(Transaction::Evict(peer));
+                        let send_future = self.consensus.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will leave shards of the lost peer unrepaired. for now fail fast to see this."),
+                        }
+                        Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                        self.consensus_gossip_timer.reset_full();
+                    },
+                    data_memory::OutEvent::DistributionChanged { epoch } => {
+                        let send_future = self.user_interaction.output.send(
+                            module::OutEvent::DistributionChanged(epoch)
+                        );
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("DistributionChanged({})", epoch)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                        }
+                    },
+                    data_memory::OutEvent::Snapshot(data) => {
+                        self.generation_snapshots.data_taken(data);
+                        self.generation_snapshots.assemble();
+                    },
+                    data_memory::OutEvent::InitializationRejected { local, cluster } => {
+                        let send_future = self.user_interaction.output.send(
+                            module::OutEvent::StorageInitializationRejected { local, cluster }
+                        );
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", "StorageInitializationRejected"),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                        }
                     },
                 },
                 Poll::Ready(None) => cant_operate_error_return!("other half of `data_memory.output` was closed. cannot operate without this module."),
@@ -587,26 +960,64 @@ impl NetworkBehaviour for Behaviour {
             match self.user_interaction.input.poll_recv(cx) {
                 Poll::Ready(Some(event)) => match event {
                     InEvent::ScheduleProgram(instructions) => {
-                        let send_future = self.consensus.input.send(
-                            consensus::graph::InEvent::ScheduleTx(Transaction::Execute(instructions))
+                        // program is checked against stored data before scheduling
+                        let send_future = self.data_memory.input.send(
+                            data_memory::InEvent::ListDistributed
                         );
                         pin_mut!(send_future);
                         match send_future.poll(cx) {
-                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", "ScheduleTx(Execute(_))"),
-                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
-                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                            Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", "ListDistributed"),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
                         }
-                        Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
-                        let send_future = self.user_interaction.output.send(
-                            module::OutEvent::ScheduleOk
-                        );
+                        self.list_requests.push_back(ListRequest::Validate { instructions, template_call: None });
+                    },
+                    InEvent::RegisterTemplate(template) => {
+                        let response = match validation::check_size(&template).and_then(|()| {
+                            template.hash().map_err(|e| validation::Error::Serialization(e.to_string()))
+                        }) {
+                            Ok(hash) => {
+                                let event = consensus::InEvent::ScheduleTx(Transaction::RegisterTemplate(template));
+                                let send_future = self.consensus.input.send(event);
+                                pin_mut!(send_future);
+                                match send_future.poll(cx) {
+                                    Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", "ScheduleTx(RegisterTemplate(_))"),
+                                    Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                    Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                                }
+                                Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                                self.consensus_gossip_timer.reset_full();
+                                module::OutEvent::TemplateRegistrationScheduled(hash)
+                            },
+                            Err(e) => {
+                                warn!("Rejecting template: {}", e);
+                                module::OutEvent::TemplateRegistrationRejected(e)
+                            },
+                        };
+                        let send_future = self.user_interaction.output.send(response.clone());
                         pin_mut!(send_future);
                         match send_future.poll(cx) {
-                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", "ScheduleOk"),
+                            Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", response)),
                             Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
                             Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
                         }
-                        self.consensus_gossip_timer.reset_full();
+                    },
+                    InEvent::ListPrograms(_) | InEvent::Cancel(_) | InEvent::ListTemplates(_) | InEvent::ScheduleTemplate { .. } => {
+                        let event = match event {
+                            InEvent::ListPrograms(prefix) => instruction_storage::InEvent::ListPrograms { prefix },
+                            InEvent::Cancel(prefix) => instruction_storage::InEvent::CancelRequest { prefix },
+                            InEvent::ListTemplates(prefix) => instruction_storage::InEvent::ListTemplates { prefix },
+                            // instantiated program is validated like the usual ones
+                            InEvent::ScheduleTemplate { prefix, bindings } => instruction_storage::InEvent::InstantiateTemplate { prefix, bindings },
+                            _ => unreachable!(),
+                        };
+                        let send_future = self.instruction_memory.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("instruction_memory.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `instruction_memory.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`instruction_memory.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                        }
                     },
                     InEvent::Get(data_id) => {
                         debug!(target: Targets::DataRecollection.into_str(), "Starting recollection of data {:?}", data_id);
@@ -631,6 +1042,19 @@ impl NetworkBehaviour for Behaviour {
                             Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
                         }
                     },
+                    InEvent::Delete(data_id) => {
+                        debug!("Proposing deletion of data {:?}", data_id);
+                        let event = consensus::InEvent::ScheduleTx(Transaction::Delete(data_id));
+                        let send_future = self.consensus.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                        }
+                        Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                        self.consensus_gossip_timer.reset_full();
+                    },
                     InEvent::ListStored => {
                         let send_future = self.data_memory.input.send(
                             data_memory::InEvent::ListDistributed
@@ -641,11 +1065,12 @@ impl NetworkBehaviour for Behaviour {
                             Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
                             Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
                         }
+                        self.list_requests.push_back(ListRequest::User);
                     },
                     InEvent::InitializeStorage => {
                         debug!(target: Targets::StorageInitialization.into_str(), "Starting storage initialization, getting list of known peers");
                         let send_future = self.consensus.input.send(
-                            consensus::graph::InEvent::KnownPeersRequest
+                            consensus::InEvent::KnownPeersRequest
                         );
                         pin_mut!(send_future);
                         match send_future.poll(cx) {
@@ -655,6 +1080,25 @@ impl NetworkBehaviour for Behaviour {
                         }
                         Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
                     },
+                    InEvent::Join | InEvent::Leave | InEvent::Evict(_) => {
+                        let tx = match event {
+                            InEvent::Join => Transaction::Join { capacity: self.capacity },
+                            InEvent::Leave => Transaction::Leave,
+                            InEvent::Evict(peer) => Transaction::Evict(peer),
+                            _ => unreachable!(),
+                        };
+                        debug!("Proposing membership change {}", tx.variant_short_string());
+                        let event = consensus::InEvent::ScheduleTx(tx);
+                        let send_future = self.consensus.input.send(event.clone());
+                        pin_mut!(send_future);
+                        match send_future.poll(cx) {
+                            Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                            Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will not notify other peers on membership change. for now fail fast to see this."),
+                        }
+                        Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                        self.consensus_gossip_timer.reset_full();
+                    },
                     InEvent::GetMetrics => {
                         let metrics = self.metrics.clone();
                         let send_future = self.user_interaction.output.send(
@@ -678,7 +1122,22 @@ impl NetworkBehaviour for Behaviour {
             match self.processor.output.poll_recv(cx) {
                 Poll::Ready(Some(single_threaded::OutEvent::FinishedExecution { program_id, results })) => {
                     debug!("Finished executing program {:?}\nResults: {:?}", program_id.clone(), results);
-                    let event = consensus::graph::InEvent::ScheduleTx(Transaction::Executed(program_id));
+                    let (updated_shards, failures): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+                    let failures = failures.into_iter().filter_map(Result::err).collect();
+                    let event = instruction_storage::InEvent::ExecutedLocally { program_id: program_id.clone(), failures };
+                    let send_future = self.instruction_memory.input.send(event);
+                    pin_mut!(send_future);
+                    match send_future.poll(cx) {
+                        Poll::Ready(Ok(_)) => channel_log_send!("instruction_memory.input", "ExecutedLocally"),
+                        Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `instruction_memory.input` was closed. cannot operate without this module."),
+                        Poll::Pending => cant_operate_error_return!("`instruction_memory.input` queue is full. continuing will stop program execution. for now fail fast to see this."),
+                    }
+                    if !self.tx_validator.is_scheduled(&program_id) {
+                        debug!("Program {:?} was cancelled during execution, not announcing it", program_id);
+                        continue;
+                    }
+                    let updated_shards: HashSet<_> = updated_shards.into_iter().filter_map(Result::ok).collect();
+                    let event = consensus::InEvent::ScheduleTx(Transaction::Executed(program_id, updated_shards.into_iter().collect()));
                     let send_future = self.consensus.input.send(event.clone());
                     pin_mut!(send_future);
                     match send_future.poll(cx) {
@@ -694,52 +1153,107 @@ impl NetworkBehaviour for Behaviour {
             }
         }
 
+        // programs are given one by one, after the processor finishes the
+        // previous one, so there is no need to check if it's busy
+        trace!("Checking instruction memory events");
         loop {
-            if self.processor.accepts_input() {
-                trace!("Checking instruction memory events");
-                match self.instruction_memory.output.poll_recv(cx) {
-                    Poll::Ready(Some(event)) => {
-                        match event {
-                            instruction_storage::OutEvent::NextProgram(program) => {
-                                let send_future = self.processor.input.send(single_threaded::InEvent::Execute(program));
-                                pin_mut!(send_future);
-                                match send_future.poll(cx) {
-                                    Poll::Ready(Ok(_)) => channel_log_send!("processor.input", "Execute"),
-                                    Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `processor.input` was closed. cannot operate without this module."),
-                                    Poll::Pending => cant_operate_error_return!("`processor.input` queue is full. continuing will skip a program for execution, which is unacceptable."),
-                                }
+            match self.instruction_memory.output.poll_recv(cx) {
+                Poll::Ready(Some(event)) => {
+                    match event {
+                        instruction_storage::OutEvent::NextProgram(program) => {
+                            let send_future = self.processor.input.send(single_threaded::InEvent::Execute(program));
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("processor.input", "Execute"),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `processor.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`processor.input` queue is full. continuing will skip a program for execution, which is unacceptable."),
                             }
-                            instruction_storage::OutEvent::FinishedExecution(program_id) => {
-                                let event = module::OutEvent::ProgramExecuted(program_id);
-                                let send_future = self.user_interaction.output.send(event.clone());
-                                pin_mut!(send_future);
-                                match send_future.poll(cx) {
-                                    Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", event)),
-                                    Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
-                                    Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
-                                }
-                            },
-                            instruction_storage::OutEvent::PeerShardsActualized {
-                                program_id: _, peer, updated_data_ids
-                            } => {
-                                let event = data_memory::InEvent::PeerShardsActualized { peer, updated_data_ids };
-                                channel_log_send!("data_memory.input", format!("{:?}", event));
-                                let send_future = self.data_memory.input.send(event);
-                                pin_mut!(send_future);
-                                match send_future.poll(cx) {
-                                    Poll::Ready(Ok(_)) => (),
-                                    Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
-                                    Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will discard shard served, which is not cool (?). at least it is in development."),
-                                }
-                            },
                         }
+                        instruction_storage::OutEvent::FinishedExecution(program_id) => {
+                            let event = module::OutEvent::ProgramExecuted(program_id);
+                            let send_future = self.user_interaction.output.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                            }
+                        },
+                        instruction_storage::OutEvent::PeerShardsActualized {
+                            program_id, peer, updated_shards
+                        } => {
+                            let event = data_memory::InEvent::PeerShardsActualized { program_id, peer, updated_shards };
+                            channel_log_send!("data_memory.input", format!("{:?}", event));
+                            let send_future = self.data_memory.input.send(event);
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => (),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will discard shard served, which is not cool (?). at least it is in development."),
+                            }
+                        },
+                        instruction_storage::OutEvent::CancelAllowed(program_id) => {
+                            let event = consensus::InEvent::ScheduleTx(Transaction::Cancel(program_id.clone()));
+                            let send_future = self.consensus.input.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                            }
+                            Metrics::update_queue_size(&self.consensus.input, &mut self.metrics.consensus_queue_size);
+                            self.consensus_gossip_timer.reset_full();
+                            let event = module::OutEvent::CancelScheduled(program_id);
+                            let send_future = self.user_interaction.output.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                            }
+                        },
+                        instruction_storage::OutEvent::TemplateInstantiated { template_hash, bindings, instructions } => {
+                            let send_future = self.data_memory.input.send(
+                                data_memory::InEvent::ListDistributed
+                            );
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", "ListDistributed"),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing might not fulfill user's expectations. for now fail fast to see this."),
+                            }
+                            self.list_requests.push_back(ListRequest::Validate {
+                                instructions,
+                                template_call: Some((template_hash, bindings)),
+                            });
+                        },
+                        instruction_storage::OutEvent::Snapshot(instructions) => {
+                            self.generation_snapshots.instructions_taken(instructions);
+                            self.generation_snapshots.assemble();
+                        },
+                        instruction_storage::OutEvent::Programs(_)
+                        | instruction_storage::OutEvent::CancelRejected { .. }
+                        | instruction_storage::OutEvent::Templates(_)
+                        | instruction_storage::OutEvent::TemplateRejected { .. } => {
+                            let event = match event {
+                                instruction_storage::OutEvent::Programs(programs) => module::OutEvent::Programs(programs),
+                                instruction_storage::OutEvent::CancelRejected { prefix, reason } => module::OutEvent::CancelRejected { prefix, reason },
+                                instruction_storage::OutEvent::Templates(templates) => module::OutEvent::Templates(templates),
+                                instruction_storage::OutEvent::TemplateRejected { prefix, reason } => module::OutEvent::TemplateRejected { prefix, reason },
+                                _ => unreachable!(),
+                            };
+                            let send_future = self.user_interaction.output.send(event);
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", "Programs"),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave user request unanswered. for now fail fast to see this."),
+                            }
+                        },
                     }
-                    Poll::Ready(None) => cant_operate_error_return!("other half of `instruction_memory.output` was closed. cannot operate without this module."),
-                    Poll::Pending => break,
                 }
-            } else {
-                trace!("Processor is busy, not polling instruction memory");
-                break;
+                Poll::Ready(None) => cant_operate_error_return!("other half of `instruction_memory.output` was closed. cannot operate without this module."),
+                Poll::Pending => break,
             }
         }
 
@@ -747,7 +1261,7 @@ impl NetworkBehaviour for Behaviour {
         loop {
             match self.consensus.output.poll_recv(cx) {
                 Poll::Ready(Some(event)) => match event {
-                    consensus::graph::OutEvent::GenerateSyncResponse { to, sync } => {
+                    consensus::OutEvent::GenerateSyncResponse { to, sync } => {
                         debug!(
                             target: Targets::Synchronization.into_str(),
                             "Sending sync to {}", to
@@ -759,24 +1273,30 @@ impl NetworkBehaviour for Behaviour {
                             event: protocol::Simple::GossipGraph(sync).into(),
                         });
                     }
-                    consensus::graph::OutEvent::KnownPeersResponse(peers) => {
+                    consensus::OutEvent::PeerTooFarBehind { to, generation } => {
+                        self.metrics.sync.record_end();
+                        return Poll::Ready(ToSwarm::NotifyHandler {
+                            peer_id: to,
+                            handler: NotifyHandler::Any,
+                            event: protocol::Simple::TooFarBehind { generation }.into(),
+                        });
+                    }
+                    consensus::OutEvent::KnownPeersResponse(peers) => {
                         let mut peers = HashSet::<_>::from_iter(peers.into_iter());
                         for p in &self.connected_peers {
                             peers.insert(*p);
                         }
-                        let peers = peers
-                            .into_iter()
-                            .enumerate()
-                            .map(|(i, peer)| (peer, Sid(i.try_into().unwrap())))
-                            .collect();
-                        debug!(target: Targets::StorageInitialization.into_str(), "Initializing storage with distribution {:?}", peers);
-                        info!("Initializing storage with distribution {:?}", peers);
+                        let peers: Vec<_> = peers.into_iter().collect();
+                        debug!(target: Targets::StorageInitialization.into_str(), "Initializing storage with members {:?}", peers);
+                        info!("Initializing storage with members {:?} and {:?} placement", peers, self.placement);
                         let send_future =
                             self.consensus
                                 .input
-                                .send(consensus::graph::InEvent::ScheduleTx(
+                                .send(consensus::InEvent::ScheduleTx(
                                     Transaction::InitializeStorage {
-                                        distribution: peers,
+                                        members: peers,
+                                        placement: self.placement,
+                                        encoding: self.encoding_settings.clone(),
                                     },
                                 ));
                         pin_mut!(send_future);
@@ -791,30 +1311,113 @@ impl NetworkBehaviour for Behaviour {
                         );
                         self.consensus_gossip_timer.reset_full();
                     }
-                    consensus::graph::OutEvent::RecognizedTransaction {
+                    consensus::OutEvent::RecognizedTransaction {
                         from,
                         tx,
                         event_hash,
                     } => {
-                        info!("Recognized tx: {}", tx.variant_short_string());
+                        // the order may still change, transactions are applied once finalized
+                        debug!("Recognized tx {} by {:?} in event {:?}", tx.variant_short_string(), from, event_hash);
                         self.consensus_gossip_timer.reset_full();
-                        match self.handle_tx(cx, from, tx, event_hash) {
-                            handlers::HandleResult::Ok => (),
-                            handlers::HandleResult::Abort => {
-                                return Poll::Ready(libp2p::swarm::ToSwarm::GenerateEvent(Err(
-                                    Error::UnableToOperate,
-                                )))
+                    }
+                    consensus::OutEvent::FinalizedTransaction {
+                        from,
+                        tx,
+                        event_hash,
+                    } => {
+                        info!("Finalized tx: {}", tx.variant_short_string());
+                        match &mut self.fast_sync {
+                            // applied after the snapshot
+                            Some(fast_sync) => fast_sync.push((from, tx, event_hash)),
+                            None => {
+                                self.last_finalized = Some((self.generation, event_hash.clone().into()));
+                                if let handlers::HandleResult::Abort = self.handle_tx(cx, from, tx, event_hash) {
+                                    return Poll::Ready(ToSwarm::GenerateEvent(Err(Error::UnableToOperate)));
+                                }
                             }
                         }
                     }
-                    consensus::graph::OutEvent::FinalizedTransaction {
-                        from: _,
+                    consensus::OutEvent::RestoredCheckpoint { generation, state } => {
+                        match bincode::deserialize(&state) {
+                            Ok(state) => {
+                                debug!("Restoring validator state of generation {}", generation);
+                                self.tx_validator.install(state);
+                                self.generation = generation;
+                            }
+                            Err(e) => cant_operate_error_return!("checkpoint of generation {} in the journal is corrupted: {}. continuing would validate transactions differently from other peers.", generation, e),
+                        }
+                    }
+                    consensus::OutEvent::ReplayedTransaction {
+                        from,
                         tx,
-                        event_hash: _,
+                        event_hash,
+                    } => match &mut self.fast_sync {
+                        // fast sync was not finished before restart, it starts over
+                        Some(fast_sync) => fast_sync.push((from, tx, event_hash)),
+                        None => {
+                            // already applied before restart, only the validator follows it
+                            self.last_finalized = Some((self.generation, event_hash.clone().into()));
+                            if let Err(reason) = self.tx_validator.validate(&from, &tx, &event_hash.into()) {
+                                trace!("Replayed {} by {:?} is rejected as before: {}", tx.variant_short_string(), from, reason);
+                            }
+                        }
+                    },
+                    consensus::OutEvent::GenerationStarted {
+                        generation,
+                        skipped,
+                        replayed,
                     } => {
-                        debug!("Finalized tx: {:?}", tx);
-                        // it's already finalized, reset not necessary I suppose
-                        // self.consensus_gossip_timer.reset_full();
+                        self.generation = generation;
+                        if skipped {
+                            warn!("Skipped to generation {}, taking the state from a snapshot", generation);
+                            self.fast_sync = Some(FastSync::new(generation, self.tx_validator.state().distribution));
+                            let event = module::OutEvent::FastSyncStarted { generation };
+                            let send_future = self.user_interaction.output.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("user_interaction.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `user_interaction.output` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`user_interaction.output` queue is full. continuing will leave the user uninformed. for now fail fast to see this."),
+                            }
+                        } else if let Some(fast_sync) = &mut self.fast_sync {
+                            fast_sync.on_generation(generation);
+                        } else if !replayed {
+                            // both modules take their part after the transactions
+                            // of the previous generations
+                            debug!("Generation {} started, taking a snapshot", generation);
+                            let event = data_memory::InEvent::TakeSnapshot;
+                            let send_future = self.data_memory.input.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will leave peers behind without a snapshot. for now fail fast to see this."),
+                            }
+                            let event = instruction_storage::InEvent::TakeSnapshot;
+                            let send_future = self.instruction_memory.input.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("instruction_memory.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `instruction_memory.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`instruction_memory.input` queue is full. continuing will leave peers behind without a snapshot. for now fail fast to see this."),
+                            }
+                            let validator_state = self.tx_validator.state();
+                            match bincode::serialize(&validator_state) {
+                                Ok(state) => {
+                                    // consensus compacts its journal to it, so transactions
+                                    // finalized before are not needed after restart
+                                    let send_future = self.consensus.input.send(consensus::InEvent::Checkpoint { generation, state });
+                                    pin_mut!(send_future);
+                                    match send_future.poll(cx) {
+                                        Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("Checkpoint {{ generation: {} }}", generation)),
+                                        Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                        Poll::Pending => warn!("`consensus.input` queue is full. skipping checkpoint of generation {}, the journal is compacted on the next one.", generation),
+                                    }
+                                }
+                                Err(e) => warn!("Could not serialize validator state, skipping checkpoint: {}", e),
+                            }
+                            self.generation_snapshots.push(generation, self.last_finalized.clone(), validator_state);
+                        }
                     }
                 },
                 Poll::Ready(None) => cant_operate_error_return!(
@@ -832,12 +1435,30 @@ impl NetworkBehaviour for Behaviour {
 
             // Time to send another one
             self.consensus_gossip_timer.start_next();
+            if let (Some(random_peer), Some(fast_sync)) = (random_peer, &mut self.fast_sync) {
+                if fast_sync.should_ask(&random_peer) {
+                    debug!("Requesting state snapshot from {:?}", random_peer);
+                    let request = protocol::Request::Snapshot;
+                    let send_future = self.request_response
+                        .input
+                        .send(crate::request_response::InEvent::MakeRequest{
+                            request: request.clone(),
+                            to: random_peer,
+                        });
+                    pin_mut!(send_future);
+                    match send_future.poll(cx) {
+                        Poll::Ready(Ok(_)) => channel_log_send!("network.request", format!("{:?}", request)),
+                        Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `network.request` was closed. cannot operate without this module."),
+                        Poll::Pending => cant_operate_error_return!("`network.request` queue is full. continuing will drop our request. for now fail fast to see this."),
+                    }
+                }
+            }
             if let Some(random_peer) = random_peer {
                 trace!("Before gossip make a standalone event");
                 let send_future = self
                     .consensus
                     .input
-                    .send(consensus::graph::InEvent::CreateStandalone);
+                    .send(consensus::InEvent::CreateStandalone);
                 pin_mut!(send_future);
                 match send_future.poll(cx) {
                     Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", "CreateStandalone"),
@@ -851,7 +1472,7 @@ impl NetworkBehaviour for Behaviour {
                 };
 
                 debug!("Chose {:?} for random gossip", random_peer);
-                let event = consensus::graph::InEvent::GenerateSyncRequest { to: random_peer };
+                let event = consensus::InEvent::GenerateSyncRequest { to: random_peer };
                 let send_future = self.consensus.input.send(event.clone());
                 pin_mut!(send_future);
                 match send_future.poll(cx) {
@@ -885,6 +1506,21 @@ impl NetworkBehaviour for Behaviour {
                         crate::request_response::OutEvent::AssignedRequestId { request_id, request } => {
                             self.pending_response.insert(request_id, request);
                         },
+                        crate::request_response::OutEvent::PeerUnreachable { request_id, peer } => {
+                            let full_shard_id = match self.pending_response.remove(&request_id) {
+                                Some(protocol::Request::GetShard(full_shard_id)) => full_shard_id,
+                                _ => continue,
+                            };
+                            debug!("Could not reach {:?} to get shard {:?}, considering it lost", peer, full_shard_id);
+                            let event = data_memory::InEvent::PeerLost(peer);
+                            let send_future = self.data_memory.input.send(event.clone());
+                            pin_mut!(send_future);
+                            match send_future.poll(cx) {
+                                Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("{:?}", event)),
+                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will leave shards of the lost peer unrepaired. for now fail fast to see this."),
+                            }
+                        },
                         crate::request_response::OutEvent::Response { request_id, response } => {
                             match self.pending_response.get(&request_id) {
                                 Some(request) => match (request, response) {
@@ -935,6 +1571,55 @@ impl NetworkBehaviour for Behaviour {
                                             Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will discard shard served, which is not cool (?). at least it is in development."),
                                         };
                                     }
+                                    (
+                                        protocol::Request::Audit(full_shard_id, _),
+                                        protocol::Response::Audit(answer),
+                                    ) => {
+                                        channel_log_recv!(
+                                            "network.response",
+                                            format!("Audit({:?})", &full_shard_id)
+                                        );
+                                        let event = data_memory::InEvent::AuditResponse(full_shard_id.clone(), answer);
+                                        let send_future = self.data_memory.input.send(event.clone());
+                                        pin_mut!(send_future);
+                                        match send_future.poll(cx) {
+                                            Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("{:?}", event)),
+                                            Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                            Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will lose result of the audit. for now fail fast to see this."),
+                                        }
+                                    }
+                                    (
+                                        protocol::Request::Snapshot,
+                                        protocol::Response::Snapshot(snapshot),
+                                    ) => {
+                                        channel_log_recv!(
+                                            "network.response",
+                                            format!("Snapshot(is_some: {:?})", snapshot.is_some())
+                                        );
+                                        let installable = self
+                                            .fast_sync
+                                            .as_mut()
+                                            .and_then(|fast_sync| fast_sync.on_response(snapshot));
+                                        if let Some(snapshot) = installable {
+                                            let fast_sync = self.fast_sync.take().expect("snapshot is taken by fast sync");
+                                            self.last_finalized = fast_sync.last_finalized().or_else(|| snapshot.last_finalized.clone());
+                                            let buffered = fast_sync.into_buffered();
+                                            if let handlers::HandleResult::Abort = self.install_snapshot(cx, snapshot, buffered) {
+                                                return Poll::Ready(ToSwarm::GenerateEvent(Err(Error::UnableToOperate)));
+                                            }
+                                        } else if let Some(generation) = self.fast_sync.as_ref().and_then(FastSync::ahead) {
+                                            // fast sync starts over once consensus skips
+                                            warn!("Most members are at generation {} already, skipping to it", generation);
+                                            let event = consensus::InEvent::SkipToGeneration(generation);
+                                            let send_future = self.consensus.input.send(event.clone());
+                                            pin_mut!(send_future);
+                                            match send_future.poll(cx) {
+                                                Poll::Ready(Ok(_)) => channel_log_send!("consensus.input", format!("{:?}", event)),
+                                                Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `consensus.input` was closed. cannot operate without this module."),
+                                                Poll::Pending => cant_operate_error_return!("`consensus.input` queue is full. continuing will leave this peer behind. for now fail fast to see this."),
+                                            }
+                                        }
+                                    }
                                     (request, response) => {
                                         warn!("Response does not match request (id {})", request_id);
                                         trace!("request: {:?}, response: {:?}", request, response);
@@ -976,10 +1661,28 @@ impl NetworkBehaviour for Behaviour {
                                         Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will ignore some peer's request, which is unacceptable (?)."),
                                     }
                                 }
+                                protocol::Request::Audit(full_shard_id, challenge) => {
+                                    let event = data_memory::InEvent::AuditRequest(full_shard_id, challenge);
+                                    let send_future = self.data_memory.input.send(event.clone());
+                                    pin_mut!(send_future);
+                                    match send_future.poll(cx) {
+                                        Poll::Ready(Ok(_)) => channel_log_send!("data_memory.input", format!("{:?}", event)),
+                                        Poll::Ready(Err(_e)) => cant_operate_error_return!("other half of `data_memory.input` was closed. cannot operate without this module."),
+                                        Poll::Pending => cant_operate_error_return!("`data_memory.input` queue is full. continuing will ignore some peer's request, which is unacceptable (?)."),
+                                    }
+                                }
+                                // answered below with the snapshot of the current generation
+                                protocol::Request::Snapshot => (),
                             }
                             channel_log_recv!("network.request", format!("{:?}", &request));
+                            let is_snapshot = matches!(request, protocol::Request::Snapshot);
                             let response_handlers = self.processed_requests.entry(request).or_default();
                             response_handlers.push((request_id, channel));
+                            if is_snapshot {
+                                if let handlers::HandleResult::Abort = self.respond_snapshots(cx) {
+                                    return Poll::Ready(ToSwarm::GenerateEvent(Err(Error::UnableToOperate)));
+                                }
+                            }
                         },
                     }
                 },
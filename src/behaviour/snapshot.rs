@@ -0,0 +1,625 @@
+//! State snapshots for peers too far behind to sync the graph.
+//!
+//! The graph is split into generations and peers keep only the previous
+//! one to sync others (see [`crate::consensus::graph::GraphWrapper`]). A peer
+//! more than a generation behind (or joining a running cluster) skips to the
+//! current generation and takes the state from a snapshot instead: data
+//! distribution, known shard locations and checksums, programs and
+//! templates.
+//!
+//! Each peer takes a snapshot when a generation starts, after applying the
+//! transactions of the previous ones, and gives it out until the next
+//! generation. The peer catching up asks several peers and buffers finalized
+//! transactions meanwhile. It installs a snapshot of a generation it has
+//! followed since the skip, then applies the transactions of that generation
+//! and later ones. If most members give snapshots of later generations only
+//! (the peer skipped to a wrong one), it skips again, to the lowest of them.
+//!
+//! A peer skips only once more than half of the members told it that it's
+//! too far behind ([`SkipVotes`]), to the lowest generation they named, so a
+//! single peer can't make it drop its graph.
+//!
+//! Snapshots are signed by the peers that made them. A snapshot is installed
+//! only when more than half of the members signed snapshots with the same
+//! state of the validation rules ([`ValidatorState`]), which is the same on
+//! all honest peers at the start of the generation. Members are the ones the
+//! peer knew before the skip. A peer that never saw the storage initialized
+//! doesn't install anything until it sees the initialization finalized after
+//! the skip, members named by the snapshots are not trusted. A snapshot also
+//! names the last event with transactions finalized before it, which has to
+//! match the transactions the peer followed since the skip. The rest
+//! of the state (shard locations and checksums, programs and templates)
+//! depends on what each peer checked itself, so it is taken from one of
+//! these signers.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use libp2p::{identity::ed25519, PeerId};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{
+    consensus::{
+        validation::{Validator, ValidatorState},
+        Transaction,
+    },
+    data_memory::{distribution::Distribution, DataSnapshot},
+    instruction_storage::InstructionSnapshot,
+    protocol::request_response::MAX_MESSAGE_SIZE,
+    signatures::EncodedEd25519Pubkey,
+    types::{Hash, Sid, Vid},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("The peer has no snapshot to give")]
+    Missing,
+    #[error("Could not (de)serialize the snapshot: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Public key of the snapshot is invalid")]
+    InvalidKey,
+    #[error("Signature does not match the snapshot")]
+    InvalidSignature,
+    #[error("Distribution of the data does not match the validator state")]
+    Inconsistent,
+    #[error("Snapshot of generation {0} is older than the skip")]
+    OtherGeneration(u64),
+    #[error("Last finalized event of the snapshot is not the one followed since the skip")]
+    OtherTail,
+}
+
+/// Finalized transaction as given by consensus
+pub type FinalizedTx = (
+    PeerId,
+    Transaction<Vid, Sid, PeerId>,
+    rust_hashgraph::algorithm::event::Hash,
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateSnapshot {
+    /// Includes transactions of the generations before this one
+    pub generation: u64,
+    /// Generation and hash of the last event with transactions finalized
+    /// before the snapshot
+    pub last_finalized: Option<(u64, Hash)>,
+    pub validator: ValidatorState,
+    pub data: DataSnapshot,
+    pub instructions: InstructionSnapshot,
+    pub pubkey: EncodedEd25519Pubkey,
+}
+
+/// Serialized [`StateSnapshot`] with signature of its bytes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedSnapshot {
+    snapshot: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl StateSnapshot {
+    pub fn signer(&self) -> PeerId {
+        PeerId::from(libp2p::identity::PublicKey::from(self.pubkey.clone()))
+    }
+}
+
+impl SignedSnapshot {
+    pub fn sign(snapshot: &StateSnapshot, keypair: &ed25519::Keypair) -> bincode::Result<Self> {
+        let snapshot = bincode::serialize(snapshot)?;
+        let signature = keypair.sign(&snapshot);
+        Ok(Self {
+            snapshot,
+            signature,
+        })
+    }
+
+    /// Check the signature and that the parts agree on the distribution.
+    /// Whether the signer is trusted is up to [`FastSync`].
+    pub fn verify(self) -> Result<StateSnapshot, Error> {
+        let snapshot: StateSnapshot = bincode::deserialize(&self.snapshot)?;
+        let pubkey = snapshot
+            .pubkey
+            .try_decode()
+            .map_err(|_| Error::InvalidKey)?;
+        if !pubkey.verify(&self.snapshot, &self.signature) {
+            return Err(Error::InvalidSignature);
+        }
+        if snapshot.validator.distribution.as_ref() != Some(&snapshot.data.distribution) {
+            return Err(Error::Inconsistent);
+        }
+        Ok(snapshot)
+    }
+}
+
+struct PendingSnapshot {
+    generation: u64,
+    last_finalized: Option<(u64, Hash)>,
+    validator: ValidatorState,
+    data: Option<Option<DataSnapshot>>,
+    instructions: Option<InstructionSnapshot>,
+}
+
+/// Snapshots taken at the start of generations, assembled from parts
+/// taken by the memory modules. The modules answer in the order of
+/// requests.
+pub struct GenerationSnapshots {
+    keypair: ed25519::Keypair,
+    pending: VecDeque<PendingSnapshot>,
+    /// Given out to peers asking for a snapshot
+    latest: Option<SignedSnapshot>,
+}
+
+impl GenerationSnapshots {
+    pub fn new(keypair: ed25519::Keypair) -> Self {
+        Self {
+            keypair,
+            pending: VecDeque::new(),
+            latest: None,
+        }
+    }
+
+    /// Parts of the snapshot were requested from the modules
+    pub fn push(
+        &mut self,
+        generation: u64,
+        last_finalized: Option<(u64, Hash)>,
+        validator: ValidatorState,
+    ) {
+        self.pending.push_back(PendingSnapshot {
+            generation,
+            last_finalized,
+            validator,
+            data: None,
+            instructions: None,
+        })
+    }
+
+    pub fn data_taken(&mut self, data: Option<DataSnapshot>) {
+        match self.pending.iter_mut().find(|p| p.data.is_none()) {
+            Some(pending) => pending.data = Some(data),
+            None => warn!("Received data snapshot that was not requested, ignoring"),
+        }
+    }
+
+    pub fn instructions_taken(&mut self, instructions: InstructionSnapshot) {
+        match self.pending.iter_mut().find(|p| p.instructions.is_none()) {
+            Some(pending) => pending.instructions = Some(instructions),
+            None => warn!("Received instruction snapshot that was not requested, ignoring"),
+        }
+    }
+
+    /// The snapshot to give out, if there is one
+    pub fn latest(&self) -> Option<SignedSnapshot> {
+        self.latest.clone()
+    }
+
+    /// Sign the snapshots with all parts taken. The latest one replaces the
+    /// previous, even if it can't be given out (e.g. the storage is not
+    /// initialized yet).
+    pub fn assemble(&mut self) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|first| first.data.is_some() && first.instructions.is_some())
+        {
+            let pending = self.pending.pop_front().unwrap();
+            self.latest = self.sign(pending);
+        }
+    }
+
+    fn sign(&self, pending: PendingSnapshot) -> Option<SignedSnapshot> {
+        let (Some(Some(data)), Some(instructions)) = (pending.data, pending.instructions) else {
+            return None;
+        };
+        let snapshot = StateSnapshot {
+            generation: pending.generation,
+            last_finalized: pending.last_finalized,
+            validator: pending.validator,
+            data,
+            instructions,
+            pubkey: self.keypair.public().into(),
+        };
+        let signed = match SignedSnapshot::sign(&snapshot, &self.keypair) {
+            Ok(signed) => signed,
+            Err(e) => {
+                warn!("Could not serialize snapshot: {}", e);
+                return None;
+            }
+        };
+        // leave some space for the rest of the response
+        if signed.snapshot.len() + signed.signature.len() > MAX_MESSAGE_SIZE - 1024 {
+            warn!(
+                "Snapshot takes {} bytes, which doesn't fit in a response",
+                signed.snapshot.len()
+            );
+            return None;
+        }
+        Some(signed)
+    }
+}
+
+/// Generations peers reported this one to be behind, as of their latest
+/// reports
+#[derive(Default)]
+pub struct SkipVotes {
+    reported: HashMap<PeerId, u64>,
+}
+
+impl SkipVotes {
+    /// Generation to skip to, once more than half of `members` reported
+    /// one. The lowest reported is taken, so that peers lying about it have
+    /// to be the majority.
+    pub fn report(
+        &mut self,
+        peer: PeerId,
+        generation: u64,
+        members: &HashSet<PeerId>,
+    ) -> Option<u64> {
+        if !members.contains(&peer) {
+            warn!(
+                "{:?} is not a member, ignoring its report of generation {}",
+                peer, generation
+            );
+            return None;
+        }
+        self.reported.insert(peer, generation);
+        self.reported.retain(|peer, _| members.contains(peer));
+        if self.reported.len() * 2 <= members.len() {
+            return None;
+        }
+        let lowest = self.reported.values().min().copied();
+        self.reported.clear();
+        lowest
+    }
+}
+
+/// Progress of fast sync on the peer that skipped generations
+pub struct FastSync {
+    /// Generation skipped to, snapshots of earlier ones miss transactions
+    /// that are not delivered
+    since: u64,
+    /// Generation of transactions finalized now
+    generation: u64,
+    /// Members known before the skip, whose signatures are counted
+    trusted: Option<Distribution>,
+    /// Follows the transactions finalized since the skip, for a peer that
+    /// didn't know members before
+    tail: Validator,
+    /// The latest verified snapshot of each signer
+    received: HashMap<PeerId, StateSnapshot>,
+    /// Finalized transactions with their generations
+    buffered: VecDeque<(u64, FinalizedTx)>,
+}
+
+impl FastSync {
+    /// `trusted` is the distribution the peer knew before skipping to
+    /// `generation`
+    pub fn new(generation: u64, trusted: Option<Distribution>) -> Self {
+        if trusted.is_none() {
+            warn!("Storage is not known to be initialized, snapshots are installed only after the initialization is finalized");
+        }
+        Self {
+            since: generation,
+            generation,
+            trusted,
+            tail: Validator::new(),
+            received: HashMap::new(),
+            buffered: VecDeque::new(),
+        }
+    }
+
+    pub fn on_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// `true` if the peer has not given a snapshot of the current
+    /// generation yet
+    pub fn should_ask(&self, peer: &PeerId) -> bool {
+        !matches!(
+            self.received.get(peer),
+            Some(snapshot) if snapshot.generation >= self.generation
+        )
+    }
+
+    /// Snapshot to install, once enough members signed the same state.
+    /// Transactions it includes are dropped from the buffer.
+    pub fn on_response(&mut self, response: Option<SignedSnapshot>) -> Option<StateSnapshot> {
+        let verified = response
+            .ok_or(Error::Missing)
+            .and_then(SignedSnapshot::verify)
+            .and_then(|snapshot| {
+                // later ones are kept to see if the peer is still behind
+                if snapshot.generation < self.since {
+                    Err(Error::OtherGeneration(snapshot.generation))
+                } else if snapshot.generation <= self.generation && !self.follows_tail(&snapshot) {
+                    Err(Error::OtherTail)
+                } else {
+                    Ok(snapshot)
+                }
+            });
+        match verified {
+            Ok(snapshot) => {
+                self.received.insert(snapshot.signer(), snapshot);
+            }
+            Err(e) => {
+                warn!("Snapshot is not usable: {}", e);
+                return None;
+            }
+        }
+        let snapshot = self.certified()?;
+        info!(
+            "Snapshot of generation {} is signed by most members, installing it",
+            snapshot.generation
+        );
+        self.buffered
+            .retain(|(generation, _)| generation >= &snapshot.generation);
+        Some(snapshot)
+    }
+
+    /// Whether the last finalized event named by the snapshot is the last one
+    /// followed before its generation. Events before the skip are not known,
+    /// so any of them is taken if none was followed.
+    fn follows_tail(&self, snapshot: &StateSnapshot) -> bool {
+        let followed = self
+            .buffered
+            .iter()
+            .rev()
+            .find(|(generation, _)| *generation < snapshot.generation);
+        match followed {
+            Some((generation, (_, _, event_hash))) => {
+                snapshot.last_finalized == Some((*generation, event_hash.clone().into()))
+            }
+            None => !matches!(
+                snapshot.last_finalized,
+                Some((generation, _)) if generation >= self.since
+            ),
+        }
+    }
+
+    /// Members whose signatures count for the state: known before the skip
+    /// or seen finalized since
+    fn members(&self) -> Option<&Distribution> {
+        self.trusted
+            .as_ref()
+            .or_else(|| self.tail.known_distribution())
+    }
+
+    /// Snapshot of a signer whose validator state and tail are signed by more than
+    /// half of the trusted members
+    fn certified(&mut self) -> Option<StateSnapshot> {
+        let members = self.members()?;
+        // generation, last finalized event and digest of the validator state
+        type Signed = (u64, Option<(u64, Hash)>, Hash);
+        let mut signers: HashMap<Signed, Vec<PeerId>> = HashMap::new();
+        let followed = self.received.iter().filter(|(_, snapshot)| {
+            // the tail may have been followed since a later one arrived
+            snapshot.generation <= self.generation && self.follows_tail(snapshot)
+        });
+        for (signer, snapshot) in followed {
+            if !members.is_member(signer) {
+                continue;
+            }
+            match snapshot.validator.digest() {
+                Ok(digest) => signers
+                    .entry((snapshot.generation, snapshot.last_finalized.clone(), digest))
+                    .or_default()
+                    .push(*signer),
+                Err(e) => warn!("Could not compute digest of validator state: {}", e),
+            }
+        }
+        let signer = signers
+            .into_values()
+            .find(|signers| signers.len() * 2 > members.members().count())?[0];
+        self.received.remove(&signer)
+    }
+
+    /// Generation to skip to if more than half of the trusted members gave
+    /// snapshots of generations after the followed one, the lowest of them
+    pub fn ahead(&self) -> Option<u64> {
+        let members = self.members()?;
+        let ahead: Vec<_> = self
+            .received
+            .iter()
+            .filter(|(signer, snapshot)| {
+                members.is_member(signer) && snapshot.generation > self.generation
+            })
+            .map(|(_, snapshot)| snapshot.generation)
+            .collect();
+        if ahead.len() * 2 <= members.members().count() {
+            return None;
+        }
+        ahead.into_iter().min()
+    }
+
+    pub fn push(&mut self, tx: FinalizedTx) {
+        if self.trusted.is_none() {
+            let (author, transaction, event_hash) = &tx;
+            // rejections don't matter, only the membership is followed
+            let _ = self
+                .tail
+                .validate(author, transaction, &event_hash.clone().into());
+        }
+        self.buffered.push_back((self.generation, tx));
+    }
+
+    /// Generation and event of the last transaction followed
+    pub fn last_finalized(&self) -> Option<(u64, Hash)> {
+        self.buffered
+            .back()
+            .map(|(generation, (_, _, event_hash))| (*generation, event_hash.clone().into()))
+    }
+
+    /// Transactions to apply after the snapshot
+    pub fn into_buffered(self) -> impl Iterator<Item = FinalizedTx> {
+        self.buffered.into_iter().map(|(_, tx)| tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity::ed25519, PeerId};
+
+    use std::collections::HashSet;
+
+    use super::{Error, FastSync, SignedSnapshot, SkipVotes, StateSnapshot};
+    use crate::{
+        consensus::{validation::ValidatorState, Transaction},
+        data_memory::{distribution::Distribution, placement::Placement, DataSnapshot},
+        encoding::reed_solomon,
+        signatures::EncodedEd25519Pubkey,
+        types::Vid,
+    };
+
+    fn snapshot(
+        keypair: &ed25519::Keypair,
+        members: Vec<PeerId>,
+        generation: u64,
+    ) -> StateSnapshot {
+        let distribution = Distribution::initial(members, Placement::RoundRobin, 3);
+        let mut validator = ValidatorState::default();
+        validator.distribution = Some(distribution.clone());
+        StateSnapshot {
+            generation,
+            last_finalized: None,
+            validator,
+            data: DataSnapshot {
+                distribution,
+                encoding: reed_solomon::Settings {
+                    data_shards_total: 3,
+                    data_shards_sufficient: 2,
+                    max_shard_size: 1024,
+                },
+                locations: Default::default(),
+                checksums: Default::default(),
+                provisional_checksums: Default::default(),
+            },
+            instructions: Default::default(),
+            pubkey: keypair.public().into(),
+        }
+    }
+
+    fn sign(keypair: &ed25519::Keypair, members: Vec<PeerId>, generation: u64) -> SignedSnapshot {
+        SignedSnapshot::sign(&snapshot(keypair, members, generation), keypair).unwrap()
+    }
+
+    fn peer(keypair: &ed25519::Keypair) -> PeerId {
+        PeerId::from(libp2p::identity::PublicKey::from(
+            EncodedEd25519Pubkey::from(keypair.public()),
+        ))
+    }
+
+    #[test]
+    fn skip_needs_most_members() {
+        let members: HashSet<_> = (0..4).map(|_| PeerId::random()).collect();
+        let mut members_iter = members.iter().cloned();
+        let mut next = || members_iter.next().unwrap();
+        let mut votes = SkipVotes::default();
+        // forged by an outsider or a single member
+        assert_eq!(votes.report(PeerId::random(), 5, &members), None);
+        let liar = next();
+        assert_eq!(votes.report(liar, u64::MAX, &members), None);
+        assert_eq!(votes.report(liar, u64::MAX, &members), None);
+        assert_eq!(votes.report(next(), 7, &members), None);
+        // the liar is outvoted
+        assert_eq!(votes.report(next(), 6, &members), Some(6));
+        assert_eq!(votes.report(next(), 8, &members), None);
+    }
+
+    #[test]
+    fn new_peer_counts_members_seen_finalized() {
+        let keypairs: Vec<_> = (0..3).map(|_| ed25519::Keypair::generate()).collect();
+        let members: Vec<_> = keypairs.iter().map(peer).collect();
+        let mut fast_sync = FastSync::new(3, None);
+        let outsider = ed25519::Keypair::generate();
+        let forged = sign(&outsider, vec![peer(&outsider)], 3);
+        assert!(fast_sync.on_response(Some(forged)).is_none());
+        // members can't be told apart before the initialization is seen
+        for keypair in &keypairs[1..] {
+            let signed = sign(keypair, members.clone(), 3);
+            assert!(fast_sync.on_response(Some(signed)).is_none());
+        }
+        let init = Transaction::InitializeStorage {
+            members: members.clone(),
+            placement: Placement::RoundRobin,
+            encoding: snapshot(&keypairs[0], vec![], 3).data.encoding,
+        };
+        let hash = rust_hashgraph::algorithm::event::Hash::from_array([1; 64]);
+        fast_sync.push((members[0], init, hash));
+        let forged = sign(&outsider, vec![peer(&outsider)], 3);
+        let installed = fast_sync.on_response(Some(forged)).unwrap();
+        assert!(members[1..].contains(&installed.signer()));
+    }
+
+    #[test]
+    fn snapshot_is_verified_and_skips_covered_transactions() {
+        let keypairs: Vec<_> = (0..3).map(|_| ed25519::Keypair::generate()).collect();
+        let members: Vec<_> = keypairs.iter().map(peer).collect();
+
+        let mut signed = sign(&keypairs[0], members.clone(), 3);
+        signed.signature[0] ^= 1;
+        assert!(matches!(signed.verify(), Err(Error::InvalidSignature)));
+        let mut inconsistent = snapshot(&keypairs[0], members.clone(), 3);
+        inconsistent.validator.distribution = None;
+        let signed = SignedSnapshot::sign(&inconsistent, &keypairs[0]).unwrap();
+        assert!(matches!(signed.verify(), Err(Error::Inconsistent)));
+
+        let tx = |event: u8| {
+            let hash = rust_hashgraph::algorithm::event::Hash::from_array([event; 64]);
+            (members[0], Transaction::Delete(Vid(event.into())), hash)
+        };
+        // skipped to generation 3, then generation 4 started
+        let trusted = snapshot(&keypairs[0], members.clone(), 0).data.distribution;
+        let mut fast_sync = FastSync::new(3, Some(trusted));
+        fast_sync.push(tx(1));
+        fast_sync.on_generation(4);
+        fast_sync.push(tx(2));
+        // snapshots of generation 4 name the transaction of generation 3
+        let sign_after = |keypair: &ed25519::Keypair, members: Vec<PeerId>| {
+            let mut snapshot = snapshot(keypair, members, 4);
+            snapshot.last_finalized = Some((3, tx(1).2.into()));
+            SignedSnapshot::sign(&snapshot, keypair).unwrap()
+        };
+        for generation in [2, 5] {
+            let signed = sign(&keypairs[0], members.clone(), generation);
+            assert!(fast_sync.on_response(Some(signed)).is_none());
+        }
+        assert_eq!(fast_sync.ahead(), None);
+        assert!(fast_sync.on_response(None).is_none());
+        // gave the latest one it has
+        assert!(!fast_sync.should_ask(&members[0]));
+        assert!(fast_sync.should_ask(&members[1]));
+
+        // outsiders agreeing on a forged distribution are not trusted
+        for _ in 0..3 {
+            let outsider = ed25519::Keypair::generate();
+            let forged = sign_after(&outsider, vec![peer(&outsider)]);
+            assert!(fast_sync.on_response(Some(forged)).is_none());
+        }
+        // a single member is not enough
+        assert!(fast_sync
+            .on_response(Some(sign_after(&keypairs[0], members.clone())))
+            .is_none());
+        assert!(!fast_sync.should_ask(&members[0]));
+        // a member signing a different state doesn't count towards it
+        let other = sign_after(&keypairs[1], members[1..].to_vec());
+        assert!(fast_sync.on_response(Some(other)).is_none());
+        // nor does one missing a transaction that was followed
+        let other_tail = sign(&keypairs[2], members.clone(), 4);
+        assert!(fast_sync.on_response(Some(other_tail)).is_none());
+        let installed = fast_sync
+            .on_response(Some(sign_after(&keypairs[2], members.clone())))
+            .unwrap();
+        assert_eq!(installed.generation, 4);
+        assert_eq!(fast_sync.last_finalized(), Some((4, tx(2).2.into())));
+        let applied: Vec<_> = fast_sync.into_buffered().map(|(_, tx, _)| tx).collect();
+        assert_eq!(applied, vec![Transaction::Delete(Vid(2))]);
+
+        // skipped to a generation nobody follows
+        let trusted = snapshot(&keypairs[0], members.clone(), 0).data.distribution;
+        let mut fast_sync = FastSync::new(3, Some(trusted));
+        for (keypair, generation) in keypairs.iter().zip([9, 8]) {
+            let signed = sign(keypair, members.clone(), generation);
+            assert!(fast_sync.on_response(Some(signed)).is_none());
+        }
+        assert_eq!(fast_sync.ahead(), Some(8));
+    }
+}
@@ -1,7 +1,9 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
 use std::task::Poll;
 use std::{fmt::Debug, sync::Arc};
 
+use async_trait::async_trait;
 use futures::Stream;
 use futures::{Future, StreamExt};
 use libp2p::PeerId;
@@ -10,7 +12,7 @@ use rust_hashgraph::algorithm::datastructure::{self, EventCreateError, Graph};
 use rust_hashgraph::algorithm::event::{EventWrapper, Hash};
 use rust_hashgraph::algorithm::PushError;
 use rust_hashgraph::algorithm::{Clock, Signer};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use tokio::pin;
 use tokio::sync::Notify;
@@ -19,83 +21,14 @@ use tracing::{debug, error, info, trace, warn};
 use crate::logging_helpers::Targets;
 use crate::module::ModuleChannelServer;
 use crate::signatures::EncodedEd25519Pubkey;
-use crate::types::{GraphSync, Sid, Vid};
+use crate::types::{Sid, Vid};
 
-use super::Transaction;
+use super::journal::{self, ClockHandle, Entry, Journal, JournalClock};
+use super::{Consensus, ConsensusSync, InEvent, Module, ModuleState, OutEvent, Transaction};
 
-pub struct Module;
-
-impl crate::module::Module for Module {
-    type InEvent = InEvent;
-    type OutEvent = OutEvent;
-    type SharedState = ModuleState;
-}
-
-#[derive(Debug, Clone)]
-pub enum OutEvent {
-    GenerateSyncResponse {
-        to: PeerId,
-        sync: GraphSync,
-    },
-    KnownPeersResponse(Vec<PeerId>),
-    /// This transaction is confirmed to be seen by supermajority
-    /// of the peers and its ordering is univocally decided by
-    /// consensus.
-    FinalizedTransaction {
-        from: PeerId,
-        event_hash: Hash,
-        tx: Transaction<Vid, Sid, PeerId>,
-    },
-    /// This transaction (tx) is not guaranteed to be seen by supermajority
-    /// of the peers; the ordering (of events) might change in finalized
-    /// version.
-    ///
-    /// Note that the order of recognized events (and, thus,
-    /// txs) follows ancestry relationship. I.e. if event $A$ is an ancestor
-    /// of event $B$, `RecognizedTransaction`'s for $A$'s txs will be emitted
-    /// before `RecognizedTransaction`'s for $B$
-    ///
-    /// ## Applications
-    ///
-    /// They still can be useful for example for:
-    /// 1. Speculative execution; a peer can start to perform some actions
-    /// associated with the transaction and have the result ready when
-    /// the transaction actually finalizes.
-    /// 2. Relying in special cases of the network, such as single peer
-    /// making all "command" transactions (txs switching order of which
-    /// can actually make a difference). Due to ancestry between all its events,
-    /// all "command" txs are going to be ordered properly, making it possible to
-    /// react to them right away and to improve system usability & responsiveness
-    RecognizedTransaction {
-        from: PeerId,
-        event_hash: Hash,
-        tx: Transaction<Vid, Sid, PeerId>,
-    },
-}
-
-#[derive(Debug, Clone)]
-pub enum InEvent {
-    GenerateSyncRequest { to: PeerId },
-    // get list of known peers to the consensus
-    KnownPeersRequest,
-    ApplySync { from: PeerId, sync: GraphSync },
-    ScheduleTx(Transaction<Vid, Sid, PeerId>),
-    CreateStandalone,
-}
-
-pub enum ModuleState {
-    Ready,
-    Busy,
-}
-
-impl crate::module::State for ModuleState {
-    fn accepts_input(&self) -> bool {
-        match self {
-            ModuleState::Ready => true,
-            ModuleState::Busy => false,
-        }
-    }
-}
+/// Number of finalized events in a generation, after the last one the
+/// graph is replaced with a new one (see [`GraphWrapper`]).
+pub const GENERATION_EVENTS: usize = 20_000;
 
 pub type SyncJobs<TDataId, TShardId> =
     datastructure::sync::Jobs<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId>;
@@ -116,52 +49,128 @@ pub struct GenesisPayload {
     pub pubkey: EncodedEd25519Pubkey,
 }
 
+type InnerGraph<TDataId, TShardId, TSigner, TClock> =
+    Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock>;
+
+type TxBuffer<TDataId, TShardId> = (
+    PeerId,
+    VecDeque<Transaction<TDataId, TShardId, PeerId>>,
+    Hash,
+);
+
 pin_project! {
     /// Async wrapper for the graph consensus. Intended to communicate
     /// with behaviour through corresponding [`ModuleChannelServer`] (the
     /// behaviour thus uses [`ModuleChannelClient`]).
     ///
-    /// Use [`Self::from_graph()`] to create, [`Self::run()`] to operate.
+    /// `rust_hashgraph` can't drop decided rounds, so the history is split
+    /// into generations instead. Once [`GENERATION_EVENTS`] events are
+    /// finalized (all peers reach the limit at the same event), the peer
+    /// continues on a new graph and drops the old one. Graph of the previous
+    /// generation is kept only to sync peers that haven't reached its end
+    /// yet; peers further behind are told to fast-sync
+    /// ([`OutEvent::PeerTooFarBehind`]). Own transactions not finalized
+    /// before the end of a generation are included again in the next one.
+    ///
+    /// Unlike pruning rounds once their transactions are applied, this
+    /// doesn't bound memory within a generation: all its events are kept
+    /// until it ends, and it doesn't end while finalized transactions are
+    /// not taken yet.
+    ///
+    /// Use [`Self::new()`] to create, [`Self::run()`] to operate.
     #[project = GraphWrapperProjection]
     pub struct GraphWrapper<TDataId, TShardId, TSigner, TClock> {
-        inner: Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock>,
+        current: Generation<TDataId, TShardId, TSigner, TClock>,
+        // graph of the previous generation, unless it was skipped
+        previous: Option<InnerGraph<TDataId, TShardId, TSigner, TClock>>,
+        // creates graph with genesis of this peer for each generation
+        new_graph: Box<dyn FnMut() -> InnerGraph<TDataId, TShardId, TSigner, TClock> + Send>,
+        // generations of the peers, as of their latest syncs
+        peer_generations: HashMap<PeerId, u64>,
         // Notification for possible new finalized/recognized transactions
         state_updated: Arc<Notify>,
         // transactions scheduled for inclusion into a next event
         included_transaction_buffer: Vec<Transaction<TDataId, TShardId, PeerId>>,
-        // transactions from latest finalized transaction
-        finalized_transaction_buffer: (PeerId, VecDeque<Transaction<TDataId, TShardId, PeerId>>, Hash),
-        // transactions from latest recognized (seen in partial order) event
-        recognized_transaction_buffer: (PeerId, VecDeque<Transaction<TDataId, TShardId, PeerId>>, Hash),
+        // operations on the graph are written there, if it's persisted
+        journal: Option<(Journal, ClockHandle)>,
+        // generations started in the journal, with positions after their
+        // starts and timestamps taken then
+        journaled_starts: BTreeMap<u64, (u64, Vec<u128>)>,
+        // the latest checkpoint of the behaviour, the journal is compacted
+        // to it once a later one arrives
+        checkpoint: Option<(u64, Vec<u8>)>,
+        // transactions and generations delivered before restart, emitted
+        // again only to follow the state
+        replayed_buffer: VecDeque<Replayed<TDataId, TShardId>>,
     }
 }
+
+/// Graph of one generation along with the transactions delivered from it
+struct Generation<TDataId, TShardId, TSigner, TClock> {
+    number: u64,
+    graph: InnerGraph<TDataId, TShardId, TSigner, TClock>,
+    // finalized events taken so far, including ones without transactions
+    finalized_events: usize,
+    // transactions from latest finalized transaction
+    finalized_transaction_buffer: TxBuffer<TDataId, TShardId>,
+    // transactions from latest recognized (seen in partial order) event
+    recognized_transaction_buffer: TxBuffer<TDataId, TShardId>,
+    // events of this peer with transactions, not finalized yet
+    own_pending: VecDeque<(Hash, EventPayload<TDataId, TShardId>)>,
+}
+
+// mostly transactions anyway
+#[allow(clippy::large_enum_variant)]
+enum Replayed<TDataId, TShardId> {
+    Transaction(NextTxData<TDataId, TShardId>),
+    Generation { generation: u64, skipped: bool },
+    Checkpoint { generation: u64, state: Vec<u8> },
+}
+
 impl<TDataId, TShardId, TSigner, TClock> GraphWrapper<TDataId, TShardId, TSigner, TClock> {
-    pub fn from_graph(
-        graph: Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock>,
-    ) -> Self {
+    /// `new_graph` creates the graph with genesis of this peer, it's called
+    /// on start of each generation.
+    pub fn new<F>(mut new_graph: F) -> Self
+    where
+        F: FnMut() -> InnerGraph<TDataId, TShardId, TSigner, TClock> + Send + 'static,
+    {
         Self {
-            inner: graph,
+            current: Generation::new(0, new_graph()),
+            previous: None,
+            new_graph: Box::new(new_graph),
+            peer_generations: HashMap::new(),
             state_updated: Arc::new(Notify::new()),
             included_transaction_buffer: Vec::new(),
-            finalized_transaction_buffer: (
-                PeerId::random(),
-                VecDeque::new(),
-                Hash::from_array([0; 64]),
-            ),
-            recognized_transaction_buffer: (
-                PeerId::random(),
-                VecDeque::new(),
-                Hash::from_array([0; 64]),
-            ),
+            journal: None,
+            journaled_starts: BTreeMap::new(),
+            checkpoint: None,
+            replayed_buffer: VecDeque::new(),
         }
     }
 
     // might be useful
     #[allow(unused)]
-    pub fn inner(
-        &self,
-    ) -> &Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock> {
-        &self.inner
+    pub fn inner(&self) -> &InnerGraph<TDataId, TShardId, TSigner, TClock> {
+        &self.current.graph
+    }
+}
+
+impl<TDataId, TShardId, TSigner, TClock> Generation<TDataId, TShardId, TSigner, TClock> {
+    fn new(number: u64, graph: InnerGraph<TDataId, TShardId, TSigner, TClock>) -> Self {
+        let empty_buffer = || (PeerId::random(), VecDeque::new(), Hash::from_array([0; 64]));
+        Self {
+            number,
+            graph,
+            finalized_events: 0,
+            finalized_transaction_buffer: empty_buffer(),
+            recognized_transaction_buffer: empty_buffer(),
+            own_pending: VecDeque::new(),
+        }
+    }
+
+    /// All transactions of the generation were taken
+    fn is_finished(&self) -> bool {
+        self.finalized_events >= GENERATION_EVENTS && self.finalized_transaction_buffer.1.is_empty()
     }
 }
 
@@ -173,6 +182,16 @@ pub enum ApplySyncError {
     UnknownPeer(PeerId),
     #[error("Failed to create new gossip event")]
     CreateError(#[from] EventCreateError<PeerId>),
+    #[error(transparent)]
+    Journal(#[from] journal::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CreateStandaloneError {
+    #[error(transparent)]
+    CreateError(#[from] EventCreateError<PeerId>),
+    #[error(transparent)]
+    Journal(#[from] journal::Error),
 }
 
 impl<TDataId, TShardId, TSigner, TClock> GraphWrapper<TDataId, TShardId, TSigner, TClock>
@@ -196,47 +215,242 @@ where
         if !sync_jobs.as_linear().is_empty() {
             self.state_updated.notify_one();
         }
-        for next_event in sync_jobs.into_linear() {
-            let (next_event, signature) = next_event.into_parts();
-            match self.inner.push_event(next_event, signature) {
-                Ok(()) => (),
-                Err(PushError::EventAlreadyExists(hash)) => {
-                    trace!(target: Targets::Synchronization.into_str(), "Received event {} is already known, skipping", hash)
-                }
-                Err(e) => return Err(Box::new(e.into())),
-            };
+        let journaled_jobs = self.journal.is_some().then(|| sync_jobs.clone());
+        let (accepted, push_result) =
+            Self::push_sync_jobs(&mut self.current.graph, sync_jobs, usize::MAX);
+        if let Some(jobs) = journaled_jobs {
+            // events pushed before an error are kept, so they're journaled anyway
+            self.record(|timestamps| Entry::Synced {
+                jobs,
+                accepted,
+                timestamps,
+            })
+            .map_err(|e| Box::new(e.into()))?;
         }
+        push_result.map_err(|e| Box::new(e.into()))?;
         let txs = std::mem::take(&mut self.included_transaction_buffer);
         let payload = EventPayload { transactions: txs };
         // Retrieving the parent after applying sync, because the latest event is likely
         // to be updated there.
         let other_parent = self
-            .inner
+            .current
+            .graph
             .peer_latest_event(&from)
-            .ok_or_else(|| ApplySyncError::UnknownPeer(from))?;
-        self.inner
-            .create_event(payload, other_parent.clone())
-            .map_err(|e| Box::new(e.into()))?;
+            .ok_or_else(|| ApplySyncError::UnknownPeer(from))?
+            .clone();
+        self.create_event(payload, other_parent)
+            .map_err(|e| match e {
+                CreateStandaloneError::CreateError(e) => Box::new(e.into()),
+                CreateStandaloneError::Journal(e) => Box::new(e.into()),
+            })?;
         self.included_transaction_buffer.clear();
         Ok(())
     }
 
-    pub fn create_standalone_event(&mut self) -> Result<(), Box<EventCreateError<PeerId>>> {
+    /// Push at most `limit` events until the first one that can't be added.
+    /// Returns the number of events accepted (added or known already) along
+    /// with the error.
+    fn push_sync_jobs(
+        graph: &mut InnerGraph<TDataId, TShardId, TSigner, TClock>,
+        sync_jobs: SyncJobs<TDataId, TShardId>,
+        limit: usize,
+    ) -> (usize, Result<(), PushError<PeerId>>) {
+        let mut accepted = 0;
+        for next_event in sync_jobs.into_linear().into_iter().take(limit) {
+            let (next_event, signature) = next_event.into_parts();
+            match graph.push_event(next_event, signature) {
+                Ok(()) => (),
+                Err(PushError::EventAlreadyExists(hash)) => {
+                    trace!(target: Targets::Synchronization.into_str(), "Received event {} is already known, skipping", hash)
+                }
+                Err(e) => return (accepted, Err(e)),
+            };
+            accepted += 1;
+        }
+        (accepted, Ok(()))
+    }
+
+    fn create_event(
+        &mut self,
+        payload: EventPayload<TDataId, TShardId>,
+        other_parent: Hash,
+    ) -> Result<(), CreateStandaloneError> {
+        let journaled_payload = self.journal.is_some().then(|| payload.clone());
+        let pending_payload = (!payload.transactions.is_empty()).then(|| payload.clone());
+        self.current
+            .graph
+            .create_event(payload, other_parent.clone())?;
+        if let Some(payload) = pending_payload {
+            let self_id = *self.current.graph.self_id();
+            let hash = self
+                .current
+                .graph
+                .peer_latest_event(&self_id)
+                .expect("Peer must know itself")
+                .clone();
+            self.current.own_pending.push_back((hash, payload));
+        }
+        if let Some(payload) = journaled_payload {
+            self.record(|timestamps| Entry::Created {
+                payload,
+                other_parent,
+                timestamps,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Write the operation to the journal, if the graph is persisted
+    fn record<F>(&mut self, entry: F) -> Result<(), journal::Error>
+    where
+        F: FnOnce(Vec<u128>) -> Entry<TDataId, TShardId>,
+    {
+        if let Some((journal, clock)) = &mut self.journal {
+            journal.append(&entry(clock.take_recorded()))?;
+        }
+        Ok(())
+    }
+
+    pub fn create_standalone_event(&mut self) -> Result<(), Box<CreateStandaloneError>> {
         self.state_updated.notify_one();
         let txs = std::mem::take(&mut self.included_transaction_buffer);
         let payload = EventPayload { transactions: txs };
         let self_parent = self
-            .inner
-            .peer_latest_event(self.inner.self_id())
+            .current
+            .graph
+            .peer_latest_event(self.current.graph.self_id())
             .expect("Peer must know itself")
             .clone();
-        self.inner.create_event(payload, self_parent)?;
+        self.create_event(payload, self_parent)?;
         Ok(())
     }
 
     pub fn push_tx(&mut self, tx: Transaction<TDataId, TShardId, PeerId>) {
         self.included_transaction_buffer.push(tx);
     }
+
+    /// Continue with a new graph. Generations after the current one up to
+    /// `number` are skipped, if any.
+    fn start_generation(&mut self, number: u64) -> Result<(), journal::Error> {
+        let graph = (self.new_graph)();
+        let finished = std::mem::replace(&mut self.current, Generation::new(number, graph));
+        let own_pending = finished
+            .own_pending
+            .into_iter()
+            .flat_map(|(_, payload)| payload.transactions);
+        if number == finished.number + 1 {
+            // not finalized in the finished generation, so not delivered by anyone
+            let mut included: Vec<_> = own_pending.collect();
+            included.append(&mut self.included_transaction_buffer);
+            self.included_transaction_buffer = included;
+            self.previous = Some(finished.graph);
+        } else {
+            let dropped = own_pending.count();
+            if dropped > 0 {
+                warn!(
+                    "{} transaction(s) of this peer might not be finalized in skipped generations",
+                    dropped
+                );
+            }
+            self.previous = None;
+        }
+        self.state_updated.notify_one();
+        if let Some((journal, clock)) = &mut self.journal {
+            let timestamps = clock.take_recorded();
+            journal.append::<TDataId, TShardId>(&Entry::Generation {
+                generation: number,
+                timestamps: timestamps.clone(),
+            })?;
+            self.journaled_starts
+                .insert(number, (journal.position(), timestamps));
+        }
+        Ok(())
+    }
+
+    /// Keep the checkpoint of the behaviour at the start of `generation`.
+    /// The journal is compacted to the previous checkpoint, so that the
+    /// previous generation is still there to sync peers after restart.
+    fn checkpoint(&mut self, generation: u64, state: Vec<u8>) -> Result<(), journal::Error> {
+        let Some((journal, _)) = &mut self.journal else {
+            return Ok(());
+        };
+        let Some((compact_to, state)) = self.checkpoint.replace((generation, state)) else {
+            return Ok(());
+        };
+        let Some((position, timestamps)) = self.journaled_starts.get(&compact_to) else {
+            // the journal starts later already
+            return Ok(());
+        };
+        let position = *position;
+        let checkpoint = Entry::Checkpoint {
+            self_id: *self.current.graph.self_id(),
+            generation: compact_to,
+            timestamps: timestamps.clone(),
+            state,
+        };
+        let new_position = journal.compact::<TDataId, TShardId>(&checkpoint, position)?;
+        self.journaled_starts = self
+            .journaled_starts
+            .split_off(&compact_to)
+            .into_iter()
+            .map(|(generation, (start, timestamps))| {
+                (generation, (start - position + new_position, timestamps))
+            })
+            .collect();
+        debug!(
+            "Journal is compacted to the start of generation {}",
+            compact_to
+        );
+        Ok(())
+    }
+
+    /// Generation to sync the peer in, `None` if its graph is dropped
+    fn sync_generation(&self, peer: &PeerId) -> Option<u64> {
+        sync_generation(
+            self.peer_generations.get(peer).copied(),
+            self.current.number,
+            self.previous.is_some(),
+        )
+    }
+
+    /// Graph of the generation, if it's still kept
+    fn graph(&self, generation: u64) -> Option<&InnerGraph<TDataId, TShardId, TSigner, TClock>> {
+        if generation == self.current.number {
+            Some(&self.current.graph)
+        } else if generation + 1 == self.current.number {
+            self.previous.as_ref()
+        } else {
+            None
+        }
+    }
+}
+
+/// Generation to sync a peer that last synced in `peer_generation`, `None`
+/// if it's too far behind. Unknown peers and peers ahead are synced in the
+/// current one.
+fn sync_generation(peer_generation: Option<u64>, current: u64, previous_kept: bool) -> Option<u64> {
+    match peer_generation {
+        Some(generation) if generation < current => {
+            (generation + 1 == current && previous_kept).then_some(generation)
+        }
+        _ => Some(current),
+    }
+}
+
+/// Whether to drop the graph and skip to `generation`. The previous one is
+/// still synced by gossip.
+fn should_skip(generation: u64, current: u64) -> bool {
+    generation.saturating_sub(current) > 1
+}
+
+/// Whether the generation started in the journal was skipped to. The next
+/// one can start only once the current one is finished.
+fn replayed_start(generation: u64, current: u64, finished: bool) -> Result<bool, &'static str> {
+    let skipped = current.checked_add(1) != Some(generation);
+    if generation <= current || (!skipped && !finished) {
+        return Err("generation started too early");
+    }
+    Ok(skipped)
 }
 
 pub type NextTxData<TDataId, TShardId> = (PeerId, Transaction<TDataId, TShardId, PeerId>, Hash);
@@ -244,28 +458,25 @@ pub type NextTxData<TDataId, TShardId> = (PeerId, Transaction<TDataId, TShardId,
 pub enum NextTx<TDataId, TShardId> {
     Recognized(NextTxData<TDataId, TShardId>),
     Finalized(NextTxData<TDataId, TShardId>),
+    /// All finalized transactions of the generation were taken
+    GenerationEnd,
 }
 
-impl<TDataId, TShardId, TSigner, TClock> GraphWrapper<TDataId, TShardId, TSigner, TClock>
+impl<TDataId, TShardId, TSigner, TClock> Generation<TDataId, TShardId, TSigner, TClock>
 where
-    TDataId: Serialize + Eq + std::hash::Hash + Debug + Clone + 'static,
-    TShardId: Serialize + Eq + std::hash::Hash + Debug + Clone + 'static,
-    TSigner: Signer<GenesisPayload, SignerIdentity = PeerId>,
+    TDataId: Clone,
+    TShardId: Clone,
     TClock: Clock,
 {
-    // todo: replace `graph` with function
-    fn next_tx<'a, F>(
-        this: &mut Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock>,
-        get_next_event: F,
-        tx_buffer: &mut (
-            PeerId,
-            VecDeque<Transaction<TDataId, TShardId, PeerId>>,
-            Hash,
-        ),
+    /// Next transaction, taking events from `get_next_event` as needed
+    fn next_tx<F>(
+        graph: &mut InnerGraph<TDataId, TShardId, TSigner, TClock>,
+        mut get_next_event: F,
+        tx_buffer: &mut TxBuffer<TDataId, TShardId>,
     ) -> Option<NextTxData<TDataId, TShardId>>
     where
-        F: for<'b> Fn(
-            &'b mut Graph<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId, TSigner, TClock>,
+        F: for<'b> FnMut(
+            &'b mut InnerGraph<TDataId, TShardId, TSigner, TClock>,
         ) -> Option<
             &'b EventWrapper<EventPayload<TDataId, TShardId>, GenesisPayload, PeerId>,
         >,
@@ -274,51 +485,227 @@ where
             if let Some(tx) = tx_buffer.1.pop_front() {
                 // feed transactions from an event one by one
                 return Some((tx_buffer.0, tx, tx_buffer.2.clone()));
-            } else {
-                // no txs left in previous event, getting a new one
-                match get_next_event(this) {
-                    Some(event) => {
-                        let author = *event.author();
-                        let mut txs: VecDeque<_> = event.payload().transactions.clone().into();
-                        let next_tx = txs.pop_front();
-                        tx_buffer.0 = author;
-                        tx_buffer.1 = txs;
-                        match next_tx {
-                            Some(tx) => return Some((author, tx, event.hash().clone())),
-                            None => {
-                                // more events might be available
-                                continue;
-                            }
-                        }
-                    }
-                    None => return None,
-                }
             }
+            // no txs left in previous event, getting a new one
+            let event = get_next_event(graph)?;
+            *tx_buffer = (
+                *event.author(),
+                event.payload().transactions.clone().into(),
+                event.hash().clone(),
+            );
         }
     }
 
     // If `None` then it might not be available yet
-    fn next_finalized(
-        this: &mut GraphWrapperProjection<'_, TDataId, TShardId, TSigner, TClock>,
-    ) -> Option<NextTx<TDataId, TShardId>> {
-        Self::next_tx(
-            this.inner,
-            |inner| inner.next_finalized_event(),
-            this.finalized_transaction_buffer,
-        )
-        .map(|d| NextTx::Finalized(d))
+    fn next_finalized(&mut self) -> Option<NextTx<TDataId, TShardId>> {
+        let self_id = *self.graph.self_id();
+        let (finalized_events, own_pending) = (&mut self.finalized_events, &mut self.own_pending);
+        let next = Self::next_tx(
+            &mut self.graph,
+            |graph| {
+                if *finalized_events >= GENERATION_EVENTS {
+                    return None;
+                }
+                let event = graph.next_finalized_event()?;
+                *finalized_events += 1;
+                if event.author() == &self_id {
+                    own_pending.retain(|(hash, _)| hash != event.hash());
+                }
+                Some(event)
+            },
+            &mut self.finalized_transaction_buffer,
+        );
+        match next {
+            Some(next) => Some(NextTx::Finalized(next)),
+            None if self.is_finished() => Some(NextTx::GenerationEnd),
+            None => None,
+        }
     }
 
     // If `None` then it might not be available yet
-    fn next_recognized(
-        this: &mut GraphWrapperProjection<'_, TDataId, TShardId, TSigner, TClock>,
-    ) -> Option<NextTx<TDataId, TShardId>> {
+    fn next_recognized(&mut self) -> Option<NextTx<TDataId, TShardId>> {
         Self::next_tx(
-            this.inner,
-            |inner| inner.next_recognized_event(),
-            this.recognized_transaction_buffer,
+            &mut self.graph,
+            |graph| graph.next_recognized_event(),
+            &mut self.recognized_transaction_buffer,
         )
-        .map(|d| NextTx::Recognized(d))
+        .map(NextTx::Recognized)
+    }
+}
+
+impl<TDataId, TShardId, TSigner, TClock>
+    GraphWrapper<TDataId, TShardId, TSigner, JournalClock<TClock>>
+where
+    TDataId: Serialize + DeserializeOwned + Eq + std::hash::Hash + Debug + Clone + 'static,
+    TShardId: Serialize + DeserializeOwned + Eq + std::hash::Hash + Debug + Clone + 'static,
+    TSigner: Signer<GenesisPayload, SignerIdentity = PeerId>,
+    TClock: Clock + Clone + Send + 'static,
+{
+    /// Rebuild the graph from the journal at `path` and keep journaling it.
+    /// `new_graph` creates the graph with genesis of this peer, as in
+    /// [`Self::new()`].
+    ///
+    /// Transactions delivered before are not handled again, finalized ones
+    /// are emitted as [`OutEvent::ReplayedTransaction`] first, along with
+    /// generations started meanwhile. If the journal was compacted, the
+    /// checkpoint it starts with is emitted before them
+    /// ([`OutEvent::RestoredCheckpoint`]).
+    pub fn from_journal<F>(
+        path: impl AsRef<Path>,
+        self_id: PeerId,
+        clock: TClock,
+        mut new_graph: F,
+    ) -> Result<Self, journal::Error>
+    where
+        F: FnMut(
+                JournalClock<TClock>,
+            ) -> InnerGraph<TDataId, TShardId, TSigner, JournalClock<TClock>>
+            + Send
+            + 'static,
+    {
+        let (mut journal, entries) = Journal::open(path)?;
+        let clock_handle = ClockHandle::default();
+        let graph_clock_handle = clock_handle.clone();
+        let new_graph = move || new_graph(graph_clock_handle.clock(clock.clone()));
+        let mut entries = entries.into_iter().enumerate();
+        let mut this = match entries.next() {
+            None => {
+                let this = Self::new(new_graph);
+                journal.append::<TDataId, TShardId>(&Entry::Genesis {
+                    self_id,
+                    timestamps: clock_handle.take_recorded(),
+                })?;
+                this
+            }
+            Some((
+                _,
+                (
+                    Entry::Genesis {
+                        self_id: journal_id,
+                        timestamps,
+                    },
+                    _,
+                ),
+            )) => {
+                if journal_id != self_id {
+                    return Err(journal::Error::ForeignJournal(journal_id));
+                }
+                clock_handle.replay(timestamps);
+                Self::new(new_graph)
+            }
+            Some((
+                _,
+                (
+                    Entry::Checkpoint {
+                        self_id: journal_id,
+                        generation,
+                        timestamps,
+                        state,
+                    },
+                    position,
+                ),
+            )) => {
+                if journal_id != self_id {
+                    return Err(journal::Error::ForeignJournal(journal_id));
+                }
+                clock_handle.replay(timestamps.clone());
+                let mut this = Self::new(new_graph);
+                this.current.number = generation;
+                this.journaled_starts
+                    .insert(generation, (position, timestamps));
+                this.replayed_buffer
+                    .push_back(Replayed::Checkpoint { generation, state });
+                this.replayed_buffer.push_back(Replayed::Generation {
+                    generation,
+                    skipped: false,
+                });
+                this
+            }
+            Some(_) => return Err(journal::Error::MissingGenesis),
+        };
+        let replay_error = |index, reason: &str| journal::Error::Replay {
+            index,
+            reason: reason.to_owned(),
+        };
+        let (mut finalized, mut recognized) = (0usize, 0usize);
+        let mut replayed = 1;
+        for (index, (entry, position)) in entries {
+            match entry {
+                Entry::Genesis { .. } | Entry::Checkpoint { .. } => {
+                    return Err(replay_error(index, "journal starts again"))
+                }
+                Entry::Synced {
+                    jobs,
+                    accepted,
+                    timestamps,
+                } => {
+                    clock_handle.replay(timestamps);
+                    // events after the rejected one are not pushed, the rest
+                    // were accepted before and have to be accepted again
+                    if let (_, Err(e)) =
+                        Self::push_sync_jobs(&mut this.current.graph, jobs, accepted)
+                    {
+                        return Err(replay_error(index, &e.to_string()));
+                    }
+                }
+                Entry::Created {
+                    payload,
+                    other_parent,
+                    timestamps,
+                } => {
+                    clock_handle.replay(timestamps);
+                    this.create_event(payload, other_parent)
+                        .map_err(|e| replay_error(index, &e.to_string()))?;
+                }
+                // transactions processed by behaviour before the restart are
+                // skipped, finalized ones are still needed to follow the state
+                Entry::Delivered { finalized: true } => match this.current.next_finalized() {
+                    Some(NextTx::Finalized(tx)) => {
+                        this.replayed_buffer.push_back(Replayed::Transaction(tx));
+                        finalized += 1;
+                    }
+                    _ => {
+                        return Err(replay_error(
+                            index,
+                            "delivered transaction is not finalized",
+                        ))
+                    }
+                },
+                Entry::Delivered { finalized: false } => match this.current.next_recognized() {
+                    Some(_) => recognized += 1,
+                    None => {
+                        return Err(replay_error(
+                            index,
+                            "delivered transaction is not recognized",
+                        ))
+                    }
+                },
+                Entry::Generation {
+                    generation,
+                    timestamps,
+                } => {
+                    let skipped =
+                        replayed_start(generation, this.current.number, this.current.is_finished())
+                            .map_err(|reason| replay_error(index, reason))?;
+                    clock_handle.replay(timestamps.clone());
+                    this.start_generation(generation)?;
+                    this.journaled_starts
+                        .insert(generation, (position, timestamps));
+                    this.replayed_buffer.push_back(Replayed::Generation {
+                        generation,
+                        skipped,
+                    });
+                }
+            }
+            replayed += 1;
+        }
+        clock_handle.replay(vec![]);
+        info!(
+            "Restored consensus from {} journal entries, {} finalized and {} recognized transactions were delivered before",
+            replayed, finalized, recognized
+        );
+        this.journal = Some((journal, clock_handle));
+        Ok(this)
     }
 }
 
@@ -335,7 +722,7 @@ where
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let mut this = self.project();
+        let this = self.project();
 
         // wake up if something changed
         {
@@ -343,7 +730,11 @@ where
             pin!(state_updated_notification);
             let _ = state_updated_notification.poll(cx);
         }
-        match Self::next_finalized(&mut this).or_else(|| Self::next_recognized(&mut this)) {
+        match this
+            .current
+            .next_finalized()
+            .or_else(|| this.current.next_recognized())
+        {
             // we're assuming infinite stream so no `None` is returned
             Some(tx) => Poll::Ready(Some(tx)),
             None => Poll::Pending,
@@ -351,12 +742,37 @@ where
     }
 }
 
-impl<TSigner, TClock> GraphWrapper<Vid, Sid, TSigner, TClock>
+#[async_trait]
+impl<TSigner, TClock> Consensus for GraphWrapper<Vid, Sid, TSigner, TClock>
 where
-    TSigner: Signer<GenesisPayload, SignerIdentity = PeerId>,
-    TClock: Clock,
+    TSigner: Signer<GenesisPayload, SignerIdentity = PeerId> + Send,
+    TClock: Clock + Send,
 {
-    pub async fn run(mut self, mut connection: ModuleChannelServer<Module>) {
+    async fn run(mut self, mut connection: ModuleChannelServer<Module>) {
+        while let Some(replayed) = self.replayed_buffer.pop_front() {
+            let out_event = match replayed {
+                Replayed::Transaction((from, tx, event_hash)) => OutEvent::ReplayedTransaction {
+                    from,
+                    tx,
+                    event_hash,
+                },
+                Replayed::Generation {
+                    generation,
+                    skipped,
+                } => OutEvent::GenerationStarted {
+                    generation,
+                    skipped,
+                    replayed: true,
+                },
+                Replayed::Checkpoint { generation, state } => {
+                    OutEvent::RestoredCheckpoint { generation, state }
+                }
+            };
+            if (connection.output.send(out_event).await).is_err() {
+                info!("`connection.output` is closed, shuttung down consensus");
+                return;
+            }
+        }
         loop {
             tokio::select! {
                 next_tx = self.next() => {
@@ -364,22 +780,37 @@ where
                         info!("stream of events ended, shuttung down consensus");
                         return;
                     };
-                    let out_event = match next_tx {
-                        NextTx::Recognized((from, tx, event_hash)) => OutEvent::RecognizedTransaction {
+                    let (out_event, delivered) = match next_tx {
+                        NextTx::Recognized((from, tx, event_hash)) => (OutEvent::RecognizedTransaction {
                             from,
                             tx,
                             event_hash,
-                        },
-                        NextTx::Finalized((from, tx, event_hash)) => OutEvent::FinalizedTransaction {
+                        }, Some(false)),
+                        NextTx::Finalized((from, tx, event_hash)) => (OutEvent::FinalizedTransaction {
                             from,
                             tx,
                             event_hash,
-                        },
+                        }, Some(true)),
+                        NextTx::GenerationEnd => {
+                            let generation = self.current.number + 1;
+                            info!("Generation {} is over, continuing with a new graph", self.current.number);
+                            if let Err(e) = self.start_generation(generation) {
+                                error!("{}, shutting down consensus", e);
+                                return;
+                            }
+                            (OutEvent::GenerationStarted { generation, skipped: false, replayed: false }, None)
+                        }
                     };
                     if (connection.output.send(out_event).await).is_err() {
                         info!("`connection.output` is closed, shuttung down consensus");
                         return;
                     }
+                    if let Some(finalized) = delivered {
+                        if let Err(e) = self.record(|_| Entry::Delivered { finalized }) {
+                            error!("{}, shutting down consensus", e);
+                            return;
+                        }
+                    }
                 }
                 in_event = connection.input.recv() => {
                     let Some(in_event) = in_event else {
@@ -388,27 +819,43 @@ where
                     };
                     match in_event {
                         InEvent::GenerateSyncRequest { to } => {
-                            debug!("Generating sync for {:?}", to);
-                            connection.set_state(ModuleState::Busy);
-                            trace!("Set consensus state to busy");
-                            let sync = match self.inner.generate_sync_for(&to) {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    error!(
-                                        "Graph state inconsistent or bug in generation of sync: {:?}",
-                                        e
-                                    );
-                                    // todo: maybe store state to debug???
-                                    return;
+                            // decided before generating, the sync could be huge
+                            let synced = self
+                                .sync_generation(&to)
+                                .and_then(|generation| Some((generation, self.graph(generation)?)));
+                            let event = match synced {
+                                Some((generation, graph)) => {
+                                    debug!("Generating sync of generation {} for {:?}", generation, to);
+                                    connection.set_state(ModuleState::Busy);
+                                    trace!("Set consensus state to busy");
+                                    let sync = match graph.generate_sync_for(&to) {
+                                        Ok(s) => s,
+                                        Err(e) => {
+                                            error!(
+                                                "Graph state inconsistent or bug in generation of sync: {:?}",
+                                                e
+                                            );
+                                            // todo: maybe store state to debug???
+                                            return;
+                                        }
+                                    };
+                                    trace!("Set consensus state to ready");
+                                    connection.set_state(ModuleState::Ready);
+                                    trace!("Generated sync successfully");
+                                    OutEvent::GenerateSyncResponse {
+                                        to,
+                                        sync: ConsensusSync::Graph { generation, sync },
+                                    }
+                                }
+                                None => {
+                                    warn!(target: Targets::Synchronization.into_str(), "{:?} is more than a generation behind, it needs fast sync", to);
+                                    OutEvent::PeerTooFarBehind { to, generation: self.current.number }
                                 }
                             };
-                            trace!("Set consensus state to ready");
-                            connection.set_state(ModuleState::Ready);
-                            trace!("Generated sync successfully");
                             // todo: maybe use `try_send` or `reserve` on each send
                             if (connection
                                 .output
-                                .send(OutEvent::GenerateSyncResponse { to, sync })
+                                .send(event)
                                 .await)
                                 .is_err()
                             {
@@ -420,7 +867,7 @@ where
                             trace!("Returning list of known peers");
                             if (connection
                                 .output
-                                .send(OutEvent::KnownPeersResponse(self.inner.peers()))
+                                .send(OutEvent::KnownPeersResponse(self.current.graph.peers()))
                                 .await)
                                 .is_err()
                             {
@@ -428,30 +875,71 @@ where
                                 return;
                             }
                         }
-                        InEvent::ApplySync { from, sync } => {
-                            trace!(target: Targets::Synchronization.into_str(), "Applying sync from: {:?}", from);
-                            connection.set_state(ModuleState::Busy);
-                            trace!("Set consensus state to busy");
-                            let apply_result = self.apply_sync(from, sync);
-                            trace!("Set consensus state to ready");
-                            connection.set_state(ModuleState::Ready);
-                            if let Err(e) = apply_result {
-                                warn!(target: Targets::Synchronization.into_str(), "Failed to apply sync from peer {}: {}", from, e);
+                        InEvent::ApplySync { from, sync: ConsensusSync::Leader(_) } => {
+                            warn!(target: Targets::Synchronization.into_str(), "Peer {} uses leader consensus, ignoring its sync", from);
+                        }
+                        InEvent::ApplySync { from, sync: ConsensusSync::Graph { generation, sync } } => {
+                            self.peer_generations.insert(from, generation);
+                            if generation != self.current.number {
+                                // the peer gets a sync it can apply on the next gossip
+                                debug!(target: Targets::Synchronization.into_str(), "Sync from {} is of generation {}, this peer is at {}, ignoring", from, generation, self.current.number);
+                            } else {
+                                trace!(target: Targets::Synchronization.into_str(), "Applying sync from: {:?}", from);
+                                connection.set_state(ModuleState::Busy);
+                                trace!("Set consensus state to busy");
+                                let apply_result = self.apply_sync(from, sync);
+                                trace!("Set consensus state to ready");
+                                connection.set_state(ModuleState::Ready);
+                                if let Err(e) = apply_result {
+                                    if let ApplySyncError::Journal(e) = *e {
+                                        error!("{}, shutting down consensus", e);
+                                        return;
+                                    }
+                                    warn!(target: Targets::Synchronization.into_str(), "Failed to apply sync from peer {}: {}", from, e);
+                                } else {
+                                    trace!(target: Targets::Synchronization.into_str(), "Applied sync successfully");
+                                }
+                            }
+                        }
+                        InEvent::SkipToGeneration(generation) => {
+                            if !should_skip(generation, self.current.number) {
+                                // the previous generation is still synced by gossip
+                                debug!("Not skipping to generation {} from {}", generation, self.current.number);
                             } else {
-                                trace!(target: Targets::Synchronization.into_str(), "Applied sync successfully");
+                                warn!("Skipping to generation {}, transactions finalized before are not delivered", generation);
+                                if let Err(e) = self.start_generation(generation) {
+                                    error!("{}, shutting down consensus", e);
+                                    return;
+                                }
+                                let event = OutEvent::GenerationStarted { generation, skipped: true, replayed: false };
+                                if (connection.output.send(event).await).is_err() {
+                                    error!("`connection.output` is closed, shuttung down consensus");
+                                    return;
+                                }
+                            }
+                        }
+                        InEvent::Checkpoint { generation, state } => {
+                            if let Err(e) = self.checkpoint(generation, state) {
+                                error!("{}, shutting down consensus", e);
+                                return;
                             }
                         }
                         InEvent::ScheduleTx(tx) => {
                             trace!("Scheduling transaction: {:?}", tx);
-                            if let Transaction::InitializeStorage { distribution: _ } = &tx {
+                            if let Transaction::InitializeStorage { .. } = &tx {
                                 debug!(target: Targets::StorageInitialization.into_str(), "Scheduling init transaction for inclusion in event");
                             }
                             self.push_tx(tx);
                         }
                         InEvent::CreateStandalone => {
                             debug!("Creating a standalone event");
-                            if let Err(e) = self.create_standalone_event() {
-                                warn!("Failed to create standalone event: {}", e);
+                            match self.create_standalone_event().map_err(|e| *e) {
+                                Ok(()) => (),
+                                Err(CreateStandaloneError::Journal(e)) => {
+                                    error!("{}, shutting down consensus", e);
+                                    return;
+                                }
+                                Err(e) => warn!("Failed to create standalone event: {}", e),
                             }
                         }
                     }
@@ -464,3 +952,55 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{replayed_start, should_skip, sync_generation};
+    use crate::{
+        consensus::journal::{Entry, Journal},
+        types::{Sid, Vid},
+    };
+
+    #[test]
+    fn generations_follow_each_other() {
+        // peers are synced in the generation they are at while its graph is kept
+        assert_eq!(sync_generation(None, 5, true), Some(5));
+        assert_eq!(sync_generation(Some(7), 5, true), Some(5));
+        assert_eq!(sync_generation(Some(4), 5, true), Some(4));
+        assert_eq!(sync_generation(Some(4), 5, false), None);
+        assert_eq!(sync_generation(Some(3), 5, true), None);
+
+        assert!(!should_skip(6, 5));
+        assert!(!should_skip(3, 5));
+        assert!(should_skip(7, 5));
+        assert!(!should_skip(u64::MAX, u64::MAX));
+
+        // generations started before restart are started again in order
+        let path =
+            std::env::temp_dir().join(format!("the-swarm-graph-test-{}", rand::random::<u64>()));
+        let (mut journal, _) = Journal::open::<Vid, Sid>(&path).unwrap();
+        for generation in [1, 4, 5] {
+            journal
+                .append::<Vid, Sid>(&Entry::Generation {
+                    generation,
+                    timestamps: vec![],
+                })
+                .unwrap();
+        }
+        let (_, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        let mut current = 0;
+        let mut skipped = vec![];
+        for (entry, _) in entries {
+            let Entry::Generation { generation, .. } = entry else {
+                panic!("only generations were journaled");
+            };
+            skipped.push(replayed_start(generation, current, true).unwrap());
+            current = generation;
+        }
+        assert_eq!(skipped, vec![false, true, false]);
+        assert!(replayed_start(6, 5, false).is_err());
+        assert!(replayed_start(5, 5, true).is_err());
+        assert!(replayed_start(u64::MAX, u64::MAX, true).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+//! Journal of the hashgraph, so that a restarted node continues its history
+//! instead of starting a new one.
+//!
+//! Every operation that changes the graph is appended to the journal along
+//! with the timestamps it took from the clock. On startup the operations are
+//! repeated on a fresh graph; the same timestamps are given back by
+//! [`JournalClock`], so recreated events of this peer get the same hashes
+//! and signatures. Transactions already delivered to the behaviour are
+//! recorded in the journal as well and are taken again in the same places of
+//! the replay, as are starts of new generations (see
+//! [`super::graph::GraphWrapper`]).
+//!
+//! Each entry is flushed to disk before the operation has any effect outside
+//! the graph, e.g. before a created event is gossiped. An entry that can't
+//! be replayed means the journal doesn't match the graph it was written for,
+//! so the node refuses to start instead of continuing with a different
+//! history.
+//!
+//! Replay doesn't need generations the behaviour has a checkpoint after
+//! (its state at the start of a later generation). The journal is rewritten
+//! to start with [`Entry::Checkpoint`] instead of the entries before it, so
+//! it holds only a few generations.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use libp2p::PeerId;
+use rust_hashgraph::algorithm::{event::Hash, Clock};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use super::graph::{EventPayload, SyncJobs};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Could not access the journal: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not (de)serialize journal entry: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Journal belongs to another peer {0:?}")]
+    ForeignJournal(PeerId),
+    #[error("Journal entry at byte {0} is damaged, entries after it would be lost")]
+    Corrupted(usize),
+    #[error("Journal does not start with genesis")]
+    MissingGenesis,
+    #[error("Could not replay journal entry {index}: {reason}")]
+    Replay { index: usize, reason: String },
+}
+
+/// Operation on the graph. Timestamps are the ones given by the clock
+/// during the operation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound(
+    serialize = "TDataId: Serialize, TShardId: Serialize",
+    deserialize = "TDataId: DeserializeOwned, TShardId: DeserializeOwned"
+))]
+pub enum Entry<TDataId, TShardId> {
+    /// The graph was created by this peer
+    Genesis {
+        self_id: PeerId,
+        timestamps: Vec<u128>,
+    },
+    /// Events received in sync were added. Only the first `accepted` ones,
+    /// if the sync was rejected at some event.
+    Synced {
+        jobs: SyncJobs<TDataId, TShardId>,
+        accepted: usize,
+        timestamps: Vec<u128>,
+    },
+    /// This peer created an event
+    Created {
+        payload: EventPayload<TDataId, TShardId>,
+        other_parent: Hash,
+        timestamps: Vec<u128>,
+    },
+    /// Transaction was delivered to the behaviour
+    Delivered { finalized: bool },
+    /// New graph was started, either after the previous generation or
+    /// skipping to a later one
+    Generation {
+        generation: u64,
+        timestamps: Vec<u128>,
+    },
+    /// Replaces the entries before the start of `generation` (including
+    /// genesis). `state` is the checkpoint of the behaviour at that start,
+    /// `timestamps` are the ones taken to start the graph.
+    Checkpoint {
+        self_id: PeerId,
+        generation: u64,
+        timestamps: Vec<u128>,
+        state: Vec<u8>,
+    },
+}
+
+/// Append-only file with journal entries, each prefixed with its length
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Length of the valid entries in bytes
+    len: u64,
+}
+
+impl Journal {
+    /// Open the journal along with the entries it already has and the
+    /// position after each of them. Partially written entry at the end (left
+    /// by a crash) is discarded, a damaged one before it is an error.
+    #[allow(clippy::type_complexity)]
+    pub fn open<TDataId, TShardId>(
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<(Entry<TDataId, TShardId>, u64)>), Error>
+    where
+        TDataId: DeserializeOwned,
+        TShardId: DeserializeOwned,
+    {
+        let path = path.as_ref().to_owned();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        let mut valid_len = 0;
+        while valid_len < bytes.len() {
+            let (entry, len) = match Self::read_entry(&bytes[valid_len..]) {
+                Some(Ok(entry)) => entry,
+                // unwritten part of the last entry might read as anything
+                Some(Err(len)) if valid_len + len == bytes.len() => break,
+                // damaged length might point past the end as well
+                None if !Self::entries_follow::<Entry<TDataId, TShardId>>(&bytes[valid_len..]) => {
+                    break
+                }
+                _ => return Err(Error::Corrupted(valid_len)),
+            };
+            valid_len += len;
+            entries.push((entry, valid_len.try_into().unwrap()));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_len < bytes.len() {
+            warn!(
+                "Discarding {} byte(s) of incomplete entry at the end of {:?}",
+                bytes.len() - valid_len,
+                path
+            );
+            file.set_len(valid_len.try_into().unwrap())?;
+        }
+        let journal = Self {
+            path,
+            file,
+            len: valid_len.try_into().unwrap(),
+        };
+        Ok((journal, entries))
+    }
+
+    /// Entry at the beginning of `bytes` and its length in bytes (the
+    /// length alone if it can't be deserialized), `None` if the entry is
+    /// incomplete
+    fn read_entry<T: DeserializeOwned>(bytes: &[u8]) -> Option<Result<(T, usize), usize>> {
+        let len = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let end = usize::try_from(len).ok()?.checked_add(8)?;
+        let entry = bincode::deserialize(bytes.get(8..end)?).map_err(|_| end);
+        Some(entry.map(|entry| (entry, end)))
+    }
+
+    /// Whether `bytes` end with whole entries starting somewhere after the
+    /// first byte, so the entry at the start is damaged rather than torn
+    fn entries_follow<T: DeserializeOwned>(bytes: &[u8]) -> bool {
+        (1..bytes.len()).any(|start| {
+            let mut rest = &bytes[start..];
+            while let Some(Ok((_, len))) = Self::read_entry::<T>(rest) {
+                rest = &rest[len..];
+            }
+            rest.is_empty()
+        })
+    }
+
+    fn encode<TDataId, TShardId>(entry: &Entry<TDataId, TShardId>) -> Result<Vec<u8>, Error>
+    where
+        TDataId: Serialize,
+        TShardId: Serialize,
+    {
+        let serialized = bincode::serialize(entry)?;
+        let mut bytes = Vec::with_capacity(serialized.len() + 8);
+        bytes.extend_from_slice(&u64::try_from(serialized.len()).unwrap().to_le_bytes());
+        bytes.extend_from_slice(&serialized);
+        Ok(bytes)
+    }
+
+    pub fn append<TDataId, TShardId>(
+        &mut self,
+        entry: &Entry<TDataId, TShardId>,
+    ) -> Result<(), Error>
+    where
+        TDataId: Serialize,
+        TShardId: Serialize,
+    {
+        let bytes = Self::encode(entry)?;
+        // single write, so a crash leaves at most one incomplete entry
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.len += u64::try_from(bytes.len()).unwrap();
+        Ok(())
+    }
+
+    /// Position after the last entry
+    pub fn position(&self) -> u64 {
+        self.len
+    }
+
+    /// Replace the entries before position `keep_from` with `checkpoint`.
+    /// Returns the new position of the kept entries. A crash during the
+    /// compaction leaves the previous journal.
+    pub fn compact<TDataId, TShardId>(
+        &mut self,
+        checkpoint: &Entry<TDataId, TShardId>,
+        keep_from: u64,
+    ) -> Result<u64, Error>
+    where
+        TDataId: Serialize,
+        TShardId: Serialize,
+    {
+        let kept = fs::read(&self.path)?.split_off(keep_from.try_into().unwrap());
+        let mut bytes = Self::encode(checkpoint)?;
+        let checkpoint_len = u64::try_from(bytes.len()).unwrap();
+        bytes.extend_from_slice(&kept);
+        let temporary = self.path.with_extension("tmp");
+        let mut compacted = File::create(&temporary)?;
+        compacted.write_all(&bytes)?;
+        compacted.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = u64::try_from(bytes.len()).unwrap();
+        Ok(checkpoint_len)
+    }
+}
+
+#[derive(Default)]
+struct Timestamps {
+    /// Given out instead of the clock readings during replay
+    replay: VecDeque<u128>,
+    /// Readings since the last [`ClockHandle::take_recorded()`]
+    recorded: Vec<u128>,
+}
+
+/// Clock that remembers its readings and repeats them on replay
+pub struct JournalClock<TClock> {
+    inner: TClock,
+    timestamps: Arc<Mutex<Timestamps>>,
+}
+
+/// Access to the timestamps of [`JournalClock`]s moved into the graphs
+#[derive(Clone, Default)]
+pub struct ClockHandle {
+    timestamps: Arc<Mutex<Timestamps>>,
+}
+
+impl<TClock: Clock> Clock for JournalClock<TClock> {
+    fn current_timestamp(&mut self) -> u128 {
+        let mut timestamps = self.timestamps.lock().expect("clock lock is not poisoned");
+        match timestamps.replay.pop_front() {
+            Some(timestamp) => timestamp,
+            None => {
+                let timestamp = self.inner.current_timestamp();
+                timestamps.recorded.push(timestamp);
+                timestamp
+            }
+        }
+    }
+}
+
+impl ClockHandle {
+    /// Clock sharing the timestamps with others of this handle
+    pub fn clock<TClock>(&self, inner: TClock) -> JournalClock<TClock> {
+        JournalClock {
+            inner,
+            timestamps: self.timestamps.clone(),
+        }
+    }
+
+    /// Readings taken since the previous call
+    pub fn take_recorded(&self) -> Vec<u128> {
+        let mut timestamps = self.timestamps.lock().expect("clock lock is not poisoned");
+        std::mem::take(&mut timestamps.recorded)
+    }
+
+    /// Give out these timestamps on the next readings
+    pub fn replay(&self, replay: Vec<u128>) {
+        let mut timestamps = self.timestamps.lock().expect("clock lock is not poisoned");
+        timestamps.replay = replay.into();
+        timestamps.recorded.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use libp2p::PeerId;
+    use rust_hashgraph::algorithm::{event::Hash, Clock};
+
+    use super::{ClockHandle, Entry, Error, Journal};
+    use crate::{
+        consensus::{graph::EventPayload, Transaction},
+        types::{Sid, Vid},
+    };
+
+    struct Counter(u128);
+
+    impl Clock for Counter {
+        fn current_timestamp(&mut self) -> u128 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    #[test]
+    fn journal_survives_reopen_and_torn_write() {
+        let path =
+            std::env::temp_dir().join(format!("the-swarm-journal-test-{}", rand::random::<u64>()));
+        let self_id = PeerId::random();
+        let (mut journal, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        assert!(entries.is_empty());
+        journal
+            .append::<Vid, Sid>(&Entry::Genesis {
+                self_id,
+                timestamps: vec![1],
+            })
+            .unwrap();
+        journal
+            .append(&Entry::Created {
+                payload: EventPayload::new(vec![Transaction::<Vid, Sid, PeerId>::Delete(Vid(1))]),
+                other_parent: Hash::from_array([3; 64]),
+                timestamps: vec![2],
+            })
+            .unwrap();
+        journal
+            .append::<Vid, Sid>(&Entry::Delivered { finalized: true })
+            .unwrap();
+        // crash in the middle of writing
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[100, 0, 0, 0, 0, 0, 0, 0, 1])
+            .unwrap();
+
+        let (mut journal, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(
+            matches!(&entries[0].0, Entry::Genesis { self_id: id, timestamps } if id == &self_id && timestamps == &[1])
+        );
+        assert!(matches!(&entries[1].0, Entry::Created { timestamps, .. } if timestamps == &[2]));
+        assert!(matches!(entries[2].0, Entry::Delivered { finalized: true }));
+        journal
+            .append::<Vid, Sid>(&Entry::Delivered { finalized: false })
+            .unwrap();
+        let (mut journal, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+
+        // entries up to the created event are replaced with a checkpoint
+        let kept_from = entries[1].1;
+        let position = journal
+            .compact::<Vid, Sid>(
+                &Entry::Checkpoint {
+                    self_id,
+                    generation: 2,
+                    timestamps: vec![5],
+                    state: vec![7; 100],
+                },
+                kept_from,
+            )
+            .unwrap();
+        assert_eq!(journal.position(), entries[3].1 - kept_from + position);
+        journal
+            .append::<Vid, Sid>(&Entry::Delivered { finalized: true })
+            .unwrap();
+        let (_, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(
+            matches!(&entries[0].0, Entry::Checkpoint { generation: 2, state, .. } if state == &[7; 100])
+        );
+        assert_eq!(entries[0].1, position);
+        assert!(matches!(entries[1].0, Entry::Delivered { finalized: true }));
+        assert!(matches!(
+            entries[2].0,
+            Entry::Delivered { finalized: false }
+        ));
+        assert!(matches!(entries[3].0, Entry::Delivered { finalized: true }));
+        std::fs::remove_file(&path).unwrap();
+
+        // recorded readings are given back on replay
+        let handle = ClockHandle::default();
+        let mut clock = handle.clock(Counter(0));
+        assert_eq!(clock.current_timestamp(), 1);
+        assert_eq!(clock.current_timestamp(), 2);
+        assert_eq!(handle.take_recorded(), vec![1, 2]);
+        handle.replay(vec![1]);
+        assert_eq!(clock.current_timestamp(), 1);
+        assert_eq!(clock.current_timestamp(), 3);
+        assert_eq!(handle.take_recorded(), vec![3]);
+    }
+
+    #[test]
+    fn damaged_entry_is_not_discarded_with_the_rest() {
+        let path =
+            std::env::temp_dir().join(format!("the-swarm-journal-test-{}", rand::random::<u64>()));
+        let (mut journal, _) = Journal::open::<Vid, Sid>(&path).unwrap();
+        for finalized in [true, false, true] {
+            journal
+                .append::<Vid, Sid>(&Entry::Delivered { finalized })
+                .unwrap();
+        }
+        let (_, entries) = Journal::open::<Vid, Sid>(&path).unwrap();
+        let damage = |position: u64, offset: usize| {
+            let mut bytes = std::fs::read(&path).unwrap();
+            let at = usize::try_from(position).unwrap() + offset;
+            bytes[at..at + 4].copy_from_slice(&[0xff; 4]);
+            std::fs::write(&path, bytes).unwrap();
+        };
+        // unknown variant of the entry
+        let tag = 8;
+
+        // the last entry might be torn in any way
+        damage(entries[1].1, tag);
+        let (mut journal, reopened) = Journal::open::<Vid, Sid>(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(journal.position(), entries[1].1);
+        journal
+            .append::<Vid, Sid>(&Entry::Delivered { finalized: true })
+            .unwrap();
+
+        // entries after a damaged one are not dropped silently, even if its
+        // length points past the end
+        let intact = std::fs::read(&path).unwrap();
+        for offset in [tag, 0] {
+            damage(entries[0].1, offset);
+            assert!(matches!(
+                Journal::open::<Vid, Sid>(&path),
+                Err(Error::Corrupted(position)) if position as u64 == entries[0].1
+            ));
+            std::fs::write(&path, &intact).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+//! Ordering of transactions by a single leader.
+//!
+//! Every peer numbers its own transactions and proposes them in its syncs
+//! until they reach the leader. The leader appends them to the log (keeping
+//! the order of each author) and sends the log back in its syncs. An entry
+//! of the log is final once it's received, so each transaction is emitted as
+//! both recognized and finalized right away.
+//!
+//! The leader is trusted completely and the log is kept in memory only, so a
+//! restarted peer is not able to continue. Other peers are not: proposals
+//! are taken only from their authors and log entries only from the leader,
+//! as syncs are received from the sender directly. It's meant for local testing and
+//! latency comparisons, not for actual deployments.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use async_trait::async_trait;
+use blake2::{Blake2b512, Digest};
+use libp2p::PeerId;
+use rust_hashgraph::algorithm::event::Hash;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info, trace, warn};
+
+use crate::logging_helpers::Targets;
+use crate::module::ModuleChannelServer;
+use crate::types::{Sid, Vid};
+
+use super::{Consensus, ConsensusSync, InEvent, Module, ModuleState, OutEvent, Transaction};
+
+/// Maximum number of log entries sent in one sync, peers further behind
+/// receive the rest in next syncs
+pub const MAX_SYNC_ENTRIES: usize = 10_000;
+/// Maximum number of proposals sent in one sync, the rest are sent once
+/// these are ordered
+pub const MAX_SYNC_PROPOSALS: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Proposal {
+    author: PeerId,
+    /// Number of the transaction among ones of the author
+    seq: u64,
+    tx: Transaction<Vid, Sid, PeerId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LeaderSync {
+    /// Length of the sender's log
+    log_len: u64,
+    /// Index of the first entry in `entries`
+    first: u64,
+    entries: Vec<Proposal>,
+    /// Transactions known to the sender, but not ordered yet
+    proposals: Vec<Proposal>,
+}
+
+#[derive(Error, Debug)]
+pub enum ApplySyncError {
+    #[error("Sync entries start at {first}, but the log has only {len}")]
+    Gap { first: u64, len: u64 },
+    #[error("Entry {0} of the sync differs from the one in the log")]
+    Diverged(u64),
+}
+
+pub struct LeaderConsensus {
+    self_id: PeerId,
+    leader: PeerId,
+    log: Vec<Proposal>,
+    /// Number of log entries already emitted
+    delivered: usize,
+    /// Next `seq` expected from each author, earlier ones are in the log
+    ordered: HashMap<PeerId, u64>,
+    /// Known proposals that are not in the log yet
+    proposals: BTreeMap<(PeerId, u64), Transaction<Vid, Sid, PeerId>>,
+    next_seq: u64,
+    /// Log lengths reported by peers in their syncs
+    peer_log_len: HashMap<PeerId, u64>,
+}
+
+impl LeaderConsensus {
+    pub fn new(self_id: PeerId, leader: PeerId) -> Self {
+        Self {
+            self_id,
+            leader,
+            log: Vec::new(),
+            delivered: 0,
+            ordered: HashMap::new(),
+            proposals: BTreeMap::new(),
+            next_seq: 0,
+            peer_log_len: HashMap::new(),
+        }
+    }
+
+    fn is_leader(&self) -> bool {
+        self.self_id == self.leader
+    }
+
+    pub fn schedule(&mut self, tx: Transaction<Vid, Sid, PeerId>) {
+        self.proposals.insert((self.self_id, self.next_seq), tx);
+        self.next_seq += 1;
+        if self.is_leader() {
+            self.order_proposals();
+        }
+    }
+
+    pub fn generate_sync(&self, to: &PeerId) -> LeaderSync {
+        let len = self.log.len();
+        let first = self
+            .peer_log_len
+            .get(to)
+            .map(|known| usize::try_from(*known).unwrap_or(len).min(len))
+            .unwrap_or(0);
+        let last = len.min(first + MAX_SYNC_ENTRIES);
+        LeaderSync {
+            log_len: len.try_into().unwrap(),
+            first: first.try_into().unwrap(),
+            entries: self.log[first..last].to_vec(),
+            // proposals of others are taken only from them
+            proposals: self
+                .proposals
+                .iter()
+                .filter(|((author, _), _)| author == &self.self_id)
+                .take(MAX_SYNC_PROPOSALS)
+                .map(|((author, seq), tx)| Proposal {
+                    author: *author,
+                    seq: *seq,
+                    tx: tx.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn apply_sync(&mut self, from: PeerId, sync: LeaderSync) -> Result<(), ApplySyncError> {
+        self.peer_log_len.insert(from, sync.log_len);
+        // the leader's log is the source of all entries
+        if !self.is_leader() && from == self.leader {
+            let len = self.log.len() as u64;
+            if sync.first > len {
+                return Err(ApplySyncError::Gap {
+                    first: sync.first,
+                    len,
+                });
+            }
+            for (index, entry) in (sync.first..).zip(sync.entries) {
+                match self.log.get(index as usize) {
+                    Some(known) if known == &entry => (),
+                    Some(_) => return Err(ApplySyncError::Diverged(index)),
+                    None => self.append(entry),
+                }
+            }
+        }
+        for proposal in sync.proposals {
+            if proposal.author != from {
+                trace!(
+                    "{:?} relayed a proposal of {:?}, ignoring",
+                    from,
+                    proposal.author
+                );
+                continue;
+            }
+            if proposal.seq >= self.next_ordered(&proposal.author) {
+                self.proposals
+                    .insert((proposal.author, proposal.seq), proposal.tx);
+            }
+        }
+        if self.is_leader() {
+            self.order_proposals();
+        }
+        Ok(())
+    }
+
+    /// Next transaction in the log that wasn't emitted yet along with its
+    /// author and hash of the entry
+    pub fn next_delivered(&mut self) -> Option<(PeerId, Transaction<Vid, Sid, PeerId>, Hash)> {
+        let entry = self.log.get(self.delivered)?;
+        let hash = Self::entry_hash(self.delivered, entry);
+        self.delivered += 1;
+        Some((entry.author, entry.tx.clone(), hash))
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        let mut peers = BTreeSet::from([self.self_id, self.leader]);
+        peers.extend(self.peer_log_len.keys());
+        peers.extend(self.ordered.keys());
+        peers.into_iter().collect()
+    }
+
+    fn next_ordered(&self, author: &PeerId) -> u64 {
+        self.ordered.get(author).copied().unwrap_or(0)
+    }
+
+    /// Append proposals that follow already ordered ones of their authors
+    fn order_proposals(&mut self) {
+        let authors: BTreeSet<_> = self.proposals.keys().map(|(author, _)| *author).collect();
+        for author in authors {
+            loop {
+                let seq = self.next_ordered(&author);
+                let Some(tx) = self.proposals.remove(&(author, seq)) else {
+                    break;
+                };
+                self.append(Proposal { author, seq, tx });
+            }
+        }
+    }
+
+    fn append(&mut self, entry: Proposal) {
+        self.proposals.remove(&(entry.author, entry.seq));
+        self.ordered.insert(entry.author, entry.seq + 1);
+        self.log.push(entry);
+    }
+
+    /// Same on all peers, so it identifies the entry as an event hash does
+    fn entry_hash(index: usize, entry: &Proposal) -> Hash {
+        let mut hasher = Blake2b512::new();
+        hasher.update((index as u64).to_be_bytes());
+        hasher.update(entry.author.to_bytes());
+        hasher.update(entry.seq.to_be_bytes());
+        Hash::from_array(hasher.finalize().into())
+    }
+}
+
+#[async_trait]
+impl Consensus for LeaderConsensus {
+    async fn run(mut self, mut connection: ModuleChannelServer<Module>) {
+        connection.set_state(ModuleState::Ready);
+        loop {
+            while let Some((from, tx, event_hash)) = self.next_delivered() {
+                let recognized = OutEvent::RecognizedTransaction {
+                    from,
+                    event_hash: event_hash.clone(),
+                    tx: tx.clone(),
+                };
+                let finalized = OutEvent::FinalizedTransaction {
+                    from,
+                    event_hash,
+                    tx,
+                };
+                for event in [recognized, finalized] {
+                    if (connection.output.send(event).await).is_err() {
+                        info!("`connection.output` is closed, shutting down consensus");
+                        return;
+                    }
+                }
+            }
+            tokio::select! {
+                in_event = connection.input.recv() => {
+                    let Some(in_event) = in_event else {
+                        info!("`connection.input` is closed, shutting down consensus");
+                        return;
+                    };
+                    match in_event {
+                        InEvent::GenerateSyncRequest { to } => {
+                            debug!("Generating sync for {:?}", to);
+                            let sync = ConsensusSync::Leader(self.generate_sync(&to));
+                            if (connection
+                                .output
+                                .send(OutEvent::GenerateSyncResponse { to, sync })
+                                .await)
+                                .is_err()
+                            {
+                                info!("`connection.output` is closed, shutting down consensus");
+                                return;
+                            }
+                        }
+                        InEvent::KnownPeersRequest => {
+                            trace!("Returning list of known peers");
+                            if (connection
+                                .output
+                                .send(OutEvent::KnownPeersResponse(self.peers()))
+                                .await)
+                                .is_err()
+                            {
+                                info!("`connection.output` is closed, shutting down consensus");
+                                return;
+                            }
+                        }
+                        InEvent::ApplySync { from, sync: ConsensusSync::Graph { .. } } => {
+                            warn!(target: Targets::Synchronization.into_str(), "Peer {} uses hashgraph consensus, ignoring its sync", from);
+                        }
+                        InEvent::ApplySync { from, sync: ConsensusSync::Leader(sync) } => {
+                            trace!(target: Targets::Synchronization.into_str(), "Applying sync from: {:?}", from);
+                            if let Err(e) = self.apply_sync(from, sync) {
+                                warn!(target: Targets::Synchronization.into_str(), "Failed to apply sync from peer {}: {}", from, e);
+                            }
+                        }
+                        InEvent::ScheduleTx(tx) => {
+                            trace!("Scheduling transaction: {:?}", tx);
+                            self.schedule(tx);
+                        }
+                        // proposals are sent with every sync anyway
+                        InEvent::CreateStandalone => (),
+                        // the log is never split, so nobody is behind by generations
+                        InEvent::SkipToGeneration(_) => (),
+                        // the log is not journaled
+                        InEvent::Checkpoint { .. } => (),
+                    }
+                }
+                _ = connection.shutdown.cancelled() => {
+                    info!("received cancel signal, shutting down consensus");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{LeaderConsensus, LeaderSync, Proposal, MAX_SYNC_ENTRIES, MAX_SYNC_PROPOSALS};
+    use crate::consensus::Transaction;
+
+    #[test]
+    fn followers_receive_the_order_of_the_leader() {
+        let (leader_id, follower_id) = (PeerId::random(), PeerId::random());
+        let mut leader = LeaderConsensus::new(leader_id, leader_id);
+        let mut follower = LeaderConsensus::new(follower_id, leader_id);
+
+        follower.schedule(Transaction::Join { capacity: 1 });
+        follower.schedule(Transaction::Leave);
+        assert!(follower.next_delivered().is_none());
+        leader.schedule(Transaction::Join { capacity: 2 });
+
+        // another peer can't propose or order in the name of others
+        let forged = Proposal {
+            author: follower_id,
+            seq: 0,
+            tx: Transaction::Leave,
+        };
+        let forged = LeaderSync {
+            log_len: 1,
+            first: 0,
+            entries: vec![forged.clone()],
+            proposals: vec![forged],
+        };
+        leader.apply_sync(PeerId::random(), forged.clone()).unwrap();
+        follower.apply_sync(PeerId::random(), forged).unwrap();
+        assert_eq!(leader.log.len(), 1);
+        assert!(follower.log.is_empty());
+
+        leader
+            .apply_sync(follower_id, follower.generate_sync(&leader_id))
+            .unwrap();
+        follower
+            .apply_sync(leader_id, leader.generate_sync(&follower_id))
+            .unwrap();
+        // repeated syncs change nothing
+        follower
+            .apply_sync(leader_id, leader.generate_sync(&follower_id))
+            .unwrap();
+        assert!(follower.proposals.is_empty());
+
+        let order = |consensus: &mut LeaderConsensus| {
+            std::iter::from_fn(|| consensus.next_delivered()).collect::<Vec<_>>()
+        };
+        let leader_order = order(&mut leader);
+        assert_eq!(
+            leader_order
+                .iter()
+                .map(|(author, ..)| *author)
+                .collect::<Vec<_>>(),
+            vec![leader_id, follower_id, follower_id]
+        );
+        assert_eq!(leader_order[2].1, Transaction::Leave);
+        assert_eq!(leader_order, order(&mut follower));
+    }
+
+    #[test]
+    fn syncs_are_limited() {
+        let (leader_id, follower_id) = (PeerId::random(), PeerId::random());
+        let mut leader = LeaderConsensus::new(leader_id, leader_id);
+        let mut follower = LeaderConsensus::new(follower_id, leader_id);
+        let scheduled = MAX_SYNC_PROPOSALS.max(MAX_SYNC_ENTRIES) + 1;
+        for _ in 0..scheduled {
+            follower.schedule(Transaction::Leave);
+        }
+
+        // the rest of proposals is sent once the first ones are in the log
+        let sync = follower.generate_sync(&leader_id);
+        assert_eq!(sync.proposals.len(), MAX_SYNC_PROPOSALS);
+        leader.apply_sync(follower_id, sync).unwrap();
+        let sync = leader.generate_sync(&follower_id);
+        assert_eq!(sync.entries.len(), MAX_SYNC_ENTRIES);
+        follower.apply_sync(leader_id, sync).unwrap();
+        leader
+            .apply_sync(follower_id, follower.generate_sync(&leader_id))
+            .unwrap();
+        assert_eq!(leader.log.len(), scheduled);
+        follower
+            .apply_sync(leader_id, leader.generate_sync(&follower_id))
+            .unwrap();
+        assert_eq!(follower.log.len(), scheduled);
+        assert!(follower.proposals.is_empty());
+    }
+}
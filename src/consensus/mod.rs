@@ -1,73 +1,274 @@
 //! Consensus and its main functions.
-//! todo: revisit docs
 //!
 //! ## Description
 //!
-//! The intention is to utulize the graph consensus in 3 ways:
-//! - Reaching agreement on system state
-//! - Instruction memory (since it seems that storing requested
-//! instructions as transactions is a good idea)
-//! - Data discovery (we can also store the information about a node holding shard of a
-//! certain data unit as transaction)
+//! Consensus orders transactions of all peers. Everything else (data
+//! distribution, shard locations, programs and their results) is derived by
+//! the behaviour from the ordered transactions, so every peer arrives at the
+//! same state.
 //!
-//! For this we have corresponding traits [GraphConsensus], [DataDiscoverer]
-//! ([InstructionMemory] is in a separate module, because it makes sense to have it
-//! together with data memory).
+//! A consensus backend implements [`Consensus`]: it runs as a module and
+//! talks with the behaviour through [`InEvent`]/[`OutEvent`]. The behaviour
+//! asks it to generate syncs for random peers and applies syncs received from
+//! them; what a sync contains is up to the backend ([`ConsensusSync`]).
 //!
-//! ## Current implementation
+//! ## Backends
 //!
-//! Due to the lack of time, now there is only a mock consensus that does not provide
-//! Byzantine fault tolerance and proper security.
+//! - [`graph`] - Hashgraph from `rust-hashgraph`. Transactions are included
+//! in events of the graph; they are recognized once the event is known and
+//! finalized once its order is decided by virtual voting.
+//! - [`leader`] - single leader assigns the order of transactions proposed by
+//! all peers. Meant for fast local testing and for measuring the data/compute
+//! pipeline without hashgraph, it provides no fault tolerance at all.
 //!
-//! In the future the same traits are expected to be implemented for the actual consensus
-//! protocol to achieve a working distributed system. The implementations shouldn't be too
-//! complicated as long as the consensus itself is ready, since the required funcitons are
-//! easily done when the protocol is ready:
-//! - Updating graph is a part of protocol
-//! - Adding transactions easily done via tx buffer + creating events
-//! - Getting next tx is easy when we have an linear ordering of events
-//! - Shard locations can be obtained by searching txs with announcements of holding a
-//! vector shard in the graph (can be cached as well to reduce complexity)
-//! - Adding/getting instructions is basically adding/getting transaction of type
-//! instruction"
+//! All peers of the network must use the same backend.
 
 use std::fmt::Debug;
 
+use async_trait::async_trait;
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 
-use crate::processor::{Instructions, Program, ProgramIdentifier};
+use crate::{
+    data_memory::placement::Placement,
+    encoding::reed_solomon,
+    module::ModuleChannelServer,
+    processor::{template::Template, Instructions, Program, ProgramIdentifier},
+    types::{GraphSync, Hash, Sid, Vid},
+};
 
 pub mod graph;
+pub mod journal;
+pub mod leader;
+pub mod validation;
+
+/// Consensus backend, operated by the behaviour through [`Module`] channel
+#[async_trait]
+pub trait Consensus: Send {
+    async fn run(self, connection: ModuleChannelServer<Module>);
+}
+
+/// Consensus backends available to the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Hashgraph,
+    Leader,
+}
+
+/// State update for another peer, its contents depend on the backend
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum ConsensusSync {
+    /// Events of the graph of this generation
+    Graph {
+        generation: u64,
+        sync: GraphSync,
+    },
+    Leader(leader::LeaderSync),
+}
+
+pub struct Module;
+
+impl crate::module::Module for Module {
+    type InEvent = InEvent;
+    type OutEvent = OutEvent;
+    type SharedState = ModuleState;
+}
+
+#[derive(Debug, Clone)]
+pub enum OutEvent {
+    GenerateSyncResponse {
+        to: PeerId,
+        sync: ConsensusSync,
+    },
+    KnownPeersResponse(Vec<PeerId>),
+    /// The peer is more than a generation behind (see
+    /// [`graph::GraphWrapper`]), so it can't be synced
+    PeerTooFarBehind {
+        to: PeerId,
+        generation: u64,
+    },
+    /// Transactions of the graph of this generation follow. `skipped` if
+    /// transactions finalized in the generations before were not delivered,
+    /// `replayed` if it was started before restart.
+    GenerationStarted {
+        generation: u64,
+        skipped: bool,
+        replayed: bool,
+    },
+    /// This transaction is confirmed to be seen by supermajority
+    /// of the peers and its ordering is univocally decided by
+    /// consensus.
+    FinalizedTransaction {
+        from: PeerId,
+        event_hash: rust_hashgraph::algorithm::event::Hash,
+        tx: Transaction<Vid, Sid, PeerId>,
+    },
+    /// This transaction (tx) is not guaranteed to be seen by supermajority
+    /// of the peers; the ordering (of events) might change in finalized
+    /// version.
+    ///
+    /// Note that the order of recognized events (and, thus,
+    /// txs) follows ancestry relationship. I.e. if event $A$ is an ancestor
+    /// of event $B$, `RecognizedTransaction`'s for $A$'s txs will be emitted
+    /// before `RecognizedTransaction`'s for $B$
+    ///
+    /// ## Applications
+    ///
+    /// They still can be useful for example for:
+    /// 1. Speculative execution; a peer can start to perform some actions
+    /// associated with the transaction and have the result ready when
+    /// the transaction actually finalizes.
+    /// 2. Relying in special cases of the network, such as single peer
+    /// making all "command" transactions (txs switching order of which
+    /// can actually make a difference). Due to ancestry between all its events,
+    /// all "command" txs are going to be ordered properly, making it possible to
+    /// react to them right away and to improve system usability & responsiveness
+    RecognizedTransaction {
+        from: PeerId,
+        event_hash: rust_hashgraph::algorithm::event::Hash,
+        tx: Transaction<Vid, Sid, PeerId>,
+    },
+    /// Checkpoint given at the start of this generation before restart.
+    /// Emitted first; replayed transactions follow it.
+    RestoredCheckpoint {
+        generation: u64,
+        state: Vec<u8>,
+    },
+    /// Finalized transaction handled before restart, in the same order.
+    /// Emitted before any new ones; the modules keep their state across
+    /// restarts, so it's only needed to follow the state the validation
+    /// rules depend on.
+    ReplayedTransaction {
+        from: PeerId,
+        event_hash: rust_hashgraph::algorithm::event::Hash,
+        tx: Transaction<Vid, Sid, PeerId>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum InEvent {
+    GenerateSyncRequest {
+        to: PeerId,
+    },
+    // get list of known peers to the consensus
+    KnownPeersRequest,
+    ApplySync {
+        from: PeerId,
+        sync: ConsensusSync,
+    },
+    /// Most members are at this generation (see
+    /// [`crate::behaviour::snapshot::SkipVotes`]) and can't sync this one, so
+    /// consensus continues from there (if it's not the next one)
+    SkipToGeneration(u64),
+    /// State of the behaviour at the start of the generation. After restart
+    /// it's given back instead of the transactions finalized before.
+    Checkpoint {
+        generation: u64,
+        state: Vec<u8>,
+    },
+    ScheduleTx(Transaction<Vid, Sid, PeerId>),
+    CreateStandalone,
+}
+
+pub enum ModuleState {
+    Ready,
+    Busy,
+}
+
+impl crate::module::State for ModuleState {
+    fn accepts_input(&self) -> bool {
+        match self {
+            ModuleState::Ready => true,
+            ModuleState::Busy => false,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
 pub enum Transaction<TDataId, TShardId, TPeerId> {
-    /// All data will be stored according to this distribution.
-    /// Before this tx other ones are not processed
+    /// All data will be spread over these members with the placement and
+    /// encoding. Before this tx other ones are not processed. Peers with
+    /// different encoding settings do not join the storage. Only the first
+    /// one is accepted, membership changes later with `Join`, `Leave` and
+    /// `Evict`.
     InitializeStorage {
-        distribution: Vec<(TPeerId, TShardId)>,
+        members: Vec<TPeerId>,
+        placement: Placement,
+        encoding: reed_solomon::Settings,
     },
     /// We want to put data at this (memory) address with distribution specified in
-    /// `InitializeStorage` before
-    StorageRequest { data_id: TDataId },
-    /// Indicates that specified shard (data) of operand is stored somewhere
-    Stored(TDataId, TShardId),
+    /// `InitializeStorage` before. Pulled shards are checked against `checksums`.
+    StorageRequest {
+        data_id: TDataId,
+        checksums: Vec<(TShardId, Hash)>,
+    },
+    /// Indicates that specified shard (data) of operand is stored somewhere,
+    /// with checksum of its contents
+    Stored(TDataId, TShardId, Hash),
+    /// The data is no longer needed, all peers forget about it
+    Delete(TDataId),
     /// Program is queued for execution by the author
     Execute(Instructions),
-    /// Program was fully executed by this peer
-    Executed(ProgramIdentifier),
+    /// Template is available for invocation by its hash
+    RegisterTemplate(Template),
+    /// Program instantiated from the registered template is queued for
+    /// execution by the author
+    ExecuteTemplate {
+        template_hash: Hash,
+        bindings: Vec<TDataId>,
+    },
+    /// Author cancels its program, if it's not started yet
+    Cancel(ProgramIdentifier),
+    /// Program was fully executed by this peer, results are stored in these
+    /// shards. Checksums of new contents commit the peer to what it computed,
+    /// other peers check them against the reconstructable data.
+    Executed(ProgramIdentifier, Vec<((TDataId, TShardId), Hash)>),
+    /// Author wants to become a member of the storage, holding shards
+    /// according to its capacity (or updates the capacity if it is a member
+    /// already)
+    Join { capacity: u64 },
+    /// Author leaves the storage gracefully, its shards are placed on the
+    /// remaining members. The author keeps serving its shards until they are
+    /// migrated.
+    Leave,
+    /// Author considers the peer unavailable and proposes to remove it from
+    /// the storage. The peer is removed in the same way as with `Leave` once
+    /// more than half of the other members proposed it.
+    Evict(TPeerId),
+    /// Author holds `shards` placed on it after distribution change (either
+    /// copied or rebuilt)
+    ShardsRepaired { shards: Vec<(TDataId, TShardId)> },
 }
 
 impl<D: Debug, S, P> Transaction<D, S, P> {
     pub fn variant_short_string(&self) -> String {
         match self {
-            Transaction::InitializeStorage { distribution: _ } => "InitializeStorage".to_owned(),
-            Transaction::StorageRequest { data_id } => format!("StorageRequest({:?})", data_id),
-            Transaction::Stored(id, _) => format!("Stored({:?})", id),
+            Transaction::InitializeStorage { .. } => "InitializeStorage".to_owned(),
+            Transaction::StorageRequest { data_id, .. } => {
+                format!("StorageRequest({:?})", data_id)
+            }
+            Transaction::Stored(id, _, _) => format!("Stored({:?})", id),
+            Transaction::Delete(id) => format!("Delete({:?})", id),
             Transaction::Execute(ins) => {
                 let hash = Program::calculate_hash(ins).unwrap();
                 format!("Execute({:?})", hash)
             }
-            Transaction::Executed(id) => format!("Executed({:?})", id),
+            Transaction::RegisterTemplate(template) => {
+                let hash = template.hash().unwrap();
+                format!("RegisterTemplate({:?})", hash)
+            }
+            Transaction::ExecuteTemplate {
+                template_hash,
+                bindings,
+            } => format!("ExecuteTemplate({:?}, {:?})", template_hash, bindings),
+            Transaction::Cancel(id) => format!("Cancel({:?})", id),
+            Transaction::Executed(id, _) => format!("Executed({:?})", id),
+            Transaction::Join { capacity } => format!("Join({})", capacity),
+            Transaction::Leave => "Leave".to_owned(),
+            Transaction::Evict(_) => "Evict".to_owned(),
+            Transaction::ShardsRepaired { shards } => {
+                format!("ShardsRepaired({} shards)", shards.len())
+            }
         }
     }
 }
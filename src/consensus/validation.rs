@@ -0,0 +1,638 @@
+//! Rules transactions have to follow to be applied.
+//!
+//! Consensus orders anything peers put into it, so by itself it doesn't stop
+//! a peer from initializing the storage again, claiming shards placed on
+//! someone else or announcing results of a program it wasn't asked to run.
+//! Each transaction is passed through [`Validator`] before it's applied. The
+//! validator follows the part of the state the rules depend on (distribution
+//! and scheduled programs), changed only by accepted transactions.
+//!
+//! A program is forgotten once every member it was scheduled for (and that
+//! is still a member) announced its execution, or once it's cancelled, so
+//! the state doesn't grow with the number of executed programs.
+//!
+//! A program is cancelled only if no member announced its execution before
+//! the `Cancel` was finalized, whatever the progress of the program on each
+//! peer. Announcements finalized after it are rejected.
+//!
+//! Programs are rejected if the storage uses
+//! [`Placement::ConsistentHashing`], it doesn't keep shards with the same id
+//! of different data together (see [`crate::data_memory::placement`]).
+//!
+//! Members are evicted only when more than half of the other members propose
+//! it, so a single peer can't remove anyone. Proposals are counted by the
+//! validator as well.
+//!
+//! Many rules depend on the order (only the first `InitializeStorage` is
+//! accepted, membership changes move shards to other holders, execution is
+//! announced once), so transactions are validated and applied once they are
+//! finalized. The order of finalization is the same on all peers, so all of
+//! them accept and reject the same ones; the order of recognition is not.
+//!
+//! The state of the validator is checkpointed into the consensus journal at
+//! the start of each generation. A restarted node installs the checkpoint
+//! and validates transactions finalized after it again (without applying
+//! them), so it arrives at the same state as the other peers.
+//!
+//! The state of the validator is the same on all peers after the same
+//! transactions. A peer catching up with a snapshot installs
+//! [`ValidatorState`] only once most members signed the same one.
+
+use std::collections::{HashMap, HashSet};
+
+use blake2::{Blake2b512, Digest};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    data_memory::{
+        distribution::{self, Distribution},
+        placement::Placement,
+        FullShardId,
+    },
+    processor::{Program, ProgramIdentifier},
+    types::{Hash, Sid, Vid},
+};
+
+use super::Transaction;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("Storage is not initialized yet")]
+    NotInitialized,
+    #[error("Storage is already initialized")]
+    AlreadyInitialized,
+    #[error("Initial distribution can't be used with the encoding")]
+    InvalidDistribution,
+    #[error("Author is not a member of the storage")]
+    NotAMember,
+    #[error("Shard {0:?} is not placed on the author")]
+    NotAssigned(FullShardId),
+    #[error("Program is not scheduled")]
+    UnknownProgram,
+    #[error("Program is scheduled by another peer")]
+    NotAuthor,
+    #[error("Author has already announced execution of the program")]
+    AlreadyExecuted,
+    #[error("Author has already proposed eviction of the peer")]
+    AlreadyProposed,
+    #[error("Execution of the program was announced before the cancellation")]
+    ExecutionAnnounced,
+    #[error("Programs can't be executed with the placement of the storage")]
+    ProgramsUnsupported,
+    #[error(transparent)]
+    Membership(#[from] distribution::Error),
+}
+
+/// What an accepted transaction does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    /// The transaction is applied
+    Apply,
+    /// Eviction is proposed, but not by enough members yet
+    Proposed,
+}
+
+/// Rejected transaction, kept as evidence of misbehaviour of its author
+#[derive(Debug, Clone)]
+pub struct Evidence {
+    pub event_hash: Hash,
+    pub tx: String,
+    pub reason: Error,
+}
+
+struct ScheduledProgram {
+    author: PeerId,
+    /// Members at the time of scheduling, expected to execute it
+    expected: HashSet<PeerId>,
+    executed_by: HashSet<PeerId>,
+}
+
+impl ScheduledProgram {
+    /// Every expected peer that is still a member announced execution
+    fn is_confirmed(&self, distribution: &Distribution) -> bool {
+        self.expected
+            .iter()
+            .all(|peer| self.executed_by.contains(peer) || !distribution.is_member(peer))
+    }
+}
+
+/// State the rules depend on, with lists sorted so that peers with the
+/// same state get the same [`Self::digest()`]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidatorState {
+    pub distribution: Option<Distribution>,
+    /// Scheduled programs with their authors, members expected to execute
+    /// them and peers that announced execution
+    programs: Vec<(ProgramIdentifier, PeerId, Vec<PeerId>, Vec<PeerId>)>,
+    /// Peers proposed for eviction, with the members that proposed it
+    evictions: Vec<(PeerId, Vec<PeerId>)>,
+}
+
+impl ValidatorState {
+    pub fn digest(&self) -> bincode::Result<Hash> {
+        let mut hasher = Blake2b512::new();
+        hasher.update(bincode::serialize(self)?);
+        Ok(Hash::from_array(hasher.finalize().into()))
+    }
+}
+
+#[derive(Default)]
+pub struct Validator {
+    distribution: Option<Distribution>,
+    programs: HashMap<ProgramIdentifier, ScheduledProgram>,
+    /// Members that proposed eviction of the peer
+    evictions: HashMap<PeerId, HashSet<PeerId>>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the transaction of `author` included in event `event_hash`.
+    /// Valid transaction is taken into account for checking next ones.
+    pub fn validate(
+        &mut self,
+        author: &PeerId,
+        tx: &Transaction<Vid, Sid, PeerId>,
+        event_hash: &Hash,
+    ) -> Result<Accepted, Error> {
+        match tx {
+            Transaction::InitializeStorage {
+                members,
+                placement,
+                encoding,
+            } => {
+                // Initialization starts epoch 0 of the distribution, later
+                // epochs come from membership changes. Another initialization
+                // would replace the placement of all stored data, so only the
+                // first one is accepted.
+                if self.distribution.is_some() {
+                    return Err(Error::AlreadyInitialized);
+                }
+                if !members.contains(author) {
+                    return Err(Error::NotAMember);
+                }
+                let distribution =
+                    Distribution::initial(members.clone(), *placement, encoding.data_shards_total);
+                if !distribution.is_valid(encoding.data_shards_total) {
+                    return Err(Error::InvalidDistribution);
+                }
+                self.distribution = Some(distribution);
+            }
+            Transaction::StorageRequest { .. } | Transaction::Delete(_) => {
+                self.distribution()?;
+            }
+            Transaction::Stored(data_id, shard_id, _) => {
+                self.check_assigned(author, [&(data_id.clone(), shard_id.clone())])?
+            }
+            Transaction::Execute(instructions) => {
+                self.check_programs_supported()?;
+                // unhashable program is rejected by instruction memory anyway
+                if let Ok(hash) = Program::calculate_hash(instructions) {
+                    self.schedule(hash, event_hash, author);
+                }
+            }
+            Transaction::RegisterTemplate(_) => (),
+            Transaction::ExecuteTemplate {
+                template_hash,
+                bindings,
+            } => {
+                self.check_programs_supported()?;
+                if let Ok(hash) = Program::calculate_template_hash(template_hash, bindings) {
+                    self.schedule(hash, event_hash, author);
+                }
+            }
+            Transaction::Cancel(program_id) => {
+                let program = self.programs.get(program_id).ok_or(Error::UnknownProgram)?;
+                if &program.author != author {
+                    return Err(Error::NotAuthor);
+                }
+                if !program.executed_by.is_empty() {
+                    return Err(Error::ExecutionAnnounced);
+                }
+                self.programs.remove(program_id);
+            }
+            Transaction::Executed(program_id, updated_shards) => {
+                if !self.distribution()?.is_member(author) {
+                    return Err(Error::NotAMember);
+                }
+                let program = self.programs.get(program_id).ok_or(Error::UnknownProgram)?;
+                if program.executed_by.contains(author) {
+                    return Err(Error::AlreadyExecuted);
+                }
+                self.check_assigned(author, updated_shards.iter().map(|(id, _)| id))?;
+                if let Some(program) = self.programs.get_mut(program_id) {
+                    program.executed_by.insert(*author);
+                }
+                self.forget_confirmed();
+            }
+            Transaction::Join { capacity } => self.distribution_mut()?.join(*author, *capacity)?,
+            Transaction::Leave => {
+                self.distribution_mut()?.leave(author)?;
+                self.evictions.remove(author);
+                self.forget_confirmed();
+            }
+            Transaction::Evict(peer) => return self.propose_eviction(author, peer),
+            Transaction::ShardsRepaired { shards } => self.check_assigned(author, shards)?,
+        }
+        Ok(Accepted::Apply)
+    }
+
+    /// Count the proposal, the peer is evicted once more than half of the
+    /// other members proposed it
+    fn propose_eviction(&mut self, author: &PeerId, peer: &PeerId) -> Result<Accepted, Error> {
+        let distribution = self.distribution.as_ref().ok_or(Error::NotInitialized)?;
+        if !distribution.is_member(author) {
+            return Err(Error::NotAMember);
+        }
+        if !distribution.is_member(peer) {
+            return Err(distribution::Error::NotJoined.into());
+        }
+        let others: Vec<_> = distribution.members().filter(|m| *m != peer).collect();
+        let proposed_by = self.evictions.entry(*peer).or_default();
+        if !proposed_by.insert(*author) {
+            return Err(Error::AlreadyProposed);
+        }
+        let votes = others.iter().filter(|m| proposed_by.contains(m)).count();
+        if votes * 2 <= others.len() {
+            return Ok(Accepted::Proposed);
+        }
+        self.distribution_mut()?.leave(peer)?;
+        self.evictions.remove(peer);
+        self.forget_confirmed();
+        Ok(Accepted::Apply)
+    }
+
+    pub fn state(&self) -> ValidatorState {
+        let sorted = |peers: &HashSet<PeerId>| {
+            let mut peers: Vec<_> = peers.iter().cloned().collect();
+            peers.sort();
+            peers
+        };
+        let mut programs: Vec<_> = self
+            .programs
+            .iter()
+            .map(|(id, program)| {
+                (
+                    id.clone(),
+                    program.author,
+                    sorted(&program.expected),
+                    sorted(&program.executed_by),
+                )
+            })
+            .collect();
+        programs
+            .sort_by(|(a, ..), (b, ..)| (&a.hash, &a.event_hash).cmp(&(&b.hash, &b.event_hash)));
+        let mut evictions: Vec<_> = self
+            .evictions
+            .iter()
+            .map(|(peer, proposed_by)| (*peer, sorted(proposed_by)))
+            .collect();
+        evictions.sort();
+        ValidatorState {
+            distribution: self.distribution.clone(),
+            programs,
+            evictions,
+        }
+    }
+
+    /// Continue from the state taken on another peer
+    pub fn install(&mut self, state: ValidatorState) {
+        self.distribution = state.distribution;
+        self.programs = state
+            .programs
+            .into_iter()
+            .map(|(id, author, expected, executed_by)| {
+                let program = ScheduledProgram {
+                    author,
+                    expected: expected.into_iter().collect(),
+                    executed_by: executed_by.into_iter().collect(),
+                };
+                (id, program)
+            })
+            .collect();
+        self.evictions = state
+            .evictions
+            .into_iter()
+            .map(|(peer, proposed_by)| (peer, proposed_by.into_iter().collect()))
+            .collect();
+    }
+
+    fn distribution(&self) -> Result<&Distribution, Error> {
+        self.distribution.as_ref().ok_or(Error::NotInitialized)
+    }
+
+    fn distribution_mut(&mut self) -> Result<&mut Distribution, Error> {
+        self.distribution.as_mut().ok_or(Error::NotInitialized)
+    }
+
+    fn check_assigned<'a, I>(&self, author: &PeerId, shards: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a FullShardId>,
+    {
+        let distribution = self.distribution()?;
+        for full_shard_id in shards {
+            if distribution.holder_of(full_shard_id).as_ref() != Some(author) {
+                return Err(Error::NotAssigned(full_shard_id.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Distribution as of the transactions validated so far
+    pub fn known_distribution(&self) -> Option<&Distribution> {
+        self.distribution.as_ref()
+    }
+
+    /// Whether the program is scheduled and not cancelled
+    pub fn is_scheduled(&self, program_id: &ProgramIdentifier) -> bool {
+        self.programs.contains_key(program_id)
+    }
+
+    /// Shards with the same id of all data have to be on the same peer
+    fn check_programs_supported(&self) -> Result<(), Error> {
+        if self.distribution()?.placement() == Placement::ConsistentHashing {
+            return Err(Error::ProgramsUnsupported);
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self, hash: Hash, event_hash: &Hash, author: &PeerId) {
+        let Some(distribution) = &self.distribution else {
+            return;
+        };
+        let id = ProgramIdentifier {
+            hash,
+            event_hash: event_hash.clone(),
+        };
+        let expected = distribution.members().cloned().collect();
+        self.programs.entry(id).or_insert_with(|| ScheduledProgram {
+            author: *author,
+            expected,
+            executed_by: HashSet::new(),
+        });
+    }
+
+    /// Drop programs executed by all expected members that are still there
+    fn forget_confirmed(&mut self) {
+        let Some(distribution) = &self.distribution else {
+            return;
+        };
+        self.programs
+            .retain(|_, program| !program.is_confirmed(distribution));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{Accepted, Error, Validator, ValidatorState};
+    use crate::{
+        consensus::Transaction,
+        data_memory::{distribution, placement::Placement},
+        encoding::reed_solomon,
+        processor::{Program, ProgramIdentifier},
+        types::{Hash, Sid, Vid},
+    };
+
+    #[test]
+    fn rejects_transactions_breaking_the_rules() {
+        let (a, b, outsider) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let event_hash = Hash::from_array([1; 64]);
+        let mut validator = Validator::new();
+        let init = |placement| Transaction::InitializeStorage {
+            members: vec![a, b],
+            placement,
+            encoding: reed_solomon::Settings {
+                data_shards_total: 2,
+                data_shards_sufficient: 1,
+                max_shard_size: 64,
+            },
+        };
+        let stored = |shard| Transaction::Stored(Vid(0), Sid(shard), Hash::from_array([0; 64]));
+        assert_eq!(
+            validator.validate(&a, &stored(0), &event_hash),
+            Err(Error::NotInitialized)
+        );
+        assert_eq!(
+            validator.validate(&a, &init(Placement::RoundRobin), &event_hash),
+            Ok(Accepted::Apply)
+        );
+        assert_eq!(
+            validator.validate(&b, &init(Placement::RoundRobin), &event_hash),
+            Err(Error::AlreadyInitialized)
+        );
+
+        // round robin places shard 0 on `a` and shard 1 on `b`
+        validator.validate(&a, &stored(0), &event_hash).unwrap();
+        assert_eq!(
+            validator.validate(&a, &stored(1), &event_hash),
+            Err(Error::NotAssigned((Vid(0), Sid(1))))
+        );
+
+        let instructions = vec![];
+        let program_id = ProgramIdentifier {
+            hash: Program::calculate_hash(&instructions).unwrap(),
+            event_hash: event_hash.clone(),
+        };
+        let executed = Transaction::Executed(program_id.clone(), vec![]);
+        assert_eq!(
+            validator.validate(&b, &executed, &event_hash),
+            Err(Error::UnknownProgram)
+        );
+        validator
+            .validate(&a, &Transaction::Execute(instructions), &event_hash)
+            .unwrap();
+        assert_eq!(
+            validator.validate(&b, &Transaction::Cancel(program_id.clone()), &event_hash),
+            Err(Error::NotAuthor)
+        );
+        validator.validate(&b, &executed, &event_hash).unwrap();
+        assert_eq!(
+            validator.validate(&b, &executed, &event_hash),
+            Err(Error::AlreadyExecuted)
+        );
+        assert_eq!(
+            validator.validate(&a, &Transaction::Cancel(program_id), &event_hash),
+            Err(Error::ExecutionAnnounced)
+        );
+        // cancelled before anyone announced execution
+        let other_event = Hash::from_array([2; 64]);
+        let cancelled = ProgramIdentifier {
+            hash: Program::calculate_hash(&vec![]).unwrap(),
+            event_hash: other_event.clone(),
+        };
+        validator
+            .validate(&a, &Transaction::Execute(vec![]), &other_event)
+            .unwrap();
+        validator
+            .validate(&a, &Transaction::Cancel(cancelled.clone()), &event_hash)
+            .unwrap();
+        assert_eq!(
+            validator.validate(&b, &Transaction::Executed(cancelled, vec![]), &event_hash),
+            Err(Error::UnknownProgram)
+        );
+        assert_eq!(
+            validator.validate(&outsider, &executed, &event_hash),
+            Err(Error::NotAMember)
+        );
+
+        // shards with the same id are not kept together
+        let mut validator = Validator::new();
+        validator
+            .validate(&a, &init(Placement::ConsistentHashing), &event_hash)
+            .unwrap();
+        assert_eq!(
+            validator.validate(&a, &Transaction::Execute(vec![]), &event_hash),
+            Err(Error::ProgramsUnsupported)
+        );
+    }
+
+    #[test]
+    fn eviction_needs_majority_of_other_members() {
+        let members: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        let event_hash = Hash::from_array([1; 64]);
+        let mut validator = Validator::new();
+        let init = Transaction::InitializeStorage {
+            members: members.clone(),
+            placement: Placement::RoundRobin,
+            encoding: reed_solomon::Settings {
+                data_shards_total: 4,
+                data_shards_sufficient: 2,
+                max_shard_size: 64,
+            },
+        };
+        validator.validate(&members[0], &init, &event_hash).unwrap();
+        let evict = Transaction::<Vid, Sid, PeerId>::Evict(members[3]);
+        // the peer itself doesn't count, 2 of the other 3 members are needed
+        for proposer in [&members[3], &members[0]] {
+            assert_eq!(
+                validator.validate(proposer, &evict, &event_hash),
+                Ok(Accepted::Proposed)
+            );
+        }
+        assert_eq!(
+            validator.validate(&members[0], &evict, &event_hash),
+            Err(Error::AlreadyProposed)
+        );
+        assert_eq!(
+            validator.validate(&members[1], &evict, &event_hash),
+            Ok(Accepted::Apply)
+        );
+        assert!(validator
+            .validate(&members[2], &evict, &event_hash)
+            .is_err());
+        assert_eq!(
+            validator.validate(&members[3], &Transaction::Evict(members[0]), &event_hash),
+            Err(Error::NotAMember)
+        );
+    }
+
+    #[test]
+    fn executed_programs_are_forgotten() {
+        let members: Vec<_> = (0..3).map(|_| PeerId::random()).collect();
+        let mut validator = Validator::new();
+        let init = Transaction::InitializeStorage {
+            members: members.clone(),
+            placement: Placement::RoundRobin,
+            encoding: reed_solomon::Settings {
+                data_shards_total: 3,
+                data_shards_sufficient: 2,
+                max_shard_size: 64,
+            },
+        };
+        validator
+            .validate(&members[0], &init, &Hash::from_array([0; 64]))
+            .unwrap();
+        let event_hash = |i: u64| {
+            let mut bytes = [0; 64];
+            bytes[..8].copy_from_slice(&i.to_le_bytes());
+            Hash::from_array(bytes)
+        };
+        // schedule a program in event `i`, returns its announcement
+        let schedule = |validator: &mut Validator, i: u64| {
+            validator
+                .validate(&members[0], &Transaction::Execute(vec![]), &event_hash(i))
+                .unwrap();
+            Transaction::Executed(
+                ProgramIdentifier {
+                    hash: Program::calculate_hash(&vec![]).unwrap(),
+                    event_hash: event_hash(i),
+                },
+                vec![],
+            )
+        };
+        for i in 1..=10_000 {
+            let executed = schedule(&mut validator, i);
+            for member in &members {
+                validator
+                    .validate(member, &executed, &event_hash(i))
+                    .unwrap();
+            }
+        }
+        assert!(validator.state().programs.is_empty());
+
+        // the last member leaves instead of executing
+        let executed = schedule(&mut validator, 0);
+        for member in &members[..2] {
+            validator
+                .validate(member, &executed, &event_hash(0))
+                .unwrap();
+        }
+        assert_eq!(validator.state().programs.len(), 1);
+        validator
+            .validate(&members[2], &Transaction::Leave, &event_hash(0))
+            .unwrap();
+        assert!(validator.state().programs.is_empty());
+    }
+
+    #[test]
+    fn state_follows_the_order_of_transactions() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let event_hash = Hash::from_array([1; 64]);
+        let init = Transaction::InitializeStorage {
+            members: vec![a, b],
+            placement: Placement::RoundRobin,
+            encoding: reed_solomon::Settings {
+                data_shards_total: 3,
+                data_shards_sufficient: 2,
+                max_shard_size: 64,
+            },
+        };
+        let join = Transaction::Join { capacity: 1 };
+        // proposed only by members
+        let evict = Transaction::Evict(b);
+        let run = |order: &[(PeerId, &Transaction<Vid, Sid, PeerId>)]| {
+            let mut validator = Validator::new();
+            let accepted: Vec<_> = order
+                .iter()
+                .map(|(author, tx)| validator.validate(author, tx, &event_hash).is_ok())
+                .collect();
+            (accepted, validator)
+        };
+
+        let (accepted, joined_first) = run(&[(a, &init), (c, &join), (c, &evict)]);
+        assert_eq!(accepted, vec![true, true, true]);
+        let (accepted, evicted_first) = run(&[(a, &init), (c, &evict), (c, &join)]);
+        assert_eq!(accepted, vec![true, false, true]);
+        // rejected transactions leave no trace
+        let (accepted, same) = run(&[(a, &init), (b, &init), (c, &join), (c, &evict)]);
+        assert_eq!(accepted, vec![true, false, true, true]);
+        let digest = |state: ValidatorState| state.digest().unwrap();
+        assert_eq!(digest(joined_first.state()), digest(same.state()));
+        assert_ne!(digest(joined_first.state()), digest(evicted_first.state()));
+
+        // installed state decides the same way
+        let mut installed = Validator::new();
+        installed.install(evicted_first.state());
+        assert_eq!(installed.state(), evicted_first.state());
+        assert_eq!(
+            installed.validate(&c, &join, &event_hash),
+            Err(Error::Membership(distribution::Error::AlreadyJoined))
+        );
+    }
+}
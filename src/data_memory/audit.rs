@@ -0,0 +1,229 @@
+//! Proof-of-storage audits.
+//!
+//! From time to time a member picks a random shard held by someone else and
+//! checks that the holder still stores it. The auditor pulls enough other
+//! shards of the data (see [`super::recollection`]) and rebuilds the audited
+//! one. Then it challenges the holder to hash a random byte range of the shard
+//! together with a random key. The answer can't be precomputed, so only a peer
+//! that has the shard is able to give it.
+//!
+//! Results are recorded per peer, a holder that doesn't serve its shard when
+//! asked fails as well. A holder that fails several audits in a row is
+//! proposed for eviction. It's evicted once more than half of the other
+//! members propose it, after which its shards are repaired by others.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use blake2::{Blake2b512, Digest};
+use libp2p::PeerId;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Hash, Shard};
+
+use super::{recollection::Recollection, FullShardId};
+
+/// How often a new audit is started
+pub const AUDIT_PERIOD: Duration = Duration::from_secs(30);
+/// How long the holder has to answer the challenge
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Failing this many audits in a row makes the holder suspicious
+pub const MAX_FAILED_IN_ROW: u32 = 3;
+
+/// Request to hash bytes `start..end` of the shard prefixed with `key`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Challenge {
+    key: u64,
+    start: u64,
+    end: u64,
+}
+
+impl Challenge {
+    /// Random challenge for a shard of this length. `None` if the shard is
+    /// empty.
+    pub fn random(shard_len: usize) -> Option<Self> {
+        if shard_len == 0 {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let start = rng.gen_range(0..shard_len);
+        let end = rng.gen_range(start + 1..=shard_len);
+        Some(Self {
+            key: rng.gen(),
+            start: start.try_into().unwrap(),
+            end: end.try_into().unwrap(),
+        })
+    }
+
+    /// `None` if the range does not fit the shard
+    pub fn respond(&self, shard: &Shard) -> Option<Hash> {
+        let start = usize::try_from(self.start).ok()?;
+        let end = usize::try_from(self.end).ok()?;
+        let bytes = shard.as_ref().get(start..end)?;
+        let mut hasher = Blake2b512::new();
+        hasher.update(self.key.to_be_bytes());
+        hasher.update(bytes);
+        Some(Hash::from_array(hasher.finalize().into()))
+    }
+}
+
+enum Stage {
+    /// Pulling shards to rebuild the audited one
+    Rebuilding(Recollection),
+    /// Waiting for the answer of the holder
+    Challenged { expected: Hash, deadline: Instant },
+}
+
+/// Audit of a single shard.
+pub struct Audit {
+    pub target: FullShardId,
+    pub holder: PeerId,
+    stage: Stage,
+}
+
+impl Audit {
+    pub fn new(target: FullShardId, holder: PeerId, recollection: Recollection) -> Self {
+        Self {
+            target,
+            holder,
+            stage: Stage::Rebuilding(recollection),
+        }
+    }
+
+    /// Progress of pulling other shards, if the audited one is not rebuilt yet
+    pub fn rebuilding(&mut self) -> Option<&mut Recollection> {
+        match &mut self.stage {
+            Stage::Rebuilding(recollection) => Some(recollection),
+            Stage::Challenged { .. } => None,
+        }
+    }
+
+    /// Challenge to send to the holder, now that the shard is rebuilt
+    pub fn challenge(&mut self, rebuilt: &Shard, now: Instant) -> Option<Challenge> {
+        let challenge = Challenge::random(rebuilt.as_ref().len())?;
+        let expected = challenge.respond(rebuilt)?;
+        self.stage = Stage::Challenged {
+            expected,
+            deadline: now + CHALLENGE_TIMEOUT,
+        };
+        Some(challenge)
+    }
+
+    /// Whether the holder passed the audit. `None` if it wasn't challenged yet.
+    pub fn verify(&self, answer: Option<&Hash>) -> Option<bool> {
+        match &self.stage {
+            Stage::Rebuilding(_) => None,
+            Stage::Challenged { expected, .. } => Some(answer == Some(expected)),
+        }
+    }
+
+    /// The holder didn't answer the challenge in time
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match &self.stage {
+            Stage::Rebuilding(_) => false,
+            Stage::Challenged { deadline, .. } => *deadline <= now,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct AuditRecord {
+    pub passed: u64,
+    pub failed: u64,
+    pub failed_in_row: u32,
+}
+
+impl AuditRecord {
+    pub fn is_suspicious(&self) -> bool {
+        self.failed_in_row >= MAX_FAILED_IN_ROW
+    }
+}
+
+/// Audit results of each peer
+#[derive(Default)]
+pub struct AuditLog {
+    records: HashMap<PeerId, AuditRecord>,
+}
+
+impl AuditLog {
+    /// Returns updated record of the peer
+    pub fn record(&mut self, peer: PeerId, passed: bool) -> &AuditRecord {
+        let record = self.records.entry(peer).or_default();
+        if passed {
+            record.passed += 1;
+            record.failed_in_row = 0;
+        } else {
+            record.failed += 1;
+            record.failed_in_row += 1;
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use libp2p::PeerId;
+
+    use super::{Audit, AuditLog, Challenge, MAX_FAILED_IN_ROW};
+    use crate::{
+        data_memory::recollection::Recollection,
+        types::{Shard, Sid, Vid},
+    };
+
+    #[test]
+    fn challenge_needs_the_shard() {
+        let shard = Shard::new(4, vec![1, 2, 3, 4]);
+        let other = Shard::new(4, vec![1, 2, 3, 5]);
+        for _ in 0..16 {
+            let challenge = Challenge::random(4).unwrap();
+            let answer = challenge.respond(&shard);
+            assert!(answer.is_some());
+            if challenge.end == 4 {
+                assert_ne!(answer, challenge.respond(&other));
+            }
+        }
+        assert!(Challenge::random(0).is_none());
+        assert!(Challenge::random(4)
+            .unwrap()
+            .respond(&Shard::new(0, vec![]))
+            .is_none());
+
+        let holder = PeerId::random();
+        let mut audit = Audit::new((Vid(0), Sid(0)), holder, Recollection::new(1));
+        assert_eq!(audit.verify(None), None);
+        let challenge = audit.challenge(&shard, Instant::now()).unwrap();
+        assert_eq!(audit.verify(challenge.respond(&shard).as_ref()), Some(true));
+        assert_eq!(audit.verify(None), Some(false));
+        // answer from a shard with other contents
+        for _ in 0..16 {
+            let mut audit = Audit::new((Vid(0), Sid(0)), holder, Recollection::new(1));
+            let challenge = audit.challenge(&shard, Instant::now()).unwrap();
+            if challenge.end == 4 {
+                assert_eq!(
+                    audit.verify(challenge.respond(&other).as_ref()),
+                    Some(false)
+                );
+            }
+        }
+
+        let mut log = AuditLog::default();
+        for _ in 1..MAX_FAILED_IN_ROW {
+            assert!(!log.record(holder, false).is_suspicious());
+        }
+        assert!(!log.record(holder, true).is_suspicious());
+        for _ in 1..MAX_FAILED_IN_ROW {
+            assert!(!log.record(holder, false).is_suspicious());
+        }
+        let record = log.record(holder, false);
+        assert!(record.is_suspicious());
+        assert_eq!(
+            (record.passed, record.failed),
+            (1, 2 * MAX_FAILED_IN_ROW as u64 - 1)
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Storage membership and its evolution.
+//!
+//! The distribution starts with `InitializeStorage` transaction (epoch 0)
+//! and then changes only with finalized membership transactions (join,
+//! leave, evict). Each change increments the epoch. Since all peers apply
+//! the same transactions in the same order, they switch to the same
+//! distribution.
+//!
+//! Shards of each data unit are spread over the members according to the
+//! placement strategy (see [`super::placement`]), so a membership change may
+//! move shards of any data. Members find shards newly placed on them and
+//! migrate them (see [`super::repair`]).
+
+use std::collections::HashSet;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::types::{Sid, Vid};
+
+use super::{
+    placement::{Member, Placement, DEFAULT_CAPACITY},
+    FullShardId,
+};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("Peer is already a member of the storage with the same capacity")]
+    AlreadyJoined,
+    #[error("Peer is not a member of the storage")]
+    NotJoined,
+    #[error("The last member can't leave the storage")]
+    LastMemberLeaving,
+    #[error("Member must be able to hold some shards")]
+    ZeroCapacity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Distribution {
+    epoch: u64,
+    placement: Placement,
+    shards_total: u64,
+    /// In order of joining
+    members: Vec<Member>,
+}
+
+impl Distribution {
+    pub fn initial(peers: Vec<PeerId>, placement: Placement, shards_total: u64) -> Self {
+        let members = peers
+            .into_iter()
+            .map(|peer| Member {
+                peer,
+                capacity: DEFAULT_CAPACITY,
+            })
+            .collect();
+        Self {
+            epoch: 0,
+            placement,
+            shards_total,
+            members,
+        }
+    }
+
+    /// Check that the distribution can be used with the encoding: there is
+    /// someone to hold the shards, each member appears once and is able to
+    /// hold some.
+    pub fn is_valid(&self, shards_total: u64) -> bool {
+        let peers: HashSet<_> = self.members.iter().map(|member| member.peer).collect();
+        self.shards_total == shards_total
+            && !self.members.is_empty()
+            && peers.len() == self.members.len()
+            && self.members.iter().all(|member| member.capacity > 0)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn placement(&self) -> Placement {
+        self.placement
+    }
+
+    pub fn is_member(&self, peer: &PeerId) -> bool {
+        self.members.iter().any(|member| &member.peer == peer)
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &PeerId> {
+        self.members.iter().map(|member| &member.peer)
+    }
+
+    /// Holders of each shard of the data unit
+    pub fn layout(&self, data_id: &Vid) -> Vec<(Sid, PeerId)> {
+        self.placement
+            .place(data_id, self.shards_total, &self.members)
+    }
+
+    pub fn holder_of(&self, full_shard_id: &FullShardId) -> Option<PeerId> {
+        self.layout(&full_shard_id.0)
+            .into_iter()
+            .find(|(shard_id, _)| shard_id == &full_shard_id.1)
+            .map(|(_, peer)| peer)
+    }
+
+    /// Shards of the data unit placed on the peer
+    pub fn shards_of(&self, data_id: &Vid, peer: &PeerId) -> Vec<Sid> {
+        self.layout(data_id)
+            .into_iter()
+            .filter(|(_, holder)| holder == peer)
+            .map(|(shard_id, _)| shard_id)
+            .collect()
+    }
+
+    /// Add the peer to members (or update its capacity if it is a member
+    /// already)
+    pub fn join(&mut self, peer: PeerId, capacity: u64) -> Result<(), Error> {
+        if capacity == 0 {
+            return Err(Error::ZeroCapacity);
+        }
+        match self.members.iter_mut().find(|member| member.peer == peer) {
+            Some(member) if member.capacity == capacity => return Err(Error::AlreadyJoined),
+            Some(member) => member.capacity = capacity,
+            None => self.members.push(Member { peer, capacity }),
+        }
+        self.epoch += 1;
+        Ok(())
+    }
+
+    /// Remove the peer from members
+    pub fn leave(&mut self, peer: &PeerId) -> Result<(), Error> {
+        let position = self
+            .members
+            .iter()
+            .position(|member| &member.peer == peer)
+            .ok_or(Error::NotJoined)?;
+        if self.members.len() == 1 {
+            return Err(Error::LastMemberLeaving);
+        }
+        self.members.remove(position);
+        self.epoch += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{Distribution, Error};
+    use crate::{
+        data_memory::placement::{Placement, DEFAULT_CAPACITY},
+        types::{Sid, Vid},
+    };
+
+    #[test]
+    fn membership_changes() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut distribution = Distribution::initial(vec![a, b], Placement::RoundRobin, 3);
+        assert!(distribution.is_valid(3));
+        assert!(!distribution.is_valid(4));
+        // fewer members than shards
+        assert_eq!(distribution.shards_of(&Vid(0), &a), vec![Sid(0), Sid(2)]);
+        assert_eq!(distribution.holder_of(&(Vid(0), Sid(1))), Some(b));
+
+        let c = PeerId::random();
+        distribution.join(c, DEFAULT_CAPACITY).unwrap();
+        assert_eq!(
+            distribution.join(c, DEFAULT_CAPACITY),
+            Err(Error::AlreadyJoined)
+        );
+        assert_eq!(distribution.holder_of(&(Vid(0), Sid(2))), Some(c));
+        // capacity update is a change as well
+        distribution.join(c, 5).unwrap();
+        // the distribution would be invalid
+        assert_eq!(distribution.join(c, 0), Err(Error::ZeroCapacity));
+        assert_eq!(
+            distribution.join(PeerId::random(), 0),
+            Err(Error::ZeroCapacity)
+        );
+
+        distribution.leave(&b).unwrap();
+        assert_eq!(distribution.leave(&b), Err(Error::NotJoined));
+        assert!(!distribution.is_member(&b));
+        assert_eq!(distribution.holder_of(&(Vid(0), Sid(1))), Some(c));
+        distribution.leave(&a).unwrap();
+        assert_eq!(distribution.leave(&c), Err(Error::LastMemberLeaving));
+        assert_eq!(distribution.shards_of(&Vid(0), &c).len(), 3);
+        assert_eq!(distribution.epoch(), 4);
+    }
+}
@@ -14,14 +14,44 @@
 //!
 //! [`InitializedDataMemory::observe_new_location()`] mainly tracks locations of other data shards
 //! but also helps to remove unnecessary stored temporal data.
+//!
+//! Assigned shards, known locations and the distribution are kept in a [`storage::ShardStorage`],
+//! which may persist them on disk to survive restarts.
+//!
+//! Shards of each data unit are spread over storage members by [`placement`].
+//! Members change as peers join and leave the storage, see [`distribution`].
+//! Peers then restore shards newly placed on them, see [`repair`].
+//!
+//! Checksums of shard contents are announced in consensus; shards received
+//! from other peers are checked against them (see [`recollection`]). Holders
+//! are audited to check that they still store their shards, see [`audit`].
+//!
+//! Non-linear instructions of programs are computed on assembled data, see
+//! [`plaintext`]. Results announced by executors are sampled and checked
+//! against the reconstructable data, see [`verification`].
 
-use std::collections::{hash_map, HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
 use libp2p::PeerId;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn};
 
+use self::{
+    audit::{Audit, AuditLog, Challenge},
+    distribution::Distribution,
+    placement::Placement,
+    plaintext::{ComputeRequest, ExecutionStep, PlaintextSteps},
+    recollection::Recollection,
+    repair::Repair,
+    storage::ShardStorage,
+    verification::{ResultCheck, ResultCommitments, Verdict},
+};
 use crate::logging_helpers::Targets;
 use crate::module::ModuleChannelServer;
 use crate::{
@@ -29,9 +59,19 @@ use crate::{
         reed_solomon::{self, ReedSolomonWrapper},
         DataEncoding,
     },
-    types::{Data, Shard, Sid, Vid},
+    processor::{Instruction, ProgramIdentifier},
+    types::{Data, Hash, Shard, Sid, Vid},
 };
 
+pub mod audit;
+pub mod distribution;
+pub mod placement;
+pub mod plaintext;
+pub mod recollection;
+pub mod repair;
+pub mod storage;
+pub mod verification;
+
 pub struct Module;
 
 impl crate::module::Module for Module {
@@ -44,13 +84,22 @@ pub type FullShardId = (Vid, Sid);
 
 #[derive(Debug, Clone)]
 pub enum OutEvent {
-    // Ready to operate
+    /// Ready to operate
     Initialized,
+    /// Storage was initialized by the cluster with encoding settings different
+    /// from ours, this peer will not participate in it
+    InitializationRejected {
+        local: reed_solomon::Settings,
+        cluster: reed_solomon::Settings,
+    },
 
     // Data distribution
     /// 1. Prepare to serve the shards to nodes
-    /// - (server node) Done!
-    PreparedServiceResponse(Vid),
+    /// - (server node) Done! Checksums of the shards are to be announced
+    PreparedServiceResponse(Vid, Vec<(Sid, Hash)>),
+    /// 1. Prepare to serve the shards to nodes
+    /// - (server node) Data can't be encoded with current settings
+    PrepareServiceFailed(Vid, reed_solomon::Error),
     /// 2. The nodes see storage request transaction and pull assigned shards
     /// - (pulling node) this node requests a served shard from author of
     /// `StorageRequest` tx
@@ -60,7 +109,7 @@ pub enum OutEvent {
     ServeShardResponse(FullShardId, Option<Shard>),
     /// 2. The nodes see storage request transaction and pull assigned shards
     /// - this node now stores the assigned shard, need to notify it in consensus!
-    AssignedStoreSuccess(FullShardId),
+    AssignedStoreSuccess(FullShardId, Hash),
     /// 3. Enough storage confirmations were seen, consider the data to be distributed
     /// successfully (though continue to serve remaining)
     DistributionSufficient(Vid),
@@ -78,6 +127,61 @@ pub enum OutEvent {
     // data recollection
     /// Successfully assembled data, ready to provide it to the user
     RecollectResponse(Result<(Vid, Data), RecollectionError>),
+
+    // data removal
+    /// Everything about the data is removed from this peer
+    Deleted(Vid),
+
+    // membership & shard repair
+    /// Member of the storage seems to be lost, propose to evict it
+    EvictionRequest(PeerId),
+    /// Switched to a new distribution after membership change
+    DistributionChanged { epoch: u64 },
+    /// This peer restored shards placed on it in the new distribution and
+    /// stores them now, need to announce it in consensus
+    RepairFinished { shards: Vec<FullShardId> },
+
+    // storage audits
+    /// (auditor) Challenge the holder to prove it stores the shard
+    AuditRequest(FullShardId, Challenge, PeerId),
+    /// (holder) Answer to the challenge, `None` if the shard is not stored
+    AuditResponse(FullShardId, Challenge, Option<Hash>),
+    /// (auditor) The peer passed or failed the audit
+    AuditFinished { peer: PeerId, passed: bool },
+
+    // result checks
+    /// (checker) Holders of the result agreed or disagreed with the
+    /// reconstructed data
+    ResultChecked { data_id: Vid, verdict: Verdict },
+
+    // non-linear instructions
+    /// (executor) Send local operand shards to the coordinator of the step
+    PlaintextOperands {
+        step: ExecutionStep,
+        operands: Vec<(FullShardId, Shard)>,
+        coordinator: PeerId,
+    },
+    /// (coordinator) Send computed result shards to their holder
+    PlaintextResult {
+        step: ExecutionStep,
+        shards: Vec<(FullShardId, Shard)>,
+        holder: PeerId,
+    },
+
+    // fast sync
+    /// Current state of the storage, `None` if it is not initialized yet
+    Snapshot(Option<DataSnapshot>),
+}
+
+/// State of the storage agreed on in consensus, enough for a peer that
+/// joins later to start without replaying the whole history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DataSnapshot {
+    pub distribution: Distribution,
+    pub encoding: reed_solomon::Settings,
+    pub locations: HashMap<Vid, HashMap<Sid, PeerId>>,
+    pub checksums: HashMap<Vid, HashMap<Sid, Hash>>,
+    pub provisional_checksums: HashMap<Vid, HashMap<Sid, Hash>>,
 }
 
 #[derive(Debug, Clone, Error)]
@@ -89,12 +193,20 @@ pub enum RecollectionError {
     UnkonwnDataId,
     #[error("The data is not fully distributed, hopefully 'yet'")]
     NotEnoughShards,
+    #[error("The data was deleted during recollection")]
+    Deleted,
+    #[error("Too many shards of the data are missing or corrupted")]
+    ShardsUnavailable,
+    #[error("Holders of the data shards did not respond in time")]
+    Timeout,
 }
 
 #[derive(Debug, Clone)]
 pub enum InEvent {
     Initialize {
-        distribution: Vec<(PeerId, Sid)>,
+        members: Vec<PeerId>,
+        placement: Placement,
+        encoding: reed_solomon::Settings,
     },
 
     // Data distribution
@@ -106,8 +218,8 @@ pub enum InEvent {
     },
     /// 2. The nodes see storage request transaction and pull assigned shards
     /// - (pulling node) this node sees the storage request transaction authored
-    /// by a certain peer
-    StorageRequestTx(Vid, PeerId),
+    /// by a certain peer, with checksums of the shards
+    StorageRequestTx(Vid, PeerId, Vec<(Sid, Hash)>),
     /// 2. The nodes see storage request transaction and pull assigned shards
     /// - (pulling node) The shard is sent (not considered distributed yet!)
     ServeShardResponse(FullShardId, Option<Shard>),
@@ -120,6 +232,7 @@ pub enum InEvent {
     StoreConfirmed {
         full_shard_id: FullShardId,
         location: PeerId,
+        checksum: Hash,
     },
 
     // Already distributed & stored data
@@ -134,32 +247,98 @@ pub enum InEvent {
     /// Recollect data with given id, request by user
     RecollectRequest(Vid),
 
+    // data removal
+    /// Deletion of the data is finalized, forget about it
+    DeleteTx(Vid),
+
+    // storage audits
+    /// (holder) Prove that the shard is stored
+    AuditRequest(FullShardId, Challenge),
+    /// (auditor) Answer of the holder to the challenge
+    AuditResponse(FullShardId, Option<Hash>),
+
+    // non-linear instructions
+    /// (coordinator) Operand shards of the step sent by an executor
+    PlaintextOperands {
+        from: PeerId,
+        step: ExecutionStep,
+        operands: Vec<(FullShardId, Shard)>,
+    },
+    /// (executor) Result shards of the step sent by the coordinator
+    PlaintextResult {
+        from: PeerId,
+        step: ExecutionStep,
+        shards: Vec<(FullShardId, Shard)>,
+    },
+
     // program execution updates
+    /// The peer executed the program and committed to results with these
+    /// checksums
     PeerShardsActualized {
+        program_id: ProgramIdentifier,
         peer: PeerId,
-        updated_data_ids: Vec<Vid>,
+        updated_shards: Vec<(FullShardId, Hash)>,
     },
+
+    // membership & shard repair
+    /// The peer seems to have left the network (expired in discovery or
+    /// unreachable), it might need to be evicted
+    PeerLost(PeerId),
+    /// Finalized join of the peer (or update of its capacity)
+    PeerJoined { peer: PeerId, capacity: u64 },
+    /// Finalized graceful leave of the peer
+    PeerLeft(PeerId),
+    /// Finalized eviction of `peer` proposed by `by`
+    PeerEvicted { peer: PeerId, by: PeerId },
+    /// `holder` now stores `shards` placed on it
+    ShardsRepaired {
+        holder: PeerId,
+        shards: Vec<FullShardId>,
+    },
+
+    // fast sync
+    /// Take a snapshot of the current state
+    TakeSnapshot,
+    /// Take the state received from another peer, replacing the local one
+    /// if the storage is initialized already
+    InstallSnapshot(DataSnapshot),
 }
 
 pub struct MemoryBus {
-    reads: mpsc::Receiver<(Vid, oneshot::Sender<Option<Shard>>)>,
-    /// Store new value of the assigned shard
-    writes: mpsc::Receiver<(Vid, Shard)>,
+    /// Get all locally stored shards of the data
+    reads: mpsc::Receiver<(Vid, oneshot::Sender<HashMap<Sid, Shard>>)>,
+    /// Store new value of the shard
+    writes: mpsc::Receiver<(FullShardId, Shard)>,
+    /// Respond once the writes sent before are stored
+    syncs: mpsc::Receiver<oneshot::Sender<()>>,
+    /// Compute non-linear instruction on assembled data
+    plaintext: mpsc::Receiver<ComputeRequest>,
 }
 
 impl MemoryBus {
     pub fn new(
-        reads: mpsc::Receiver<(Vid, oneshot::Sender<Option<Shard>>)>,
-        writes: mpsc::Receiver<(Vid, Shard)>,
+        reads: mpsc::Receiver<(Vid, oneshot::Sender<HashMap<Sid, Shard>>)>,
+        writes: mpsc::Receiver<(FullShardId, Shard)>,
+        syncs: mpsc::Receiver<oneshot::Sender<()>>,
+        plaintext: mpsc::Receiver<ComputeRequest>,
     ) -> Self {
-        Self { reads, writes }
+        Self {
+            reads,
+            writes,
+            syncs,
+            plaintext,
+        }
     }
 
     pub fn channel(buffer: usize) -> (Self, crate::processor::single_threaded::MemoryBus) {
         let reads = mpsc::channel(buffer);
         let writes = mpsc::channel(buffer);
-        let this_end = Self::new(reads.1, writes.1);
-        let other_end = crate::processor::single_threaded::MemoryBus::new(reads.0, writes.0);
+        let syncs = mpsc::channel(buffer);
+        let plaintext = mpsc::channel(buffer);
+        let this_end = Self::new(reads.1, writes.1, syncs.1, plaintext.1);
+        let other_end = crate::processor::single_threaded::MemoryBus::new(
+            reads.0, writes.0, syncs.0, plaintext.0,
+        );
         (this_end, other_end)
     }
 }
@@ -168,52 +347,93 @@ struct UninitializedDataMemory {
     bus: MemoryBus,
     encoding: ReedSolomonWrapper,
     local_id: PeerId,
+    storage: Box<dyn ShardStorage>,
 }
 
 impl UninitializedDataMemory {
-    fn new(local_id: PeerId, bus: MemoryBus, encoding_settings: reed_solomon::Settings) -> Self {
+    fn new(
+        local_id: PeerId,
+        bus: MemoryBus,
+        encoding_settings: reed_solomon::Settings,
+        storage: Box<dyn ShardStorage>,
+    ) -> Self {
         let encoding = ReedSolomonWrapper::new(encoding_settings);
         Self {
             bus,
             encoding,
             local_id,
+            storage,
         }
     }
 
     /// `true` == valid
-    fn verify_distribution(&self, distribution: &HashMap<PeerId, Sid>) -> bool {
-        let shard_ids: HashSet<_> = distribution.values().collect();
-        let expected_ids_owned: HashSet<_> = (0..)
-            .take(
-                self.encoding
-                    .settings()
-                    .data_shards_total
-                    .try_into()
-                    .unwrap(),
-            )
-            .map(Sid)
-            .collect();
-        let expected_ids: HashSet<_> = expected_ids_owned.iter().collect();
-        shard_ids == expected_ids
+    fn verify_distribution(&self, distribution: &Distribution) -> bool {
+        let settings = self.encoding.settings();
+        distribution.is_valid(settings.data_shards_total)
     }
 
-    fn initialize(self, distribution: HashMap<PeerId, Sid>) -> InitializedDataMemory {
+    fn initialize(self, distribution: Distribution) -> InitializedDataMemory {
+        let restored = self.storage.restore();
+        let data_known_locations = restored.locations.clone();
+        let shard_checksums = restored.checksums.clone();
+        let provisional_checksums = restored.provisional_checksums.clone();
         InitializedDataMemory {
-            local_storage: HashMap::new(),
+            storage: self.storage,
             to_distribute: HashMap::new(),
             currently_assembled: HashMap::new(),
             distribution,
             local_id: self.local_id,
             bus: self.bus,
             encoding: self.encoding,
-            data_known_locations: HashMap::new(),
+            data_known_locations,
+            shard_checksums,
+            provisional_checksums,
+            repair: None,
+            reported_lost: HashSet::new(),
+            audit: None,
+            audit_log: AuditLog::default(),
+            plaintext: PlaintextSteps::default(),
+            result_commitments: ResultCommitments::default(),
+            result_check: None,
+        }
+    }
+
+    /// Distribution saved in the storage during previous launch, if it is valid
+    fn restored_distribution(&self) -> Option<Distribution> {
+        let restored = self.storage.restore();
+        let distribution = restored.distribution.clone()?;
+        if restored.encoding.as_ref() != Some(&self.encoding.settings()) {
+            warn!(
+                "encoding settings restored from storage ({:?}) differ from current ones ({:?}), \
+                waiting for initialization",
+                restored.encoding,
+                self.encoding.settings()
+            );
+            return None;
         }
+        if !self.verify_distribution(&distribution) {
+            warn!(
+                "distribution restored from storage doesn't match expected pattern \
+                (encoding settings changed?), waiting for initialization; got: {:?}",
+                distribution
+            );
+            return None;
+        }
+        Some(distribution)
     }
 
     async fn run(
         mut self,
         connection: &mut ModuleChannelServer<Module>,
     ) -> Option<InitializedDataMemory> {
+        if let Some(distribution) = self.restored_distribution() {
+            info!("storage restored from previous launch, ready");
+            if (connection.output.send(OutEvent::Initialized).await).is_err() {
+                error!("`connection.output` is closed, shutting down data memory");
+                return None;
+            }
+            return Some(self.initialize(distribution));
+        }
         loop {
             tokio::select! {
                 in_event = connection.input.recv() => {
@@ -222,18 +442,42 @@ impl UninitializedDataMemory {
                         return None;
                     };
                     match in_event {
-                        InEvent::Initialize { distribution } => {
+                        InEvent::Initialize { members, placement, encoding } => {
                             debug!(target: Targets::StorageInitialization.into_str(), "Initializing storage...");
-                            let distribution = distribution.into_iter().collect();
+                            let local_encoding = self.encoding.settings();
+                            if encoding != local_encoding {
+                                error!(
+                                    "storage is initialized with encoding settings {:?}, \
+                                    but this peer is configured with {:?}. refusing to join",
+                                    encoding, local_encoding
+                                );
+                                let event = OutEvent::InitializationRejected {
+                                    local: local_encoding,
+                                    cluster: encoding,
+                                };
+                                if (connection.output.send(event).await).is_err() {
+                                    error!("`connection.output` is closed, shutting down data memory");
+                                    return None;
+                                }
+                                continue;
+                            }
+                            let distribution = Distribution::initial(members, placement, encoding.data_shards_total);
                             if !self.verify_distribution(&distribution) {
                                 warn!(
                                     "received distribution doesn't match expected pattern; \
-                                expected to have a peer for each shard id from 0 to <total shard number>; \
+                                expected to have at least one member, each member once; \
                                 got: {:?}",
                                     distribution
                                 );
                                 continue;
                             }
+                            if let Err(e) = self.storage.store_distribution(distribution.clone(), encoding) {
+                                error!(
+                                    "could not save distribution to the storage, \
+                                    it will be lost on restart: {}",
+                                    e
+                                );
+                            }
                             info!("storage initialized, ready");
                             debug!(target: Targets::StorageInitialization.into_str(), "Notifying the user");
                             if (connection.output.send(OutEvent::Initialized).await).is_err() {
@@ -242,25 +486,102 @@ impl UninitializedDataMemory {
                             }
                             return Some(self.initialize(distribution));
                         }
+                        InEvent::InstallSnapshot(snapshot) => {
+                            let local_encoding = self.encoding.settings();
+                            if snapshot.encoding != local_encoding {
+                                error!(
+                                    "snapshot has encoding settings {:?}, \
+                                    but this peer is configured with {:?}. refusing to join",
+                                    snapshot.encoding, local_encoding
+                                );
+                                let event = OutEvent::InitializationRejected {
+                                    local: local_encoding,
+                                    cluster: snapshot.encoding,
+                                };
+                                if (connection.output.send(event).await).is_err() {
+                                    error!("`connection.output` is closed, shutting down data memory");
+                                    return None;
+                                }
+                                continue;
+                            }
+                            if !self.verify_distribution(&snapshot.distribution) {
+                                warn!(
+                                    "distribution from the snapshot doesn't match expected pattern, \
+                                    ignoring it; got: {:?}",
+                                    snapshot.distribution
+                                );
+                                continue;
+                            }
+                            if let Err(e) = self.storage.store_distribution(snapshot.distribution.clone(), snapshot.encoding) {
+                                error!(
+                                    "could not save distribution to the storage, \
+                                    it will be lost on restart: {}",
+                                    e
+                                );
+                            }
+                            let mut memory = self.initialize(snapshot.distribution.clone());
+                            for (data_id, locations) in snapshot.locations {
+                                for (shard_id, location) in locations {
+                                    memory.track_location((data_id.clone(), shard_id), location);
+                                }
+                            }
+                            for (data_id, checksums) in snapshot.checksums {
+                                for (shard_id, checksum) in checksums {
+                                    memory.track_checksum((data_id.clone(), shard_id), checksum);
+                                }
+                            }
+                            for (data_id, checksums) in snapshot.provisional_checksums {
+                                for (shard_id, checksum) in checksums {
+                                    memory.track_provisional_checksum((data_id.clone(), shard_id), Some(checksum));
+                                }
+                            }
+                            info!("storage initialized from snapshot, ready");
+                            if (connection.output.send(OutEvent::Initialized).await).is_err() {
+                                error!("`connection.output` is closed, shutting down data memory");
+                                return None;
+                            }
+                            return Some(memory);
+                        }
+                        InEvent::TakeSnapshot => {
+                            if (connection.output.send(OutEvent::Snapshot(None)).await).is_err() {
+                                error!("`connection.output` is closed, shutting down data memory");
+                                return None;
+                            }
+                        }
+                        InEvent::ListDistributed => {
+                            // nothing is stored yet; answered so that the requester
+                            // is not left waiting
+                            if (connection.output.send(OutEvent::ListDistributed(Vec::new())).await).is_err() {
+                                error!("`connection.output` is closed, shutting down data memory");
+                                return None;
+                            }
+                        }
                         InEvent::StoreConfirmed {
                             full_shard_id: _,
                             location: _,
+                            checksum: _,
                         }
                         | InEvent::PrepareServiceRequest {
                             data_id: _,
                             data: _,
                         }
-                        | InEvent::StorageRequestTx(_, _)
+                        | InEvent::StorageRequestTx(_, _, _)
                         | InEvent::ServeShardRequest(_)
                         | InEvent::ServeShardResponse(_, _)
                         | InEvent::AssignedRequest(_)
-                        | InEvent::ListDistributed
                         | InEvent::RecollectRequest(_)
+                        | InEvent::DeleteTx(_)
+                        | InEvent::AuditRequest(_, _)
+                        | InEvent::AuditResponse(_, _)
+                        | InEvent::PlaintextOperands { .. }
+                        | InEvent::PlaintextResult { .. }
                         | InEvent::AssignedResponse(_, _)
-                        | InEvent::PeerShardsActualized {
-                            peer: _,
-                            updated_data_ids: _,
-                        } => warn!(
+                        | InEvent::PeerShardsActualized { .. }
+                        | InEvent::PeerLost(_)
+                        | InEvent::PeerJoined { .. }
+                        | InEvent::PeerLeft(_)
+                        | InEvent::PeerEvicted { .. }
+                        | InEvent::ShardsRepaired { .. } => warn!(
                             "have not initialized storage, ignoring request {:?}",
                             in_event
                         ),
@@ -280,6 +601,21 @@ impl UninitializedDataMemory {
                     };
                     warn!("have not initialized storage, ignoring write request from memory bus");
                 }
+                sync_request = self.bus.syncs.recv() => {
+                    let Some(response_handle) = sync_request else {
+                        error!("memory bus is closed, shutting down data memory");
+                        return None;
+                    };
+                    // writes are ignored anyway
+                    let _ = response_handle.send(());
+                }
+                plaintext_request = self.bus.plaintext.recv() => {
+                    let Some(_) = plaintext_request else {
+                        error!("memory bus is closed, shutting down data memory");
+                        return None;
+                    };
+                    warn!("have not initialized storage, ignoring non-linear instruction from memory bus");
+                }
             }
         }
     }
@@ -296,43 +632,144 @@ impl UninitializedDataMemory {
 ///
 /// Use [`Self::run()`] to operate.
 struct InitializedDataMemory {
-    // `HashMap<Sid, Shard>` because in the future we might store multiple shards on each peer
-    local_storage: HashMap<Vid, HashMap<Sid, Shard>>,
+    /// Shards assigned to this peer. Placement may put multiple shards of the
+    /// same data on one peer, hence nested `HashMap<Sid, Shard>` inside
+    storage: Box<dyn ShardStorage>,
     to_distribute: HashMap<Vid, HashMap<Sid, Shard>>,
-    currently_assembled: HashMap<Vid, HashMap<Sid, Shard>>,
-    /// `None` means it is the memory (and the system) is not active
-    distribution: HashMap<PeerId, Sid>,
+    currently_assembled: HashMap<Vid, Recollection>,
+    distribution: Distribution,
     data_known_locations: HashMap<Vid, HashMap<Sid, PeerId>>,
+    /// Expected checksums of shard contents, as announced in consensus
+    shard_checksums: HashMap<Vid, HashMap<Sid, Hash>>,
+    /// Checksums of program results claimed by their executors. They are
+    /// used instead of `shard_checksums` until the result is checked.
+    provisional_checksums: HashMap<Vid, HashMap<Sid, Hash>>,
     local_id: PeerId,
     bus: MemoryBus,
     encoding: ReedSolomonWrapper,
+    /// Shards newly placed on this peer that are being restored now
+    repair: Option<Repair>,
+    /// Lost peers we've already proposed to evict
+    reported_lost: HashSet<PeerId>,
+    /// Audit of a shard stored by another peer, at most one at a time
+    audit: Option<Audit>,
+    audit_log: AuditLog,
+    /// Non-linear instructions computed on assembled data
+    plaintext: PlaintextSteps,
+    /// Results that are not committed to by all holders yet
+    result_commitments: ResultCommitments,
+    /// Check of a program result, at most one at a time
+    result_check: Option<ResultCheck>,
 }
 
 impl InitializedDataMemory {
-    fn assigned_shard_id(&self) -> Option<&Sid> {
-        self.distribution.get(&self.local_id)
-    }
-
     /// Get locally stored shard assigned to this peer, if present
     fn get_shard(&self, full_shard_id: &FullShardId) -> Option<&Shard> {
-        let shards = self.local_storage.get(&full_shard_id.0)?;
-        shards.get(&full_shard_id.1)
+        self.storage.get(full_shard_id)
     }
 
     /// Put data assigned to this peer in the local storage, updating
     /// and returning old value, if there was any.
-    fn store_shard(&mut self, full_shard_id: FullShardId, data: Shard) -> Option<Shard> {
-        let shards = self.local_storage.entry(full_shard_id.0).or_default();
-        shards.insert(full_shard_id.1, data)
+    fn store_shard(
+        &mut self,
+        full_shard_id: FullShardId,
+        data: Shard,
+    ) -> Result<Option<Shard>, storage::Error> {
+        self.storage
+            .store(full_shard_id.clone(), data)
+            .map_err(|e| {
+                error!("failed to store shard {:?}: {}", full_shard_id, e);
+                e
+            })
     }
 
-    // todo: make some removal mechanism
     /// Remove shard from the local storage and return it (if there was any)
-    #[allow(unused)]
     fn remove_shard(&mut self, full_shard_id: &FullShardId) -> Option<Shard> {
-        self.local_storage
-            .get_mut(&full_shard_id.0)
-            .and_then(|shards| shards.remove(&full_shard_id.1))
+        match self.storage.remove(full_shard_id) {
+            Ok(previous) => previous,
+            Err(e) => {
+                error!("failed to remove shard {:?}: {}", full_shard_id, e);
+                None
+            }
+        }
+    }
+
+    /// Remember location of the shard (also in the storage, to not lose it on restart)
+    fn track_location(&mut self, full_shard_id: FullShardId, location: PeerId) {
+        if let Err(e) = self.storage.store_location(full_shard_id.clone(), location) {
+            error!(
+                "failed to save location of {:?} to the storage, it will be lost on restart: {}",
+                full_shard_id, e
+            );
+        }
+        let shards = self
+            .data_known_locations
+            .entry(full_shard_id.0)
+            .or_default();
+        shards.insert(full_shard_id.1, location);
+    }
+
+    /// Remember expected checksum of the shard (also in the storage)
+    fn track_checksum(&mut self, full_shard_id: FullShardId, checksum: Hash) {
+        if let Err(e) = self
+            .storage
+            .store_checksum(full_shard_id.clone(), checksum.clone())
+        {
+            error!(
+                "failed to save checksum of {:?} to the storage, it will be lost on restart: {}",
+                full_shard_id, e
+            );
+        }
+        if let Some(provisional) = self.provisional_checksums.get_mut(&full_shard_id.0) {
+            provisional.remove(&full_shard_id.1);
+        }
+        let checksums = self.shard_checksums.entry(full_shard_id.0).or_default();
+        checksums.insert(full_shard_id.1, checksum);
+    }
+
+    /// Remember checksum claimed by the executor until the result is checked
+    /// (also in the storage), `None` forgets it
+    fn track_provisional_checksum(&mut self, full_shard_id: FullShardId, checksum: Option<Hash>) {
+        if let Err(e) = self
+            .storage
+            .store_provisional_checksum(full_shard_id.clone(), checksum.clone())
+        {
+            error!(
+                "failed to save provisional checksum of {:?} to the storage: {}",
+                full_shard_id, e
+            );
+        }
+        let checksums = self.provisional_checksums.entry(full_shard_id.0).or_default();
+        match checksum {
+            Some(checksum) => checksums.insert(full_shard_id.1, checksum),
+            None => checksums.remove(&full_shard_id.1),
+        };
+    }
+
+    fn provisional_checksum(&self, full_shard_id: &FullShardId) -> Option<&Hash> {
+        self.provisional_checksums
+            .get(&full_shard_id.0)
+            .and_then(|checksums| checksums.get(&full_shard_id.1))
+    }
+
+    fn expected_checksum(&self, full_shard_id: &FullShardId) -> Option<&Hash> {
+        self.provisional_checksum(full_shard_id).or_else(|| {
+            self.shard_checksums
+                .get(&full_shard_id.0)
+                .and_then(|checksums| checksums.get(&full_shard_id.1))
+        })
+    }
+
+    /// Check the shard against its announced checksum. Shards with unknown
+    /// checksum can't be checked, so they are rejected.
+    fn is_intact(&self, full_shard_id: &FullShardId, shard: &Shard) -> bool {
+        match self.expected_checksum(full_shard_id) {
+            Some(checksum) => checksum == &shard.checksum(),
+            None => {
+                warn!("checksum of shard {:?} is not known, can't verify it", full_shard_id);
+                false
+            }
+        }
     }
 
     /// Notify the data memory about observed location of some data shard.
@@ -346,23 +783,19 @@ impl InitializedDataMemory {
         if let Some(distributed_shards) = self.to_distribute.get_mut(&full_shard_id.0) {
             distributed_shards.remove(&full_shard_id.1);
         }
-        if let Some(expected_shard_id) = self.distribution.get(&location) {
-            if expected_shard_id != &full_shard_id.1 {
+        if !self.distribution.is_member(&location) {
+            warn!("observed location does not appear in known data distribution. shouldn't happen");
+        } else if let Some(expected_location) = self.distribution.holder_of(&full_shard_id) {
+            if expected_location != location {
                 warn!(
-                    "observed shard at unexpected location. observed at {:?}, expected at {:?}",
-                    &full_shard_id.1, expected_shard_id
+                    "observed shard {:?} at unexpected location. observed at {:?}, expected at {:?}",
+                    full_shard_id, location, expected_location
                 );
             }
-        } else {
-            warn!("observed location does not appear in known data distribution. shouldn't happen");
         }
 
         // need to track location to count successful distributions
-        let shards = self
-            .data_known_locations
-            .entry(full_shard_id.0)
-            .or_default();
-        shards.insert(full_shard_id.1, location);
+        self.track_location(full_shard_id, location);
     }
 
     /// Get ready to serve & track the progress of service of data shards during
@@ -386,6 +819,16 @@ impl InitializedDataMemory {
         let shards = self.to_distribute.get(&full_shard_id.0)?;
         shards.get(&full_shard_id.1).cloned()
     }
+
+    fn snapshot(&self) -> DataSnapshot {
+        DataSnapshot {
+            distribution: self.distribution.clone(),
+            encoding: self.encoding.settings(),
+            locations: self.data_known_locations.clone(),
+            checksums: self.shard_checksums.clone(),
+            provisional_checksums: self.provisional_checksums.clone(),
+        }
+    }
 }
 
 enum HandleResult {
@@ -404,14 +847,25 @@ impl InitializedDataMemory {
             warn!("peer that announced event distribution doesn't have shard assigned to us. strange but ok.");
             return HandleResult::Ok;
         };
+        if !self.is_intact(&full_shard_id, &shard) {
+            warn!(
+                "served shard {:?} doesn't match the announced checksum, not storing it",
+                full_shard_id
+            );
+            return HandleResult::Ok;
+        }
+        let checksum = shard.checksum();
         debug!(
             target: Targets::DataDistribution.into_str(),
             "Storing shard {:?}", full_shard_id
         );
-        self.store_shard(full_shard_id.clone(), shard);
+        if self.store_shard(full_shard_id.clone(), shard).is_err() {
+            // not announced, so nobody expects it here
+            return HandleResult::Ok;
+        }
         if (connection
             .output
-            .send(OutEvent::AssignedStoreSuccess(full_shard_id))
+            .send(OutEvent::AssignedStoreSuccess(full_shard_id, checksum))
             .await)
             .is_err()
         {
@@ -427,144 +881,1233 @@ impl InitializedDataMemory {
         shard: Option<Shard>,
         connection: &mut ModuleChannelServer<Module>,
     ) -> HandleResult {
+        let shard = match shard {
+            Some(shard) if self.is_intact(&full_shard_id, &shard) => Some(shard),
+            Some(_) => {
+                warn!(
+                    "Received shard {:?} doesn't match the announced checksum, discarding it",
+                    full_shard_id
+                );
+                None
+            }
+            None => {
+                warn!("Peer that announced that it stores assigned shard doesn't have it. Misbehaviour??");
+                None
+            }
+        };
         let Some(shard) = shard else {
-            warn!("Peer that announced that it stores assigned shard doesn't have it. Misbehaviour??");
-            return HandleResult::Ok;
+            return self.handle_unavailable_shard(full_shard_id, connection).await;
         };
+        if self
+            .repair
+            .as_ref()
+            .is_some_and(|repair| repair.is_pending(&full_shard_id.0))
+        {
+            if let HandleResult::Abort = self
+                .handle_repair_shard(full_shard_id.clone(), shard.clone(), connection)
+                .await
+            {
+                return HandleResult::Abort;
+            }
+        }
+        if let Some(rebuilding) = self
+            .audit
+            .as_mut()
+            .filter(|audit| audit.target.0 == full_shard_id.0)
+            .and_then(Audit::rebuilding)
+        {
+            if rebuilding.add_shard(full_shard_id.1.clone(), shard.clone()) {
+                if let HandleResult::Abort = self.continue_audit(connection).await {
+                    return HandleResult::Abort;
+                }
+            }
+        }
+        if let Some(check) = self
+            .result_check
+            .as_mut()
+            .filter(|check| check.data_id == full_shard_id.0)
+        {
+            if check.recollection.add_shard(full_shard_id.1.clone(), shard.clone()) {
+                if let HandleResult::Abort = self.continue_result_check(connection).await {
+                    return HandleResult::Abort;
+                }
+            }
+        }
         debug!(target: Targets::DataRecollection.into_str(), "Received shard {:?} for data {:?} from a peer", full_shard_id.1, full_shard_id.0);
-        let Some(received_shards) = self.currently_assembled.get_mut(&full_shard_id.0) else {
+        let Some(recollection) = self.currently_assembled.get_mut(&full_shard_id.0) else {
             debug!(target: Targets::DataRecollection.into_str(), "received shard was likely already assembled, skipping");
             return HandleResult::Ok;
         };
-        if let std::collections::hash_map::Entry::Vacant(e) = received_shards.entry(full_shard_id.1)
+        if !recollection.add_shard(full_shard_id.1, shard) {
+            // not sure when it's possible
+            warn!("received shard that is already present, weird");
+            return HandleResult::Ok;
+        }
+        self.continue_recollection(full_shard_id.0, connection).await
+    }
+
+    /// The shard could not be obtained intact, so the data has to be
+    /// assembled from shards of other holders
+    async fn handle_unavailable_shard(
+        &mut self,
+        full_shard_id: FullShardId,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        if let Some(rebuilding) = self
+            .audit
+            .as_mut()
+            .filter(|audit| audit.target.0 == full_shard_id.0)
+            .and_then(Audit::rebuilding)
         {
-            e.insert(shard);
-            let sufficient_shards_n = self
-                .encoding
-                .settings()
-                .data_shards_sufficient
-                .try_into()
-                .expect("# of shards sufficient is too large");
-            if received_shards.len() >= sufficient_shards_n {
-                debug!(target: Targets::DataRecollection.into_str(), "Enough shards for {:?} were found, we can assemble the data", full_shard_id.0);
-                // # of shards is sufficient to reassemble
-                let shards = self
-                    .currently_assembled
-                    .remove(&full_shard_id.0)
-                    .expect("Just had this entry");
-                let data = match self.encoding.decode(shards) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        // todo: handle better
-                        warn!("Collected enough shards but failed to decode: {}", e);
-                        return HandleResult::Ok;
-                    }
-                };
-                if connection
-                    .output
-                    .send(OutEvent::RecollectResponse(Ok((
-                        full_shard_id.0,
-                        data.clone(),
-                    ))))
-                    .await
-                    .is_err()
-                {
+            rebuilding.reject(&full_shard_id.1);
+            if let HandleResult::Abort = self.continue_audit(connection).await {
+                return HandleResult::Abort;
+            }
+        }
+        if let Some(check) = self
+            .result_check
+            .as_mut()
+            .filter(|check| check.data_id == full_shard_id.0)
+        {
+            check.recollection.reject(&full_shard_id.1);
+            if let HandleResult::Abort = self.continue_result_check(connection).await {
+                return HandleResult::Abort;
+            }
+        }
+        let Some(recollection) = self.currently_assembled.get_mut(&full_shard_id.0) else {
+            return HandleResult::Ok;
+        };
+        recollection.reject(&full_shard_id.1);
+        debug!(target: Targets::DataRecollection.into_str(), "Assembling {:?} without shard {:?}", full_shard_id.0, full_shard_id.1);
+        self.continue_recollection(full_shard_id.0, connection).await
+    }
+}
+
+impl InitializedDataMemory {
+    async fn handle_recollect_request(
+        &mut self,
+        data_id: Vid,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let Some(known_locations) = self.data_known_locations.get(&data_id) else {
+            debug!(target: Targets::DataRecollection.into_str(), "Do not know about {:?}", data_id);
+            if (connection.output.send(
+                OutEvent::RecollectResponse(Err(RecollectionError::UnkonwnDataId))
+            ).await).is_err() {
+                error!("`connection.output` is closed, shuttung down data memory");
+                return HandleResult::Abort;
+            }
+            return HandleResult::Ok;
+        };
+        trace!(target: Targets::DataRecollection.into_str(), "We know data {:?} distribution: {:?}", data_id, known_locations);
+        let sufficient_shards_n = self
+            .encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .expect("# of shards sufficient is too large");
+        if known_locations.len() < sufficient_shards_n {
+            debug!(target: Targets::DataRecollection.into_str(), "We do not know enough locations of data {:?}: we track {}/{} sufficient shards", data_id, known_locations.len(), sufficient_shards_n);
+            if (connection
+                .output
+                .send(OutEvent::RecollectResponse(Err(
+                    RecollectionError::NotEnoughShards,
+                )))
+                .await)
+                .is_err()
+            {
+                error!("`connection.output` is closed, shuttung down data memory");
+                return HandleResult::Abort;
+            }
+            return HandleResult::Ok;
+        }
+        if self.currently_assembled.contains_key(&data_id) {
+            // requests were already sent, just wait for the response
+            debug!(target: Targets::DataRecollection.into_str(), "Data {:?} is already in process of recollection", data_id);
+            return HandleResult::Ok;
+        }
+        let mut recollection = Recollection::new(sufficient_shards_n);
+        let mut known_locations: Vec<_> = known_locations.clone().into_iter().collect();
+        known_locations.sort_by_key(|(shard_id, _)| shard_id.0);
+        for (shard_id, owner) in known_locations {
+            if owner != self.local_id {
+                recollection.add_holder(shard_id, owner);
+                continue;
+            }
+            trace!(target: Targets::DataRecollection.into_str(), "Getting shard {:?} from local storage", shard_id);
+            let full_shard_id = (data_id.clone(), shard_id);
+            match self.get_shard(&full_shard_id) {
+                Some(shard) if self.is_intact(&full_shard_id, shard) => {
+                    recollection.add_local_shard(full_shard_id.1, shard.clone())
+                }
+                Some(_) => warn!("locally stored shard {:?} doesn't match the announced checksum", full_shard_id),
+                None => warn!("shard {:?} is expected to be stored locally, but it's not", full_shard_id),
+            }
+        }
+        self.currently_assembled.insert(data_id.clone(), recollection);
+        self.continue_recollection(data_id, connection).await
+    }
+
+    /// Assemble the data if enough shards are received. Otherwise request
+    /// more shards (if needed) or report failure.
+    async fn continue_recollection(
+        &mut self,
+        data_id: Vid,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let Some(recollection) = self.currently_assembled.get_mut(&data_id) else {
+            return HandleResult::Ok;
+        };
+        if recollection.is_sufficient() {
+            debug!(target: Targets::DataRecollection.into_str(), "Enough shards for {:?} were found, we can assemble the data", data_id);
+            // # of shards is sufficient to reassemble
+            let shards = self
+                .currently_assembled
+                .remove(&data_id)
+                .expect("Just had this entry")
+                .into_shards();
+            let data = match self.encoding.decode(shards) {
+                Ok(data) => data,
+                Err(e) => {
+                    // todo: handle better
+                    warn!("Collected enough shards but failed to decode: {}", e);
+                    return HandleResult::Ok;
+                }
+            };
+            if connection
+                .output
+                .send(OutEvent::RecollectResponse(Ok((data_id, data))))
+                .await
+                .is_err()
+            {
+                error!("`connection.output` is closed, shuttung down data memory");
+                return HandleResult::Abort;
+            }
+            return HandleResult::Ok;
+        }
+        let requests = match recollection.next_requests(Instant::now()) {
+            Ok(requests) => requests,
+            Err(e) => {
+                debug!(target: Targets::DataRecollection.into_str(), "Giving up on recollection of {:?}: {}", data_id, e);
+                self.currently_assembled.remove(&data_id);
+                if (connection.output.send(OutEvent::RecollectResponse(Err(e))).await).is_err() {
                     error!("`connection.output` is closed, shuttung down data memory");
                     return HandleResult::Abort;
                 }
+                return HandleResult::Ok;
+            }
+        };
+        for (shard_id, holder) in requests {
+            trace!(target: Targets::DataRecollection.into_str(), "Requesting shard {:?} from peer {:?}", shard_id, holder);
+            if (connection
+                .output
+                .send(OutEvent::AssignedRequest((data_id.clone(), shard_id), holder))
+                .await)
+                .is_err()
+            {
+                error!("`connection.output` is closed, shutting down data memory");
+                return HandleResult::Abort;
+            }
+        }
+        HandleResult::Ok
+    }
+
+    /// Ask someone else instead of holders that didn't respond in time
+    async fn expire_recollection_requests(
+        &mut self,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let now = Instant::now();
+        let data_ids: Vec<_> = self.currently_assembled.keys().cloned().collect();
+        for data_id in data_ids {
+            if let Some(recollection) = self.currently_assembled.get_mut(&data_id) {
+                recollection.expire(now);
+            }
+            if let HandleResult::Abort = self.continue_recollection(data_id, connection).await {
+                return HandleResult::Abort;
             }
-        } else {
-            // not sure when it's possible
-            warn!("received shard that is already present, weird");
         }
         HandleResult::Ok
     }
 }
 
 impl InitializedDataMemory {
-    async fn run(mut self, connection: &mut ModuleChannelServer<Module>) {
-        loop {
-            // todo: format the code semi-automatically (for each `select!`)
-            tokio::select! {
-                in_event = connection.input.recv() => {
-                    let Some(in_event) = in_event else {
-                        error!("`connection.output` is closed, shuttung down data memory");
-                        return;
-                    };
-                    match in_event {
-                        InEvent::Initialize { distribution: _ } => {
-                            warn!("received `InitializeStorage` transaction but storage was already initialized. ignoring");
-                        }
-                        // initial distribution
-                        InEvent::PrepareServiceRequest { data_id, data } => {
-                            debug!(
-                                target: Targets::DataDistribution.into_str(),
-                                "Encoding data {:?} into shards", data_id
-                            );
-                            let shards = self.encoding.encode(data).expect(
-                                "Encoding settings are likely incorrect, \
-                                all problems should've been caught at type-level",
-                            );
-                            debug!(
-                                target: Targets::DataDistribution.into_str(),
-                                "Encoded into {} shards", shards.len()
-                            );
-                            self.prepare_to_serve_shards(data_id.clone(), shards);
-                            debug!(
-                                target: Targets::DataDistribution.into_str(),
-                                "Prepared to serve shards of {:?}", data_id
-                            );
-                            if connection
-                                .output
-                                .send(OutEvent::PreparedServiceResponse(data_id))
-                                .await
-                                .is_err()
+    /// Drop everything known about the data: local shards, locations, shards
+    /// waiting to be distributed, recollection and migration progress.
+    async fn handle_delete(
+        &mut self,
+        data_id: Vid,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        info!("data {:?} was deleted, removing it", data_id);
+        if let Err(e) = self.storage.remove_data(&data_id) {
+            error!(
+                "failed to remove data {:?} from the storage, it may reappear on restart: {}",
+                data_id, e
+            );
+        }
+        self.data_known_locations.remove(&data_id);
+        self.shard_checksums.remove(&data_id);
+        self.provisional_checksums.remove(&data_id);
+        if let Some(not_distributed) = self.to_distribute.remove(&data_id) {
+            debug!(
+                "dropping {} shards of {:?} that were not confirmed to be stored",
+                not_distributed.len(),
+                data_id
+            );
+        }
+        if let Some(repair) = &mut self.repair {
+            repair.forget(&data_id);
+        }
+        if self
+            .audit
+            .as_ref()
+            .is_some_and(|audit| audit.target.0 == data_id)
+        {
+            self.audit = None;
+        }
+        self.result_commitments.forget(&data_id);
+        if self
+            .result_check
+            .as_ref()
+            .is_some_and(|check| check.data_id == data_id)
+        {
+            self.result_check = None;
+        }
+        if self.currently_assembled.remove(&data_id).is_some()
+            && (connection
+                .output
+                .send(OutEvent::RecollectResponse(Err(RecollectionError::Deleted)))
+                .await)
+                .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        if (connection.output.send(OutEvent::Deleted(data_id)).await).is_err() {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        self.finish_repair_if_done(connection).await
+    }
+}
+
+impl InitializedDataMemory {
+    /// Peer that computes non-linear instruction with the result, holder of
+    /// its first shard
+    fn plaintext_coordinator(&self, result_id: &Vid) -> Option<PeerId> {
+        self.distribution.holder_of(&(result_id.clone(), Sid(0)))
+    }
+
+    async fn handle_compute_request(
+        &mut self,
+        request: ComputeRequest,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let ComputeRequest {
+            step,
+            instruction,
+            operands,
+            response,
+        } = request;
+        let Some(coordinator) = self.plaintext_coordinator(&instruction.result) else {
+            warn!("storage has no members to compute non-linear instruction {:?}", step);
+            return HandleResult::Ok;
+        };
+        if self
+            .distribution
+            .shards_of(&instruction.result, &self.local_id)
+            .is_empty()
+        {
+            // no result shards are placed on this peer, nothing to wait for
+            let _ = response.send(HashMap::new());
+        } else {
+            self.plaintext
+                .expect_result(step.clone(), response, Instant::now());
+        }
+        if coordinator == self.local_id {
+            // instruction of the program executed here is the one finalized
+            // in consensus, executors only send their operands
+            return self
+                .handle_plaintext_operands(step, Some(instruction), operands, connection)
+                .await;
+        }
+        debug!(target: Targets::ProgramExecution.into_str(), "Sending {} operand shard(s) of {:?} to {:?}", operands.len(), step, coordinator);
+        if (connection
+            .output
+            .send(OutEvent::PlaintextOperands {
+                step,
+                operands,
+                coordinator,
+            })
+            .await)
+            .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        HandleResult::Ok
+    }
+
+    /// Collect operands of the coordinated step, once there are enough of
+    /// them (and the instruction is known from the local executor) compute
+    /// it and send the result to its holders
+    async fn handle_plaintext_operands(
+        &mut self,
+        step: ExecutionStep,
+        instruction: Option<Instruction<Vid, Vid>>,
+        operands: Vec<(FullShardId, Shard)>,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let now = Instant::now();
+        let computed = self.plaintext.add_operands(
+            step.clone(),
+            instruction,
+            operands,
+            &self.encoding,
+            now,
+        );
+        let (result_id, mut shards) = match computed {
+            Some(Ok(computed)) => computed,
+            Some(Err(e)) => {
+                warn!("could not compute non-linear instruction {:?}: {}", step, e);
+                return HandleResult::Ok;
+            }
+            None => return HandleResult::Ok,
+        };
+        debug!(target: Targets::ProgramExecution.into_str(), "Computed {:?} on assembled data, sending result to holders", step);
+        let mut by_holder: HashMap<PeerId, Vec<(FullShardId, Shard)>> = HashMap::new();
+        for (shard_id, holder) in self.distribution.layout(&result_id) {
+            if let Some(shard) = shards.remove(&shard_id) {
+                by_holder
+                    .entry(holder)
+                    .or_default()
+                    .push(((result_id.clone(), shard_id), shard));
+            }
+        }
+        for (holder, shards) in by_holder {
+            if holder == self.local_id {
+                let shards = shards
+                    .into_iter()
+                    .map(|((_, shard_id), shard)| (shard_id, shard))
+                    .collect();
+                self.plaintext.add_result(step.clone(), shards, now);
+                continue;
+            }
+            if (connection
+                .output
+                .send(OutEvent::PlaintextResult {
+                    step: step.clone(),
+                    shards,
+                    holder,
+                })
+                .await)
+                .is_err()
+            {
+                error!("`connection.output` is closed, shutting down data memory");
+                return HandleResult::Abort;
+            }
+        }
+        HandleResult::Ok
+    }
+
+    fn handle_plaintext_result(
+        &mut self,
+        from: PeerId,
+        step: ExecutionStep,
+        shards: Vec<(FullShardId, Shard)>,
+    ) {
+        let from_coordinator = shards
+            .iter()
+            .all(|((data_id, _), _)| self.plaintext_coordinator(data_id) == Some(from));
+        if !from_coordinator {
+            warn!("{:?} sent result of {:?} but it doesn't coordinate the step, ignoring", from, step);
+            return;
+        }
+        let shards = shards
+            .into_iter()
+            .map(|((_, shard_id), shard)| (shard_id, shard))
+            .collect();
+        self.plaintext.add_result(step, shards, Instant::now());
+    }
+}
+
+impl InitializedDataMemory {
+    /// Track results committed to by the executor. Committed checksums are
+    /// provisional until the result is checked. Results committed to by all
+    /// holders are sampled for a check.
+    async fn handle_peer_shards_actualized(
+        &mut self,
+        program_id: ProgramIdentifier,
+        peer: PeerId,
+        updated_shards: Vec<(FullShardId, Hash)>,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let total: usize = self.encoding.settings().data_shards_total.try_into().unwrap();
+        let mut complete = Vec::new();
+        for (full_shard_id, checksum) in updated_shards {
+            self.track_provisional_checksum(full_shard_id.clone(), Some(checksum.clone()));
+            self.track_location(full_shard_id.clone(), peer);
+            if self
+                .result_check
+                .as_ref()
+                .is_some_and(|check| check.data_id == full_shard_id.0)
+            {
+                debug!("{:?} is updated during the check, dropping it", full_shard_id.0);
+                self.result_check = None;
+            }
+            if let Some(commitments) = self.result_commitments.add(program_id.clone(), full_shard_id.clone(), peer, checksum, total) {
+                complete.push((full_shard_id.0, commitments));
+            }
+        }
+        if self.result_check.is_some() || !self.distribution.is_member(&self.local_id) {
+            return HandleResult::Ok;
+        }
+        let sampled = complete
+            .into_iter()
+            .find(|_| rand::thread_rng().gen_bool(verification::CHECK_PROBABILITY));
+        let Some((data_id, commitments)) = sampled else {
+            return HandleResult::Ok;
+        };
+        debug!("checking result {:?} of program {:?}", data_id, program_id);
+        let mut recollection = Recollection::new(commitments.len());
+        for (shard_id, (holder, _)) in &commitments {
+            if holder != &self.local_id {
+                recollection.add_holder(shard_id.clone(), *holder);
+                continue;
+            }
+            let full_shard_id = (data_id.clone(), shard_id.clone());
+            if let Some(shard) = self.get_shard(&full_shard_id) {
+                if self.is_intact(&full_shard_id, shard) {
+                    recollection.add_local_shard(full_shard_id.1, shard.clone());
+                }
+            }
+        }
+        self.result_check = Some(ResultCheck {
+            data_id,
+            commitments,
+            recollection,
+        });
+        self.continue_result_check(connection).await
+    }
+
+    /// Request more shards of the checked result, or judge its holders once
+    /// no more shards can be obtained
+    async fn continue_result_check(&mut self, connection: &mut ModuleChannelServer<Module>) -> HandleResult {
+        let Some(check) = &mut self.result_check else {
+            return HandleResult::Ok;
+        };
+        if !check.recollection.is_sufficient() {
+            match check.recollection.next_requests(Instant::now()) {
+                Ok(requests) if !requests.is_empty() => {
+                    for (shard_id, holder) in requests {
+                        if (connection
+                            .output
+                            .send(OutEvent::AssignedRequest((check.data_id.clone(), shard_id), holder))
+                            .await)
+                            .is_err()
+                        {
+                            error!("`connection.output` is closed, shutting down data memory");
+                            return HandleResult::Abort;
+                        }
+                    }
+                    return HandleResult::Ok;
+                }
+                // waiting for the requested shards
+                Ok(_) => return HandleResult::Ok,
+                // judge with the shards we've got
+                Err(e) => debug!("could not collect all shards of result {:?}: {}", check.data_id, e),
+            }
+        }
+        let check = self.result_check.take().expect("checked above");
+        let Some(verdict) = check.judge(check.recollection.shards(), &self.encoding) else {
+            warn!("could not decide which holders computed result {:?} correctly", check.data_id);
+            return HandleResult::Ok;
+        };
+        // shards of holders that disagree are rejected from now on
+        for (shard_id, (_, committed)) in &check.commitments {
+            let full_shard_id = (check.data_id.clone(), shard_id.clone());
+            if self.provisional_checksum(&full_shard_id) != Some(committed) {
+                // updated by another program already
+                continue;
+            }
+            if let Some(checksum) = verdict.checksums.get(shard_id) {
+                self.track_checksum(full_shard_id, checksum.clone());
+            }
+        }
+        if verdict.disagreed.is_empty() {
+            debug!("all holders agree on result {:?}", check.data_id);
+        } else {
+            warn!(
+                "{:?} committed to result {:?} that disagrees with the reconstructed data",
+                verdict.disagreed, check.data_id
+            );
+        }
+        if (connection
+            .output
+            .send(OutEvent::ResultChecked {
+                data_id: check.data_id,
+                verdict,
+            })
+            .await)
+            .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        HandleResult::Ok
+    }
+
+    /// Check deadlines of shard requests of the result check
+    async fn expire_result_check(&mut self, connection: &mut ModuleChannelServer<Module>) -> HandleResult {
+        let Some(check) = &mut self.result_check else {
+            return HandleResult::Ok;
+        };
+        check.recollection.expire(Instant::now());
+        self.continue_result_check(connection).await
+    }
+}
+
+impl InitializedDataMemory {
+    /// Pick a random shard stored by another peer and start auditing it
+    async fn start_audit(&mut self, connection: &mut ModuleChannelServer<Module>) -> HandleResult {
+        if self.audit.is_some() || !self.distribution.is_member(&self.local_id) {
+            return HandleResult::Ok;
+        }
+        let sufficient_shards: usize = self
+            .encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .unwrap();
+        // enough other shards must be available to rebuild the audited one
+        let candidates: Vec<_> = self
+            .data_known_locations
+            .iter()
+            .filter(|(_, locations)| locations.len() > sufficient_shards)
+            .flat_map(|(data_id, locations)| {
+                locations
+                    .iter()
+                    .filter(|(_, holder)| **holder != self.local_id)
+                    .map(|(shard_id, holder)| ((data_id.clone(), shard_id.clone()), *holder))
+            })
+            .collect();
+        let Some((target, holder)) = candidates.choose(&mut rand::thread_rng()).cloned() else {
+            trace!("no shards to audit");
+            return HandleResult::Ok;
+        };
+        debug!("auditing shard {:?} stored by {:?}", target, holder);
+        let mut recollection = Recollection::new(sufficient_shards);
+        for (shard_id, location) in &self.data_known_locations[&target.0] {
+            if shard_id == &target.1 {
+                continue;
+            }
+            if location != &self.local_id {
+                recollection.add_holder(shard_id.clone(), *location);
+                continue;
+            }
+            let full_shard_id = (target.0.clone(), shard_id.clone());
+            if let Some(shard) = self.get_shard(&full_shard_id) {
+                if self.is_intact(&full_shard_id, shard) {
+                    recollection.add_local_shard(full_shard_id.1, shard.clone());
+                }
+            }
+        }
+        self.audit = Some(Audit::new(target, holder, recollection));
+        self.continue_audit(connection).await
+    }
+
+    /// Request more shards for rebuilding the audited one, or challenge the
+    /// holder once it's rebuilt
+    async fn continue_audit(&mut self, connection: &mut ModuleChannelServer<Module>) -> HandleResult {
+        let Some(audit) = &mut self.audit else {
+            return HandleResult::Ok;
+        };
+        let target = audit.target.clone();
+        let Some(rebuilding) = audit.rebuilding() else {
+            return HandleResult::Ok;
+        };
+        if !rebuilding.is_sufficient() {
+            let requests = match rebuilding.next_requests(Instant::now()) {
+                Ok(requests) => requests,
+                Err(e) => {
+                    debug!("could not collect shards to audit {:?}, skipping: {}", target, e);
+                    self.audit = None;
+                    return HandleResult::Ok;
+                }
+            };
+            for (shard_id, holder) in requests {
+                if (connection
+                    .output
+                    .send(OutEvent::AssignedRequest((target.0.clone(), shard_id), holder))
+                    .await)
+                    .is_err()
+                {
+                    error!("`connection.output` is closed, shutting down data memory");
+                    return HandleResult::Abort;
+                }
+            }
+            return HandleResult::Ok;
+        }
+        let shards = rebuilding.shards().clone();
+        let rebuilt = match self.encoding.rebuild(shards, &target.1) {
+            Ok(rebuilt) if self.is_intact(&target, &rebuilt) => rebuilt,
+            Ok(_) => {
+                warn!("shard {:?} rebuilt for audit doesn't match its checksum, skipping", target);
+                self.audit = None;
+                return HandleResult::Ok;
+            }
+            Err(e) => {
+                warn!("could not rebuild shard {:?} for audit, skipping: {}", target, e);
+                self.audit = None;
+                return HandleResult::Ok;
+            }
+        };
+        let audit = self.audit.as_mut().expect("checked above");
+        let Some(challenge) = audit.challenge(&rebuilt, Instant::now()) else {
+            debug!("shard {:?} is empty, nothing to audit", target);
+            self.audit = None;
+            return HandleResult::Ok;
+        };
+        let holder = audit.holder;
+        if (connection
+            .output
+            .send(OutEvent::AuditRequest(target, challenge, holder))
+            .await)
+            .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        HandleResult::Ok
+    }
+
+    async fn handle_audit_response(
+        &mut self,
+        full_shard_id: FullShardId,
+        answer: Option<Hash>,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let Some(audit) = self.audit.as_ref().filter(|audit| audit.target == full_shard_id) else {
+            debug!("answer to audit of {:?} is late, ignoring", full_shard_id);
+            return HandleResult::Ok;
+        };
+        let Some(passed) = audit.verify(answer.as_ref()) else {
+            warn!("answer to audit of {:?} came before the challenge, ignoring", full_shard_id);
+            return HandleResult::Ok;
+        };
+        let holder = audit.holder;
+        self.audit = None;
+        self.finish_audit(holder, full_shard_id, passed, connection).await
+    }
+
+    /// Check deadlines of the audit in progress
+    async fn expire_audit(&mut self, connection: &mut ModuleChannelServer<Module>) -> HandleResult {
+        let now = Instant::now();
+        let Some(audit) = &mut self.audit else {
+            return HandleResult::Ok;
+        };
+        if let Some(rebuilding) = audit.rebuilding() {
+            rebuilding.expire(now);
+            return self.continue_audit(connection).await;
+        }
+        if !audit.is_expired(now) {
+            return HandleResult::Ok;
+        }
+        let (holder, target) = (audit.holder, audit.target.clone());
+        self.audit = None;
+        self.finish_audit(holder, target, false, connection).await
+    }
+
+    /// Record the result. Holders failing audits repeatedly are proposed for
+    /// eviction, so that their shards are repaired.
+    async fn finish_audit(
+        &mut self,
+        holder: PeerId,
+        target: FullShardId,
+        passed: bool,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let record = self.audit_log.record(holder, passed);
+        if passed {
+            debug!("{:?} passed audit of shard {:?}", holder, target);
+        } else {
+            warn!(
+                "{:?} failed audit of shard {:?} ({} passed, {} failed in total)",
+                holder, target, record.passed, record.failed
+            );
+        }
+        let suspicious = record.is_suspicious();
+        if (connection
+            .output
+            .send(OutEvent::AuditFinished {
+                peer: holder,
+                passed,
+            })
+            .await)
+            .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        if suspicious && self.distribution.is_member(&holder) {
+            info!("{:?} keeps failing storage audits", holder);
+            return self.propose_eviction(holder, connection).await;
+        }
+        HandleResult::Ok
+    }
+
+    /// The member did not serve its shard. It counts as a failed audit, so
+    /// only a member that keeps failing is proposed for eviction.
+    async fn handle_peer_lost(
+        &mut self,
+        peer: PeerId,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        if !self.distribution.is_member(&peer) {
+            trace!("lost peer {:?} is not a member of the storage, ignoring", peer);
+            return HandleResult::Ok;
+        }
+        if !self.distribution.is_member(&self.local_id) {
+            debug!(
+                "member {:?} is lost, but we are not a member to propose its eviction",
+                peer
+            );
+            return HandleResult::Ok;
+        }
+        let record = self.audit_log.record(peer, false);
+        if !record.is_suspicious() {
+            debug!(
+                "member {:?} did not serve its shard ({} failure(s) in a row)",
+                peer, record.failed_in_row
+            );
+            return HandleResult::Ok;
+        }
+        self.propose_eviction(peer, connection).await
+    }
+
+    /// Ask to evict the member, it is removed once enough members agree
+    async fn propose_eviction(
+        &mut self,
+        peer: PeerId,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        if !self.reported_lost.insert(peer) {
+            trace!("eviction of {:?} was already proposed", peer);
+            return HandleResult::Ok;
+        }
+        info!("member {:?} is lost, proposing to evict it", peer);
+        if (connection
+            .output
+            .send(OutEvent::EvictionRequest(peer))
+            .await)
+            .is_err()
+        {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        HandleResult::Ok
+    }
+
+    /// Continue from the state of the cluster after transactions this peer
+    /// missed. Data deleted meanwhile is removed, shards placed on this peer
+    /// since then are restored.
+    async fn install_snapshot(
+        &mut self,
+        snapshot: DataSnapshot,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let settings = self.encoding.settings();
+        if snapshot.encoding != settings
+            || !snapshot.distribution.is_valid(settings.data_shards_total)
+        {
+            warn!(
+                "snapshot doesn't match the storage of this peer, ignoring it; got: {:?}",
+                snapshot.distribution
+            );
+            return HandleResult::Ok;
+        }
+        let deleted: HashSet<Vid> = self
+            .data_known_locations
+            .keys()
+            .chain(self.shard_checksums.keys())
+            .filter(|data_id| {
+                !snapshot.locations.contains_key(*data_id)
+                    && !snapshot.checksums.contains_key(*data_id)
+            })
+            .cloned()
+            .collect();
+        for data_id in deleted {
+            if let HandleResult::Abort = self.handle_delete(data_id, connection).await {
+                return HandleResult::Abort;
+            }
+        }
+        for (data_id, locations) in snapshot.locations {
+            for (shard_id, location) in locations {
+                self.track_location((data_id.clone(), shard_id), location);
+            }
+        }
+        for (data_id, checksums) in snapshot.checksums {
+            for (shard_id, checksum) in checksums {
+                self.track_checksum((data_id.clone(), shard_id), checksum);
+            }
+        }
+        for (data_id, checksums) in snapshot.provisional_checksums {
+            for (shard_id, checksum) in checksums {
+                self.track_provisional_checksum((data_id.clone(), shard_id), Some(checksum));
+            }
+        }
+        info!("installed snapshot over the local state");
+        self.distribution = snapshot.distribution;
+        self.handle_membership_change(Ok(()), None, connection).await
+    }
+
+    /// Apply finalized membership change
+    async fn handle_membership_change(
+        &mut self,
+        change: Result<(), distribution::Error>,
+        unreachable: Option<PeerId>,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        if let Err(e) = change {
+            debug!("ignoring membership transaction: {}", e);
+            return HandleResult::Ok;
+        }
+        if let Some(peer) = unreachable {
+            self.reported_lost.remove(&peer);
+        }
+        info!(
+            "switched to distribution epoch {}: {:?}",
+            self.distribution.epoch(),
+            self.distribution
+        );
+        if let Err(e) = self
+            .storage
+            .store_distribution(self.distribution.clone(), self.encoding.settings())
+        {
+            error!(
+                "could not save updated distribution to the storage, \
+                it will be outdated on restart: {}",
+                e
+            );
+        }
+        let event = OutEvent::DistributionChanged {
+            epoch: self.distribution.epoch(),
+        };
+        if (connection.output.send(event).await).is_err() {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        if let Some(repair) = self.repair.take() {
+            debug!("placement changed during migration, announcing what is done and starting over");
+            if let HandleResult::Abort = self.announce_repaired(repair, connection).await {
+                return HandleResult::Abort;
+            }
+        }
+        self.start_repair(unreachable, connection).await
+    }
+
+    /// Start restoring shards placed on this peer that it doesn't store yet.
+    /// Shards are pulled from all known locations except `unreachable` peer.
+    /// If the shard itself is available (e.g. the previous holder left
+    /// gracefully), it is taken as is.
+    async fn start_repair(
+        &mut self,
+        unreachable: Option<PeerId>,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let sufficient_shards: usize = self
+            .encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .unwrap();
+        let mut requests = Vec::new();
+        let mut targets = HashMap::new();
+        for (data_id, locations) in &self.data_known_locations {
+            let missing: HashSet<Sid> = self
+                .distribution
+                .shards_of(data_id, &self.local_id)
+                .into_iter()
+                .filter(|shard_id| self.get_shard(&(data_id.clone(), shard_id.clone())).is_none())
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let sources: Vec<_> = locations
+                .iter()
+                .filter(|(_, location)| Some(**location) != unreachable)
+                .collect();
+            let has_shards_themselves = missing
+                .iter()
+                .all(|shard_id| sources.iter().any(|(id, _)| *id == shard_id));
+            if !has_shards_themselves && sources.len() < sufficient_shards {
+                warn!(
+                    "only {} shards of {:?} are available, can't restore shards {:?}",
+                    sources.len(),
+                    data_id,
+                    missing
+                );
+                continue;
+            }
+            for (source_id, location) in sources {
+                requests.push(((data_id.clone(), source_id.clone()), *location));
+            }
+            targets.insert(data_id.clone(), missing);
+        }
+        if targets.is_empty() {
+            return HandleResult::Ok;
+        }
+        info!(
+            "new shards are placed on this peer, restoring them for {} data units",
+            targets.len()
+        );
+        self.repair = Some(Repair::new(targets));
+        for (full_shard_id, location) in requests {
+            if location == self.local_id {
+                let shard = self.get_shard(&full_shard_id).cloned();
+                if let HandleResult::Abort = self
+                    .handle_assigned_response(full_shard_id, shard, connection)
+                    .await
+                {
+                    return HandleResult::Abort;
+                }
+                continue;
+            }
+            if (connection
+                .output
+                .send(OutEvent::AssignedRequest(full_shard_id, location))
+                .await)
+                .is_err()
+            {
+                error!("`connection.output` is closed, shutting down data memory");
+                return HandleResult::Abort;
+            }
+        }
+        self.finish_repair_if_done(connection).await
+    }
+
+    /// Use received shard for restoring the ones placed on this peer
+    async fn handle_repair_shard(
+        &mut self,
+        full_shard_id: FullShardId,
+        shard: Shard,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let sufficient_shards: usize = self
+            .encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .unwrap();
+        let Some(repair) = &mut self.repair else {
+            return HandleResult::Ok;
+        };
+        let data_id = full_shard_id.0.clone();
+        let Some((target_ids, shards)) = repair.add_shard(full_shard_id, shard, sufficient_shards) else {
+            return HandleResult::Ok;
+        };
+        for target_id in target_ids {
+            let rebuilt = match shards.get(&target_id) {
+                Some(shard) => Ok(shard.clone()),
+                None => self.encoding.rebuild(shards.clone(), &target_id),
+            };
+            match rebuilt {
+                Ok(rebuilt) => {
+                    debug!("restored shard {:?} of {:?}", target_id, data_id);
+                    let full_shard_id = (data_id.clone(), target_id);
+                    // not announced as repaired if it's not written
+                    if self.store_shard(full_shard_id.clone(), rebuilt).is_err() {
+                        continue;
+                    }
+                    if let Some(repair) = &mut self.repair {
+                        repair.mark_rebuilt(full_shard_id);
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "could not rebuild shard {:?} of {:?}: {}",
+                        target_id, data_id, e
+                    );
+                }
+            }
+        }
+        self.finish_repair_if_done(connection).await
+    }
+
+    async fn finish_repair_if_done(
+        &mut self,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        if !self.repair.as_ref().is_some_and(Repair::is_finished) {
+            return HandleResult::Ok;
+        }
+        let repair = self.repair.take().expect("just checked");
+        self.announce_repaired(repair, connection).await
+    }
+
+    /// Announce restored shards that are still placed on this peer, drop
+    /// the rest
+    async fn announce_repaired(
+        &mut self,
+        repair: Repair,
+        connection: &mut ModuleChannelServer<Module>,
+    ) -> HandleResult {
+        let (shards, moved) = repair.into_rebuilt(&self.distribution, &self.local_id);
+        for full_shard_id in moved {
+            self.remove_shard(&full_shard_id);
+        }
+        if shards.is_empty() {
+            return HandleResult::Ok;
+        }
+        info!("restored {} shards, announcing", shards.len());
+        if (connection.output.send(OutEvent::RepairFinished { shards }).await).is_err() {
+            error!("`connection.output` is closed, shutting down data memory");
+            return HandleResult::Abort;
+        }
+        HandleResult::Ok
+    }
+
+    /// Track locations of restored shards; previous holders of the shards
+    /// no longer need them
+    fn handle_shards_repaired(&mut self, holder: PeerId, shards: Vec<FullShardId>) {
+        debug!("{:?} now stores {} more shards", holder, shards.len());
+        for full_shard_id in shards {
+            if self.distribution.holder_of(&full_shard_id) != Some(holder) {
+                debug!(
+                    "{:?} announced shard {:?} not placed on it (stale?), ignoring",
+                    holder, full_shard_id
+                );
+                continue;
+            }
+            if holder != self.local_id && self.get_shard(&full_shard_id).is_some() {
+                self.remove_shard(&full_shard_id);
+            }
+            self.track_location(full_shard_id, holder);
+        }
+    }
+}
+
+impl InitializedDataMemory {
+    async fn run(mut self, connection: &mut ModuleChannelServer<Module>) {
+        let mut recollection_deadlines = tokio::time::interval(recollection::DEADLINE_CHECK_PERIOD);
+        let mut audits = tokio::time::interval(audit::AUDIT_PERIOD);
+        loop {
+            // todo: format the code semi-automatically (for each `select!`)
+            tokio::select! {
+                in_event = connection.input.recv() => {
+                    let Some(in_event) = in_event else {
+                        error!("`connection.output` is closed, shuttung down data memory");
+                        return;
+                    };
+                    match in_event {
+                        InEvent::Initialize { .. } => {
+                            warn!("received `InitializeStorage` transaction but storage was already initialized. ignoring");
+                        }
+                        InEvent::InstallSnapshot(snapshot) => {
+                            match self.install_snapshot(snapshot, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
+                                }
+                            }
+                        }
+                        InEvent::TakeSnapshot => {
+                            let snapshot = self.snapshot();
+                            if (connection.output.send(OutEvent::Snapshot(Some(snapshot))).await).is_err() {
+                                error!("`connection.output` is closed, shutting down data memory");
+                                return;
+                            }
+                        }
+                        // initial distribution
+                        InEvent::PrepareServiceRequest { data_id, data } => {
+                            debug!(
+                                target: Targets::DataDistribution.into_str(),
+                                "Encoding data {:?} into shards", data_id
+                            );
+                            let shards = match self.encoding.encode(data) {
+                                Ok(shards) => shards,
+                                Err(e) => {
+                                    warn!("could not encode data {:?}: {}", data_id, e);
+                                    let event = OutEvent::PrepareServiceFailed(data_id, e);
+                                    if connection.output.send(event).await.is_err() {
+                                        error!("`connection.output` is closed, shutting down data memory");
+                                        return;
+                                    }
+                                    continue;
+                                }
+                            };
+                            debug!(
+                                target: Targets::DataDistribution.into_str(),
+                                "Encoded into {} shards", shards.len()
+                            );
+                            let checksums = shards
+                                .iter()
+                                .map(|(shard_id, shard)| (shard_id.clone(), shard.checksum()))
+                                .collect();
+                            self.prepare_to_serve_shards(data_id.clone(), shards);
+                            debug!(
+                                target: Targets::DataDistribution.into_str(),
+                                "Prepared to serve shards of {:?}", data_id
+                            );
+                            if connection
+                                .output
+                                .send(OutEvent::PreparedServiceResponse(data_id, checksums))
+                                .await
+                                .is_err()
                             {
                                 error!("`connection.output` is closed, shuttung down data memory");
                                 return;
                             }
                         }
-                        InEvent::StorageRequestTx(data_id, author) => {
+                        InEvent::StorageRequestTx(data_id, author, checksums) => {
+                            if self.storage.is_deleted(&data_id) {
+                                warn!("storage request for deleted data {:?}, ids are not reused. ignoring.", data_id);
+                                continue;
+                            }
+                            for (shard_id, checksum) in checksums {
+                                self.track_checksum((data_id.clone(), shard_id), checksum);
+                            }
                             debug!(
                                 target: Targets::DataDistribution.into_str(),
-                                "Checking assigned shard id"
+                                "Checking assigned shard ids"
                             );
-                            let Some(assigned_sid) = self.assigned_shard_id() else {
+                            let assigned_sids = self.distribution.shards_of(&data_id, &self.local_id);
+                            if assigned_sids.is_empty() {
                                 debug!("see a storage request transaction from consensus, \
-                                but no shard ids are assigned to this peer. ignoring.");
-                                continue;
-                            };
-                            if author == self.local_id {
-                                let full_shard_id = (data_id, assigned_sid.clone());
-                                let shard = self.serve_shard(&full_shard_id);
-                                // imitate incoming `ServeShardResponse`
-                                match self
-                                    .handle_serve_shard_response(full_shard_id, shard, connection)
-                                    .await
-                                {
-                                    HandleResult::Ok => (),
-                                    HandleResult::Abort => {
-                                        connection.shutdown.cancel();
+                                but no shards of {:?} are placed on this peer. ignoring.", data_id);
+                            }
+                            for assigned_sid in assigned_sids {
+                                let full_shard_id = (data_id.clone(), assigned_sid);
+                                if author == self.local_id {
+                                    let shard = self.serve_shard(&full_shard_id);
+                                    // imitate incoming `ServeShardResponse`
+                                    match self
+                                        .handle_serve_shard_response(full_shard_id, shard, connection)
+                                        .await
+                                    {
+                                        HandleResult::Ok => (),
+                                        HandleResult::Abort => {
+                                            connection.shutdown.cancel();
+                                            return;
+                                        }
+                                    }
+                                } else {
+                                    debug!(
+                                        target: Targets::DataDistribution.into_str(),
+                                        "Requesting shard {:?}", full_shard_id
+                                    );
+                                    if (connection
+                                        .output
+                                        .send(OutEvent::ServeShardRequest(full_shard_id, author))
+                                        .await)
+                                        .is_err()
+                                    {
+                                        error!("`connection.output` is closed, shuttung down data memory");
                                         return;
                                     }
                                 }
-                            } else {
-                                debug!(
-                                    target: Targets::DataDistribution.into_str(),
-                                    "Requesting shard {:?} for {:?}", assigned_sid, data_id
-                                );
-                                if (connection
-                                    .output
-                                    .send(OutEvent::ServeShardRequest(
-                                        (data_id, assigned_sid.clone()),
-                                        author,
-                                    ))
-                                    .await)
-                                    .is_err()
-                                {
-                                    error!("`connection.output` is closed, shuttung down data memory");
-                                    return;
-                                }
                             }
                         }
                         InEvent::ServeShardRequest(full_shard_id) => {
@@ -595,11 +2138,24 @@ impl InitializedDataMemory {
                         InEvent::StoreConfirmed {
                             full_shard_id,
                             location,
+                            checksum,
                         } => {
+                            if self.storage.is_deleted(&full_shard_id.0) {
+                                debug!("{:?} announced shard {:?} of deleted data, ignoring", location, full_shard_id);
+                                continue;
+                            }
                             debug!(
                                 target: Targets::DataDistribution.into_str(),
                                 "Observed shard {:?} location: {:?}", full_shard_id, location
                             );
+                            match self.expected_checksum(&full_shard_id) {
+                                Some(expected) if expected != &checksum => {
+                                    warn!("{:?} announced shard {:?} with unexpected checksum, not tracking it", location, full_shard_id);
+                                    continue;
+                                }
+                                Some(_) => (),
+                                None => self.track_checksum(full_shard_id.clone(), checksum),
+                            }
                             self.observe_new_location(full_shard_id.clone(), location);
                             let Some(this_data_locations) = self.data_known_locations.get(&full_shard_id.0) else {
                                 warn!("bug in tracking data locations, new locations are not registered for some reason");
@@ -687,84 +2243,122 @@ impl InitializedDataMemory {
                         }
                         // data recollection
                         InEvent::RecollectRequest(data_id) => {
-                            let Some(known_locations) = self.data_known_locations.get(&data_id) else {
-                                debug!(target: Targets::DataRecollection.into_str(), "Do not know about {:?}", data_id);
-                                if (connection.output.send(
-                                    OutEvent::RecollectResponse(Err(RecollectionError::UnkonwnDataId))
-                                ).await).is_err() {
-                                    error!("`connection.output` is closed, shuttung down data memory");
+                            match self.handle_recollect_request(data_id, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
                                     return;
                                 }
-                                continue;
-                            };
-                            trace!(target: Targets::DataRecollection.into_str(), "We know data {:?} distribution: {:?}", data_id, known_locations);
-                            let sufficient_shards_n = self
-                                .encoding
-                                .settings()
-                                .data_shards_sufficient
-                                .try_into()
-                                .expect("# of shards sufficient is too large");
-                            if known_locations.len() < sufficient_shards_n {
-                                debug!(target: Targets::DataRecollection.into_str(), "We do not know enough locations of data {:?}: we track {}/{} sufficient shards", data_id, known_locations.len(), sufficient_shards_n);
-                                if (connection
-                                    .output
-                                    .send(OutEvent::RecollectResponse(Err(
-                                        RecollectionError::NotEnoughShards,
-                                    )))
-                                    .await)
-                                    .is_err()
-                                {
-                                    error!("`connection.output` is closed, shuttung down data memory");
+                            }
+                        }
+                        InEvent::AuditRequest(full_shard_id, challenge) => {
+                            let answer = self
+                                .get_shard(&full_shard_id)
+                                .and_then(|shard| challenge.respond(shard));
+                            if (connection
+                                .output
+                                .send(OutEvent::AuditResponse(full_shard_id, challenge, answer))
+                                .await)
+                                .is_err()
+                            {
+                                error!("`connection.output` is closed, shutting down data memory");
+                                return;
+                            }
+                        }
+                        InEvent::AuditResponse(full_shard_id, answer) => {
+                            match self.handle_audit_response(full_shard_id, answer, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
                                     return;
                                 }
-                                continue;
                             }
-                            match self.currently_assembled.entry(data_id.clone()) {
-                                // requests were already sent, just wait for the response
-                                hash_map::Entry::Occupied(_) => {
-                                    debug!(target: Targets::DataRecollection.into_str(), "Data {:?} is already in process of recollection", data_id);
-                                    continue;
+                        }
+                        InEvent::DeleteTx(data_id) => {
+                            match self.handle_delete(data_id, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
                                 }
-                                hash_map::Entry::Vacant(v) => {
-                                    v.insert(HashMap::new());
+                            }
+                        }
+                        InEvent::AssignedResponse(full_shard_id, shard) => {
+                            match self
+                                .handle_assigned_response(full_shard_id, shard, connection)
+                                .await
+                            {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
                                 }
                             }
-                            for (shard_id, owner) in known_locations.clone() {
-                                if owner == self.local_id {
-                                    trace!(target: Targets::DataRecollection.into_str(), "Getting shard {:?} from local storage", shard_id);
-                                    let full_shard_id = (data_id.clone(), shard_id);
-                                    let shard = self.get_shard(&full_shard_id).cloned();
-                                    // imitate incoming `ServeShardResponse`
-                                    match self
-                                        .handle_assigned_response(full_shard_id, shard, connection)
-                                        .await
-                                    {
-                                        HandleResult::Ok => (),
-                                        HandleResult::Abort => {
-                                            connection.shutdown.cancel();
-                                            return;
-                                        }
-                                    }
-                                } else {
-                                    trace!(target: Targets::DataRecollection.into_str(), "Requesting shard {:?} from peer {:?}", shard_id, owner);
-                                    if (connection
-                                        .output
-                                        .send(OutEvent::AssignedRequest(
-                                            (data_id.clone(), shard_id),
-                                            owner,
-                                        ))
-                                        .await)
-                                        .is_err()
-                                    {
-                                        error!("`connection.output` is closed, shuttung down data memory");
-                                        return;
-                                    }
+                        }
+                        InEvent::PeerLost(peer) => {
+                            match self.handle_peer_lost(peer, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
                                 }
                             }
                         }
-                        InEvent::AssignedResponse(full_shard_id, shard) => {
+                        InEvent::PeerJoined { peer, capacity } => {
+                            let change = self.distribution.join(peer, capacity);
+                            match self.handle_membership_change(change, None, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
+                                }
+                            }
+                        }
+                        InEvent::PeerLeft(peer) => {
+                            let change = self.distribution.leave(&peer);
+                            match self.handle_membership_change(change, None, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
+                                }
+                            }
+                        }
+                        InEvent::PeerEvicted { peer, by } => {
+                            if !self.distribution.is_member(&by) {
+                                warn!("{:?} proposed eviction of {:?} but is not a member itself, ignoring", by, peer);
+                                continue;
+                            }
+                            let change = self.distribution.leave(&peer);
+                            match self.handle_membership_change(change, Some(peer), connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
+                                }
+                            }
+                        }
+                        InEvent::ShardsRepaired { holder, shards } => {
+                            self.handle_shards_repaired(holder, shards)
+                        }
+                        InEvent::PlaintextOperands {
+                            from,
+                            step,
+                            mut operands,
+                        } => {
+                            if !self.distribution.is_member(&from) {
+                                warn!("{:?} sent operands of {:?} but is not a member of the storage, ignoring", from, step);
+                                continue;
+                            }
+                            let sent = operands.len();
+                            operands.retain(|(full_shard_id, _)| {
+                                self.distribution.holder_of(full_shard_id) == Some(from)
+                            });
+                            if operands.len() < sent {
+                                warn!("{:?} sent operand shards of {:?} that are not placed on it, ignoring them", from, step);
+                            }
                             match self
-                                .handle_assigned_response(full_shard_id, shard, connection)
+                                .handle_plaintext_operands(step, None, operands, connection)
                                 .await
                             {
                                 HandleResult::Ok => (),
@@ -774,18 +2368,25 @@ impl InitializedDataMemory {
                                 }
                             }
                         }
+                        InEvent::PlaintextResult { from, step, shards } => {
+                            self.handle_plaintext_result(from, step, shards)
+                        }
                         InEvent::PeerShardsActualized {
+                            program_id,
                             peer,
-                            updated_data_ids,
+                            updated_shards,
                         } => {
-                            let Some(expected_shard_id) = self.distribution.get(&peer) else {
+                            if !self.distribution.is_member(&peer) {
                                 warn!("Received program execution notification but the peer \
-                                is not assigned any shards. It shouldn't've send it.");
-                                return;
-                            };
-                            for data_id in updated_data_ids {
-                                let shards = self.data_known_locations.entry(data_id).or_default();
-                                shards.insert(expected_shard_id.clone(), peer);
+                                is not a member of the storage. It shouldn't've send it.");
+                                continue;
+                            }
+                            match self.handle_peer_shards_actualized(program_id, peer, updated_shards, connection).await {
+                                HandleResult::Ok => (),
+                                HandleResult::Abort => {
+                                    connection.shutdown.cancel();
+                                    return;
+                                }
                             }
                         }
                     }
@@ -795,12 +2396,12 @@ impl InitializedDataMemory {
                         error!("memory bus is closed, shuttung down data memory");
                         return;
                     };
-                    let shards: Vec<_> = self
-                        .local_storage
-                        .get(&data_id)
-                        .map(|mapping| mapping.values().cloned().collect())
+                    let shards = self
+                        .storage
+                        .data_shards(&data_id)
+                        .cloned()
                         .unwrap_or_default();
-                    if let Err(e) = response_handle.send(shards.get(0).cloned()) {
+                    if let Err(e) = response_handle.send(shards) {
                         warn!(
                             "response handle for memory bus request is closed, shouldn't happen \
                             but let's try to continue operation; {:?}",
@@ -809,15 +2410,71 @@ impl InitializedDataMemory {
                     }
                 }
                 write_request = self.bus.writes.recv() => {
-                    let Some((data_id, shard)) = write_request else {
+                    let Some((full_shard_id, shard)) = write_request else {
                         error!("memory bus is closed, shuttung down data memory");
                         return;
                     };
-                    let Some(assigned_sid) = self.assigned_shard_id() else {
-                        warn!("received write request from memory bus but no shards are assigned to this node. likely a bug, just ignoring");
-                        continue;
+                    // already logged, the bus has no way to report it back
+                    let _ = self.store_shard(full_shard_id, shard);
+                }
+                sync_request = self.bus.syncs.recv() => {
+                    let Some(response_handle) = sync_request else {
+                        error!("memory bus is closed, shutting down data memory");
+                        return;
+                    };
+                    // writes sent before the request are already in the channel
+                    while let Ok((full_shard_id, shard)) = self.bus.writes.try_recv() {
+                        let _ = self.store_shard(full_shard_id, shard);
+                    }
+                    if response_handle.send(()).is_err() {
+                        warn!("response handle for memory bus sync is closed, ignoring");
+                    }
+                }
+                plaintext_request = self.bus.plaintext.recv() => {
+                    let Some(request) = plaintext_request else {
+                        error!("memory bus is closed, shutting down data memory");
+                        return;
                     };
-                    self.store_shard((data_id, assigned_sid.clone()), shard);
+                    match self.handle_compute_request(request, connection).await {
+                        HandleResult::Ok => (),
+                        HandleResult::Abort => {
+                            connection.shutdown.cancel();
+                            return;
+                        }
+                    }
+                }
+                _ = recollection_deadlines.tick() => {
+                    self.plaintext.expire(Instant::now());
+                    match self.expire_recollection_requests(connection).await {
+                        HandleResult::Ok => (),
+                        HandleResult::Abort => {
+                            connection.shutdown.cancel();
+                            return;
+                        }
+                    }
+                    match self.expire_audit(connection).await {
+                        HandleResult::Ok => (),
+                        HandleResult::Abort => {
+                            connection.shutdown.cancel();
+                            return;
+                        }
+                    }
+                    match self.expire_result_check(connection).await {
+                        HandleResult::Ok => (),
+                        HandleResult::Abort => {
+                            connection.shutdown.cancel();
+                            return;
+                        }
+                    }
+                }
+                _ = audits.tick() => {
+                    match self.start_audit(connection).await {
+                        HandleResult::Ok => (),
+                        HandleResult::Abort => {
+                            connection.shutdown.cancel();
+                            return;
+                        }
+                    }
                 }
             }
         }
@@ -835,9 +2492,10 @@ impl DistributedDataMemory {
         local_id: PeerId,
         bus: MemoryBus,
         encoding_settings: reed_solomon::Settings,
+        storage: Box<dyn ShardStorage>,
     ) -> Self {
         Self {
-            uninit: UninitializedDataMemory::new(local_id, bus, encoding_settings),
+            uninit: UninitializedDataMemory::new(local_id, bus, encoding_settings, storage),
         }
     }
 
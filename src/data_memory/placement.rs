@@ -0,0 +1,213 @@
+//! Mapping of data shards to storage members.
+//!
+//! Each member may hold any number of shards, so the storage works with fewer
+//! peers than the total shard count, as well as with more of them. How shards
+//! are spread is decided by a [`PlacementStrategy`], chosen for the whole
+//! storage on initialization (see [`Placement`]).
+//!
+//! Strategies must be deterministic: every peer computes placement on its own
+//! and expects to get the same result as others.
+//!
+//! Program execution combines shards with the same id stored on the same peer.
+//! [`RoundRobin`] and [`CapacityWeighted`] place shard ids regardless of the data
+//! unit, so it always holds. [`ConsistentHashing`] spreads each data unit
+//! differently, so operands of an instruction are generally on different
+//! peers; programs are rejected by the
+//! [validator](crate::consensus::validation) of a storage using it.
+
+use blake2::{Blake2b512, Digest};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Sid, Vid};
+
+/// Capacity of a member that did not announce any
+pub const DEFAULT_CAPACITY: u64 = 1;
+
+/// Points each member gets on the hash ring
+const VIRTUAL_NODES_PER_MEMBER: u64 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub peer: PeerId,
+    /// Relative amount of shards the member is willing to hold
+    pub capacity: u64,
+}
+
+pub trait PlacementStrategy {
+    /// Holders of the data unit shards, `result[i]` holds shard `Sid(i)`.
+    /// `members` must not be empty.
+    fn place(&self, data_id: &Vid, shards_total: u64, members: &[Member]) -> Vec<PeerId>;
+}
+
+/// Shard `i` goes to member `i mod <number of members>`
+pub struct RoundRobin;
+
+impl PlacementStrategy for RoundRobin {
+    fn place(&self, _data_id: &Vid, shards_total: u64, members: &[Member]) -> Vec<PeerId> {
+        members
+            .iter()
+            .cycle()
+            .take(shards_total.try_into().unwrap())
+            .map(|member| member.peer)
+            .collect()
+    }
+}
+
+/// Members are put on a hash ring; shards of data unit go to members met
+/// clockwise starting from the hash of data id. Adding or removing a member
+/// moves only a small part of the shards.
+pub struct ConsistentHashing;
+
+fn hash_of(bytes: &[u8]) -> u64 {
+    let mut hasher = Blake2b512::new();
+    hasher.update(bytes);
+    let digest = hasher.finalize();
+    u64::from_be_bytes(
+        digest[..8]
+            .try_into()
+            .expect("digest is longer than 8 bytes"),
+    )
+}
+
+impl PlacementStrategy for ConsistentHashing {
+    fn place(&self, data_id: &Vid, shards_total: u64, members: &[Member]) -> Vec<PeerId> {
+        let mut ring: Vec<(u64, PeerId)> = members
+            .iter()
+            .flat_map(|member| {
+                (0..VIRTUAL_NODES_PER_MEMBER).map(move |i| {
+                    let mut point = member.peer.to_bytes();
+                    point.extend_from_slice(&i.to_be_bytes());
+                    (hash_of(&point), member.peer)
+                })
+            })
+            .collect();
+        ring.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let start = hash_of(&data_id.0.to_be_bytes());
+        let start_position = ring.partition_point(|(point, _)| *point < start);
+        // distinct members in the order they are met
+        let mut order: Vec<PeerId> = Vec::with_capacity(members.len());
+        for (_, peer) in ring[start_position..].iter().chain(&ring[..start_position]) {
+            if !order.contains(peer) {
+                order.push(*peer);
+            }
+        }
+        order
+            .into_iter()
+            .cycle()
+            .take(shards_total.try_into().unwrap())
+            .collect()
+    }
+}
+
+/// Each shard goes to the member with the least shards relative to its
+/// capacity (earlier member on ties)
+pub struct CapacityWeighted;
+
+impl PlacementStrategy for CapacityWeighted {
+    fn place(&self, _data_id: &Vid, shards_total: u64, members: &[Member]) -> Vec<PeerId> {
+        let mut assigned = vec![0u64; members.len()];
+        let mut holders = Vec::with_capacity(shards_total.try_into().unwrap());
+        for _ in 0..shards_total {
+            let mut best = 0;
+            for i in 1..members.len() {
+                // (assigned_i + 1) / capacity_i < (assigned_best + 1) / capacity_best
+                let candidate = u128::from(assigned[i] + 1) * u128::from(members[best].capacity);
+                let current = u128::from(assigned[best] + 1) * u128::from(members[i].capacity);
+                if candidate < current {
+                    best = i;
+                }
+            }
+            assigned[best] += 1;
+            holders.push(members[best].peer);
+        }
+        holders
+    }
+}
+
+/// Strategy used by the storage, agreed on during initialization
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Placement {
+    RoundRobin,
+    ConsistentHashing,
+    CapacityWeighted,
+}
+
+impl Placement {
+    pub fn strategy(&self) -> &'static dyn PlacementStrategy {
+        match self {
+            Placement::RoundRobin => &RoundRobin,
+            Placement::ConsistentHashing => &ConsistentHashing,
+            Placement::CapacityWeighted => &CapacityWeighted,
+        }
+    }
+
+    /// Holders of each shard of the data unit
+    pub fn place(
+        &self,
+        data_id: &Vid,
+        shards_total: u64,
+        members: &[Member],
+    ) -> Vec<(Sid, PeerId)> {
+        self.strategy()
+            .place(data_id, shards_total, members)
+            .into_iter()
+            .enumerate()
+            .map(|(i, peer)| (Sid(i.try_into().unwrap()), peer))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use libp2p::PeerId;
+
+    use super::{Member, Placement, DEFAULT_CAPACITY};
+    use crate::types::Vid;
+
+    fn members(capacities: &[u64]) -> Vec<Member> {
+        capacities
+            .iter()
+            .map(|capacity| Member {
+                peer: PeerId::random(),
+                capacity: *capacity,
+            })
+            .collect()
+    }
+
+    fn count(holders: &[PeerId], peer: &PeerId) -> usize {
+        holders.iter().filter(|holder| *holder == peer).count()
+    }
+
+    #[test]
+    fn strategies_place_all_shards() {
+        // fewer members than shards
+        let few = members(&[DEFAULT_CAPACITY; 2]);
+        let holders = Placement::RoundRobin.strategy().place(&Vid(0), 5, &few);
+        assert_eq!(holders.len(), 5);
+        assert_eq!(count(&holders, &few[0].peer), 3);
+        assert_eq!(count(&holders, &few[1].peer), 2);
+
+        // more members than shards, different data is spread differently
+        let many = members(&[DEFAULT_CAPACITY; 12]);
+        let strategy = Placement::ConsistentHashing.strategy();
+        let mut used = HashSet::new();
+        for data_id in 0..16 {
+            let holders = strategy.place(&Vid(data_id), 3, &many);
+            assert_eq!(holders, strategy.place(&Vid(data_id), 3, &many));
+            assert_eq!(holders.iter().collect::<HashSet<_>>().len(), 3);
+            used.extend(holders);
+        }
+        assert!(used.len() > 3);
+
+        // capacity is respected
+        let weighted = members(&[1, 3]);
+        let holders = Placement::CapacityWeighted
+            .strategy()
+            .place(&Vid(0), 8, &weighted);
+        assert_eq!(count(&holders, &weighted[0].peer), 2);
+        assert_eq!(count(&holders, &weighted[1].peer), 6);
+    }
+}
@@ -0,0 +1,284 @@
+//! Execution of non-linear instructions.
+//!
+//! Linear instructions commute with Reed-Solomon encoding, so each peer
+//! computes them on its shards (see [`crate::processor::single_threaded`]).
+//! Non-linear ones (e.g. `Nand`) don't, so their operands are assembled and
+//! computed on plaintext instead.
+//!
+//! The step is coordinated by the holder of the first shard of the result.
+//! Peers executing the program send their operand shards to it; each shard
+//! is accepted only from its holder. The coordinator takes the instruction
+//! from the program it executes itself, so executors can't make it compute
+//! anything else. Once it has `data_shards_sufficient` shards of each
+//! operand, the coordinator decodes them, computes the instruction, encodes
+//! the result and sends each of its holders their shards. The executors wait for these shards and continue
+//! with the program.
+//!
+//! Operands and results that don't arrive in time are dropped, the waiting
+//! executor gets an error then.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    encoding::{reed_solomon::ReedSolomonWrapper, DataEncoding},
+    processor::{mock::MockProcessor, Instruction, ProgramIdentifier},
+    types::{Data, Shard, Sid, Vid},
+};
+
+use super::FullShardId;
+
+/// How long operands of a step are collected and its result is waited for
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Instruction `index` of the program
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExecutionStep {
+    pub program: ProgramIdentifier,
+    pub index: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Encoding(#[from] crate::encoding::reed_solomon::Error),
+    #[error(transparent)]
+    Calculation(#[from] crate::processor::mock::Error),
+}
+
+/// Request of the processor to execute a non-linear instruction
+#[derive(Debug)]
+pub struct ComputeRequest {
+    pub step: ExecutionStep,
+    pub instruction: Instruction<Vid, Vid>,
+    /// Operand shards stored by this peer
+    pub operands: Vec<(FullShardId, Shard)>,
+    /// Result shards placed on this peer
+    pub response: oneshot::Sender<HashMap<Sid, Shard>>,
+}
+
+struct Operands {
+    /// Known once the coordinator's own executor reaches the step
+    instruction: Option<Instruction<Vid, Vid>>,
+    shards: HashMap<Vid, HashMap<Sid, Shard>>,
+}
+
+enum StepResult {
+    Requested(oneshot::Sender<HashMap<Sid, Shard>>),
+    Arrived(HashMap<Sid, Shard>),
+}
+
+/// Progress of non-linear steps, both coordinated by this peer and waited for
+#[derive(Default)]
+pub struct PlaintextSteps {
+    /// Operands collected by the coordinator. `None` once the step is
+    /// computed, to ignore late operands.
+    coordinated: HashMap<ExecutionStep, (Instant, Option<Operands>)>,
+    results: HashMap<ExecutionStep, (Instant, StepResult)>,
+}
+
+impl PlaintextSteps {
+    /// Remember operand shards of the coordinated step. `instruction` is
+    /// given along with the local operands, taken from the executed program.
+    /// Once there are enough operands, the instruction is computed and shards
+    /// of the result are returned.
+    #[allow(clippy::type_complexity)]
+    pub fn add_operands(
+        &mut self,
+        step: ExecutionStep,
+        instruction: Option<Instruction<Vid, Vid>>,
+        shards: Vec<(FullShardId, Shard)>,
+        encoding: &ReedSolomonWrapper,
+        now: Instant,
+    ) -> Option<Result<(Vid, HashMap<Sid, Shard>), Error>> {
+        let (_, operands) = self.coordinated.entry(step).or_insert_with(|| {
+            (
+                now + STEP_TIMEOUT,
+                Some(Operands {
+                    instruction: None,
+                    shards: HashMap::new(),
+                }),
+            )
+        });
+        let collected = operands.as_mut()?;
+        if instruction.is_some() {
+            collected.instruction = instruction;
+        }
+        for ((data_id, shard_id), shard) in shards {
+            collected
+                .shards
+                .entry(data_id)
+                .or_default()
+                .insert(shard_id, shard);
+        }
+        let sufficient: usize = encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .unwrap();
+        let instruction = collected.instruction.as_ref()?;
+        let is_ready = instruction
+            .operation
+            .args_as_list()
+            .into_iter()
+            .all(|data_id| {
+                collected
+                    .shards
+                    .get(data_id)
+                    .is_some_and(|shards| shards.len() >= sufficient)
+            });
+        if !is_ready {
+            return None;
+        }
+        let Operands {
+            instruction,
+            shards,
+        } = operands.take().expect("checked above");
+        let instruction = instruction.expect("checked above");
+        Some(Self::compute(instruction, shards, encoding))
+    }
+
+    fn compute(
+        instruction: Instruction<Vid, Vid>,
+        shards: HashMap<Vid, HashMap<Sid, Shard>>,
+        encoding: &ReedSolomonWrapper,
+    ) -> Result<(Vid, HashMap<Sid, Shard>), Error> {
+        let mut data: HashMap<Vid, Data> = HashMap::new();
+        for (data_id, shards) in shards {
+            data.insert(data_id, encoding.decode(shards)?);
+        }
+        let Instruction { operation, result } = instruction
+            .map_operands(|data_id| data.get(&data_id).cloned())
+            .transpose_operation()
+            .ok_or(crate::processor::mock::Error::DataNotFound)?;
+        let value = MockProcessor::calculate(&operation)?;
+        Ok((result, encoding.encode(value)?))
+    }
+
+    /// Wait for result shards of the step. They are sent right away if
+    /// they've arrived already.
+    pub fn expect_result(
+        &mut self,
+        step: ExecutionStep,
+        response: oneshot::Sender<HashMap<Sid, Shard>>,
+        now: Instant,
+    ) {
+        match self.results.remove(&step) {
+            Some((_, StepResult::Arrived(shards))) => {
+                let _ = response.send(shards);
+            }
+            _ => {
+                self.results
+                    .insert(step, (now + STEP_TIMEOUT, StepResult::Requested(response)));
+            }
+        }
+    }
+
+    /// Result shards of the step arrived from the coordinator
+    pub fn add_result(&mut self, step: ExecutionStep, shards: HashMap<Sid, Shard>, now: Instant) {
+        match self.results.remove(&step) {
+            Some((_, StepResult::Requested(response))) => {
+                let _ = response.send(shards);
+            }
+            _ => {
+                self.results
+                    .insert(step, (now + STEP_TIMEOUT, StepResult::Arrived(shards)));
+            }
+        }
+    }
+
+    /// Forget steps that took too long. Executors waiting for them are
+    /// notified by the dropped response channel.
+    pub fn expire(&mut self, now: Instant) {
+        self.coordinated.retain(|_, (deadline, _)| *deadline > now);
+        self.results.retain(|_, (deadline, _)| *deadline > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::sync::oneshot::{self, error::TryRecvError};
+
+    use super::{ExecutionStep, PlaintextSteps, STEP_TIMEOUT};
+    use crate::{
+        encoding::{
+            reed_solomon::{ReedSolomonWrapper, Settings},
+            DataEncoding,
+        },
+        processor::{Instruction, ProgramIdentifier},
+        types::{Data, Hash, Sid, Vid},
+    };
+
+    #[test]
+    fn nand_is_computed_on_plaintext() {
+        let encoding = ReedSolomonWrapper::new(Settings {
+            data_shards_total: 3,
+            data_shards_sufficient: 2,
+            max_shard_size: 5_000,
+        });
+        let step = ExecutionStep {
+            program: ProgramIdentifier {
+                hash: Hash::from_array([0; 64]),
+                event_hash: Hash::from_array([1; 64]),
+            },
+            index: 0,
+        };
+        let instruction = Instruction::nand(Vid(1), Vid(2), Vid(3));
+        let first = encoding.encode(Data(vec![0, 0, 5, 7])).unwrap();
+        let second = encoding.encode(Data(vec![0, 3, 0, 1])).unwrap();
+        let mut steps = PlaintextSteps::default();
+        let now = Instant::now();
+
+        // operands of other executors wait for the instruction of the local one
+        let from_other_peer = vec![
+            ((Vid(1), Sid(2)), first[&Sid(2)].clone()),
+            ((Vid(2), Sid(2)), second[&Sid(2)].clone()),
+        ];
+        let from_third_peer = vec![
+            ((Vid(1), Sid(1)), first[&Sid(1)].clone()),
+            ((Vid(2), Sid(1)), second[&Sid(1)].clone()),
+        ];
+        assert!(steps
+            .add_operands(step.clone(), None, from_other_peer.clone(), &encoding, now)
+            .is_none());
+        assert!(steps
+            .add_operands(step.clone(), None, from_third_peer, &encoding, now)
+            .is_none());
+        let local = vec![
+            ((Vid(1), Sid(0)), first[&Sid(0)].clone()),
+            ((Vid(2), Sid(0)), second[&Sid(0)].clone()),
+        ];
+        let (result, shards) = steps
+            .add_operands(step.clone(), Some(instruction), local, &encoding, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Vid(3));
+        assert_eq!(
+            encoding.decode(shards.clone()).unwrap(),
+            Data(vec![1, 1, 1, 0])
+        );
+        // computed only once
+        assert!(steps
+            .add_operands(step.clone(), None, from_other_peer, &encoding, now)
+            .is_none());
+
+        // result may arrive before the executor asks for it
+        steps.add_result(step.clone(), shards.clone(), now);
+        let (sender, mut receiver) = oneshot::channel();
+        steps.expect_result(step.clone(), sender, now);
+        assert_eq!(receiver.try_recv().unwrap(), shards);
+
+        let (sender, mut receiver) = oneshot::channel();
+        steps.expect_result(step, sender, now);
+        steps.expire(now + STEP_TIMEOUT);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    }
+}
@@ -0,0 +1,224 @@
+//! Assembling data from shards pulled from their holders.
+//!
+//! Local shards are taken right away, then just enough remote holders are
+//! asked to make up `data_shards_sufficient` shards. Each request has a
+//! deadline. Holders that respond with a missing or corrupted shard (checked
+//! against the checksum announced in consensus) or do not respond in time are
+//! replaced by holders of other shards. Once there is no one else left, the
+//! silent holders are asked again a few times. If it does not help, the
+//! recollection fails.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::types::{Shard, Sid};
+
+use super::RecollectionError;
+
+/// How long to wait for a single shard before asking someone else
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times a holder is asked again when there is no one else to ask
+pub const MAX_RETRIES: u32 = 2;
+/// How often deadlines of the requests are checked
+pub const DEADLINE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+struct Request {
+    holder: PeerId,
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Progress of assembling a single data unit.
+pub struct Recollection {
+    sufficient: usize,
+    /// Shards that passed the integrity check
+    received: HashMap<Sid, Shard>,
+    /// Requests waiting for a response
+    pending: HashMap<Sid, Request>,
+    /// Requests that were not answered in time. A late response is still
+    /// accepted.
+    timed_out: HashMap<Sid, Request>,
+    /// Holders not asked yet
+    spare: VecDeque<(Sid, PeerId)>,
+}
+
+impl Recollection {
+    pub fn new(sufficient: usize) -> Self {
+        Self {
+            sufficient,
+            received: HashMap::new(),
+            pending: HashMap::new(),
+            timed_out: HashMap::new(),
+            spare: VecDeque::new(),
+        }
+    }
+
+    /// Remember remote holder of the shard, to be asked if needed
+    pub fn add_holder(&mut self, shard_id: Sid, holder: PeerId) {
+        self.spare.push_back((shard_id, holder));
+    }
+
+    /// Returns `false` if the shard was not requested (e.g. is already received)
+    pub fn add_shard(&mut self, shard_id: Sid, shard: Shard) -> bool {
+        let requested =
+            self.pending.remove(&shard_id).is_some() || self.timed_out.remove(&shard_id).is_some();
+        if !requested {
+            return false;
+        }
+        self.received.insert(shard_id, shard);
+        true
+    }
+
+    /// Shard stored by this peer, there is no need to request it
+    pub fn add_local_shard(&mut self, shard_id: Sid, shard: Shard) {
+        self.received.insert(shard_id, shard);
+    }
+
+    /// The holder could not provide an intact shard
+    pub fn reject(&mut self, shard_id: &Sid) {
+        self.pending.remove(shard_id);
+        self.timed_out.remove(shard_id);
+    }
+
+    /// Consider requests that passed their deadline unanswered
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(shard_id, _)| shard_id.clone())
+            .collect();
+        for shard_id in expired {
+            let request = self.pending.remove(&shard_id).expect("just found");
+            self.timed_out.insert(shard_id, request);
+        }
+    }
+
+    pub fn is_sufficient(&self) -> bool {
+        self.received.len() >= self.sufficient
+    }
+
+    /// Requests to send so that enough shards could arrive: new holders first,
+    /// then retries of the silent ones. Fails if it's not possible anymore.
+    pub fn next_requests(&mut self, now: Instant) -> Result<Vec<(Sid, PeerId)>, RecollectionError> {
+        let mut requests = Vec::new();
+        while self.received.len() + self.pending.len() < self.sufficient {
+            if let Some((shard_id, holder)) = self.spare.pop_front() {
+                let request = Request {
+                    holder,
+                    deadline: now + REQUEST_TIMEOUT,
+                    retries: 0,
+                };
+                requests.push((shard_id.clone(), holder));
+                self.pending.insert(shard_id, request);
+                continue;
+            }
+            let retry = self
+                .timed_out
+                .iter()
+                .find(|(_, request)| request.retries < MAX_RETRIES)
+                .map(|(shard_id, _)| shard_id.clone());
+            let Some(shard_id) = retry else {
+                break;
+            };
+            let mut request = self.timed_out.remove(&shard_id).expect("just found");
+            request.retries += 1;
+            request.deadline = now + REQUEST_TIMEOUT;
+            requests.push((shard_id.clone(), request.holder));
+            self.pending.insert(shard_id, request);
+        }
+        if self.pending.is_empty() && !self.is_sufficient() {
+            if self.timed_out.is_empty() {
+                return Err(RecollectionError::ShardsUnavailable);
+            }
+            return Err(RecollectionError::Timeout);
+        }
+        Ok(requests)
+    }
+
+    pub fn shards(&self) -> &HashMap<Sid, Shard> {
+        &self.received
+    }
+
+    pub fn into_shards(self) -> HashMap<Sid, Shard> {
+        self.received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use libp2p::PeerId;
+
+    use super::{Recollection, MAX_RETRIES, REQUEST_TIMEOUT};
+    use crate::{
+        data_memory::RecollectionError,
+        types::{Shard, Sid},
+    };
+
+    #[test]
+    fn failed_holders_are_replaced() {
+        let shard = Shard::new(1, vec![1]);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut recollection = Recollection::new(2);
+        recollection.add_local_shard(Sid(0), shard.clone());
+        recollection.add_holder(Sid(1), a);
+        recollection.add_holder(Sid(2), b);
+        recollection.add_holder(Sid(3), c);
+        let mut now = Instant::now();
+        assert_eq!(recollection.next_requests(now).unwrap(), vec![(Sid(1), a)]);
+
+        // corrupted shard, ask the next holder
+        recollection.reject(&Sid(1));
+        assert!(!recollection.add_shard(Sid(1), shard.clone()));
+        assert_eq!(recollection.next_requests(now).unwrap(), vec![(Sid(2), b)]);
+
+        // no response in time
+        now += REQUEST_TIMEOUT;
+        recollection.expire(now);
+        assert_eq!(recollection.next_requests(now).unwrap(), vec![(Sid(3), c)]);
+        recollection.reject(&Sid(3));
+        // nobody else left, retry the silent one
+        for _ in 0..MAX_RETRIES {
+            assert_eq!(recollection.next_requests(now).unwrap(), vec![(Sid(2), b)]);
+            now += REQUEST_TIMEOUT;
+            recollection.expire(now);
+        }
+        assert!(matches!(
+            recollection.next_requests(now),
+            Err(RecollectionError::Timeout)
+        ));
+
+        // late response is fine
+        assert!(recollection.add_shard(Sid(2), shard));
+        assert!(recollection.is_sufficient());
+        assert_eq!(recollection.into_shards().len(), 2);
+    }
+
+    #[test]
+    fn fails_once_every_holder_gave_a_bad_shard() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut recollection = Recollection::new(1);
+        recollection.add_holder(Sid(0), a);
+        recollection.add_holder(Sid(1), b);
+        let now = Instant::now();
+        for (shard_id, holder) in [(Sid(0), a), (Sid(1), b)] {
+            assert_eq!(
+                recollection.next_requests(now).unwrap(),
+                vec![(shard_id.clone(), holder)]
+            );
+            recollection.reject(&shard_id);
+        }
+        // nobody is silent, so there is nothing to wait for
+        assert!(matches!(
+            recollection.next_requests(now),
+            Err(RecollectionError::ShardsUnavailable)
+        ));
+        assert!(!recollection.is_sufficient());
+    }
+}
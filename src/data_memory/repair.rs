@@ -0,0 +1,158 @@
+//! Restoration of shards newly placed on this peer.
+//!
+//! When members join or leave (or are evicted from) the storage, placement
+//! of shards changes (see [`super::distribution`]). For each shard now placed
+//! on it, the peer either copies the shard from its current location, or
+//! pulls `data_shards_sufficient` other shards of the data unit and rebuilds
+//! the missing one. Then it announces itself as the new location with
+//! `ShardsRepaired` transaction.
+//!
+//! Lost peers are detected by expiry in discovery or failed requests, and
+//! are then proposed for eviction.
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::PeerId;
+
+use crate::types::{Shard, Sid, Vid};
+
+use super::{distribution::Distribution, FullShardId};
+
+/// Progress of restoring shards placed on this peer.
+pub struct Repair {
+    /// Data units still waiting for enough shards: ids of shards to restore
+    /// and shards received so far
+    pending: HashMap<Vid, (HashSet<Sid>, HashMap<Sid, Shard>)>,
+    /// Shards restored and stored locally
+    rebuilt: Vec<FullShardId>,
+}
+
+impl Repair {
+    pub fn new(targets: HashMap<Vid, HashSet<Sid>>) -> Self {
+        Self {
+            pending: targets
+                .into_iter()
+                .map(|(data_id, shard_ids)| (data_id, (shard_ids, HashMap::new())))
+                .collect(),
+            rebuilt: Vec::new(),
+        }
+    }
+
+    pub fn is_pending(&self, data_id: &Vid) -> bool {
+        self.pending.contains_key(data_id)
+    }
+
+    /// Remember received shard. Once `sufficient` shards of the data are
+    /// collected (or all shards to restore are received as is), they are
+    /// returned (and no longer tracked) together with ids to restore.
+    #[allow(clippy::type_complexity)]
+    pub fn add_shard(
+        &mut self,
+        full_shard_id: FullShardId,
+        shard: Shard,
+        sufficient: usize,
+    ) -> Option<(HashSet<Sid>, HashMap<Sid, Shard>)> {
+        let (data_id, shard_id) = full_shard_id;
+        let (targets, received) = self.pending.get_mut(&data_id)?;
+        received.insert(shard_id, shard);
+        let all_received = targets.iter().all(|target| received.contains_key(target));
+        if !all_received && received.len() < sufficient {
+            return None;
+        }
+        self.pending.remove(&data_id)
+    }
+
+    pub fn mark_rebuilt(&mut self, full_shard_id: FullShardId) {
+        self.rebuilt.push(full_shard_id);
+    }
+
+    /// Stop restoring shards of the data, e.g. if it was deleted
+    pub fn forget(&mut self, data_id: &Vid) {
+        self.pending.remove(data_id);
+        self.rebuilt.retain(|(rebuilt_id, _)| rebuilt_id != data_id);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Restored shards still placed on `local_id` and the ones placed
+    /// elsewhere since the repair started
+    pub fn into_rebuilt(
+        self,
+        distribution: &Distribution,
+        local_id: &PeerId,
+    ) -> (Vec<FullShardId>, Vec<FullShardId>) {
+        self.rebuilt.into_iter().partition(|full_shard_id| {
+            distribution.holder_of(full_shard_id).as_ref() == Some(local_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use libp2p::PeerId;
+
+    use super::Repair;
+    use crate::{
+        data_memory::{distribution::Distribution, placement::Placement},
+        encoding::{
+            reed_solomon::{ReedSolomonWrapper, Settings},
+            DataEncoding,
+        },
+        types::{Data, Sid, Vid},
+    };
+
+    #[test]
+    fn shards_are_restored_and_kept_only_if_still_placed() {
+        let encoding = ReedSolomonWrapper::new(Settings {
+            data_shards_total: 3,
+            data_shards_sufficient: 2,
+            max_shard_size: 64,
+        });
+        let mut shards = encoding.encode(Data(vec![1, 2, 3, 4, 5])).unwrap();
+        let (local, other) = (PeerId::random(), PeerId::random());
+        let targets = HashMap::from([
+            (Vid(0), HashSet::from([Sid(0)])),
+            (Vid(1), HashSet::from([Sid(1)])),
+        ]);
+        let mut repair = Repair::new(targets);
+        assert!(repair.is_pending(&Vid(0)));
+
+        // rebuilt from sufficient other shards
+        assert!(repair
+            .add_shard((Vid(0), Sid(1)), shards[&Sid(1)].clone(), 2)
+            .is_none());
+        let (restore, received) = repair
+            .add_shard((Vid(0), Sid(2)), shards[&Sid(2)].clone(), 2)
+            .unwrap();
+        assert_eq!(restore, HashSet::from([Sid(0)]));
+        assert_eq!(
+            encoding.rebuild(received, &Sid(0)).unwrap(),
+            shards[&Sid(0)]
+        );
+        assert!(!repair.is_pending(&Vid(0)));
+        repair.mark_rebuilt((Vid(0), Sid(0)));
+
+        // the shard itself is copied as is, without waiting for others
+        let (restore, received) = repair
+            .add_shard((Vid(1), Sid(1)), shards.remove(&Sid(1)).unwrap(), 2)
+            .unwrap();
+        assert_eq!(restore, HashSet::from([Sid(1)]));
+        assert_eq!(received.len(), 1);
+        repair.mark_rebuilt((Vid(1), Sid(1)));
+        assert!(repair.is_finished());
+        // no longer tracked
+        assert!(repair
+            .add_shard((Vid(1), Sid(0)), shards[&Sid(0)].clone(), 2)
+            .is_none());
+
+        // placement changed meanwhile: round robin puts shard 1 on `other`
+        let distribution = Distribution::initial(vec![local, other], Placement::RoundRobin, 3);
+        let (placed, moved) = repair.into_rebuilt(&distribution, &local);
+        assert_eq!(placed, vec![(Vid(0), Sid(0))]);
+        assert_eq!(moved, vec![(Vid(1), Sid(1))]);
+    }
+}
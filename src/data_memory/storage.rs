@@ -0,0 +1,813 @@
+//! Backends for shards assigned to this peer.
+//!
+//! [`InitializedDataMemory`](super::InitializedDataMemory) keeps assigned shards
+//! behind [`ShardStorage`], so it does not care whether they live only in memory
+//! ([`MemoryStorage`]) or survive restarts ([`DiskStorage`]).
+//!
+//! Besides the shards themselves, the storage remembers the distribution the
+//! memory was initialized with, the observed locations of data shards and their
+//! checksums. This
+//! is enough for a restarted node to continue serving `get`s without anyone
+//! repeating `put`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::{
+    encoding::reed_solomon,
+    types::{Hash, Shard, Sid, Vid},
+};
+
+use super::{distribution::Distribution, FullShardId};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Could not access the storage: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not (de)serialize storage record: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error(
+        "Record at byte {position} of segment {path:?} is damaged, records after it would be lost"
+    )]
+    Corrupted { path: PathBuf, position: usize },
+}
+
+/// Bookkeeping that is restored from the storage on startup. Shards
+/// themselves stay in the storage and are read with [`ShardStorage::get`].
+#[derive(Default, Debug, Clone)]
+pub struct PersistedMetadata {
+    pub locations: HashMap<Vid, HashMap<Sid, PeerId>>,
+    pub checksums: HashMap<Vid, HashMap<Sid, Hash>>,
+    /// Checksums of program results claimed by their executors, not checked
+    /// yet
+    pub provisional_checksums: HashMap<Vid, HashMap<Sid, Hash>>,
+    /// Latest distribution, if the memory was initialized before
+    pub distribution: Option<Distribution>,
+    /// Encoding settings agreed on together with the distribution
+    pub encoding: Option<reed_solomon::Settings>,
+    /// Data deleted in consensus, shards and locations announced for it
+    /// later are not tracked
+    pub deleted: HashSet<Vid>,
+}
+
+#[derive(Default, Debug)]
+struct PersistedState {
+    shards: HashMap<Vid, HashMap<Sid, Shard>>,
+    metadata: PersistedMetadata,
+}
+
+impl PersistedState {
+    fn apply(&mut self, record: Record) {
+        if record
+            .data_id()
+            .is_some_and(|data_id| self.metadata.deleted.contains(data_id))
+        {
+            return;
+        }
+        match record {
+            Record::Initialized(distribution, encoding) => {
+                self.metadata.distribution = Some(distribution);
+                self.metadata.encoding = Some(encoding);
+            }
+            Record::Stored((data_id, shard_id), shard) => {
+                self.shards
+                    .entry(data_id)
+                    .or_default()
+                    .insert(shard_id, shard);
+            }
+            Record::Removed((data_id, shard_id)) => {
+                if let Some(shards) = self.shards.get_mut(&data_id) {
+                    shards.remove(&shard_id);
+                    if shards.is_empty() {
+                        self.shards.remove(&data_id);
+                    }
+                }
+            }
+            Record::Located((data_id, shard_id), location) => {
+                self.metadata
+                    .locations
+                    .entry(data_id)
+                    .or_default()
+                    .insert(shard_id, location);
+            }
+            Record::Deleted(data_id) => {
+                self.shards.remove(&data_id);
+                self.metadata.locations.remove(&data_id);
+                self.metadata.checksums.remove(&data_id);
+                self.metadata.provisional_checksums.remove(&data_id);
+                self.metadata.deleted.insert(data_id);
+            }
+            Record::Checksum((data_id, shard_id), checksum) => {
+                if let Some(provisional) = self.metadata.provisional_checksums.get_mut(&data_id) {
+                    provisional.remove(&shard_id);
+                }
+                self.metadata
+                    .checksums
+                    .entry(data_id)
+                    .or_default()
+                    .insert(shard_id, checksum);
+            }
+            Record::ProvisionalChecksum((data_id, shard_id), Some(checksum)) => {
+                self.metadata
+                    .provisional_checksums
+                    .entry(data_id)
+                    .or_default()
+                    .insert(shard_id, checksum);
+            }
+            Record::ProvisionalChecksum((data_id, shard_id), None) => {
+                if let Some(provisional) = self.metadata.provisional_checksums.get_mut(&data_id) {
+                    provisional.remove(&shard_id);
+                }
+            }
+        }
+    }
+
+    /// Minimal list of records that reproduces this state
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let distribution = self
+            .metadata
+            .distribution
+            .clone()
+            .zip(self.metadata.encoding.clone())
+            .map(|(distribution, encoding)| Record::Initialized(distribution, encoding));
+        let shards = self.shards.iter().flat_map(|(data_id, shards)| {
+            shards.iter().map(|(shard_id, shard)| {
+                Record::Stored((data_id.clone(), shard_id.clone()), shard.clone())
+            })
+        });
+        let locations = self
+            .metadata
+            .locations
+            .iter()
+            .flat_map(|(data_id, locations)| {
+                locations.iter().map(|(shard_id, location)| {
+                    Record::Located((data_id.clone(), shard_id.clone()), *location)
+                })
+            });
+        let checksums = self
+            .metadata
+            .checksums
+            .iter()
+            .flat_map(|(data_id, checksums)| {
+                checksums.iter().map(|(shard_id, checksum)| {
+                    Record::Checksum((data_id.clone(), shard_id.clone()), checksum.clone())
+                })
+            });
+        // confirmed checksums replace provisional ones, so they go first
+        let provisional_checksums =
+            self.metadata
+                .provisional_checksums
+                .iter()
+                .flat_map(|(data_id, checksums)| {
+                    checksums.iter().map(|(shard_id, checksum)| {
+                        Record::ProvisionalChecksum(
+                            (data_id.clone(), shard_id.clone()),
+                            Some(checksum.clone()),
+                        )
+                    })
+                });
+        let deleted = self.metadata.deleted.iter().cloned().map(Record::Deleted);
+        distribution
+            .into_iter()
+            .chain(shards)
+            .chain(locations)
+            .chain(checksums)
+            .chain(provisional_checksums)
+            .chain(deleted)
+    }
+}
+
+/// Storage of the shards assigned to this peer (and the bookkeeping needed to
+/// resume operation after a restart).
+pub trait ShardStorage: Send {
+    /// Bookkeeping that was saved before the last shutdown
+    fn restore(&self) -> &PersistedMetadata;
+
+    fn get(&self, full_shard_id: &FullShardId) -> Option<&Shard>;
+
+    /// All locally stored shards of the data unit
+    fn data_shards(&self, data_id: &Vid) -> Option<&HashMap<Sid, Shard>>;
+
+    /// Returns previous value of the shard, if there was any
+    fn store(&mut self, full_shard_id: FullShardId, shard: Shard) -> Result<Option<Shard>, Error>;
+
+    fn remove(&mut self, full_shard_id: &FullShardId) -> Result<Option<Shard>, Error>;
+
+    fn store_location(&mut self, full_shard_id: FullShardId, location: PeerId)
+        -> Result<(), Error>;
+
+    /// Remember expected checksum of the shard contents
+    fn store_checksum(&mut self, full_shard_id: FullShardId, checksum: Hash) -> Result<(), Error>;
+
+    /// Remember checksum of the shard claimed by the executor of a program
+    /// until it's checked (`None` forgets it). Replaced by the one given to
+    /// [`Self::store_checksum`].
+    fn store_provisional_checksum(
+        &mut self,
+        full_shard_id: FullShardId,
+        checksum: Option<Hash>,
+    ) -> Result<(), Error>;
+
+    /// Forget the data unit completely: its local shards, known locations and
+    /// checksums. Shards, locations and checksums of it stored afterwards are
+    /// ignored.
+    fn remove_data(&mut self, data_id: &Vid) -> Result<(), Error>;
+
+    fn is_deleted(&self, data_id: &Vid) -> bool {
+        self.restore().deleted.contains(data_id)
+    }
+
+    /// Remember current distribution (replacing the previous one)
+    fn store_distribution(
+        &mut self,
+        distribution: Distribution,
+        encoding: reed_solomon::Settings,
+    ) -> Result<(), Error>;
+}
+
+/// Keeps everything in memory, nothing survives the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: PersistedState,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ShardStorage for MemoryStorage {
+    fn restore(&self) -> &PersistedMetadata {
+        // never filled, see below
+        &self.state.metadata
+    }
+
+    fn get(&self, full_shard_id: &FullShardId) -> Option<&Shard> {
+        self.state
+            .shards
+            .get(&full_shard_id.0)
+            .and_then(|shards| shards.get(&full_shard_id.1))
+    }
+
+    fn data_shards(&self, data_id: &Vid) -> Option<&HashMap<Sid, Shard>> {
+        self.state.shards.get(data_id)
+    }
+
+    fn store(&mut self, full_shard_id: FullShardId, shard: Shard) -> Result<Option<Shard>, Error> {
+        if self.is_deleted(&full_shard_id.0) {
+            return Ok(None);
+        }
+        let shards = self.state.shards.entry(full_shard_id.0).or_default();
+        Ok(shards.insert(full_shard_id.1, shard))
+    }
+
+    fn remove(&mut self, full_shard_id: &FullShardId) -> Result<Option<Shard>, Error> {
+        Ok(self
+            .state
+            .shards
+            .get_mut(&full_shard_id.0)
+            .and_then(|shards| shards.remove(&full_shard_id.1)))
+    }
+
+    fn store_location(
+        &mut self,
+        _full_shard_id: FullShardId,
+        _location: PeerId,
+    ) -> Result<(), Error> {
+        // locations are tracked by data memory itself
+        Ok(())
+    }
+
+    fn store_checksum(
+        &mut self,
+        _full_shard_id: FullShardId,
+        _checksum: Hash,
+    ) -> Result<(), Error> {
+        // as well as checksums
+        Ok(())
+    }
+
+    fn store_provisional_checksum(
+        &mut self,
+        _full_shard_id: FullShardId,
+        _checksum: Option<Hash>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn remove_data(&mut self, data_id: &Vid) -> Result<(), Error> {
+        self.state.apply(Record::Deleted(data_id.clone()));
+        Ok(())
+    }
+
+    fn store_distribution(
+        &mut self,
+        _distribution: Distribution,
+        _encoding: reed_solomon::Settings,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Single change of the storage state. Segments consist of these.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    Initialized(Distribution, reed_solomon::Settings),
+    Stored(FullShardId, Shard),
+    Removed(FullShardId),
+    Located(FullShardId, PeerId),
+    Deleted(Vid),
+    Checksum(FullShardId, Hash),
+    ProvisionalChecksum(FullShardId, Option<Hash>),
+}
+
+impl Record {
+    /// Data unit the record is about, if any
+    fn data_id(&self) -> Option<&Vid> {
+        match self {
+            Record::Initialized(..) => None,
+            Record::Stored((data_id, _), _)
+            | Record::Removed((data_id, _))
+            | Record::Located((data_id, _), _)
+            | Record::Deleted(data_id)
+            | Record::Checksum((data_id, _), _)
+            | Record::ProvisionalChecksum((data_id, _), _) => Some(data_id),
+        }
+    }
+}
+
+const SEGMENT_EXTENSION: &str = "segment";
+/// Start a new segment after the active one grows beyond this size
+const SEGMENT_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+/// Rewrite the live state into a fresh segment once there are more segments than this
+const SEGMENTS_BEFORE_COMPACTION: usize = 8;
+
+/// Append-only log of [`Record`]s split into segment files inside a directory.
+///
+/// Each record is written as its length (`u64`, big endian) followed by its
+/// `bincode` encoding. Each launch continues in a new segment, so a record torn by
+/// a crash can only be at the very end of some segment and is skipped on replay.
+///
+/// The whole state is also kept in memory, so reads never touch the disk.
+pub struct DiskStorage {
+    directory: PathBuf,
+    state: PersistedState,
+    /// Indices of existing segments in ascending order, the last one is active
+    segments: Vec<u64>,
+    active_segment: File,
+    active_segment_size: u64,
+}
+
+impl DiskStorage {
+    /// Open storage at `directory` (creating it if needed) and replay its segments.
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self, Error> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        let mut segments = Self::list_segments(&directory)?;
+        let mut state = PersistedState::default();
+        for index in &segments {
+            Self::replay_segment(&Self::segment_path(&directory, *index), &mut state)?;
+        }
+        info!(
+            "Restored {} data units and {} known locations from {} segment(s) in {:?}",
+            state.shards.len(),
+            state.metadata.locations.len(),
+            segments.len(),
+            directory
+        );
+        let next_index = segments.last().map(|i| i + 1).unwrap_or(0);
+        let active_segment = Self::create_segment(&directory, next_index)?;
+        segments.push(next_index);
+        let mut storage = Self {
+            directory,
+            state,
+            segments,
+            active_segment,
+            active_segment_size: 0,
+        };
+        if storage.segments.len() > SEGMENTS_BEFORE_COMPACTION {
+            storage.compact()?;
+        }
+        Ok(storage)
+    }
+
+    fn segment_path(directory: &Path, index: u64) -> PathBuf {
+        directory.join(format!("{:020}.{}", index, SEGMENT_EXTENSION))
+    }
+
+    fn list_segments(directory: &Path) -> Result<Vec<u64>, Error> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let Some(index) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                warn!("Unexpected file {:?} in storage directory, ignoring", path);
+                continue;
+            };
+            segments.push(index);
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn create_segment(directory: &Path, index: u64) -> Result<File, Error> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(Self::segment_path(directory, index))?;
+        Ok(file)
+    }
+
+    /// Apply records of the segment. Only the last record can be torn by a
+    /// crash, a damaged one before it is an error.
+    fn replay_segment(path: &Path, state: &mut PersistedState) -> Result<(), Error> {
+        let bytes = fs::read(path)?;
+        let mut position = 0;
+        while position < bytes.len() {
+            let (record, length) = match Self::read_record(&bytes[position..]) {
+                Some(Ok(record)) => record,
+                Some(Err(length)) if position + length == bytes.len() => break,
+                None if !Self::records_follow(&bytes[position..]) => break,
+                _ => {
+                    return Err(Error::Corrupted {
+                        path: path.to_owned(),
+                        position,
+                    })
+                }
+            };
+            state.apply(record);
+            position += length;
+        }
+        if position < bytes.len() {
+            warn!(
+                "Segment {:?} ends with incomplete record (likely a crash during write), skipping it",
+                path
+            );
+        }
+        Ok(())
+    }
+
+    /// Record at the beginning of `bytes` and its length in bytes (the
+    /// length alone if it can't be deserialized), `None` if the record is
+    /// incomplete
+    fn read_record(bytes: &[u8]) -> Option<Result<(Record, usize), usize>> {
+        let length = u64::from_be_bytes(bytes.get(..8)?.try_into().unwrap());
+        let end = usize::try_from(length).ok()?.checked_add(8)?;
+        let record = bincode::deserialize(bytes.get(8..end)?).map_err(|_| end);
+        Some(record.map(|record| (record, end)))
+    }
+
+    /// Whether `bytes` end with whole records starting somewhere after the
+    /// first byte, so the record at the start is damaged rather than torn
+    fn records_follow(bytes: &[u8]) -> bool {
+        (1..bytes.len()).any(|start| {
+            let mut rest = &bytes[start..];
+            while let Some(Ok((_, length))) = Self::read_record(rest) {
+                rest = &rest[length..];
+            }
+            rest.is_empty()
+        })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<(), Error> {
+        let bytes = bincode::serialize(record)?;
+        let length = u64::try_from(bytes.len()).expect("record does not fit into u64");
+        let mut buffer = Vec::with_capacity(bytes.len() + 8);
+        buffer.extend_from_slice(&length.to_be_bytes());
+        buffer.extend_from_slice(&bytes);
+        self.active_segment.write_all(&buffer)?;
+        self.active_segment.sync_data()?;
+        self.active_segment_size += u64::try_from(buffer.len()).unwrap_or(u64::MAX);
+        if self.active_segment_size > SEGMENT_SIZE_LIMIT {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.active_segment.sync_all()?;
+        let next_index = self.segments.last().map(|i| i + 1).unwrap_or(0);
+        self.active_segment = Self::create_segment(&self.directory, next_index)?;
+        self.active_segment_size = 0;
+        self.segments.push(next_index);
+        debug!("Switched to storage segment {}", next_index);
+        if self.segments.len() > SEGMENTS_BEFORE_COMPACTION {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Write current state into a new segment and remove all older ones
+    fn compact(&mut self) -> Result<(), Error> {
+        debug!("Compacting {} storage segments", self.segments.len());
+        let next_index = self.segments.last().map(|i| i + 1).unwrap_or(0);
+        let mut compacted = Self::create_segment(&self.directory, next_index)?;
+        let mut size = 0u64;
+        for record in self.state.records() {
+            let bytes = bincode::serialize(&record)?;
+            let length = u64::try_from(bytes.len()).expect("record does not fit into u64");
+            compacted.write_all(&length.to_be_bytes())?;
+            compacted.write_all(&bytes)?;
+            size += 8 + length;
+        }
+        compacted.sync_all()?;
+        for index in std::mem::take(&mut self.segments) {
+            fs::remove_file(Self::segment_path(&self.directory, index))?;
+        }
+        self.segments.push(next_index);
+        self.active_segment = compacted;
+        self.active_segment_size = size;
+        Ok(())
+    }
+}
+
+impl ShardStorage for DiskStorage {
+    fn restore(&self) -> &PersistedMetadata {
+        &self.state.metadata
+    }
+
+    fn get(&self, full_shard_id: &FullShardId) -> Option<&Shard> {
+        self.state
+            .shards
+            .get(&full_shard_id.0)
+            .and_then(|shards| shards.get(&full_shard_id.1))
+    }
+
+    fn data_shards(&self, data_id: &Vid) -> Option<&HashMap<Sid, Shard>> {
+        self.state.shards.get(data_id)
+    }
+
+    fn store(&mut self, full_shard_id: FullShardId, shard: Shard) -> Result<Option<Shard>, Error> {
+        if self.is_deleted(&full_shard_id.0) {
+            return Ok(None);
+        }
+        self.append(&Record::Stored(full_shard_id.clone(), shard.clone()))?;
+        let shards = self.state.shards.entry(full_shard_id.0).or_default();
+        Ok(shards.insert(full_shard_id.1, shard))
+    }
+
+    fn remove(&mut self, full_shard_id: &FullShardId) -> Result<Option<Shard>, Error> {
+        if self.get(full_shard_id).is_none() {
+            return Ok(None);
+        }
+        let record = Record::Removed(full_shard_id.clone());
+        self.append(&record)?;
+        let previous = self.get(full_shard_id).cloned();
+        self.state.apply(record);
+        Ok(previous)
+    }
+
+    fn store_location(
+        &mut self,
+        full_shard_id: FullShardId,
+        location: PeerId,
+    ) -> Result<(), Error> {
+        if self.is_deleted(&full_shard_id.0) {
+            return Ok(());
+        }
+        let already_known = self
+            .state
+            .metadata
+            .locations
+            .get(&full_shard_id.0)
+            .and_then(|l| l.get(&full_shard_id.1))
+            == Some(&location);
+        if already_known {
+            return Ok(());
+        }
+        let record = Record::Located(full_shard_id, location);
+        self.append(&record)?;
+        self.state.apply(record);
+        Ok(())
+    }
+
+    fn store_checksum(&mut self, full_shard_id: FullShardId, checksum: Hash) -> Result<(), Error> {
+        if self.is_deleted(&full_shard_id.0) {
+            return Ok(());
+        }
+        let already_known = self
+            .state
+            .metadata
+            .checksums
+            .get(&full_shard_id.0)
+            .and_then(|c| c.get(&full_shard_id.1))
+            == Some(&checksum);
+        if already_known {
+            return Ok(());
+        }
+        let record = Record::Checksum(full_shard_id, checksum);
+        self.append(&record)?;
+        self.state.apply(record);
+        Ok(())
+    }
+
+    fn store_provisional_checksum(
+        &mut self,
+        full_shard_id: FullShardId,
+        checksum: Option<Hash>,
+    ) -> Result<(), Error> {
+        if self.is_deleted(&full_shard_id.0) {
+            return Ok(());
+        }
+        let record = Record::ProvisionalChecksum(full_shard_id, checksum);
+        self.append(&record)?;
+        self.state.apply(record);
+        Ok(())
+    }
+
+    fn remove_data(&mut self, data_id: &Vid) -> Result<(), Error> {
+        let record = Record::Deleted(data_id.clone());
+        self.append(&record)?;
+        self.state.apply(record);
+        Ok(())
+    }
+
+    fn store_distribution(
+        &mut self,
+        distribution: Distribution,
+        encoding: reed_solomon::Settings,
+    ) -> Result<(), Error> {
+        let record = Record::Initialized(distribution, encoding);
+        self.append(&record)?;
+        self.state.apply(record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use libp2p::PeerId;
+
+    use super::{DiskStorage, Error, MemoryStorage, ShardStorage};
+    use crate::{
+        data_memory::{distribution::Distribution, placement::Placement},
+        encoding::reed_solomon,
+        types::{Shard, Sid, Vid},
+    };
+
+    #[test]
+    fn disk_storage_survives_reopen() {
+        let directory =
+            std::env::temp_dir().join(format!("the-swarm-storage-test-{}", rand::random::<u64>()));
+        let peer = PeerId::random();
+        let shard = Shard::new(3, vec![7, 7]);
+        let distribution = Distribution::initial(vec![peer], Placement::RoundRobin, 1);
+        let encoding = reed_solomon::Settings {
+            data_shards_total: 1,
+            data_shards_sufficient: 1,
+            max_shard_size: 2,
+        };
+        {
+            let mut storage = DiskStorage::open(&directory).unwrap();
+            storage
+                .store_distribution(distribution.clone(), encoding.clone())
+                .unwrap();
+            storage.store((Vid(1), Sid(0)), shard.clone()).unwrap();
+            storage.store((Vid(2), Sid(0)), shard.clone()).unwrap();
+            storage.remove(&(Vid(2), Sid(0))).unwrap();
+            storage.store_location((Vid(1), Sid(0)), peer).unwrap();
+            storage
+                .store_checksum((Vid(1), Sid(0)), shard.checksum())
+                .unwrap();
+            // claimed by an executor, the second one is checked afterwards
+            for shard_id in [Sid(1), Sid(2)] {
+                storage
+                    .store_provisional_checksum((Vid(1), shard_id), Some(shard.checksum()))
+                    .unwrap();
+            }
+            storage
+                .store_checksum((Vid(1), Sid(2)), shard.checksum())
+                .unwrap();
+            storage.store((Vid(3), Sid(0)), shard.clone()).unwrap();
+            storage.store_location((Vid(3), Sid(0)), peer).unwrap();
+            storage.remove_data(&Vid(3)).unwrap();
+        }
+        let storage = DiskStorage::open(&directory).unwrap();
+        assert_eq!(storage.get(&(Vid(1), Sid(0))), Some(&shard));
+        assert_eq!(storage.get(&(Vid(2), Sid(0))), None);
+        assert_eq!(storage.get(&(Vid(3), Sid(0))), None);
+        let restored = storage.restore();
+        assert_eq!(restored.distribution, Some(distribution));
+        assert_eq!(restored.encoding, Some(encoding));
+        assert_eq!(restored.locations[&Vid(1)][&Sid(0)], peer);
+        assert_eq!(restored.checksums[&Vid(1)][&Sid(0)], shard.checksum());
+        assert!(!restored.locations.contains_key(&Vid(3)));
+        assert_eq!(
+            restored.provisional_checksums[&Vid(1)]
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&Sid(1)]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn deleted_data_is_not_tracked_again() {
+        let directory =
+            std::env::temp_dir().join(format!("the-swarm-storage-test-{}", rand::random::<u64>()));
+        let peer = PeerId::random();
+        let shard = Shard::new(3, vec![7, 7]);
+        let full_shard_id = (Vid(1), Sid(0));
+        let mut memory = MemoryStorage::new();
+        let mut disk = DiskStorage::open(&directory).unwrap();
+        for storage in [&mut memory as &mut dyn ShardStorage, &mut disk] {
+            storage.store(full_shard_id.clone(), shard.clone()).unwrap();
+            storage.store_location(full_shard_id.clone(), peer).unwrap();
+            storage
+                .store_checksum(full_shard_id.clone(), shard.checksum())
+                .unwrap();
+            storage.remove_data(&Vid(1)).unwrap();
+            // late `Stored` of the deleted data
+            storage.store(full_shard_id.clone(), shard.clone()).unwrap();
+            storage.store_location(full_shard_id.clone(), peer).unwrap();
+            storage
+                .store_checksum(full_shard_id.clone(), shard.checksum())
+                .unwrap();
+            assert!(storage.is_deleted(&Vid(1)));
+            assert_eq!(storage.get(&full_shard_id), None);
+            assert!(!storage.restore().locations.contains_key(&Vid(1)));
+            assert!(!storage.restore().checksums.contains_key(&Vid(1)));
+        }
+        drop(disk);
+        let mut disk = DiskStorage::open(&directory).unwrap();
+        disk.compact().unwrap();
+        let disk = DiskStorage::open(&directory).unwrap();
+        assert!(disk.is_deleted(&Vid(1)));
+        assert_eq!(disk.get(&full_shard_id), None);
+        assert!(!disk.restore().locations.contains_key(&Vid(1)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn torn_record_skips_only_its_segment() {
+        let directory =
+            std::env::temp_dir().join(format!("the-swarm-storage-test-{}", rand::random::<u64>()));
+        let shard = Shard::new(3, vec![7, 7]);
+        {
+            let mut storage = DiskStorage::open(&directory).unwrap();
+            storage.store((Vid(1), Sid(0)), shard.clone()).unwrap();
+        }
+        // crash in the middle of writing to the first segment
+        let first = DiskStorage::segment_path(&directory, 0);
+        let mut torn = 100u64.to_be_bytes().to_vec();
+        torn.push(1);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&first)
+            .unwrap()
+            .write_all(&torn)
+            .unwrap();
+        {
+            let mut storage = DiskStorage::open(&directory).unwrap();
+            assert_eq!(storage.get(&(Vid(1), Sid(0))), Some(&shard));
+            storage.store((Vid(2), Sid(0)), shard.clone()).unwrap();
+        }
+        let storage = DiskStorage::open(&directory).unwrap();
+        assert_eq!(storage.get(&(Vid(1), Sid(0))), Some(&shard));
+        assert_eq!(storage.get(&(Vid(2), Sid(0))), Some(&shard));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn damaged_record_before_the_end_is_an_error() {
+        let directory =
+            std::env::temp_dir().join(format!("the-swarm-storage-test-{}", rand::random::<u64>()));
+        let shard = Shard::new(3, vec![7, 7]);
+        {
+            let mut storage = DiskStorage::open(&directory).unwrap();
+            storage.store((Vid(1), Sid(0)), shard.clone()).unwrap();
+            storage.store((Vid(2), Sid(0)), shard.clone()).unwrap();
+        }
+        let first = DiskStorage::segment_path(&directory, 0);
+        let intact = std::fs::read(&first).unwrap();
+        // damaged length (pointing past the end) and unknown variant
+        for offset in [0, 8] {
+            let mut damaged = intact.clone();
+            damaged[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+            std::fs::write(&first, damaged).unwrap();
+            assert!(matches!(
+                DiskStorage::open(&directory),
+                Err(Error::Corrupted { position: 0, .. })
+            ));
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! Sampled checks of program results.
+//!
+//! Executors announce checksums of the result shards they computed in
+//! `Executed` transaction, committing to them. Once commitments to all shards
+//! of a result are known, the result is checked with [`CHECK_PROBABILITY`].
+//! The checker pulls shards of the result (see [`super::recollection`]),
+//! reconstructs the data from different subsets of them and encodes it again.
+//! The reconstruction agreeing with most commitments is considered the actual
+//! result; holders whose commitments differ from it computed something else.
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::PeerId;
+
+use crate::{
+    encoding::{reed_solomon::ReedSolomonWrapper, DataEncoding},
+    processor::ProgramIdentifier,
+    types::{Hash, Shard, Sid, Vid},
+};
+
+use super::{recollection::Recollection, FullShardId};
+
+/// Share of results that are checked
+pub const CHECK_PROBABILITY: f64 = 0.25;
+/// At most this many subsets of shards are tried to reconstruct the result
+pub const MAX_RECONSTRUCTIONS: usize = 32;
+
+/// Commitment of the holder to the checksum of a result shard
+pub type Commitments = HashMap<Sid, (PeerId, Hash)>;
+
+/// Commitments to results of the latest program that wrote them
+#[derive(Default)]
+pub struct ResultCommitments {
+    results: HashMap<Vid, (ProgramIdentifier, Commitments)>,
+}
+
+impl ResultCommitments {
+    /// Remember the commitment. Returns commitments to all `total` shards of
+    /// the result once the last of them arrives.
+    pub fn add(
+        &mut self,
+        program: ProgramIdentifier,
+        (data_id, shard_id): FullShardId,
+        holder: PeerId,
+        checksum: Hash,
+        total: usize,
+    ) -> Option<Commitments> {
+        let entry = self
+            .results
+            .entry(data_id.clone())
+            .or_insert_with(|| (program.clone(), HashMap::new()));
+        if entry.0 != program {
+            // the data was overwritten by another program
+            *entry = (program, HashMap::new());
+        }
+        entry.1.insert(shard_id, (holder, checksum));
+        if entry.1.len() < total {
+            return None;
+        }
+        self.results
+            .remove(&data_id)
+            .map(|(_, commitments)| commitments)
+    }
+
+    pub fn forget(&mut self, data_id: &Vid) {
+        self.results.remove(data_id);
+    }
+}
+
+/// Check of a single result
+pub struct ResultCheck {
+    pub data_id: Vid,
+    pub commitments: Commitments,
+    /// Pulls all shards of the result, so that faulty ones can be outvoted
+    pub recollection: Recollection,
+}
+
+/// Holders of the checked result, by whether they computed it correctly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub agreed: Vec<PeerId>,
+    pub disagreed: Vec<PeerId>,
+    /// Checksums of the shards of the reconstructed result
+    pub checksums: HashMap<Sid, Hash>,
+}
+
+/// Subsets of `size` elements of `items`, in lexicographic order
+fn subsets<T: Clone>(items: &[T], size: usize) -> impl Iterator<Item = Vec<T>> + '_ {
+    let mut indices: Option<Vec<usize>> = (size <= items.len()).then(|| (0..size).collect());
+    std::iter::from_fn(move || {
+        let current = indices.as_mut()?;
+        let subset = current.iter().map(|&i| items[i].clone()).collect();
+        // advance to the next subset
+        match (0..size)
+            .rev()
+            .find(|&i| current[i] < items.len() - size + i)
+        {
+            Some(i) => {
+                current[i] += 1;
+                for j in i + 1..size {
+                    current[j] = current[j - 1] + 1;
+                }
+            }
+            None => indices = None,
+        }
+        Some(subset)
+    })
+}
+
+impl ResultCheck {
+    /// Decide which holders committed to the actual result, using the
+    /// pulled shards. `None` if no reconstruction agrees with most
+    /// commitments.
+    pub fn judge(
+        &self,
+        shards: &HashMap<Sid, Shard>,
+        encoding: &ReedSolomonWrapper,
+    ) -> Option<Verdict> {
+        let sufficient: usize = encoding
+            .settings()
+            .data_shards_sufficient
+            .try_into()
+            .unwrap();
+        let mut shard_ids: Vec<_> = shards.keys().cloned().collect();
+        shard_ids.sort_by_key(|shard_id| shard_id.0);
+        let mut best: Option<(HashSet<Sid>, HashMap<Sid, Shard>)> = None;
+        for subset in subsets(&shard_ids, sufficient).take(MAX_RECONSTRUCTIONS) {
+            let subset = subset
+                .into_iter()
+                .map(|shard_id| (shard_id.clone(), shards[&shard_id].clone()))
+                .collect();
+            let Ok(encoded) = encoding
+                .decode(subset)
+                .and_then(|data| encoding.encode(data))
+            else {
+                continue;
+            };
+            let agreeing: HashSet<Sid> = self
+                .commitments
+                .iter()
+                .filter(|(shard_id, (_, checksum))| {
+                    encoded
+                        .get(shard_id)
+                        .is_some_and(|shard| &shard.checksum() == checksum)
+                })
+                .map(|(shard_id, _)| shard_id.clone())
+                .collect();
+            let is_unanimous = agreeing.len() == self.commitments.len();
+            if agreeing.len() > best.as_ref().map_or(0, |(agreeing, _)| agreeing.len()) {
+                best = Some((agreeing, encoded));
+            }
+            if is_unanimous {
+                break;
+            }
+        }
+        let (best, encoded) = best?;
+        if best.len() * 2 <= self.commitments.len() {
+            return None;
+        }
+        let mut disagreed: Vec<PeerId> = self
+            .commitments
+            .iter()
+            .filter(|(shard_id, _)| !best.contains(shard_id))
+            .map(|(_, (holder, _))| *holder)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut agreed: Vec<PeerId> = self
+            .commitments
+            .values()
+            .map(|(holder, _)| *holder)
+            .filter(|holder| !disagreed.contains(holder))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        agreed.sort();
+        disagreed.sort();
+        let checksums = encoded
+            .into_iter()
+            .map(|(shard_id, shard)| (shard_id, shard.checksum()))
+            .collect();
+        Some(Verdict {
+            agreed,
+            disagreed,
+            checksums,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use libp2p::PeerId;
+
+    use super::{ResultCheck, ResultCommitments, Verdict};
+    use crate::{
+        data_memory::recollection::Recollection,
+        encoding::{
+            reed_solomon::{ReedSolomonWrapper, Settings},
+            DataEncoding,
+        },
+        processor::ProgramIdentifier,
+        types::{Data, Hash, Shard, Sid, Vid},
+    };
+
+    #[test]
+    fn faulty_executor_is_outvoted() {
+        let encoding = ReedSolomonWrapper::new(Settings {
+            data_shards_total: 4,
+            data_shards_sufficient: 2,
+            max_shard_size: 5_000,
+        });
+        let program = ProgramIdentifier {
+            hash: Hash::from_array([0; 64]),
+            event_hash: Hash::from_array([1; 64]),
+        };
+        let mut holders: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        holders.sort();
+        let mut shards = encoding.encode(Data(vec![1, 2, 3, 4, 5, 6])).unwrap();
+        let checksums = shards
+            .iter()
+            .map(|(shard_id, shard)| (shard_id.clone(), shard.checksum()))
+            .collect();
+        // the last holder computed something else
+        shards.insert(Sid(3), Shard::new(6, vec![0, 0, 0]));
+
+        let mut commitments = ResultCommitments::default();
+        let mut complete = None;
+        for (i, holder) in holders.iter().enumerate() {
+            let shard_id = Sid(i.try_into().unwrap());
+            let checksum = shards[&shard_id].checksum();
+            assert!(complete.is_none());
+            complete = commitments.add(
+                program.clone(),
+                (Vid(1), shard_id),
+                *holder,
+                checksum,
+                holders.len(),
+            );
+        }
+        let check = ResultCheck {
+            data_id: Vid(1),
+            commitments: complete.unwrap(),
+            recollection: Recollection::new(holders.len()),
+        };
+        assert_eq!(
+            check.judge(&shards, &encoding),
+            Some(Verdict {
+                agreed: holders[..3].to_vec(),
+                disagreed: vec![holders[3]],
+                checksums,
+            })
+        );
+
+        // no majority with only two correct shards
+        let pulled: HashMap<_, _> = shards
+            .into_iter()
+            .filter(|(shard_id, _)| shard_id.0 != 0)
+            .collect();
+        let mut check = check;
+        check.commitments.get_mut(&Sid(0)).unwrap().1 = Hash::from_array([2; 64]);
+        assert_eq!(check.judge(&pulled, &encoding), None);
+    }
+}
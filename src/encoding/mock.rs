@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::types::{Data, Shard, Sid};
@@ -10,7 +11,7 @@ pub struct MockEncoding {
     settings: MockEncodingSettings,
 }
 
-#[derive(Error, Debug, Clone)]
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
 pub enum Error {
     #[error("Did not recieve enough shards to rebuild data")]
     NotEnoughShards,
@@ -18,18 +19,18 @@ pub enum Error {
 
 impl DataEncoding<Data, Sid, Shard, MockEncodingSettings, Error> for MockEncoding {
     fn encode(&self, data: Data) -> Result<HashMap<Sid, Shard>, Error> {
-        let shards: HashMap<Sid, Shard> = data
-            .0
-            .chunks_exact(4)
+        let shards_total: usize = self.settings.data_shards_total.try_into().unwrap();
+        let data_len: u64 = data.0.len().try_into().unwrap();
+        let shard_len = data.0.len().div_ceil(shards_total).max(1);
+        let mut bytes = data.0;
+        bytes.resize(shard_len * shards_total, 0);
+        let shards: HashMap<Sid, Shard> = bytes
+            .chunks_exact(shard_len)
             .enumerate()
             .map(|(i, slice)| {
                 (
                     Sid(i.try_into().unwrap()),
-                    Shard(
-                        slice
-                            .try_into()
-                            .expect("chunk_exact(4) must return slices of len 4"),
-                    ),
+                    Shard::new(data_len, slice.to_vec()),
                 )
             })
             .collect();
@@ -38,14 +39,21 @@ impl DataEncoding<Data, Sid, Shard, MockEncodingSettings, Error> for MockEncodin
     }
 
     fn decode(&self, shards: HashMap<Sid, Shard>) -> Result<Data, Error> {
+        if (shards.len() as u64) < self.settings.data_shards_total {
+            return Err(Error::NotEnoughShards);
+        }
         let mut shards: Vec<_> = shards.into_iter().collect();
         shards.sort_by_key(|(i, _)| i.0);
-        let kek: Vec<_> = shards
+        let data_len = shards
+            .first()
+            .map(|(_, shard)| shard.data_len())
+            .unwrap_or_default();
+        let mut kek: Vec<_> = shards
             .into_iter()
-            .flat_map(|(_, shard)| shard.0.into_iter())
+            .flat_map(|(_, shard)| shard.into_bytes().into_iter())
             .collect();
-        let arr = kek.try_into().map_err(|_| Error::NotEnoughShards)?;
-        Ok(Data(arr))
+        kek.truncate(data_len.try_into().unwrap());
+        Ok(Data(kek))
     }
 
     fn settings(&self) -> MockEncodingSettings {
@@ -1,37 +1,109 @@
 use std::iter::repeat;
 
 use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::types::{Data, Shard, Sid, DATA_SHARDS_COUNT, SHARD_BYTES_NUMBER};
+use crate::types::{Data, Shard, Sid};
 
 use super::DataEncoding;
 
 pub struct ReedSolomonWrapper {
     inner: ReedSolomon,
+    max_shard_size: u64,
 }
 
 impl ReedSolomonWrapper {
+    /// Panics on invalid settings, check them with [`Settings::validate`]
+    /// beforehand.
     pub fn new(encoding_settings: Settings) -> Self {
-        let data_shards = encoding_settings.data_shards_sufficient;
-        let total_shards = encoding_settings.data_shards_total;
-        let inner = ReedSolomon::new(
-            data_shards.try_into().unwrap(),
-            (total_shards - data_shards).try_into().unwrap(),
-        )
-        .unwrap();
-        Self { inner }
+        let inner = encoding_settings
+            .codec()
+            .expect("encoding settings must be validated beforehand");
+        Self {
+            inner,
+            max_shard_size: encoding_settings.max_shard_size,
+        }
+    }
+}
+
+impl ReedSolomonWrapper {
+    /// Rebuild a single (lost) shard from at least `data_shards_sufficient`
+    /// other shards of the same data.
+    pub fn rebuild(
+        &self,
+        shards: std::collections::HashMap<Sid, Shard>,
+        missing: &Sid,
+    ) -> Result<Shard, Error> {
+        let missing_index: usize = missing.0.try_into().unwrap();
+        if missing_index >= self.inner.total_shard_count() {
+            return Err(Error::WrongShardId);
+        }
+        let (mut shards_vec, data_len) = self.positioned(shards)?;
+        self.inner.reconstruct(&mut shards_vec)?;
+        let bytes = shards_vec
+            .swap_remove(missing_index)
+            .expect("`reconstruct` fills all shards on success");
+        Ok(Shard::new(data_len.unwrap_or_default(), bytes))
+    }
+
+    /// Place shards at their positions in the full list of shards; also
+    /// returns the original data length the shards agree on.
+    #[allow(clippy::type_complexity)]
+    fn positioned(
+        &self,
+        shards: std::collections::HashMap<Sid, Shard>,
+    ) -> Result<(Vec<Option<Vec<u8>>>, Option<u64>), Error> {
+        let mut shards_vec: Vec<Option<Vec<u8>>> =
+            repeat(None).take(self.inner.total_shard_count()).collect();
+        let mut data_len = None;
+        for (index, shard) in shards {
+            let vec_index: usize = index.0.try_into().unwrap();
+            let shard_position = shards_vec.get_mut(vec_index).ok_or(Error::WrongShardId)?;
+            match data_len {
+                None => data_len = Some(shard.data_len()),
+                Some(len) if len != shard.data_len() => return Err(Error::DataLengthMismatch),
+                Some(_) => (),
+            }
+            *shard_position = Some(shard.into_bytes());
+        }
+        Ok((shards_vec, data_len))
     }
 }
 
-// for returning only, the actual settings are stored in `ReedSolomon`
-#[derive(Clone)]
+/// Encoding parameters, must be the same on all peers of the cluster
+/// (they are agreed on in `InitializeStorage` transaction).
+#[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
 pub struct Settings {
     pub data_shards_total: u64,
     pub data_shards_sufficient: u64,
+    /// Upper limit on the length of a single shard in bytes, thus data
+    /// can be up to `data_shards_sufficient * max_shard_size` bytes long
+    pub max_shard_size: u64,
 }
 
-#[derive(Error, Debug)]
+impl Settings {
+    /// Check that the encoding can be used with these settings
+    pub fn validate(&self) -> Result<(), Error> {
+        self.codec().map(|_| ())
+    }
+
+    fn codec(&self) -> Result<ReedSolomon, Error> {
+        let parity_shards = self
+            .data_shards_total
+            .checked_sub(self.data_shards_sufficient)
+            .ok_or(Error::MoreSufficientThanTotal)?;
+        let to_usize = |count: u64| {
+            usize::try_from(count).map_err(|_| reed_solomon_erasure::Error::TooManyShards)
+        };
+        Ok(ReedSolomon::new(
+            to_usize(self.data_shards_sufficient)?,
+            to_usize(parity_shards)?,
+        )?)
+    }
+}
+
+#[derive(Error, Debug, Clone)]
 pub enum Error {
     #[error(transparent)]
     ReedSolomon(#[from] reed_solomon_erasure::Error),
@@ -40,28 +112,34 @@ pub enum Error {
     expected index of shard in vector of all shards"
     )]
     WrongShardId,
+    #[error("Number of sufficient shards exceeds the total number of shards")]
+    MoreSufficientThanTotal,
+    #[error("Provided shards disagree on the length of the original data")]
+    DataLengthMismatch,
+    #[error("Data of {len} bytes does not fit into shards of at most {max_shard_size} bytes")]
+    DataTooLarge { len: u64, max_shard_size: u64 },
 }
 
 impl DataEncoding<Data, Sid, Shard, Settings, Error> for ReedSolomonWrapper {
     fn encode(&self, data: Data) -> Result<std::collections::HashMap<Sid, Shard>, Error> {
-        let shard_byte_number = SHARD_BYTES_NUMBER
-            .try_into()
-            .expect("unsupported encoding settings by the system");
-        let mut shards: Vec<Shard> =
-            data.0
-                .chunks(shard_byte_number)
-                .map(|slice| {
-                    Shard(slice.try_into().expect(
-                        "number of bytes in `Data` must be divisible by SHARD_BYTES_NUMBER",
-                    ))
-                })
-                .collect();
-        assert_eq!(
-            u64::try_from(shards.len()).unwrap(),
-            DATA_SHARDS_COUNT,
-            "Data must hace SHARD_BYTES_NUMBER*DATA_SHARDS_COUNT bytes"
-        );
-        let parity_shard = Shard([0; SHARD_BYTES_NUMBER as usize]);
+        let data_shards = self.inner.data_shard_count();
+        let data_len: u64 = data.0.len().try_into().unwrap();
+        // all shards must be of equal (non-zero) length, so the data is
+        // padded with zeroes up to the nearest multiple of shards count
+        let shard_len = data.0.len().div_ceil(data_shards).max(1);
+        if u64::try_from(shard_len).unwrap() > self.max_shard_size {
+            return Err(Error::DataTooLarge {
+                len: data_len,
+                max_shard_size: self.max_shard_size,
+            });
+        }
+        let mut bytes = data.0;
+        bytes.resize(shard_len * data_shards, 0);
+        let mut shards: Vec<Shard> = bytes
+            .chunks_exact(shard_len)
+            .map(|slice| Shard::new(data_len, slice.to_vec()))
+            .collect();
+        let parity_shard = Shard::new(data_len, vec![0; shard_len]);
         let parity_shards = repeat(parity_shard).take(self.inner.parity_shard_count());
         shards.extend(parity_shards);
         self.inner.encode(&mut shards)?;
@@ -74,53 +152,70 @@ impl DataEncoding<Data, Sid, Shard, Settings, Error> for ReedSolomonWrapper {
     }
 
     fn decode(&self, shards: std::collections::HashMap<Sid, Shard>) -> Result<Data, Error> {
-        let mut shards_vec: Vec<Option<Vec<u8>>> =
-            repeat(None).take(self.inner.total_shard_count()).collect();
-        for (index, shard) in shards {
-            let vec_index: usize = index.0.try_into().unwrap();
-            let shard_position = shards_vec.get_mut(vec_index).ok_or(Error::WrongShardId)?;
-            *shard_position = Some(shard.0.into());
-        }
-        self.inner.reconstruct(&mut shards_vec)?;
-        let data: Vec<_> = shards_vec
+        let (mut shards_vec, data_len) = self.positioned(shards)?;
+        self.inner.reconstruct_data(&mut shards_vec)?;
+        let mut data: Vec<_> = shards_vec
             .into_iter()
-            .map(|option| option.unwrap().into_iter())
-            .take(DATA_SHARDS_COUNT.try_into().unwrap())
-            .flatten()
+            .take(self.inner.data_shard_count())
+            .flat_map(|option| option.unwrap().into_iter())
             .collect();
-        Ok(Data(data.try_into().unwrap()))
+        // `reconstruct_data` fails on empty input, so `data_len` is known here
+        data.truncate(data_len.unwrap_or_default().try_into().unwrap());
+        Ok(Data(data))
     }
 
     fn settings(&self) -> Settings {
         Settings {
             data_shards_total: self.inner.total_shard_count().try_into().unwrap(),
             data_shards_sufficient: self.inner.data_shard_count().try_into().unwrap(),
+            max_shard_size: self.max_shard_size,
         }
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use libp2p::PeerId;
-
-//     use crate::{
-//         encoding::{DataEncoding, MockEncodingSettings, reed_solomon::{ReedSolomonWrapper, Settings}},
-//         types::Sid,
-//     };
-
-//     #[test]
-//     fn it_works() {
-//         let encoding = ReedSolomonWrapper::new(Settings {
-//             data_shards_total: 3,
-//             data_shards_sufficient: 2,
-//         });
-//         let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
-//         let encoded = encoding.encode(data).unwrap();
-//         assert_eq!(encoded.len(), 3);
-//         assert_eq!(encoded.get(&Sid(0)).unwrap(), &[1, 2, 3, 4]);
-//         assert_eq!(encoded.get(&Sid(1)).unwrap(), &[5, 6, 7, 8]);
-//         assert_eq!(encoded.get(&Sid(2)).unwrap(), &[9, 10, 11, 12]);
-//         let decoded = encoding.decode(encoded).unwrap();
-//         assert_eq!(data, decoded)
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use crate::{
+        encoding::{
+            reed_solomon::{ReedSolomonWrapper, Settings},
+            DataEncoding,
+        },
+        types::{Data, Sid},
+    };
+
+    #[test]
+    fn it_works() {
+        let encoding = ReedSolomonWrapper::new(Settings {
+            data_shards_total: 3,
+            data_shards_sufficient: 2,
+            max_shard_size: 5_000,
+        });
+        for len in [0, 1, 3, 4, 10_000] {
+            let data = Data((0..len).map(|i| (i % 251) as u8).collect());
+            let mut encoded = encoding.encode(data.clone()).unwrap();
+            assert_eq!(encoded.len(), 3);
+            let lost = encoded.remove(&Sid(0)).unwrap();
+            let mut surviving = encoded.clone();
+            surviving.remove(&Sid(1));
+            assert!(encoding.rebuild(surviving, &Sid(0)).is_err());
+            assert_eq!(encoding.rebuild(encoded.clone(), &Sid(0)).unwrap(), lost);
+            let decoded = encoding.decode(encoded).unwrap();
+            assert_eq!(data, decoded)
+        }
+        assert!(encoding.encode(Data(vec![0; 10_001])).is_err());
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let settings = |data_shards_total, data_shards_sufficient| Settings {
+            data_shards_total,
+            data_shards_sufficient,
+            max_shard_size: 1,
+        };
+        assert!(settings(3, 2).validate().is_ok());
+        assert!(settings(2, 3).validate().is_err());
+        assert!(settings(2, 0).validate().is_err());
+        assert!(settings(2, 2).validate().is_err());
+        assert!(settings(257, 2).validate().is_err());
+    }
+}
@@ -1,14 +1,21 @@
-use std::collections::{hash_map, HashMap, HashSet};
+use std::collections::{hash_map, HashMap, HashSet, VecDeque};
+use std::path::Path;
 
 use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
 use crate::module::ModuleChannelServer;
+use crate::processor::single_threaded::Failure;
+use crate::processor::template::Template;
 use crate::processor::{Instructions, Program, ProgramIdentifier};
-use crate::types::Vid;
+use crate::types::{Hash, Sid, Vid};
 
+pub mod records;
 mod traits;
 
+use records::{Record, RecordLog};
+
 pub struct Module;
 
 impl crate::module::Module for Module {
@@ -19,54 +26,288 @@ impl crate::module::Module for Module {
 
 #[derive(Debug, Clone)]
 pub enum OutEvent {
-    /// Next program for execution is available.
+    /// Next program for execution is available. Sent once the previous one
+    /// is executed by this peer.
     NextProgram(Program),
     /// Enough peers completed the program; we can safely consider it completed.
     FinishedExecution(ProgramIdentifier),
-    /// The peer completed program with this identifier and its shards for
-    /// provided data ids are updated during the execution
+    /// The peer completed program with this identifier and provided shards
+    /// are updated during the execution
     PeerShardsActualized {
         program_id: ProgramIdentifier,
         peer: PeerId,
-        updated_data_ids: Vec<Vid>,
+        updated_shards: Vec<((Vid, Sid), Hash)>,
+    },
+    /// Programs matching the requested prefix, in the order of finalization
+    Programs(Vec<(ProgramIdentifier, ProgramStatus)>),
+    /// The program can be cancelled, need to announce it in consensus
+    CancelAllowed(ProgramIdentifier),
+    /// The program can't be cancelled by the user
+    CancelRejected { prefix: String, reason: String },
+    /// Registered templates whose hex hash starts with the requested prefix
+    Templates(Vec<(Hash, Template)>),
+    /// Program the user wants to run from the template, needs to be
+    /// validated and announced in consensus
+    TemplateInstantiated {
+        template_hash: Hash,
+        bindings: Vec<Vid>,
+        instructions: Instructions,
     },
+    /// The template can't be invoked with these bindings
+    TemplateRejected { prefix: String, reason: String },
+    /// Current state of the memory
+    Snapshot(InstructionSnapshot),
 }
 
 #[derive(Debug, Clone)]
 pub enum InEvent {
     /// New program has been finalized
-    FinalizedProgram(Program),
+    FinalizedProgram { program: Program, author: PeerId },
     /// Track completion of programs (`k` found - success)
     ExecutedProgram {
         peer: PeerId,
         program_id: ProgramIdentifier,
+        /// Shards the peer computed and stores (with their checksums)
+        updated_shards: Vec<((Vid, Sid), Hash)>,
+    },
+    /// This peer finished executing the program
+    ExecutedLocally {
+        program_id: ProgramIdentifier,
+        failures: Vec<Failure>,
+    },
+    /// Finalized cancellation of the program
+    Cancelled {
+        program_id: ProgramIdentifier,
+        by: PeerId,
     },
+    /// List programs whose [hex identifier](ProgramIdentifier::to_hex) starts
+    /// with the prefix
+    ListPrograms { prefix: String },
+    /// The user wants to cancel the program with this prefix of the identifier
+    CancelRequest { prefix: String },
+    /// Template was registered in consensus
+    FinalizedTemplate(Template),
+    /// Invocation of the template was finalized, the instantiated program is
+    /// queued like the ones from `Execute`
+    FinalizedTemplateCall {
+        template_hash: Hash,
+        bindings: Vec<Vid>,
+        event_hash: Hash,
+        author: PeerId,
+    },
+    /// List templates whose hex hash starts with the prefix
+    ListTemplates { prefix: String },
+    /// The user wants to invoke the template with this hash prefix
+    InstantiateTemplate { prefix: String, bindings: Vec<Vid> },
+    /// Take a snapshot of the current state
+    TakeSnapshot,
+    /// Start with programs and templates received from another peer
+    InstallSnapshot(InstructionSnapshot),
+}
+
+/// Known programs and templates, so that a peer joining later doesn't
+/// need to see all transactions that scheduled them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstructionSnapshot {
+    /// In the order of finalization
+    programs: Vec<ProgramSnapshot>,
+    templates: Vec<Template>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ProgramSnapshot {
+    id: ProgramIdentifier,
+    state: ProgramState,
+    author: PeerId,
+    peers_finished: Vec<PeerId>,
+    affected_data_ids: Vec<Vid>,
+    /// The program itself, if it's not executed yet
+    queued: Option<Program>,
+}
+
+/// What happens to the program on this peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ProgramState {
+    /// Waiting for programs finalized earlier to be executed
+    Queued,
+    Executing,
+    /// All instructions were executed
+    Executed,
+    /// Some instructions could not be executed
+    Failed,
+    /// Cancelled by the author before anyone announced its execution
+    Cancelled,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgramStatus {
+    pub state: ProgramState,
+    /// Peers that announced execution of the program
+    pub confirmations: usize,
+    /// Confirmations needed to consider the program finished
+    pub threshold: usize,
+    /// Errors of execution on this peer, one per instruction
+    pub failures: Vec<Failure>,
+}
+
+/// Whole state of the memory, written to the log on compaction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PersistedState {
+    /// In the order of finalization
+    programs: Vec<(ProgramIdentifier, ProgramMetadata)>,
+    queue: Vec<Program>,
+    executing: Option<Program>,
+    templates: Vec<Template>,
 }
 
 /// Async data memory/data manager. Intended to communicate
 /// with behaviour through corresponding [`ModuleChannelServer`] (the
 /// behaviour thus uses [`ModuleChannelClient`](crate::module::ModuleChannelServer)).
 ///
-/// Stores scheduled programs and tracks their execution
+/// Stores scheduled programs and tracks their execution. Programs are given
+/// to the processor one by one, so the ones not started yet can be cancelled.
 ///
 /// Use [`Self::run()`] to operate.
 pub struct InstructionMemory {
-    currently_executed: HashMap<ProgramIdentifier, ProgramMetadata>,
+    programs: HashMap<ProgramIdentifier, ProgramMetadata>,
+    /// All known programs in the order of finalization
+    finalized: Vec<ProgramIdentifier>,
+    queue: VecDeque<Program>,
+    executing: Option<Program>,
+    templates: HashMap<Hash, Template>,
+    local_id: PeerId,
     accept_threshold: usize,
+    /// Changes are written there, if the memory is persisted
+    log: Option<RecordLog>,
 }
 
 impl InstructionMemory {
-    pub fn new(execution_confirmations_threshold: usize) -> Self {
+    pub fn new(local_id: PeerId, execution_confirmations_threshold: usize) -> Self {
         Self {
-            currently_executed: HashMap::new(),
+            programs: HashMap::new(),
+            finalized: Vec::new(),
+            queue: VecDeque::new(),
+            executing: None,
+            templates: HashMap::new(),
+            local_id,
             accept_threshold: execution_confirmations_threshold,
+            log: None,
+        }
+    }
+
+    /// Restore the memory from the log at `path` and keep writing changes
+    /// there
+    pub fn open(
+        local_id: PeerId,
+        execution_confirmations_threshold: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, records::Error> {
+        let (log, records) = RecordLog::open(path)?;
+        let mut memory = Self::new(local_id, execution_confirmations_threshold);
+        let replayed = records.len();
+        for record in records {
+            memory.apply(record);
+        }
+        info!(
+            "Restored {} program(s) and {} template(s) from {} record(s)",
+            memory.finalized.len(),
+            memory.templates.len(),
+            replayed
+        );
+        memory.log = Some(log);
+        if memory.log.as_ref().is_some_and(RecordLog::needs_compaction) {
+            memory.compact();
+        }
+        Ok(memory)
+    }
+
+    /// Repeat the change written to the log
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Finalized { program, author } => self.notify_finalized(program, author),
+            Record::Template(template) => self.notify_template(template),
+            Record::TemplateCall {
+                template_hash,
+                bindings,
+                event_hash,
+                author,
+            } => self.notify_template_call(&template_hash, &bindings, event_hash, author),
+            Record::Started => {
+                self.next_program();
+            }
+            Record::ExecutedLocally {
+                program_id,
+                failures,
+            } => self.notify_executed_locally(&program_id, failures),
+            Record::ExecutedBy { peer, program_id } => {
+                self.notify_executed(peer, program_id);
+            }
+            Record::Cancelled { program_id, by } => self.notify_cancelled(&program_id, by),
+            Record::Installed(snapshot) => self.install_snapshot(snapshot),
+            Record::State(state) => self.restore(state),
+        }
+    }
+
+    /// Write the change to the log, if the memory is persisted, and apply it
+    fn record(&mut self, record: Record) {
+        self.persist(&record);
+        self.apply(record);
+    }
+
+    fn persist(&mut self, record: &Record) {
+        let Some(log) = &mut self.log else {
+            return;
+        };
+        if let Err(e) = log.append(record) {
+            error!(
+                "could not write to the instruction log, the change will be lost on restart: {}",
+                e
+            );
+        }
+        if log.needs_compaction() {
+            self.compact();
+        }
+    }
+
+    fn compact(&mut self) {
+        let state = self.persisted_state();
+        if let Some(Err(e)) = self.log.as_mut().map(|log| log.compact(state)) {
+            error!("could not compact the instruction log: {}", e);
+        }
+    }
+
+    fn persisted_state(&self) -> PersistedState {
+        PersistedState {
+            programs: self
+                .finalized
+                .iter()
+                .map(|id| (id.clone(), self.programs[id].clone()))
+                .collect(),
+            queue: self.queue.iter().cloned().collect(),
+            executing: self.executing.clone(),
+            templates: self.templates.values().cloned().collect(),
+        }
+    }
+
+    fn restore(&mut self, state: PersistedState) {
+        self.programs.clear();
+        self.finalized.clear();
+        for (id, metadata) in state.programs {
+            self.programs.insert(id.clone(), metadata);
+            self.finalized.push(id);
+        }
+        self.queue = state.queue.into();
+        self.executing = state.executing;
+        self.templates.clear();
+        for template in state.templates {
+            self.notify_template(template);
         }
     }
 
     /// Returns `true` if this notification resulted in `Finished`
     /// execution state.
     fn notify_executed(&mut self, who: PeerId, program: ProgramIdentifier) -> bool {
-        let entry = self.currently_executed.entry(program.clone());
+        let entry = self.programs.entry(program.clone());
         let hash_map::Entry::Occupied(mut metadata) = entry else {
             warn!(
                 "Received notification on execution, but the program is unknown. \
@@ -77,41 +318,295 @@ impl InstructionMemory {
             return false;
         };
         let metadata = metadata.get_mut();
-        match &mut metadata.state {
-            ExecutionState::Executing { peers_finished } => {
-                peers_finished.insert(who);
-                if peers_finished.len() >= self.accept_threshold {
-                    debug!(
-                        "Program {:?} is finished by {} peers. It is enough for us.",
-                        program,
-                        peers_finished.len()
-                    );
-                    metadata.state = ExecutionState::Finished;
-                    true
-                } else {
-                    false
-                }
-            }
-            // just ignore, we already decided it
-            ExecutionState::Finished => false,
+        if metadata.state == ProgramState::Cancelled {
+            warn!(
+                "{:?} announced execution of cancelled program {:?}",
+                who, program
+            );
+            return false;
         }
+        let was_finished = metadata.is_finished(self.accept_threshold);
+        metadata.peers_finished.insert(who);
+        if was_finished || !metadata.is_finished(self.accept_threshold) {
+            return false;
+        }
+        debug!(
+            "Program {:?} is finished by {} peers. It is enough for us.",
+            program,
+            metadata.peers_finished.len()
+        );
+        true
     }
 
-    fn notify_finalized(&mut self, program: ProgramIdentifier, instructions: &Instructions) {
-        match self.currently_executed.entry(program) {
+    fn notify_finalized(&mut self, program: Program, author: PeerId) {
+        match self.programs.entry(program.identifier().clone()) {
             hash_map::Entry::Occupied(_) => warn!(
                 "Finalized program is already known \
                 Realistically can be if someone submitted 2 exactly the same programs"
             ),
             hash_map::Entry::Vacant(vacant) => {
-                vacant.insert(ProgramMetadata::new_started_executing(instructions));
+                vacant.insert(ProgramMetadata::new_queued(program.instructions(), author));
+                self.finalized.push(program.identifier().clone());
+                self.queue.push_back(program);
+            }
+        }
+    }
+
+    fn notify_executed_locally(&mut self, program: &ProgramIdentifier, failures: Vec<Failure>) {
+        if self
+            .executing
+            .as_ref()
+            .is_some_and(|executing| executing.identifier() == program)
+        {
+            self.executing = None;
+        }
+        let Some(metadata) = self.programs.get_mut(program) else {
+            warn!("Executed unknown program {:?}", program);
+            return;
+        };
+        if metadata.state == ProgramState::Cancelled {
+            info!("Program {:?} was cancelled during execution", program);
+            return;
+        }
+        for failure in failures {
+            let is_new = metadata
+                .failures
+                .iter()
+                .all(|known| known.instruction != failure.instruction);
+            if is_new {
+                metadata.failures.push(failure);
+            }
+        }
+        metadata.state = if metadata.failures.is_empty() {
+            ProgramState::Executed
+        } else {
+            ProgramState::Failed
+        };
+    }
+
+    fn notify_cancelled(&mut self, program: &ProgramIdentifier, by: PeerId) {
+        let Some(metadata) = self.programs.get_mut(program) else {
+            warn!("Cancelled unknown program {:?}", program);
+            return;
+        };
+        if metadata.author != by {
+            warn!(
+                "{:?} tried to cancel program {:?} of another peer, ignoring",
+                by, program
+            );
+            return;
+        }
+        // the validator accepts it only if no execution was finalized before,
+        // so it's cancelled even if this peer started it
+        info!("Program {:?} is cancelled", program);
+        metadata.state = ProgramState::Cancelled;
+        self.queue.retain(|queued| queued.identifier() != program);
+    }
+
+    fn notify_template(&mut self, template: Template) {
+        match template.hash() {
+            Ok(hash) => {
+                debug!("Registered template {:?}", hash);
+                self.templates.insert(hash, template);
+            }
+            Err(e) => error!("could not compute hash of a template: {}", e),
+        }
+    }
+
+    /// Queue program instantiated from the registered template
+    fn notify_template_call(
+        &mut self,
+        template_hash: &Hash,
+        bindings: &[Vid],
+        event_hash: Hash,
+        author: PeerId,
+    ) {
+        let Some(template) = self.templates.get(template_hash) else {
+            warn!(
+                "{:?} invoked unknown template {:?}, ignoring",
+                author, template_hash
+            );
+            return;
+        };
+        match Program::from_template(template, bindings, event_hash) {
+            Ok(program) => self.notify_finalized(program, author),
+            Err(e) => warn!(
+                "{:?} invoked template {:?} incorrectly, ignoring: {}",
+                author, template_hash, e
+            ),
+        }
+    }
+
+    fn find_templates(&self, prefix: &str) -> Vec<(Hash, Template)> {
+        let mut found: Vec<_> = self
+            .templates
+            .iter()
+            .filter(|(hash, _)| hash.to_hex().starts_with(prefix))
+            .map(|(hash, template)| (hash.clone(), template.clone()))
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+
+    /// Instructions the user gets by invoking the template
+    fn resolve_template(
+        &self,
+        prefix: &str,
+        bindings: &[Vid],
+    ) -> Result<(Hash, Instructions), String> {
+        let mut found = self.find_templates(prefix);
+        if found.len() > 1 {
+            return Err(format!(
+                "{} templates match, specify more digits",
+                found.len()
+            ));
+        }
+        let Some((hash, template)) = found.pop() else {
+            return Err("no such template".to_owned());
+        };
+        let instructions = template.instantiate(bindings).map_err(|e| e.to_string())?;
+        Ok((hash, instructions))
+    }
+
+    fn snapshot(&self) -> InstructionSnapshot {
+        let programs = self
+            .finalized
+            .iter()
+            .map(|id| {
+                let metadata = &self.programs[id];
+                let queued = self
+                    .queue
+                    .iter()
+                    .chain(self.executing.as_ref())
+                    .find(|program| program.identifier() == id)
+                    .cloned();
+                ProgramSnapshot {
+                    id: id.clone(),
+                    state: metadata.state.clone(),
+                    author: metadata.author,
+                    peers_finished: metadata.peers_finished.iter().cloned().collect(),
+                    affected_data_ids: metadata.affected_data_ids.clone(),
+                    queued,
+                }
+            })
+            .collect();
+        InstructionSnapshot {
+            programs,
+            templates: self.templates.values().cloned().collect(),
+        }
+    }
+
+    /// Continue from the state of another peer. Programs that were
+    /// started there are queued again, since the shards this peer takes
+    /// later may not be updated by them yet. Programs executed there are
+    /// considered executed.
+    /// Replace known programs and templates with the ones of the snapshot.
+    /// The program given to the processor is not queued again.
+    fn install_snapshot(&mut self, snapshot: InstructionSnapshot) {
+        self.programs.clear();
+        self.finalized.clear();
+        self.queue.clear();
+        self.templates.clear();
+        let executing = self
+            .executing
+            .as_ref()
+            .map(|program| program.identifier().clone());
+        for program in snapshot.programs {
+            let state = match (program.state, &program.queued) {
+                _ if executing.as_ref() == Some(&program.id) => ProgramState::Executing,
+                (ProgramState::Queued | ProgramState::Executing, queued) => {
+                    if queued.is_none() {
+                        warn!(
+                            "Snapshot misses program {:?} that is not executed yet, \
+                            waiting for confirmations of other peers",
+                            program.id
+                        );
+                    }
+                    ProgramState::Queued
+                }
+                (ProgramState::Cancelled, _) => ProgramState::Cancelled,
+                (ProgramState::Executed | ProgramState::Failed, _) => ProgramState::Executed,
+            };
+            if let Some(queued) = program.queued.filter(|_| state == ProgramState::Queued) {
+                self.queue.push_back(queued);
             }
+            self.programs.insert(
+                program.id.clone(),
+                ProgramMetadata {
+                    state,
+                    author: program.author,
+                    peers_finished: program.peers_finished.into_iter().collect(),
+                    failures: Vec::new(),
+                    affected_data_ids: program.affected_data_ids,
+                },
+            );
+            self.finalized.push(program.id);
+        }
+        for template in snapshot.templates {
+            self.notify_template(template);
+        }
+        info!(
+            "Installed snapshot with {} program(s), {} queued",
+            self.finalized.len(),
+            self.queue.len()
+        );
+    }
+
+    /// Program to execute next, if the previous one is finished
+    fn next_program(&mut self) -> Option<Program> {
+        if self.executing.is_some() {
+            return None;
+        }
+        let program = self.queue.pop_front()?;
+        if let Some(metadata) = self.programs.get_mut(program.identifier()) {
+            metadata.state = ProgramState::Executing;
         }
+        self.executing = Some(program.clone());
+        Some(program)
+    }
+
+    fn find(&self, prefix: &str) -> Vec<(ProgramIdentifier, ProgramStatus)> {
+        self.finalized
+            .iter()
+            .filter(|program_id| program_id.to_hex().starts_with(prefix))
+            .map(|program_id| {
+                let status = self.programs[program_id].status(self.accept_threshold);
+                (program_id.clone(), status)
+            })
+            .collect()
+    }
+
+    /// Check that the user can ask to cancel the program
+    fn resolve_cancel(&self, prefix: &str) -> Result<ProgramIdentifier, String> {
+        let mut found = self.find(prefix);
+        if found.len() > 1 {
+            return Err(format!(
+                "{} programs match, specify more digits",
+                found.len()
+            ));
+        }
+        let Some((program_id, status)) = found.pop() else {
+            return Err("no such program".to_owned());
+        };
+        if self.programs[&program_id].author != self.local_id {
+            return Err("the program is scheduled by another peer".to_owned());
+        }
+        if status.state == ProgramState::Cancelled {
+            return Err("the program is cancelled already".to_owned());
+        }
+        if status.confirmations > 0 {
+            return Err("execution of the program is announced already".to_owned());
+        }
+        Ok(program_id)
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct ProgramMetadata {
-    state: ExecutionState,
+    state: ProgramState,
+    author: PeerId,
+    peers_finished: HashSet<PeerId>,
+    failures: Vec<Failure>,
     affected_data_ids: Vec<Vid>,
 }
 
@@ -124,30 +619,64 @@ impl ProgramMetadata {
         affected_ids.into_iter().collect()
     }
 
-    fn new_started_executing(instrucitons: &Instructions) -> Self {
+    fn new_queued(instrucitons: &Instructions, author: PeerId) -> Self {
         let affected_data_ids = Self::compute_affected_data_ids(instrucitons);
         Self {
-            state: Default::default(),
+            state: ProgramState::Queued,
+            author,
+            peers_finished: HashSet::new(),
+            failures: Vec::new(),
             affected_data_ids,
         }
     }
-}
 
-enum ExecutionState {
-    Executing { peers_finished: HashSet<PeerId> },
-    Finished,
-}
+    fn is_finished(&self, threshold: usize) -> bool {
+        self.peers_finished.len() >= threshold
+    }
 
-impl Default for ExecutionState {
-    fn default() -> Self {
-        Self::Executing {
-            peers_finished: Default::default(),
+    fn status(&self, threshold: usize) -> ProgramStatus {
+        ProgramStatus {
+            state: self.state.clone(),
+            confirmations: self.peers_finished.len(),
+            threshold,
+            failures: self.failures.clone(),
         }
     }
 }
 
 impl InstructionMemory {
+    /// Give the next program to the processor if it's idle
+    async fn start_next(&mut self, connection: &mut ModuleChannelServer<Module>) -> Result<(), ()> {
+        let Some(program) = self.next_program() else {
+            return Ok(());
+        };
+        self.persist(&Record::Started);
+        debug!("Starting execution of program {:?}", program.identifier());
+        connection
+            .output
+            .send(OutEvent::NextProgram(program))
+            .await
+            .map_err(|_| ())
+    }
+
     pub async fn run(mut self, mut connection: ModuleChannelServer<Module>) {
+        // the program started before restart is given again, the processor
+        // continues it from its checkpoint
+        let resumed = match self.executing.clone() {
+            Some(program) => {
+                info!("Resuming execution of program {:?}", program.identifier());
+                connection
+                    .output
+                    .send(OutEvent::NextProgram(program))
+                    .await
+                    .map_err(|_| ())
+            }
+            None => self.start_next(&mut connection).await,
+        };
+        if resumed.is_err() {
+            error!("`connection.output` is closed, shutting down instruction memory");
+            return;
+        }
         loop {
             tokio::select! {
                 in_event = connection.input.recv() => {
@@ -156,14 +685,88 @@ impl InstructionMemory {
                         return;
                     };
                     match in_event {
-                        InEvent::FinalizedProgram(program) => {
-                            self.notify_finalized(program.identifier().clone(), program.instructions());
-                            if (connection.output.send(OutEvent::NextProgram(program)).await).is_err() {
+                        InEvent::FinalizedProgram { program, author } => {
+                            self.record(Record::Finalized { program, author });
+                            if self.start_next(&mut connection).await.is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::FinalizedTemplate(template) => self.record(Record::Template(template)),
+                        InEvent::FinalizedTemplateCall { template_hash, bindings, event_hash, author } => {
+                            self.record(Record::TemplateCall { template_hash, bindings, event_hash, author });
+                            if self.start_next(&mut connection).await.is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::ListTemplates { prefix } => {
+                            let templates = self.find_templates(&prefix);
+                            if (connection.output.send(OutEvent::Templates(templates)).await).is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::InstantiateTemplate { prefix, bindings } => {
+                            let event = match self.resolve_template(&prefix, &bindings) {
+                                Ok((template_hash, instructions)) => OutEvent::TemplateInstantiated {
+                                    template_hash,
+                                    bindings,
+                                    instructions,
+                                },
+                                Err(reason) => OutEvent::TemplateRejected { prefix, reason },
+                            };
+                            if (connection.output.send(event).await).is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::ExecutedLocally { program_id, failures } => {
+                            self.record(Record::ExecutedLocally { program_id, failures });
+                            if self.start_next(&mut connection).await.is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::Cancelled { program_id, by } => {
+                            self.record(Record::Cancelled { program_id, by });
+                        }
+                        InEvent::TakeSnapshot => {
+                            if (connection.output.send(OutEvent::Snapshot(self.snapshot())).await).is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::InstallSnapshot(snapshot) => {
+                            self.record(Record::Installed(snapshot));
+                            if self.start_next(&mut connection).await.is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::ListPrograms { prefix } => {
+                            let programs = self.find(&prefix);
+                            if (connection.output.send(OutEvent::Programs(programs)).await).is_err() {
+                                error!("`connection.output` is closed, shutting down instruction memory");
+                                return;
+                            }
+                        }
+                        InEvent::CancelRequest { prefix } => {
+                            let event = match self.resolve_cancel(&prefix) {
+                                Ok(program_id) => OutEvent::CancelAllowed(program_id),
+                                Err(reason) => OutEvent::CancelRejected { prefix, reason },
+                            };
+                            if (connection.output.send(event).await).is_err() {
                                 error!("`connection.output` is closed, shuttung down instruction memory");
                                 return;
                             }
                         }
-                        InEvent::ExecutedProgram { peer, program_id } => {
+                        InEvent::ExecutedProgram {
+                            peer,
+                            program_id,
+                            updated_shards,
+                        } => {
+                            self.persist(&Record::ExecutedBy { peer, program_id: program_id.clone() });
                             if self.notify_executed(peer, program_id.clone())
                                 && (connection
                                     .output
@@ -174,14 +777,24 @@ impl InstructionMemory {
                                 error!("`connection.output` is closed, shuttung down instruction memory");
                                 return;
                             }
-                            if let Some(metadata) = self.currently_executed.get(&program_id) {
-                                let updated_data_ids = metadata.affected_data_ids.clone();
+                            if let Some(metadata) = self
+                                .programs
+                                .get(&program_id)
+                                .filter(|metadata| metadata.state != ProgramState::Cancelled)
+                            {
+                                // only results of the program could be updated
+                                let updated_shards = updated_shards
+                                    .into_iter()
+                                    .filter(|((data_id, _), _)| {
+                                        metadata.affected_data_ids.contains(data_id)
+                                    })
+                                    .collect();
                                 if (connection
                                     .output
                                     .send(OutEvent::PeerShardsActualized {
                                         program_id,
                                         peer,
-                                        updated_data_ids,
+                                        updated_shards,
                                     })
                                     .await)
                                     .is_err()
@@ -201,3 +814,152 @@ impl InstructionMemory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{records::Record, InstructionMemory, ProgramState};
+    use crate::{
+        processor::{template::Template, Instruction, Program},
+        types::{Hash, Vid},
+    };
+
+    #[test]
+    fn queued_programs_are_cancelled() {
+        let author = PeerId::random();
+        let mut memory = InstructionMemory::new(author, 2);
+        let programs: Vec<_> = (0..3)
+            .map(|i| {
+                let instructions = vec![Instruction::plus(Vid(1), Vid(2), Vid(3))];
+                Program::new(instructions, Hash::from_array([i; 64])).unwrap()
+            })
+            .collect();
+        for program in &programs {
+            memory.notify_finalized(program.clone(), author);
+        }
+        let [first, second, third] = [0, 1, 2].map(|i| programs[i].identifier().clone());
+        assert_eq!(memory.next_program().unwrap().identifier(), &first);
+        assert!(memory.next_program().is_none());
+
+        // the started program is cancelled too, programs of others are not
+        memory.notify_cancelled(&first, author);
+        memory.notify_cancelled(&second, PeerId::random());
+        memory.notify_cancelled(&third, author);
+        assert!(memory.resolve_cancel(&third.to_hex()).is_err());
+        assert_eq!(memory.resolve_cancel(&second.short()), Ok(second.clone()));
+
+        memory.notify_executed_locally(&first, vec![]);
+        assert_eq!(memory.next_program().unwrap().identifier(), &second);
+        memory.notify_executed_locally(&second, vec![]);
+        assert!(memory.next_program().is_none());
+
+        assert!(!memory.notify_executed(author, first.clone()));
+        assert!(!memory.notify_executed(author, second.clone()));
+        assert!(memory.notify_executed(PeerId::random(), second.clone()));
+        assert!(memory.resolve_cancel(&second.to_hex()).is_err());
+        let states: Vec<_> = memory
+            .find("")
+            .into_iter()
+            .map(|(_, status)| (status.state, status.confirmations))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (ProgramState::Cancelled, 0),
+                (ProgramState::Executed, 2),
+                (ProgramState::Cancelled, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn started_programs_are_queued_from_snapshot() {
+        let author = PeerId::random();
+        let mut memory = InstructionMemory::new(author, 2);
+        for i in 0..3 {
+            let instructions = vec![Instruction::plus(Vid(1), Vid(2), Vid(3))];
+            let program = Program::new(instructions, Hash::from_array([i; 64])).unwrap();
+            memory.notify_finalized(program, author);
+        }
+        let first = memory.next_program().unwrap();
+        memory.notify_executed_locally(first.identifier(), vec![]);
+        let second = memory.next_program().unwrap();
+
+        // the second one may not be applied to the shards the new peer takes
+        let mut joined = InstructionMemory::new(PeerId::random(), 2);
+        joined.install_snapshot(memory.snapshot());
+        let states: Vec<_> = joined
+            .find("")
+            .into_iter()
+            .map(|(_, status)| status.state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ProgramState::Executed,
+                ProgramState::Queued,
+                ProgramState::Queued
+            ]
+        );
+        assert_eq!(joined.next_program(), Some(second));
+    }
+
+    #[test]
+    fn state_survives_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "the-swarm-instructions-test-{}",
+            rand::random::<u64>()
+        ));
+        let author = PeerId::random();
+        let template = Template::new(
+            vec![Vid(100)],
+            vec![Instruction::plus(Vid(100), Vid(1), Vid(2))],
+        )
+        .unwrap();
+        let template_hash = template.hash().unwrap();
+        let call = |i| Record::TemplateCall {
+            template_hash: template_hash.clone(),
+            bindings: vec![Vid(3)],
+            event_hash: Hash::from_array([i; 64]),
+            author,
+        };
+
+        let mut memory = InstructionMemory::open(author, 1, &path).unwrap();
+        memory.record(Record::Template(template));
+        memory.record(call(1));
+        memory.record(call(2));
+        let first = memory.next_program().unwrap();
+        memory.persist(&Record::Started);
+        drop(memory);
+
+        // the template is still known and the started program is resumed
+        let mut memory = InstructionMemory::open(author, 1, &path).unwrap();
+        assert_eq!(memory.find_templates("").len(), 1);
+        assert_eq!(memory.executing.as_ref(), Some(&first));
+        memory.record(Record::ExecutedLocally {
+            program_id: first.identifier().clone(),
+            failures: vec![],
+        });
+        memory.record(call(3));
+        memory.compact();
+        drop(memory);
+
+        let memory = InstructionMemory::open(author, 1, &path).unwrap();
+        let states: Vec<_> = memory
+            .find("")
+            .into_iter()
+            .map(|(_, status)| status.state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ProgramState::Executed,
+                ProgramState::Queued,
+                ProgramState::Queued
+            ]
+        );
+        assert_eq!(memory.queue.len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
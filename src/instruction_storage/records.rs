@@ -0,0 +1,184 @@
+//! Instruction memory kept on disk, so that a restarted node still knows
+//! scheduled programs, their progress on this peer and registered templates.
+//!
+//! Consensus doesn't deliver transactions again after restart (see
+//! [`crate::consensus::journal`]), so without the log a template registered
+//! before the restart could not be invoked and programs would be forgotten.
+//!
+//! Every change of the memory is appended to the log as a [`Record`] and
+//! flushed to disk. On startup the records are applied to an empty memory in
+//! the same order. Once the log has more than [`RECORDS_BEFORE_COMPACTION`]
+//! records, it's replaced with a single record of the whole state.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{
+    processor::{single_threaded::Failure, template::Template, Program, ProgramIdentifier},
+    types::{Hash, Vid},
+};
+
+use super::{InstructionSnapshot, PersistedState};
+
+/// Rewrite the log as a single state record once it has more records than this
+const RECORDS_BEFORE_COMPACTION: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Could not access the instruction log: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not (de)serialize instruction log record: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Instruction log record at byte {0} is damaged, records after it would be lost")]
+    Corrupted(usize),
+}
+
+/// Single change of the instruction memory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Record {
+    Finalized {
+        program: Program,
+        author: PeerId,
+    },
+    Template(Template),
+    TemplateCall {
+        template_hash: Hash,
+        bindings: Vec<Vid>,
+        event_hash: Hash,
+        author: PeerId,
+    },
+    /// The next queued program was given to the processor
+    Started,
+    ExecutedLocally {
+        program_id: ProgramIdentifier,
+        failures: Vec<Failure>,
+    },
+    ExecutedBy {
+        peer: PeerId,
+        program_id: ProgramIdentifier,
+    },
+    Cancelled {
+        program_id: ProgramIdentifier,
+        by: PeerId,
+    },
+    Installed(InstructionSnapshot),
+    /// Whole state, replaces everything before it
+    State(PersistedState),
+}
+
+/// Append-only file with records, each prefixed with its length
+pub struct RecordLog {
+    path: PathBuf,
+    file: File,
+    records: usize,
+}
+
+impl RecordLog {
+    /// Open the log along with the records it already has. Partially
+    /// written record at the end (left by a crash) is discarded, a damaged
+    /// one before it is an error.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<Record>), Error> {
+        let path = path.as_ref().to_owned();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        while valid_len < bytes.len() {
+            let (record, len) = match Self::read_record(&bytes[valid_len..]) {
+                Some(Ok(record)) => record,
+                // unwritten part of the last record might read as anything
+                Some(Err(len)) if valid_len + len == bytes.len() => break,
+                // damaged length might point past the end as well
+                None if !Self::records_follow(&bytes[valid_len..]) => break,
+                _ => return Err(Error::Corrupted(valid_len)),
+            };
+            records.push(record);
+            valid_len += len;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_len < bytes.len() {
+            warn!(
+                "Discarding {} byte(s) of incomplete record at the end of {:?}",
+                bytes.len() - valid_len,
+                path
+            );
+            file.set_len(valid_len.try_into().unwrap())?;
+        }
+        let log = Self {
+            path,
+            file,
+            records: records.len(),
+        };
+        Ok((log, records))
+    }
+
+    /// Record at the beginning of `bytes` and its length in bytes (the
+    /// length alone if it can't be deserialized), `None` if the record is
+    /// incomplete
+    fn read_record(bytes: &[u8]) -> Option<Result<(Record, usize), usize>> {
+        let len = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let end = usize::try_from(len).ok()?.checked_add(8)?;
+        let record = bincode::deserialize(bytes.get(8..end)?).map_err(|_| end);
+        Some(record.map(|record| (record, end)))
+    }
+
+    /// Whether `bytes` end with whole records starting somewhere after the
+    /// first byte, so the record at the start is damaged rather than torn
+    fn records_follow(bytes: &[u8]) -> bool {
+        (1..bytes.len()).any(|start| {
+            let mut rest = &bytes[start..];
+            while let Some(Ok((_, len))) = Self::read_record(rest) {
+                rest = &rest[len..];
+            }
+            rest.is_empty()
+        })
+    }
+
+    fn encode(record: &Record) -> Result<Vec<u8>, Error> {
+        let serialized = bincode::serialize(record)?;
+        let mut bytes = Vec::with_capacity(serialized.len() + 8);
+        bytes.extend_from_slice(&u64::try_from(serialized.len()).unwrap().to_le_bytes());
+        bytes.extend_from_slice(&serialized);
+        Ok(bytes)
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        // single write, so a crash leaves at most one incomplete record
+        self.file.write_all(&Self::encode(record)?)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.records > RECORDS_BEFORE_COMPACTION
+    }
+
+    /// Replace all records with the state they result in. A crash during
+    /// the compaction leaves the previous log.
+    pub fn compact(&mut self, state: PersistedState) -> Result<(), Error> {
+        debug!("Compacting instruction log of {} records", self.records);
+        let temporary = self.path.with_extension("tmp");
+        let mut compacted = File::create(&temporary)?;
+        compacted.write_all(&Self::encode(&Record::State(state))?)?;
+        compacted.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+//! Text format of programs, easier to write than JSON.
+//!
+//! One instruction per line, `#` starts a comment:
+//!
+//! ```text
+//! let x = v1              # name for data id 1
+//! let y = v2
+//! v3 = x + y              # Plus
+//! v4 = x - y              # Sub
+//! v5 = inv v4             # Inv
+//! v6 = x nand y           # Nand (also `nor`)
+//! v7 = 3 * x              # ScalarMul
+//! v8 = lincomb(2 * x, 7 * y)
+//! repeat 100 {            # the block is repeated 100 times
+//!     x = x + y
+//! }
+//! ```
+//!
+//! Templates declare their parameters with `param`, which also names them:
+//!
+//! ```text
+//! param a = v100
+//! param b = v101
+//! a = a + b
+//! ```
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    processor::{
+        template::Template, validation::MAX_PROGRAM_SIZE, BinaryOp, Instruction, Instructions,
+        LinearCombination, Operation, ScalarOp, UnaryOp,
+    },
+    types::Vid,
+};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("line {line}: {kind}")]
+pub struct Error {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    #[error("expected {expected}, found `{found}`")]
+    Unexpected {
+        expected: &'static str,
+        found: String,
+    },
+    #[error("unknown data id or label `{0}`")]
+    UnknownOperand(String),
+    #[error("label `{0}` is already defined")]
+    DuplicateLabel(String),
+    #[error("`{0}` is not a valid coefficient, expected number from 0 to 255")]
+    InvalidCoefficient(String),
+    #[error("`}}` without matching `repeat`")]
+    UnmatchedBlockEnd,
+    #[error("`repeat` block is not closed")]
+    UnclosedBlock,
+    #[error("parameter {0:?} is already declared")]
+    DuplicateParameter(Vid),
+    #[error("parameters are only allowed in templates")]
+    UnexpectedParameter,
+    #[error("program expands to more than {0} instructions, it would not fit into a transaction")]
+    TooLong(usize),
+}
+
+/// Every instruction takes at least 20 bytes serialized (operation tag and
+/// two data ids), so longer programs are rejected anyway. `repeat` blocks are
+/// not expanded beyond this.
+const MAX_INSTRUCTIONS: usize = (MAX_PROGRAM_SIZE / 20) as usize;
+
+fn tokenize(line: &str) -> Vec<&str> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        let is_symbol = "=+-*(),{}".contains(c);
+        if c.is_whitespace() || is_symbol {
+            if let Some(s) = start.take() {
+                tokens.push(&line[s..i]);
+            }
+            if is_symbol {
+                tokens.push(&line[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&line[s..]);
+    }
+    tokens
+}
+
+/// Tokens of a single line
+struct Line<'a> {
+    tokens: std::iter::Peekable<std::vec::IntoIter<&'a str>>,
+    labels: &'a HashMap<String, Vid>,
+}
+
+impl<'a> Line<'a> {
+    fn next(&mut self, expected: &'static str) -> Result<&'a str, ErrorKind> {
+        self.tokens.next().ok_or(ErrorKind::Unexpected {
+            expected,
+            found: "end of line".to_owned(),
+        })
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), ErrorKind> {
+        match self.next(token)? {
+            found if found == token => Ok(()),
+            found => Err(ErrorKind::Unexpected {
+                expected: token,
+                found: found.to_owned(),
+            }),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), ErrorKind> {
+        match self.tokens.next() {
+            None => Ok(()),
+            Some(found) => Err(ErrorKind::Unexpected {
+                expected: "end of line",
+                found: found.to_owned(),
+            }),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Vid, ErrorKind> {
+        let token = self.next("data id")?;
+        parse_data_id(token)
+            .or_else(|| self.labels.get(token).cloned())
+            .ok_or_else(|| ErrorKind::UnknownOperand(token.to_owned()))
+    }
+
+    fn coefficient(&mut self) -> Result<u8, ErrorKind> {
+        let token = self.next("coefficient")?;
+        token
+            .parse()
+            .map_err(|_| ErrorKind::InvalidCoefficient(token.to_owned()))
+    }
+
+    fn operation(&mut self) -> Result<Operation<Vid>, ErrorKind> {
+        match self.tokens.peek().copied() {
+            Some("inv") => {
+                self.tokens.next();
+                Ok(Operation::Inv(UnaryOp {
+                    operand: self.operand()?,
+                }))
+            }
+            Some("lincomb") => {
+                self.tokens.next();
+                self.expect("(")?;
+                let mut terms = Vec::new();
+                if self.tokens.peek() == Some(&")") {
+                    self.tokens.next();
+                    return Ok(Operation::LinComb(LinearCombination { terms }));
+                }
+                loop {
+                    let coefficient = self.coefficient()?;
+                    self.expect("*")?;
+                    terms.push((coefficient, self.operand()?));
+                    match self.next("`,` or `)`")? {
+                        "," => continue,
+                        ")" => break,
+                        found => {
+                            return Err(ErrorKind::Unexpected {
+                                expected: "`,` or `)`",
+                                found: found.to_owned(),
+                            })
+                        }
+                    }
+                }
+                Ok(Operation::LinComb(LinearCombination { terms }))
+            }
+            Some(token) if token.starts_with(|c: char| c.is_ascii_digit()) => {
+                let coefficient = self.coefficient()?;
+                self.expect("*")?;
+                Ok(Operation::ScalarMul(ScalarOp {
+                    coefficient,
+                    operand: self.operand()?,
+                }))
+            }
+            _ => {
+                let first = self.operand()?;
+                let operator = self.next("operator")?;
+                let second = self.operand()?;
+                let operands = BinaryOp { first, second };
+                match operator {
+                    "+" => Ok(Operation::Plus(operands)),
+                    "-" => Ok(Operation::Sub(operands)),
+                    "nand" => Ok(Operation::Nand(operands)),
+                    "nor" => Ok(Operation::Nor(operands)),
+                    found => Err(ErrorKind::Unexpected {
+                        expected: "`+`, `-`, `nand` or `nor`",
+                        found: found.to_owned(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// Can't be used as labels
+const KEYWORDS: [&str; 7] = ["let", "param", "repeat", "inv", "nand", "nor", "lincomb"];
+
+fn parse_data_id(token: &str) -> Option<Vid> {
+    token.strip_prefix('v')?.parse().ok().map(Vid)
+}
+
+/// What a line of the program means
+enum Statement {
+    Instruction(Instruction<Vid, Vid>),
+    Label(String, Vid),
+    /// Labelled parameter of a template
+    Parameter(String, Vid),
+    RepeatStart(usize),
+    RepeatEnd,
+}
+
+fn parse_line(
+    tokens: Vec<&str>,
+    labels: &HashMap<String, Vid>,
+) -> Result<Option<Statement>, ErrorKind> {
+    let mut line = Line {
+        tokens: tokens.into_iter().peekable(),
+        labels,
+    };
+    let statement = match line.tokens.peek().copied() {
+        None => return Ok(None),
+        Some(keyword @ ("let" | "param")) => {
+            line.tokens.next();
+            let name = line.next("label name")?;
+            if parse_data_id(name).is_some()
+                || KEYWORDS.contains(&name)
+                || name.starts_with(|c: char| !c.is_alphabetic())
+            {
+                return Err(ErrorKind::Unexpected {
+                    expected: "label name",
+                    found: name.to_owned(),
+                });
+            }
+            line.expect("=")?;
+            let data_id = line.operand()?;
+            match keyword {
+                "let" => Statement::Label(name.to_owned(), data_id),
+                _ => Statement::Parameter(name.to_owned(), data_id),
+            }
+        }
+        Some("repeat") => {
+            line.tokens.next();
+            let count = line.next("repeat count")?;
+            let count = count.parse().map_err(|_| ErrorKind::Unexpected {
+                expected: "repeat count",
+                found: count.to_owned(),
+            })?;
+            line.expect("{")?;
+            Statement::RepeatStart(count)
+        }
+        Some("}") => {
+            line.tokens.next();
+            Statement::RepeatEnd
+        }
+        Some(_) => {
+            let result = line.operand()?;
+            line.expect("=")?;
+            let operation = line.operation()?;
+            Statement::Instruction(Instruction { operation, result })
+        }
+    };
+    line.end()?;
+    Ok(Some(statement))
+}
+
+/// Parse program written in the [text format](self)
+pub fn parse(source: &str) -> Result<Instructions, Error> {
+    let (parameters, instructions) = parse_with_parameters(source)?;
+    match parameters.first() {
+        Some((line, _)) => Err(Error {
+            line: *line,
+            kind: ErrorKind::UnexpectedParameter,
+        }),
+        None => Ok(instructions),
+    }
+}
+
+/// Parse template written in the [text format](self), parameters are bound
+/// in the order of declaration
+pub fn parse_template(source: &str) -> Result<Template, Error> {
+    let (parameters, instructions) = parse_with_parameters(source)?;
+    let parameters = parameters.into_iter().map(|(_, data_id)| data_id).collect();
+    Ok(Template::new(parameters, instructions).expect("duplicates are rejected by the parser"))
+}
+
+/// Instructions along with declared parameters and their lines
+fn parse_with_parameters(source: &str) -> Result<(Vec<(usize, Vid)>, Instructions), Error> {
+    let mut labels = HashMap::new();
+    let mut parameters: Vec<(usize, Vid)> = Vec::new();
+    // instructions of the open blocks along with their repeat counts
+    let mut blocks: Vec<(usize, usize, Instructions)> = vec![(0, 1, Vec::new())];
+    for (index, raw_line) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| Error { line, kind };
+        let statement = parse_line(tokenize(raw_line), &labels).map_err(error)?;
+        match statement {
+            None => (),
+            Some(Statement::Instruction(instruction)) => {
+                let block = &mut blocks.last_mut().expect("top level").2;
+                if block.len() >= MAX_INSTRUCTIONS {
+                    return Err(error(ErrorKind::TooLong(MAX_INSTRUCTIONS)));
+                }
+                block.push(instruction)
+            }
+            Some(Statement::Label(name, data_id)) => {
+                if labels.contains_key(&name) {
+                    return Err(error(ErrorKind::DuplicateLabel(name)));
+                }
+                labels.insert(name, data_id);
+            }
+            Some(Statement::Parameter(name, data_id)) => {
+                if labels.contains_key(&name) {
+                    return Err(error(ErrorKind::DuplicateLabel(name)));
+                }
+                if parameters.iter().any(|(_, declared)| declared == &data_id) {
+                    return Err(error(ErrorKind::DuplicateParameter(data_id)));
+                }
+                labels.insert(name, data_id.clone());
+                parameters.push((line, data_id));
+            }
+            Some(Statement::RepeatStart(count)) => blocks.push((line, count, Vec::new())),
+            Some(Statement::RepeatEnd) => {
+                if blocks.len() == 1 {
+                    return Err(error(ErrorKind::UnmatchedBlockEnd));
+                }
+                let (_, count, instructions) = blocks.pop().expect("checked above");
+                let outer = &mut blocks.last_mut().expect("checked above").2;
+                let expanded = instructions
+                    .len()
+                    .checked_mul(count)
+                    .and_then(|repeated| repeated.checked_add(outer.len()));
+                if !matches!(expanded, Some(expanded) if expanded <= MAX_INSTRUCTIONS) {
+                    return Err(error(ErrorKind::TooLong(MAX_INSTRUCTIONS)));
+                }
+                for _ in 0..count {
+                    outer.extend(instructions.iter().cloned());
+                }
+            }
+        }
+    }
+    let (line, _, instructions) = blocks.pop().expect("top level");
+    if !blocks.is_empty() {
+        return Err(Error {
+            line,
+            kind: ErrorKind::UnclosedBlock,
+        });
+    }
+    Ok((parameters, instructions))
+}
+
+/// Write the program in the [text format](self), one instruction per line
+pub fn print(program: &Instructions) -> String {
+    let mut output = String::new();
+    for Instruction { operation, result } in program {
+        let binary =
+            |op: &BinaryOp<Vid>, operator| format!("v{} {} v{}", op.first.0, operator, op.second.0);
+        let expression = match operation {
+            Operation::Plus(op) => binary(op, "+"),
+            Operation::Sub(op) => binary(op, "-"),
+            Operation::Nand(op) => binary(op, "nand"),
+            Operation::Nor(op) => binary(op, "nor"),
+            Operation::Inv(op) => format!("inv v{}", op.operand.0),
+            Operation::ScalarMul(op) => format!("{} * v{}", op.coefficient, op.operand.0),
+            Operation::LinComb(op) => {
+                let terms: Vec<_> = op
+                    .terms
+                    .iter()
+                    .map(|(coefficient, operand)| format!("{} * v{}", coefficient, operand.0))
+                    .collect();
+                format!("lincomb({})", terms.join(", "))
+            }
+        };
+        output.push_str(&format!("v{} = {}\n", result.0, expression));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_template, print, Error, ErrorKind, MAX_INSTRUCTIONS};
+    use crate::{processor::Instruction, types::Vid};
+
+    #[test]
+    fn parses_and_prints_programs() {
+        let source = "
+            # sample program
+            let x = v1
+            let y = v2
+            v3 = x + y
+            v4 = x - v2   # mixed
+            v5 = inv v4
+            repeat 2 {
+                v6 = x nand y
+                repeat 2 {
+                    x = 3 * x
+                }
+            }
+            v7 = lincomb(2 * x, 7 * y)
+        ";
+        let expected = vec![
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::sub(Vid(1), Vid(2), Vid(4)),
+            Instruction::inv(Vid(4), Vid(5)),
+            Instruction::nand(Vid(1), Vid(2), Vid(6)),
+            Instruction::scalar_mul(3, Vid(1), Vid(1)),
+            Instruction::scalar_mul(3, Vid(1), Vid(1)),
+            Instruction::nand(Vid(1), Vid(2), Vid(6)),
+            Instruction::scalar_mul(3, Vid(1), Vid(1)),
+            Instruction::scalar_mul(3, Vid(1), Vid(1)),
+            Instruction::lin_comb(vec![(2, Vid(1)), (7, Vid(2))], Vid(7)),
+        ];
+        let program = parse(source).unwrap();
+        assert_eq!(program, expected);
+        assert_eq!(parse(&print(&program)).unwrap(), program);
+
+        assert_eq!(
+            parse("v1 = v2 + z"),
+            Err(Error {
+                line: 1,
+                kind: ErrorKind::UnknownOperand("z".to_owned())
+            })
+        );
+        assert_eq!(
+            parse("repeat 2 {\nv1 = v1 + v1"),
+            Err(Error {
+                line: 1,
+                kind: ErrorKind::UnclosedBlock
+            })
+        );
+
+        assert_eq!(
+            parse("repeat 1000000 {\nrepeat 1000000 {\nv1 = v1 + v1\n}\n}"),
+            Err(Error {
+                line: 4,
+                kind: ErrorKind::TooLong(MAX_INSTRUCTIONS)
+            })
+        );
+
+        let template = parse_template("param a = v100\nparam b = v101\na = a + b").unwrap();
+        assert_eq!(template.parameters(), [Vid(100), Vid(101)]);
+        assert_eq!(
+            template.instructions(),
+            &vec![Instruction::plus(Vid(100), Vid(101), Vid(100))]
+        );
+        assert_eq!(
+            parse("v1 = v1 + v1\nparam a = v100"),
+            Err(Error {
+                line: 2,
+                kind: ErrorKind::UnexpectedParameter
+            })
+        );
+    }
+}
@@ -4,8 +4,10 @@ use rand::{thread_rng, Rng};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{iter::repeat, path::Path};
 
+pub mod assembly;
+
 use crate::{
-    processor::{Instruction, Instructions},
+    processor::{template::Template, Instruction, Instructions},
     types::{Data, Vid},
 };
 
@@ -19,6 +21,12 @@ pub struct InputProgram {
     pub instructions: Instructions,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InputTemplate {
+    pub parameters: Vec<Vid>,
+    pub instructions: Instructions,
+}
+
 pub async fn read_input<P, T>(path: P) -> anyhow::Result<T>
 where
     P: AsRef<Path>,
@@ -29,6 +37,35 @@ where
     Ok(data)
 }
 
+/// Read program either in JSON (`.json` files) or in the
+/// [text format](assembly) (any other file)
+pub async fn read_program<P>(path: P) -> anyhow::Result<Instructions>
+where
+    P: AsRef<Path>,
+{
+    if path.as_ref().extension().is_some_and(|e| e == "json") {
+        return Ok(read_input::<_, InputProgram>(path).await?.instructions);
+    }
+    let raw = tokio::fs::read_to_string(&path).await?;
+    Ok(assembly::parse(&raw)?)
+}
+
+/// Read template the same way as [`read_program`]
+pub async fn read_template<P>(path: P) -> anyhow::Result<Template>
+where
+    P: AsRef<Path>,
+{
+    if path.as_ref().extension().is_some_and(|e| e == "json") {
+        let InputTemplate {
+            parameters,
+            instructions,
+        } = read_input(path).await?;
+        return Ok(Template::new(parameters, instructions)?);
+    }
+    let raw = tokio::fs::read_to_string(&path).await?;
+    Ok(assembly::parse_template(&raw)?)
+}
+
 #[allow(dead_code)]
 pub async fn write_input<P, T>(path: P, data: T) -> anyhow::Result<()>
 where
@@ -42,21 +79,17 @@ where
 
 #[allow(dead_code)]
 /// Write some basic layout to path to see the format
-/// for generating other inputs
-pub async fn test_write_input<P>(path_data: P, path_program: P) -> anyhow::Result<()>
+/// for generating other inputs. Generated data units are `len` bytes long.
+pub async fn test_write_input<P>(path_data: P, path_program: P, len: usize) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
-    let mut first =
-        [0u8; (crate::types::SHARD_BYTES_NUMBER * crate::types::DATA_SHARDS_COUNT) as usize];
-    for byte in &mut first {
-        *byte = thread_rng().gen_range(u8::MIN..=u8::MAX);
-    }
-    let mut second =
-        [0u8; (crate::types::SHARD_BYTES_NUMBER * crate::types::DATA_SHARDS_COUNT) as usize];
-    for byte in &mut second {
-        *byte = thread_rng().gen_range(u8::MIN..=u8::MAX);
-    }
+    let first = (0..len)
+        .map(|_| thread_rng().gen_range(u8::MIN..=u8::MAX))
+        .collect();
+    let second = (0..len)
+        .map(|_| thread_rng().gen_range(u8::MIN..=u8::MAX))
+        .collect();
     let test_data = InputData {
         data: vec![(Vid(1), Data(first)), (Vid(2), Data(second))],
     };
@@ -69,6 +102,8 @@ where
             Instruction::plus(Vid(1), Vid(2), Vid(3)),
             Instruction::sub(Vid(1), Vid(2), Vid(4)),
             Instruction::inv(Vid(4), Vid(5)),
+            Instruction::scalar_mul(3, Vid(1), Vid(6)),
+            Instruction::lin_comb(vec![(2, Vid(1)), (7, Vid(2))], Vid(7)),
         ],
     };
 
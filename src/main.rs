@@ -9,7 +9,6 @@ use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{
     prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, Layer,
 };
-use types::DATA_SHARDS_COUNT;
 
 #[cfg(feature = "console-log")]
 use console_subscriber::ConsoleLayer;
@@ -58,10 +57,36 @@ struct Args {
     #[clap(short, long)]
     dial_address: Option<String>,
 
-    /// Number of parity shards in the encoding.
-    #[clap(long, default_value_t = 1)]
+    /// Number of data shards in the encoding, i.e. number of shards
+    /// sufficient to restore the data.
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    data_shards: u64,
+
+    /// Number of parity shards in the encoding. Together with data shards
+    /// there can be at most 256 of them.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
     parity_shards: u64,
 
+    /// Maximum size of a single shard in bytes. Shards are sent in a single
+    /// message, so it should stay well below 1 MiB.
+    #[clap(long, default_value_t = 65536, value_parser = clap::value_parser!(u64).range(1..))]
+    max_shard_size: u64,
+
+    /// How shards are placed on peers. Proposed to other peers when
+    /// initializing the storage.
+    #[clap(long, value_enum, default_value_t = data_memory::placement::Placement::RoundRobin)]
+    placement: data_memory::placement::Placement,
+
+    /// Relative amount of shards this peer is willing to hold (used by
+    /// capacity-weighted placement).
+    #[clap(long, default_value_t = data_memory::placement::DEFAULT_CAPACITY, value_parser = clap::value_parser!(u64).range(1..))]
+    capacity: u64,
+
+    /// Execute independent instructions of programs in parallel on this many
+    /// workers. Without it instructions are executed one by one.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    execution_workers: Option<u64>,
+
     /// Address to launch console_subscriber/
     #[cfg(feature = "console-log")]
     #[clap(short, long)]
@@ -70,6 +95,22 @@ struct Args {
     /// Seed to generate key. Optional.
     #[clap(long)]
     key_seed: Option<u8>,
+
+    /// Directory to persist assigned shards (along with node identity,
+    /// consensus journal and progress of the executed program) in.
+    /// Without it everything is kept in memory and lost on exit.
+    #[clap(long)]
+    data_dir: Option<std::path::PathBuf>,
+
+    /// How transactions are ordered. All peers of the network must use the
+    /// same consensus.
+    #[clap(long, value_enum, default_value_t = consensus::Backend::Hashgraph)]
+    consensus: consensus::Backend,
+
+    /// Peer ordering transactions with `--consensus leader`. Without it this
+    /// peer is the leader.
+    #[clap(long)]
+    leader: Option<PeerId>,
 }
 
 #[tokio::main]
@@ -79,6 +120,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         crate::io::test_write_input(
             "input/performance/data.json",
             "input/performance/program.json",
+            (args.data_shards * args.max_shard_size).try_into().unwrap(),
         )
         .await
         .unwrap();
@@ -86,15 +128,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 
     let encoding_settings = encoding::reed_solomon::Settings {
-        data_shards_total: DATA_SHARDS_COUNT + args.parity_shards,
-        data_shards_sufficient: DATA_SHARDS_COUNT,
+        data_shards_total: args.data_shards + args.parity_shards,
+        data_shards_sufficient: args.data_shards,
+        max_shard_size: args.max_shard_size,
     };
+    if let Err(e) = encoding_settings.validate() {
+        return Err(format!("Invalid encoding settings: {}", e).into());
+    }
     let listen_address: Multiaddr = args.listen_address.parse().unwrap();
 
     let (mut swarm, mut request_response_server, join_handles, shutdown_token) =
-        network::new(None, encoding_settings, args.interactive, listen_address)
-            .await
-            .unwrap();
+        network::new(
+            None,
+            encoding_settings,
+            args.placement,
+            args.capacity,
+            args.execution_workers.map(|workers| workers.try_into().unwrap()),
+            args.interactive,
+            listen_address,
+            args.data_dir,
+            args.consensus,
+            args.leader,
+        )
+        .await
+        .unwrap();
 
     // doesn't seem to work well, useless info.
     // probably issue with libp2p not supporting this logger.
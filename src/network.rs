@@ -4,19 +4,27 @@ use libp2p::{
     swarm::{NetworkBehaviour, Swarm, SwarmBuilder},
     PeerId,
 };
-use rust_hashgraph::algorithm::datastructure::Graph;
+use rust_hashgraph::algorithm::{datastructure::Graph, Clock};
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, warn};
 
 use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use crate::consensus::graph::{EventPayload, GenesisPayload, GraphWrapper};
+use crate::consensus::leader::LeaderConsensus;
+use crate::consensus::{Backend, Consensus};
+use crate::data_memory::placement::Placement;
+use crate::data_memory::storage::{DiskStorage, MemoryStorage, ShardStorage};
 use crate::data_memory::{DistributedDataMemory, MemoryBus};
 use crate::encoding::reed_solomon;
 use crate::instruction_storage::InstructionMemory;
 use crate::module::ModuleChannelServer;
-use crate::processor::single_threaded::ShardProcessor;
+use crate::processor::{
+    checkpoint::CheckpointFile, parallel::ParallelProcessor, single_threaded::ShardProcessor,
+};
 use crate::protocol::request_response::SwarmRequestResponse;
 use crate::protocol::versions::RequestResponseVersion;
 use crate::signatures::Ed25519Signer;
@@ -59,11 +67,71 @@ impl From<crate::request_response::Event> for CombinedBehaviourEvent {
     }
 }
 
+const IDENTITY_FILE: &str = "identity";
+const SHARDS_DIRECTORY: &str = "shards";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const JOURNAL_FILE: &str = "consensus.journal";
+const INSTRUCTIONS_FILE: &str = "instructions.log";
+
+/// Persisted node has to keep its identity, otherwise the distribution it
+/// restores won't include it.
+fn load_or_generate_keypair(data_dir: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    let path = data_dir.join(IDENTITY_FILE);
+    match std::fs::read(&path) {
+        Ok(mut secret) => {
+            info!("Loaded identity from {:?}", path);
+            Ok(identity::Keypair::ed25519_from_bytes(&mut secret)?)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::create_dir_all(data_dir)?;
+            let keypair = identity::ed25519::Keypair::generate();
+            write_secret(&path, keypair.secret().as_ref())?;
+            info!("Generated new identity, saved to {:?}", path);
+            Ok(keypair.into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Only the owner should be able to read the key
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(secret)?;
+    file.sync_all()
+}
+
+fn new_graph<TClock: Clock>(
+    local_peer_id: PeerId,
+    keypair: &identity::ed25519::Keypair,
+    clock: TClock,
+) -> Graph<EventPayload<Vid, Sid>, GenesisPayload, PeerId, Ed25519Signer, TClock> {
+    Graph::new(
+        local_peer_id,
+        EventPayload::<Vid, Sid>::new(vec![]),
+        GenesisPayload {
+            pubkey: keypair.public().into(),
+        },
+        30,
+        Ed25519Signer::new(keypair.clone()),
+        clock,
+    )
+}
+
 pub async fn new(
     key_seed: Option<u8>,
     encoding_settings: reed_solomon::Settings,
+    placement: Placement,
+    capacity: u64,
+    execution_workers: Option<usize>,
     run_ui: bool,
     listen_address: libp2p::Multiaddr,
+    data_dir: Option<PathBuf>,
+    consensus: Backend,
+    leader: Option<PeerId>,
 ) -> Result<
     (
         Swarm<CombinedBehaviour>,
@@ -74,13 +142,14 @@ pub async fn new(
     Box<dyn Error>,
 > {
     // Create a public/private key pair, either random or based on a seed.
-    let local_keypair = match key_seed {
-        Some(s) => {
+    let local_keypair = match (key_seed, &data_dir) {
+        (Some(s), _) => {
             let mut bytes = [0u8; 32];
             bytes[0] = s;
             identity::Keypair::ed25519_from_bytes(bytes).unwrap()
         }
-        None => identity::Keypair::generate_ed25519(),
+        (None, Some(data_dir)) => load_or_generate_keypair(data_dir)?,
+        (None, None) => identity::Keypair::generate_ed25519(),
     };
     let local_ed25519_keypair = local_keypair
         .clone()
@@ -95,39 +164,68 @@ pub async fn new(
     let shutdown_token = CancellationToken::new();
 
     // consensus
-    let signer = Ed25519Signer::new(local_ed25519_keypair.clone());
-    let graph = Graph::new(
-        local_peer_id,
-        EventPayload::<Vid, Sid>::new(vec![]),
-        GenesisPayload {
-            pubkey: local_ed25519_keypair.public().into(),
-        },
-        30,
-        signer,
-        (),
-    );
-    let consensus = GraphWrapper::from_graph(graph);
     let (consensus_server, consensus_client) = ModuleChannelServer::new(
-        Some(crate::consensus::graph::ModuleState::Ready),
+        Some(crate::consensus::ModuleState::Ready),
         CHANNEL_BUFFER_LIMIT,
         shutdown_token.clone(),
     );
-    join_handles.push(tokio::spawn(consensus.run(consensus_server)));
+    match (consensus, &data_dir) {
+        (Backend::Leader, data_dir) => {
+            let leader = leader.unwrap_or(local_peer_id);
+            if leader == local_peer_id {
+                info!("Ordering transactions as the leader");
+            } else {
+                info!("Following leader {:?}", leader);
+            }
+            if data_dir.is_some() {
+                warn!("Leader consensus is not journaled, its log is lost on exit");
+            }
+            let consensus = LeaderConsensus::new(local_peer_id, leader);
+            join_handles.push(tokio::spawn(consensus.run(consensus_server)));
+        }
+        (Backend::Hashgraph, Some(data_dir)) => {
+            let keypair = local_ed25519_keypair.clone();
+            let consensus = GraphWrapper::from_journal(
+                data_dir.join(JOURNAL_FILE),
+                local_peer_id,
+                (),
+                move |clock| new_graph(local_peer_id, &keypair, clock),
+            )?;
+            join_handles.push(tokio::spawn(consensus.run(consensus_server)));
+        }
+        (Backend::Hashgraph, None) => {
+            let keypair = local_ed25519_keypair.clone();
+            let consensus = GraphWrapper::new(move || new_graph(local_peer_id, &keypair, ()));
+            join_handles.push(tokio::spawn(consensus.run(consensus_server)));
+        }
+    }
 
     // data memory
     let (memory_bus_data_memory, memory_bus_processor) = MemoryBus::channel(CHANNEL_BUFFER_LIMIT);
+    let shard_storage: Box<dyn ShardStorage> = match &data_dir {
+        Some(data_dir) => Box::new(DiskStorage::open(data_dir.join(SHARDS_DIRECTORY))?),
+        None => Box::new(MemoryStorage::new()),
+    };
     let data_memory = DistributedDataMemory::new(
         local_peer_id,
         memory_bus_data_memory,
         encoding_settings.clone(),
+        shard_storage,
     );
     let (data_memory_server, data_memory_client) =
         ModuleChannelServer::new(None, CHANNEL_BUFFER_LIMIT, shutdown_token.clone());
     join_handles.push(tokio::spawn(data_memory.run(data_memory_server)));
 
     // instruction memory
-    let instruction_memory =
-        InstructionMemory::new(encoding_settings.data_shards_sufficient.try_into().unwrap());
+    let execution_confirmations = encoding_settings.data_shards_sufficient.try_into().unwrap();
+    let instruction_memory = match &data_dir {
+        Some(data_dir) => InstructionMemory::open(
+            local_peer_id,
+            execution_confirmations,
+            data_dir.join(INSTRUCTIONS_FILE),
+        )?,
+        None => InstructionMemory::new(local_peer_id, execution_confirmations),
+    };
     let (instruction_memory_server, instruction_memory_client) =
         ModuleChannelServer::new(None, CHANNEL_BUFFER_LIMIT, shutdown_token.clone());
     join_handles.push(tokio::spawn(
@@ -135,13 +233,25 @@ pub async fn new(
     ));
 
     // processor
-    let processor = ShardProcessor::new(memory_bus_processor);
     let (processor_server, processor_client) = ModuleChannelServer::new(
         Some(crate::processor::single_threaded::ModuleState::Ready),
         CHANNEL_BUFFER_LIMIT,
         shutdown_token.clone(),
     );
-    join_handles.push(tokio::spawn(processor.run(processor_server)));
+    let checkpoints = data_dir
+        .as_ref()
+        .map(|data_dir| CheckpointFile::new(data_dir.join(CHECKPOINT_FILE)));
+    match execution_workers {
+        Some(workers) => {
+            info!("Executing programs in parallel on {} workers", workers);
+            let processor = ParallelProcessor::new(memory_bus_processor, workers, checkpoints);
+            join_handles.push(tokio::spawn(processor.run(processor_server)));
+        }
+        None => {
+            let processor = ShardProcessor::new(memory_bus_processor, checkpoints);
+            join_handles.push(tokio::spawn(processor.run(processor_server)));
+        }
+    }
 
     let (behaviour_server, behaviour_client) =
         ModuleChannelServer::new(None, CHANNEL_BUFFER_LIMIT, shutdown_token.clone());
@@ -169,6 +279,10 @@ pub async fn new(
         data_memory_client,
         processor_client,
         request_response_client,
+        encoding_settings,
+        placement,
+        capacity,
+        local_ed25519_keypair,
     );
     let mdns = mdns::async_io::Behaviour::new(Default::default(), local_peer_id)?;
 
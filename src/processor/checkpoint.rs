@@ -0,0 +1,185 @@
+//! Progress of the program being executed, saved so that a restarted node
+//! continues the program instead of skipping it or starting it over.
+//!
+//! Every [`CHECKPOINT_INTERVAL`] instructions the executor saves a checkpoint
+//! with the shards written since the previous one and only then stores these
+//! shards through the [`MemoryBus`](super::single_threaded::MemoryBus). The
+//! checkpoint is replaced only after the storage confirms the writes, so on
+//! restart the storage contains data as it was at some checkpoint, except
+//! for the shards pending in it. They are stored again before the execution
+//! continues.
+//!
+//! The checkpoint of a finished program stays until the next program starts.
+//! Instruction memory gives the started program again after restart, since it
+//! can't know whether the processor finished it; in that case the results
+//! are taken from the checkpoint instead of executing the program twice.
+//! If the checkpoint can't be read, the program given first after restart is
+//! failed, since running it from the start could apply its writes twice.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    data_memory::FullShardId,
+    types::{Hash, Shard},
+};
+
+use super::{single_threaded::Failure, Program};
+
+/// Instructions executed between checkpoints
+pub const CHECKPOINT_INTERVAL: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Could not access the checkpoint file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not (de)serialize the checkpoint: {0}")]
+    Serialization(#[from] bincode::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub program: Program,
+    /// Whether the instruction with this index was executed
+    pub executed: Vec<bool>,
+    /// Results stored so far with their checksums
+    pub stored: HashMap<FullShardId, Hash>,
+    pub failures: Vec<Failure>,
+    /// Shards written at this checkpoint, possibly not stored yet
+    pub pending: HashMap<FullShardId, Shard>,
+}
+
+impl Checkpoint {
+    /// Nothing is executed yet
+    pub fn start(program: Program) -> Self {
+        Self {
+            executed: vec![false; program.instructions().len()],
+            program,
+            stored: HashMap::new(),
+            failures: Vec::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn executed_count(&self) -> usize {
+        self.executed.iter().filter(|executed| **executed).count()
+    }
+}
+
+/// Keeps the latest checkpoint in a file
+pub struct CheckpointFile {
+    path: PathBuf,
+}
+
+impl CheckpointFile {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    pub fn load(&self) -> Result<Option<Checkpoint>, Error> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the saved checkpoint. A crash during the save leaves the
+    /// previous one, the new one is on disk once this returns.
+    pub fn save(&self, checkpoint: &Checkpoint) -> Result<(), Error> {
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bincode::serialize(checkpoint)?)?;
+        file.sync_all()?;
+        fs::rename(temporary, &self.path)?;
+        // the rename itself is durable only after the directory is synced
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(directory)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Nothing to resume, e.g. the checkpoint doesn't match the storage
+    pub fn clear(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, CheckpointFile, Error as CheckpointError};
+    use crate::{
+        processor::{
+            single_threaded::{Error, Failure},
+            Instruction, Program,
+        },
+        types::{Hash, Shard, Sid, Vid},
+    };
+
+    #[test]
+    fn checkpoint_survives_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "the-swarm-checkpoint-test-{}",
+            rand::random::<u64>()
+        ));
+        let program = Program::new(
+            vec![
+                Instruction::plus(Vid(1), Vid(2), Vid(3)),
+                Instruction::nand(Vid(1), Vid(3), Vid(4)),
+            ],
+            Hash::from_array([0; 64]),
+        )
+        .unwrap();
+        let file = CheckpointFile::new(&path);
+        assert!(file.load().unwrap().is_none());
+
+        let mut checkpoint = Checkpoint::start(program.clone());
+        checkpoint.executed[0] = true;
+        let shard = Shard::new(2, vec![1, 2]);
+        checkpoint.stored.insert((Vid(3), Sid(0)), shard.checksum());
+        checkpoint.pending.insert((Vid(3), Sid(0)), shard.clone());
+        checkpoint
+            .failures
+            .push(Failure::at(1, Error::NoShardsAssigned));
+        file.save(&checkpoint).unwrap();
+
+        let restored = CheckpointFile::new(&path).load().unwrap().unwrap();
+        assert_eq!(restored.program, program);
+        assert_eq!(restored.executed, vec![true, false]);
+        assert_eq!(restored.executed_count(), 1);
+        assert_eq!(restored.stored[&(Vid(3), Sid(0))], shard.checksum());
+        assert_eq!(restored.pending[&(Vid(3), Sid(0))], shard);
+        assert_eq!(restored.failures[0].instruction, Some(1));
+
+        // crash during the next save leaves the previous checkpoint
+        std::fs::write(path.with_extension("tmp"), [1, 2, 3]).unwrap();
+        assert_eq!(file.load().unwrap().unwrap().executed_count(), 1);
+        // damaged file is an error, not a checkpoint with nothing executed
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(matches!(
+            file.load(),
+            Err(CheckpointError::Serialization(_))
+        ));
+        file.save(&checkpoint).unwrap();
+        assert!(file.load().unwrap().is_some());
+
+        file.clear().unwrap();
+        assert!(file.load().unwrap().is_none());
+        file.clear().unwrap();
+    }
+}
@@ -35,8 +35,6 @@ pub struct Instruction<TOperand, TResult> {
 }
 
 impl<TOperand, TResult> Instruction<TOperand, TResult> {
-    // might be useful
-    #[allow(unused)]
     pub fn map_operands<F, TNewOperand>(self, f: F) -> Instruction<TNewOperand, TResult>
     where
         F: Fn(TOperand) -> TNewOperand,
@@ -61,6 +59,17 @@ impl<TOperand, TResult> Instruction<TOperand, TResult> {
                 first: f(binary.first),
                 second: f(binary.second),
             }),
+            Operation::ScalarMul(scalar) => Operation::ScalarMul(ScalarOp {
+                coefficient: scalar.coefficient,
+                operand: f(scalar.operand),
+            }),
+            Operation::LinComb(combination) => Operation::LinComb(LinearCombination {
+                terms: combination
+                    .terms
+                    .into_iter()
+                    .map(|(coefficient, operand)| (coefficient, f(operand)))
+                    .collect(),
+            }),
         };
         Instruction {
             operation: new_op,
@@ -77,6 +86,8 @@ impl<TOperand, TResult> Instruction<TOperand, TResult> {
             Operation::Inv(ref o) => Operation::Inv(o.as_ref()),
             Operation::Nand(ref o) => Operation::Nand(o.as_ref()), // Added Nand handling
             Operation::Nor(ref o) => Operation::Nor(o.as_ref()), // Added Nor handling
+            Operation::ScalarMul(ref o) => Operation::ScalarMul(o.as_ref()),
+            Operation::LinComb(ref o) => Operation::LinComb(o.as_ref()),
         };
         Instruction {
             operation: ref_op,
@@ -110,6 +121,14 @@ impl<O, R> Instruction<Option<O>, R> {
                 operation: Operation::Nor(o),
                 result: self.result,
             }),
+            Operation::ScalarMul(o) => o.transpose().map(|o| Instruction {
+                operation: Operation::ScalarMul(o),
+                result: self.result,
+            }),
+            Operation::LinComb(o) => o.transpose().map(|o| Instruction {
+                operation: Operation::LinComb(o),
+                result: self.result,
+            }),
         }
     }
 }
@@ -121,16 +140,37 @@ pub enum Operation<TOperand> {
     Inv(UnaryOp<TOperand>),
     Nand(BinaryOp<TOperand>), // Added Nand variant
     Nor(BinaryOp<TOperand>),  // Add Nor as a variant
+    /// Multiplication by a constant in GF(2^8)
+    ScalarMul(ScalarOp<TOperand>),
+    /// Sum of operands multiplied by constants in GF(2^8)
+    LinComb(LinearCombination<TOperand>),
 }
 
 impl<TOperand> Operation<TOperand> {
+    /// Linear operations commute with encoding, so they can be computed on
+    /// shards. Others are computed on the assembled data.
+    pub fn is_linear(&self) -> bool {
+        match self {
+            Operation::Sub(_)
+            | Operation::Plus(_)
+            | Operation::Inv(_)
+            | Operation::ScalarMul(_)
+            | Operation::LinComb(_) => true,
+            Operation::Nand(_) | Operation::Nor(_) => false,
+        }
+    }
+
     pub fn args_as_list(&self) -> Vec<&TOperand> {
         match self {
             Operation::Sub(BinaryOp { first, second })
             | Operation::Plus(BinaryOp { first, second })
             | Operation::Nand(BinaryOp { first, second })
             | Operation::Nor(BinaryOp { first, second }) => vec![first, second],  // Add case for Nand & NOR
-            Operation::Inv(UnaryOp { operand }) => vec![operand],
+            Operation::Inv(UnaryOp { operand })
+            | Operation::ScalarMul(ScalarOp { operand, .. }) => vec![operand],
+            Operation::LinComb(LinearCombination { terms }) => {
+                terms.iter().map(|(_, operand)| operand).collect()
+            }
         }
     }
 }
@@ -141,6 +181,25 @@ impl_unary!(inv, Inv);
 impl_binary_constructor!(nand, Nand); // Added constructor for Nand
 impl_binary_constructor!(nor, Nor); // Added constructor for Nor
 
+impl<TOperand, TResult> Instruction<TOperand, TResult> {
+    pub fn scalar_mul(coefficient: u8, operand: TOperand, result: TResult) -> Self {
+        Instruction {
+            operation: Operation::ScalarMul(ScalarOp {
+                coefficient,
+                operand,
+            }),
+            result,
+        }
+    }
+
+    pub fn lin_comb(terms: Vec<(u8, TOperand)>, result: TResult) -> Self {
+        Instruction {
+            operation: Operation::LinComb(LinearCombination { terms }),
+            result,
+        }
+    }
+}
+
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
 pub struct BinaryOp<TOperand> {
@@ -184,3 +243,57 @@ impl<O> UnaryOp<Option<O>> {
         self.operand.map(|operand| UnaryOp { operand })
     }
 }
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
+pub struct ScalarOp<TOperand> {
+    pub coefficient: u8,
+    pub operand: TOperand,
+}
+
+impl<O> ScalarOp<O> {
+    pub fn as_ref(&self) -> ScalarOp<&O> {
+        ScalarOp {
+            coefficient: self.coefficient,
+            operand: &self.operand,
+        }
+    }
+}
+
+impl<O> ScalarOp<Option<O>> {
+    pub fn transpose(self) -> Option<ScalarOp<O>> {
+        let coefficient = self.coefficient;
+        self.operand.map(|operand| ScalarOp {
+            coefficient,
+            operand,
+        })
+    }
+}
+
+/// `c_1 * x_1 + c_2 * x_2 + ...` for `terms` `[(c_1, x_1), (c_2, x_2), ...]`
+#[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
+pub struct LinearCombination<TOperand> {
+    pub terms: Vec<(u8, TOperand)>,
+}
+
+impl<O> LinearCombination<O> {
+    pub fn as_ref(&self) -> LinearCombination<&O> {
+        LinearCombination {
+            terms: self
+                .terms
+                .iter()
+                .map(|(coefficient, operand)| (*coefficient, operand))
+                .collect(),
+        }
+    }
+}
+
+impl<O> LinearCombination<Option<O>> {
+    pub fn transpose(self) -> Option<LinearCombination<O>> {
+        let terms = self
+            .terms
+            .into_iter()
+            .map(|(coefficient, operand)| operand.map(|operand| (coefficient, operand)))
+            .collect::<Option<_>>()?;
+        Some(LinearCombination { terms })
+    }
+}
@@ -8,58 +8,74 @@ use crate::{
     types::{Data, Vid},
 };
 
-use super::{BinaryOp, Instruction, Operation, Program, UnaryOp};
+use super::{
+    linear_combination, map_zip, BinaryOp, Instruction, LinearCombination, OperandError, Operation,
+    Program, ScalarOp, UnaryOp,
+};
 
 pub struct MockProcessor {}
 
-fn map_zip<T, const N: usize, F>(a: &[T; N], b: &[T; N], f: F) -> [T; N]
-where
-    T: Clone,
-    F: Fn(T, T) -> T,
-{
-    let mut result = a.clone();
-    for (r, b_item) in result.iter_mut().zip(b.iter()) {
-        *r = f(r.clone(), b_item.clone());
-    }
-    result
-}
-
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("No data with specified id is found")]
     DataNotFound,
+    #[error("Operands of the instruction have different lengths")]
+    OperandLengthMismatch,
+    #[error("Linear combination has no terms")]
+    EmptyCombination,
+}
+
+impl From<OperandError> for Error {
+    fn from(e: OperandError) -> Self {
+        match e {
+            OperandError::LengthMismatch => Error::OperandLengthMismatch,
+            OperandError::EmptyCombination => Error::EmptyCombination,
+        }
+    }
 }
 
 impl MockProcessor {
-    fn calculate(operation: &Operation<Data>) -> Data {
+    pub fn calculate(operation: &Operation<Data>) -> Result<Data, Error> {
         let array = match operation {
             Operation::Sub(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
+                operation.first.as_ref(),
+                operation.second.as_ref(),
                 reed_solomon_erasure::galois_8::add,
-            ),
+            )?,
             Operation::Plus(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
+                operation.first.as_ref(),
+                operation.second.as_ref(),
                 reed_solomon_erasure::galois_8::add,
-            ),
+            )?,
             // inverses in GF(2^8) are the same values, because
             // the arithmetic is done on polynomials over GF(2)
             // and addition of any coefficient on itself gives 0
             // in GF(2)
-            Operation::Inv(operation) => operation.operand.as_inner().map(|n| n),
+            Operation::Inv(operation) => operation.operand.0.clone(),
             Operation::Nand(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
+                operation.first.as_ref(),
+                operation.second.as_ref(),
                 |a, b| if a == 0 || b == 0 { 1 } else { 0 }, // NAND logic (0: false, 1: true)
-            ),
+            )?,
             Operation::Nor(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
+                operation.first.as_ref(),
+                operation.second.as_ref(),
                 |a, b| if a == 0 && b == 0 { 1 } else { 0 }, // NOR logic (0: false, 1: true)
-            ),
+            )?,
+            Operation::ScalarMul(operation) => operation
+                .operand
+                .0
+                .iter()
+                .map(|x| reed_solomon_erasure::galois_8::mul(operation.coefficient, *x))
+                .collect(),
+            Operation::LinComb(operation) => linear_combination(
+                operation
+                    .terms
+                    .iter()
+                    .map(|(coefficient, operand)| (*coefficient, operand.as_ref())),
+            )?,
         };
-        Data(array)
+        Ok(Data(array))
     }
 
     fn retrieve_operand(operand: Vid, data_storage: &HashMap<Vid, Data>) -> Result<Data, Error> {
@@ -87,6 +103,31 @@ impl MockProcessor {
         Ok(UnaryOp { operand })
     }
 
+    fn retrieve_scalar(
+        scalar: ScalarOp<Vid>,
+        data_storage: &HashMap<Vid, Data>,
+    ) -> Result<ScalarOp<Data>, Error> {
+        let operand = Self::retrieve_operand(scalar.operand, data_storage)?;
+        Ok(ScalarOp {
+            coefficient: scalar.coefficient,
+            operand,
+        })
+    }
+
+    fn retrieve_combination(
+        combination: LinearCombination<Vid>,
+        data_storage: &HashMap<Vid, Data>,
+    ) -> Result<LinearCombination<Data>, Error> {
+        let terms = combination
+            .terms
+            .into_iter()
+            .map(|(coefficient, operand)| {
+                Self::retrieve_operand(operand, data_storage).map(|data| (coefficient, data))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LinearCombination { terms })
+    }
+
     fn retrieve_operands(
         op: Operation<Vid>,
         data_storage: &mut HashMap<Vid, Data>,
@@ -99,6 +140,12 @@ impl MockProcessor {
             Operation::Inv(unary) => Operation::Inv(Self::retrieve_unary(unary, data_storage)?),
             Operation::Nand(binary) => Operation::Nand(Self::retrieve_binary(binary, data_storage)?),
             Operation::Nor(binary) => Operation::Nor(Self::retrieve_binary(binary, data_storage)?),
+            Operation::ScalarMul(scalar) => {
+                Operation::ScalarMul(Self::retrieve_scalar(scalar, data_storage)?)
+            }
+            Operation::LinComb(combination) => {
+                Operation::LinComb(Self::retrieve_combination(combination, data_storage)?)
+            }
         };
         Ok(retrieved)
     }
@@ -111,7 +158,7 @@ impl MockProcessor {
         debug!(target: Targets::ProgramExecution.into_str(), "(mock) Starting execution of program {:?}", program_id);
         for Instruction { operation, result } in program.instructions {
             let operation = Self::retrieve_operands(operation, data_storage)?;
-            let result_value = Self::calculate(&operation);
+            let result_value = Self::calculate(&operation)?;
             data_storage.insert(result, result_value);
         }
         debug!(target: Targets::ProgramExecution.into_str(), "(mock) Saving results of execution of program {:?}", program_id);
@@ -3,13 +3,66 @@ use std::fmt::Debug;
 use async_trait::async_trait;
 use blake2::{Blake2b512, Digest};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-pub use self::instruction::{BinaryOp, Instruction, Operation, UnaryOp};
+pub use self::instruction::{
+    BinaryOp, Instruction, LinearCombination, Operation, ScalarOp, UnaryOp,
+};
 use crate::types::{Hash, Vid};
 
+use self::template::Template;
+
+pub mod checkpoint;
 pub mod instruction;
 pub mod mock;
+pub mod parallel;
 pub mod single_threaded;
+pub mod template;
+pub mod validation;
+
+/// Operands of an arithmetic operation that don't fit together
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandError {
+    #[error("Operands of the instruction have different lengths")]
+    LengthMismatch,
+    #[error("Linear combination has no terms")]
+    EmptyCombination,
+}
+
+fn map_zip<F>(a: &[u8], b: &[u8], f: F) -> Result<Vec<u8>, OperandError>
+where
+    F: Fn(u8, u8) -> u8,
+{
+    if a.len() != b.len() {
+        return Err(OperandError::LengthMismatch);
+    }
+    Ok(a.iter().zip(b.iter()).map(|(a, b)| f(*a, *b)).collect())
+}
+
+/// `sum(c_i * x_i)` in GF(2^8), operands must be of the same length
+fn linear_combination<'a, I>(terms: I) -> Result<Vec<u8>, OperandError>
+where
+    I: IntoIterator<Item = (u8, &'a [u8])>,
+{
+    let mut terms = terms.into_iter();
+    let (coefficient, operand) = terms.next().ok_or(OperandError::EmptyCombination)?;
+    let mut sum: Vec<u8> = operand
+        .iter()
+        .map(|x| reed_solomon_erasure::galois_8::mul(coefficient, *x))
+        .collect();
+    for (coefficient, operand) in terms {
+        if operand.len() != sum.len() {
+            return Err(OperandError::LengthMismatch);
+        }
+        for (acc, x) in sum.iter_mut().zip(operand) {
+            *acc = reed_solomon_erasure::galois_8::add(
+                *acc,
+                reed_solomon_erasure::galois_8::mul(coefficient, *x),
+            );
+        }
+    }
+    Ok(sum)
+}
 
 #[async_trait]
 pub trait Processor<TProgram>
@@ -41,6 +94,19 @@ pub struct ProgramIdentifier {
     pub event_hash: Hash,
 }
 
+impl ProgramIdentifier {
+    /// Hex of the hash of the event that scheduled the program. Unlike the
+    /// program hash, it's different for each scheduling.
+    pub fn to_hex(&self) -> String {
+        self.event_hash.to_hex()
+    }
+
+    /// Beginning of [`Self::to_hex()`] shown to the user
+    pub fn short(&self) -> String {
+        self.to_hex()[..16].to_owned()
+    }
+}
+
 impl Program {
     pub fn new(instructions: Instructions, event_hash: Hash) -> bincode::Result<Self> {
         let hash = Self::calculate_hash(&instructions)?;
@@ -50,20 +116,53 @@ impl Program {
         })
     }
 
+    /// Program instantiated from the template. Unlike with [`Self::new()`],
+    /// the hash is calculated from the template and the bindings.
+    pub fn from_template(
+        template: &Template,
+        bindings: &[Vid],
+        event_hash: Hash,
+    ) -> Result<Self, template::Error> {
+        let instructions = template.instantiate(bindings)?;
+        let hash = Self::calculate_template_hash(&template.hash()?, bindings)?;
+        Ok(Self {
+            instructions,
+            identifier: ProgramIdentifier { hash, event_hash },
+        })
+    }
+
     fn instructions_digest(list: &Instructions) -> bincode::Result<Vec<u8>> {
         bincode::serialize(list)
     }
 
-    pub fn calculate_hash(value: &Instructions) -> bincode::Result<Hash> {
+    fn digest(parts: &[&[u8]]) -> Hash {
         let mut hasher = Blake2b512::new();
-        hasher.update(Self::instructions_digest(value)?);
-        Ok(Hash::from_array(hasher.finalize().try_into().expect(
-            "Fixed hash function must return same result length",
-        )))
+        for part in parts {
+            hasher.update(part);
+        }
+        Hash::from_array(
+            hasher
+                .finalize()
+                .try_into()
+                .expect("Fixed hash function must return same result length"),
+        )
+    }
+
+    pub fn calculate_hash(value: &Instructions) -> bincode::Result<Hash> {
+        Ok(Self::digest(&[&Self::instructions_digest(value)?]))
+    }
+
+    /// Hash of the template invocation
+    pub fn calculate_template_hash(
+        template_hash: &Hash,
+        bindings: &[Vid],
+    ) -> bincode::Result<Hash> {
+        Ok(Self::digest(&[
+            template_hash.as_ref(),
+            &bincode::serialize(bindings)?,
+        ]))
     }
 
-    // just in case
-    #[allow(unused)]
     pub fn instructions(&self) -> &Instructions {
         &self.instructions
     }
@@ -71,10 +170,6 @@ impl Program {
     pub fn identifier(&self) -> &ProgramIdentifier {
         &self.identifier
     }
-
-    pub fn into_parts(self) -> (Instructions, ProgramIdentifier) {
-        (self.instructions, self.identifier)
-    }
 }
 
 impl IntoIterator for Program {
@@ -0,0 +1,221 @@
+//! Program execution with independent instructions run at the same time.
+//!
+//! An instruction depends on earlier ones that write its operands or its
+//! result, and on earlier ones that read its result. The program is split
+//! into levels: each instruction is put right after the instructions it
+//! depends on. Instructions of one level read data as it was before the level
+//! and write distinct data, so they (for each shard id) are computed in
+//! parallel on a pool of workers. Results are the same as with
+//! [`ShardProcessor`] running instructions one by one.
+//!
+//! Data is loaded and released level by level, checkpoints are saved between
+//! levels. A checkpoint of either executor can be resumed by the other one.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::sync::Semaphore;
+use tracing::error;
+
+use crate::{
+    module::ModuleChannelServer,
+    types::{Shard, Sid, Vid},
+};
+
+use super::{
+    checkpoint::CheckpointFile,
+    single_threaded::{ComputeStrategy, Indexed, MemoryBus, Module, ShardProcessor, ShardResult},
+    Instruction, Instructions,
+};
+
+/// Indices of program instructions grouped into levels; an instruction only
+/// depends on instructions of the previous levels
+pub fn dependency_levels(program: &Instructions) -> Vec<Vec<usize>> {
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    // level of the last instruction writing the data
+    let mut last_write: HashMap<&Vid, usize> = HashMap::new();
+    // last level reading the data
+    let mut last_read: HashMap<&Vid, usize> = HashMap::new();
+    for (index, instruction) in program.iter().enumerate() {
+        let operands = instruction.operation.args_as_list();
+        let level = operands
+            .iter()
+            .chain([&&instruction.result])
+            .filter_map(|data_id| last_write.get(data_id).map(|level| level + 1))
+            // reads see data as it was before the level, so the result may be
+            // written on the same level
+            .chain(last_read.get(&instruction.result).copied())
+            .max()
+            .unwrap_or(0);
+        for data_id in operands {
+            let read = last_read.entry(data_id).or_default();
+            *read = (*read).max(level);
+        }
+        last_write.insert(&instruction.result, level);
+        if levels.len() <= level {
+            levels.resize_with(level + 1, Vec::new);
+        }
+        levels[level].push(index);
+    }
+    levels
+}
+
+/// Compute linear instructions of one level (with their indices) for each
+/// shard id. The work is split into jobs run on `workers`.
+async fn compute_linear(
+    instructions: Vec<Indexed>,
+    contexts: &mut HashMap<Sid, HashMap<Vid, Shard>>,
+    workers: &Arc<Semaphore>,
+) -> Vec<ShardResult> {
+    if instructions.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = instructions
+        .len()
+        .div_ceil(workers.available_permits().max(1));
+    let chunks: Vec<Arc<[Indexed]>> = instructions.chunks(chunk_size).map(Arc::from).collect();
+    let shared: Vec<(Sid, Arc<HashMap<Vid, Shard>>)> = contexts
+        .drain()
+        .map(|(shard_id, context)| (shard_id, Arc::new(context)))
+        .collect();
+    let compute = |shard_id: Sid, chunk: &[Indexed], context: &HashMap<Vid, Shard>| {
+        chunk
+            .iter()
+            .cloned()
+            .map(|(index, Instruction { operation, result })| {
+                let output = ShardProcessor::retrieve_operands(operation, context)
+                    .and_then(|operation| ShardProcessor::calculate(&operation));
+                (index, shard_id.clone(), result, output)
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut outputs = Vec::new();
+    if shared.len() * chunks.len() == 1 {
+        // not worth sending to a worker
+        let (shard_id, context) = &shared[0];
+        outputs.extend(compute(shard_id.clone(), &chunks[0], context));
+    } else {
+        let mut jobs = Vec::with_capacity(shared.len() * chunks.len());
+        for (shard_id, context) in &shared {
+            for chunk in &chunks {
+                let permit = workers
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                let (shard_id, context, chunk) = (shard_id.clone(), context.clone(), chunk.clone());
+                jobs.push(tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    compute(shard_id, &chunk, &context)
+                }));
+            }
+        }
+        for job in jobs {
+            match job.await {
+                Ok(job_outputs) => outputs.extend(job_outputs),
+                Err(e) => error!("worker computing instructions failed: {}", e),
+            }
+        }
+    }
+    contexts.extend(shared.into_iter().map(|(shard_id, context)| {
+        let context = Arc::try_unwrap(context).expect("all jobs are finished");
+        (shard_id, context)
+    }));
+    outputs
+}
+
+/// Executes programs level by level, see [module docs](self)
+pub struct ParallelProcessor {
+    inner: ShardProcessor,
+    workers: Arc<Semaphore>,
+}
+
+/// Levels of the program computed on the pool of workers
+struct Parallel {
+    workers: Arc<Semaphore>,
+}
+
+#[async_trait]
+impl ComputeStrategy for Parallel {
+    fn steps(&self, program: &Instructions) -> Vec<Vec<usize>> {
+        dependency_levels(program)
+    }
+
+    async fn compute_linear(
+        &self,
+        instructions: Vec<Indexed>,
+        contexts: &mut HashMap<Sid, HashMap<Vid, Shard>>,
+    ) -> Vec<ShardResult> {
+        compute_linear(instructions, contexts, &self.workers).await
+    }
+}
+
+impl ParallelProcessor {
+    pub fn new(bus: MemoryBus, workers: usize, checkpoints: Option<CheckpointFile>) -> Self {
+        Self {
+            inner: ShardProcessor::new(bus, checkpoints),
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+        }
+    }
+
+    pub async fn run(self, connection: ModuleChannelServer<Module>) {
+        let strategy = Parallel {
+            workers: self.workers,
+        };
+        self.inner.serve(strategy, connection).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::sync::Semaphore;
+
+    use super::{compute_linear, dependency_levels};
+    use crate::{
+        processor::{single_threaded::ShardProcessor, Instruction},
+        types::{Shard, Sid, Vid},
+    };
+
+    #[tokio::test]
+    async fn levels_give_sequential_results() {
+        let program = vec![
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::sub(Vid(1), Vid(2), Vid(4)),
+            Instruction::scalar_mul(5, Vid(3), Vid(1)),
+            Instruction::plus(Vid(4), Vid(4), Vid(3)),
+            Instruction::lin_comb(vec![(2, Vid(1)), (3, Vid(2))], Vid(2)),
+            Instruction::plus(Vid(1), Vid(2), Vid(1)),
+        ];
+        assert_eq!(
+            dependency_levels(&program),
+            vec![vec![0, 1], vec![2, 3], vec![4], vec![5]]
+        );
+
+        let context = HashMap::from([
+            (Vid(1), Shard::new(3, vec![1, 2, 3])),
+            (Vid(2), Shard::new(3, vec![7, 11, 13])),
+        ]);
+        let initial = HashMap::from([(Sid(0), context.clone()), (Sid(1), context)]);
+        let mut sequential = initial.clone();
+        for context in sequential.values_mut() {
+            for Instruction { operation, result } in program.iter().cloned() {
+                let operation = ShardProcessor::retrieve_operands(operation, context).unwrap();
+                context.insert(result, ShardProcessor::calculate(&operation).unwrap());
+            }
+        }
+
+        let mut parallel = initial;
+        let workers = Arc::new(Semaphore::new(2));
+        for level in dependency_levels(&program) {
+            let instructions = level.into_iter().map(|i| (i, program[i].clone())).collect();
+            let outputs = compute_linear(instructions, &mut parallel, &workers).await;
+            for (_, shard_id, result, output) in outputs {
+                let context = parallel.get_mut(&shard_id).unwrap();
+                context.insert(result, output.unwrap());
+            }
+        }
+        assert_eq!(parallel, sequential);
+    }
+}
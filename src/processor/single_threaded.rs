@@ -1,21 +1,26 @@
 use std::collections::{HashMap, HashSet};
 
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::{
-    join,
-    sync::{mpsc, oneshot},
-};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    data_memory::{
+        plaintext::{ComputeRequest, ExecutionStep},
+        FullShardId,
+    },
     encoding::{self},
-    types::{Shard, Vid},
+    types::{Hash, Shard, Sid, Vid},
 };
 use crate::{logging_helpers::Targets, module::ModuleChannelServer};
 
 use super::{
-    instruction::Instruction, BinaryOp, Instructions, Operation, Program, ProgramIdentifier,
-    UnaryOp,
+    checkpoint::{self, Checkpoint, CheckpointFile, CHECKPOINT_INTERVAL},
+    instruction::Instruction,
+    linear_combination, map_zip, BinaryOp, Instructions, LinearCombination, OperandError,
+    Operation, Program, ProgramIdentifier, ScalarOp, UnaryOp,
 };
 
 pub struct Module;
@@ -30,7 +35,8 @@ impl crate::module::Module for Module {
 pub enum OutEvent {
     FinishedExecution {
         program_id: ProgramIdentifier,
-        results: Vec<Result<Vid, Error>>,
+        /// Stored results with checksums of their contents
+        results: Vec<Result<(FullShardId, Hash), Failure>>,
     },
 }
 
@@ -54,16 +60,25 @@ impl crate::module::State for ModuleState {
 }
 
 pub struct MemoryBus {
-    reads: mpsc::Sender<(Vid, oneshot::Sender<Option<Shard>>)>,
-    writes: mpsc::Sender<(Vid, Shard)>,
+    reads: mpsc::Sender<(Vid, oneshot::Sender<HashMap<Sid, Shard>>)>,
+    writes: mpsc::Sender<(FullShardId, Shard)>,
+    syncs: mpsc::Sender<oneshot::Sender<()>>,
+    plaintext: mpsc::Sender<ComputeRequest>,
 }
 
 impl MemoryBus {
     pub fn new(
-        reads: mpsc::Sender<(Vid, oneshot::Sender<Option<Shard>>)>,
-        writes: mpsc::Sender<(Vid, Shard)>,
+        reads: mpsc::Sender<(Vid, oneshot::Sender<HashMap<Sid, Shard>>)>,
+        writes: mpsc::Sender<(FullShardId, Shard)>,
+        syncs: mpsc::Sender<oneshot::Sender<()>>,
+        plaintext: mpsc::Sender<ComputeRequest>,
     ) -> Self {
-        Self { reads, writes }
+        Self {
+            reads,
+            writes,
+            syncs,
+            plaintext,
+        }
     }
 
     #[allow(unused)]
@@ -74,10 +89,10 @@ impl MemoryBus {
 
 impl MemoryBus {
     // ideally should just request data from local storage; not with currently used math
-    async fn request_local_shard(
+    async fn request_local_shards(
         &self,
         data_id: Vid,
-    ) -> Result<oneshot::Receiver<Option<Shard>>, Error> {
+    ) -> Result<oneshot::Receiver<HashMap<Sid, Shard>>, Error> {
         let (response_sender, response_reciever) = oneshot::channel();
         self.reads
             .send((data_id, response_sender))
@@ -86,240 +101,711 @@ impl MemoryBus {
         Ok(response_reciever)
     }
 
-    /// Assemble data necessary to complete the instruction(s)
-    pub async fn retrieve_local_shard(&self, data_id: Vid) -> Result<Option<Shard>, Error> {
-        let reciever = self.request_local_shard(data_id).await?;
+    /// Assemble data necessary to complete the instruction(s). The peer may
+    /// store several shards of the data.
+    pub async fn retrieve_local_shards(&self, data_id: Vid) -> Result<HashMap<Sid, Shard>, Error> {
+        let reciever = self.request_local_shards(data_id).await?;
         reciever.await.map_err(|_| Error::ResponseChannelClosed)
     }
 
-    // todo: change to shard when switching encoding
-    pub async fn store_local_shard(&self, data_id: Vid, shard: Shard) -> Result<(), Error> {
+    pub async fn store_local_shard(
+        &self,
+        full_shard_id: FullShardId,
+        shard: Shard,
+    ) -> Result<(), Error> {
         self.writes
-            .send((data_id, shard))
+            .send((full_shard_id, shard))
             .await
             .map_err(|_| Error::DataWriteChannelClosed)
     }
+
+    /// Wait until shards sent with [`Self::store_local_shard()`] are stored
+    pub async fn sync(&self) -> Result<(), Error> {
+        let (response, reciever) = oneshot::channel();
+        self.syncs
+            .send(response)
+            .await
+            .map_err(|_| Error::SyncChannelClosed)?;
+        reciever.await.map_err(|_| Error::SyncChannelClosed)
+    }
+
+    /// Have the non-linear instruction computed on assembled data (see
+    /// [`crate::data_memory::plaintext`]). Returns result shards placed on
+    /// this peer.
+    pub async fn compute_on_plaintext(
+        &self,
+        step: ExecutionStep,
+        instruction: Instruction<Vid, Vid>,
+        operands: Vec<(FullShardId, Shard)>,
+    ) -> Result<HashMap<Sid, Shard>, Error> {
+        let (response, reciever) = oneshot::channel();
+        self.plaintext
+            .send(ComputeRequest {
+                step,
+                instruction,
+                operands,
+                response,
+            })
+            .await
+            .map_err(|_| Error::PlaintextChannelClosed)?;
+        reciever.await.map_err(|_| Error::PlaintextResultMissing)
+    }
 }
 
 pub struct ShardProcessor {
     memory_access: MemoryBus,
+    /// Saved progress of the current program, if the node is persisted
+    checkpoints: Option<CheckpointFile>,
 }
 
-fn map_zip<T, const N: usize, F>(a: &[T; N], b: &[T; N], f: F) -> [T; N]
-where
-    T: Clone,
-    F: Fn(T, T) -> T,
-{
-    let mut result = a.clone();
-    for (r, b_item) in result.iter_mut().zip(b.iter()) {
-        *r = f(r.clone(), b_item.clone());
+/// Instruction along with its index in the program
+pub(super) type Indexed = (usize, Instruction<Vid, Vid>);
+/// Result of computing instruction (by its index) for the shard
+pub(super) type ShardResult = (usize, Sid, Vid, Result<Shard, Error>);
+
+/// How [`ShardProcessor`] computes instructions of a program
+#[async_trait]
+pub(super) trait ComputeStrategy: Send + Sync {
+    /// Indices of the instructions grouped into steps executed one after
+    /// another; instructions of a step must not depend on each other
+    fn steps(&self, program: &Instructions) -> Vec<Vec<usize>>;
+
+    /// Compute linear instructions of a step for each shard id
+    async fn compute_linear(
+        &self,
+        instructions: Vec<Indexed>,
+        contexts: &mut HashMap<Sid, HashMap<Vid, Shard>>,
+    ) -> Vec<ShardResult>;
+}
+
+/// Instructions are executed one by one
+pub(super) struct Sequential;
+
+#[async_trait]
+impl ComputeStrategy for Sequential {
+    fn steps(&self, program: &Instructions) -> Vec<Vec<usize>> {
+        (0..program.len()).map(|index| vec![index]).collect()
     }
-    result
+
+    async fn compute_linear(
+        &self,
+        instructions: Vec<Indexed>,
+        contexts: &mut HashMap<Sid, HashMap<Vid, Shard>>,
+    ) -> Vec<ShardResult> {
+        let mut outputs = Vec::new();
+        for (shard_id, context) in contexts.iter() {
+            for (index, Instruction { operation, result }) in instructions.iter().cloned() {
+                let output = ShardProcessor::retrieve_operands(operation, context)
+                    .and_then(|operation| ShardProcessor::calculate(&operation));
+                outputs.push((index, shard_id.clone(), result, output));
+            }
+        }
+        outputs
+    }
+}
+
+/// Binary operations are defined only on data of the same length
+fn binary_data_len(operation: &BinaryOp<Shard>) -> Result<u64, Error> {
+    let len = operation.first.data_len();
+    if len != operation.second.data_len() {
+        return Err(Error::OperandLengthMismatch);
+    }
+    Ok(len)
+}
+
+/// Terms of linear combination are defined only on data of the same length
+fn combination_data_len(operation: &LinearCombination<Shard>) -> Result<u64, Error> {
+    let mut lengths = operation.terms.iter().map(|(_, shard)| shard.data_len());
+    let len = lengths.next().ok_or(Error::EmptyCombination)?;
+    if lengths.any(|other| other != len) {
+        return Err(Error::OperandLengthMismatch);
+    }
+    Ok(len)
 }
 
 impl ShardProcessor {
-    fn calculate(operation: &Operation<Shard>) -> Shard {
-        let array = match operation {
-            Operation::Sub(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
-                reed_solomon_erasure::galois_8::add,
+    pub(super) fn calculate(operation: &Operation<Shard>) -> Result<Shard, Error> {
+        let (bytes, data_len) = match operation {
+            Operation::Sub(operation) => (
+                map_zip(
+                    operation.first.as_ref(),
+                    operation.second.as_ref(),
+                    reed_solomon_erasure::galois_8::add,
+                )?,
+                binary_data_len(operation)?,
+            ),
+            Operation::Plus(operation) => (
+                map_zip(
+                    operation.first.as_ref(),
+                    operation.second.as_ref(),
+                    reed_solomon_erasure::galois_8::add,
+                )?,
+                binary_data_len(operation)?,
             ),
-            Operation::Plus(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
-                reed_solomon_erasure::galois_8::add,
+            Operation::Inv(operation) => (
+                operation.operand.as_ref().to_vec(),
+                operation.operand.data_len(),
             ),
-            Operation::Inv(operation) => operation.operand.as_inner().map(|n| n),
-            Operation::Nand(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
-                |a, b| (!(a != 0 && b != 0)) as u8,  // Convert bool to u8
+            // don't commute with encoding, computed on assembled data instead
+            Operation::Nand(_) | Operation::Nor(_) => return Err(Error::NotLinear),
+            Operation::ScalarMul(operation) => (
+                operation
+                    .operand
+                    .as_ref()
+                    .iter()
+                    .map(|x| reed_solomon_erasure::galois_8::mul(operation.coefficient, *x))
+                    .collect(),
+                operation.operand.data_len(),
+            ),
+            Operation::LinComb(operation) => (
+                linear_combination(
+                    operation
+                        .terms
+                        .iter()
+                        .map(|(coefficient, shard)| (*coefficient, shard.as_ref())),
+                )?,
+                combination_data_len(operation)?,
             ),
-            Operation::Nor(operation) => map_zip(
-                operation.first.as_inner(),
-                operation.second.as_inner(),
-                |a, b| (!(a != 0 || b != 0)) as u8,  // Convert bool to u8
-            ),            
         };
-        Shard(array)
-    }
-    
-
-    async fn retrieve_operand(
-        &self,
-        operand: Vid,
-        operand_context: Option<Shard>,
-    ) -> Result<Option<Shard>, Error> {
-        if let Some(o) = operand_context {
-            return Ok(Some(o));
-        }
-        self.memory_access.retrieve_local_shard(operand).await
+        Ok(Shard::new(data_len, bytes))
     }
 
-    async fn retrieve_binary(
-        &self,
+    fn retrieve_binary(
         binary: BinaryOp<Vid>,
         context: &HashMap<Vid, Shard>,
-    ) -> Result<Option<BinaryOp<Shard>>, Error> {
+    ) -> Option<BinaryOp<Shard>> {
         let BinaryOp { first, second } = binary;
-        let first_cx = context.get(&first).cloned();
-        let second_cx = context.get(&second).cloned();
-        // don't use heavy (?) join! macro if not necessary
-        match (first_cx, second_cx) {
-            (Some(first), Some(second)) => Ok(Some(BinaryOp { first, second })),
-            (Some(first), None) => {
-                let Some(second) = self.retrieve_operand(second, None).await? else {
-                    return Ok(None);
-                };
-                Ok(Some(BinaryOp { first, second }))
-            }
-            (None, Some(second)) => {
-                let Some(first) = self.retrieve_operand(first, None).await? else {
-                    return Ok(None);
-                };
-                Ok(Some(BinaryOp { first, second }))
-            }
-            (None, None) => {
-                let (first, second) = join!(
-                    self.retrieve_operand(first, None),
-                    self.retrieve_operand(second, None)
-                );
-                let operation = if let (Some(first), Some(second)) = (first?, second?) {
-                    Some(BinaryOp { first, second })
-                } else {
-                    None
-                };
-                Ok(operation)
-            }
-        }
+        Some(BinaryOp {
+            first: context.get(&first)?.clone(),
+            second: context.get(&second)?.clone(),
+        })
     }
 
-    async fn retrieve_unary(
-        &self,
-        unary: UnaryOp<Vid>,
+    fn retrieve_unary(unary: UnaryOp<Vid>, context: &HashMap<Vid, Shard>) -> Option<UnaryOp<Shard>> {
+        let operand = context.get(&unary.operand)?.clone();
+        Some(UnaryOp { operand })
+    }
+
+    fn retrieve_scalar(
+        scalar: ScalarOp<Vid>,
         context: &HashMap<Vid, Shard>,
-    ) -> Result<Option<UnaryOp<Shard>>, Error> {
-        let operand = unary.operand;
-        let cx = context.get(&operand);
-        let Some(operand) = self.retrieve_operand(operand, cx.cloned()).await? else {
-            return Ok(None);
-        };
-        Ok(Some(UnaryOp { operand }))
+    ) -> Option<ScalarOp<Shard>> {
+        let operand = context.get(&scalar.operand)?.clone();
+        Some(ScalarOp {
+            coefficient: scalar.coefficient,
+            operand,
+        })
     }
 
-    async fn retrieve_operands(
-        &self,
+    fn retrieve_combination(
+        combination: LinearCombination<Vid>,
+        context: &HashMap<Vid, Shard>,
+    ) -> Option<LinearCombination<Shard>> {
+        let terms = combination
+            .terms
+            .into_iter()
+            .map(|(coefficient, operand)| Some((coefficient, context.get(&operand)?.clone())))
+            .collect::<Option<_>>()?;
+        Some(LinearCombination { terms })
+    }
+
+    pub(super) fn retrieve_operands(
         op: Operation<Vid>,
         context: &HashMap<Vid, Shard>,
     ) -> Result<Operation<Shard>, Error> {
         let retrieved = match op {
             Operation::Sub(binary) => Operation::Sub(
-                self.retrieve_binary(binary, context)
-                    .await?
-                    .ok_or(Error::NoShardsAssigned)?,
+                Self::retrieve_binary(binary, context).ok_or(Error::NoShardsAssigned)?,
             ),
             Operation::Plus(binary) => Operation::Plus(
-                self.retrieve_binary(binary, context)
-                    .await?
-                    .ok_or(Error::NoShardsAssigned)?,
+                Self::retrieve_binary(binary, context).ok_or(Error::NoShardsAssigned)?,
             ),
             Operation::Inv(unary) => Operation::Inv(
-                self.retrieve_unary(unary, context)
-                    .await?
-                    .ok_or(Error::NoShardsAssigned)?,
+                Self::retrieve_unary(unary, context).ok_or(Error::NoShardsAssigned)?,
             ),
             Operation::Nand(binary) => Operation::Nand(
-                self.retrieve_binary(binary, context)
-                    .await?
-                    .ok_or(Error::NoShardsAssigned)?,
-            ),Operation::Nor(binary) => Operation::Nor(
-                self.retrieve_binary(binary, context)
-                    .await?
-                    .ok_or(Error::NoShardsAssigned)?,
+                Self::retrieve_binary(binary, context).ok_or(Error::NoShardsAssigned)?,
+            ),
+            Operation::Nor(binary) => Operation::Nor(
+                Self::retrieve_binary(binary, context).ok_or(Error::NoShardsAssigned)?,
+            ),
+            Operation::ScalarMul(scalar) => Operation::ScalarMul(
+                Self::retrieve_scalar(scalar, context).ok_or(Error::NoShardsAssigned)?,
+            ),
+            Operation::LinComb(combination) => Operation::LinComb(
+                Self::retrieve_combination(combination, context).ok_or(Error::NoShardsAssigned)?,
             ),
         };
         Ok(retrieved)
     }
 }
 
-#[derive(Error, Debug, Clone)]
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
 pub enum Error {
     #[error("Channel to memory for data requests was closed.")]
     DataRequestChannelClosed,
     #[error("Channel to memory for data writes was closed.")]
     DataWriteChannelClosed,
+    #[error("Channel to memory for confirming writes was closed.")]
+    SyncChannelClosed,
     #[error("Channel for getting response from memory bus was closed")]
     ResponseChannelClosed,
     #[error(transparent)]
     Encoding(#[from] encoding::mock::Error),
     #[error("This peer is not assigned to shards from the operation")]
     NoShardsAssigned,
+    #[error("Operands of the instruction have different lengths")]
+    OperandLengthMismatch,
+    #[error("Linear combination has no terms")]
+    EmptyCombination,
+    #[error("Non-linear operation can't be computed on shards")]
+    NotLinear,
+    #[error("Channel to memory for non-linear operations was closed.")]
+    PlaintextChannelClosed,
+    #[error("Result of non-linear operation was not received in time")]
+    PlaintextResultMissing,
+    #[error("Checkpoint of the program could not be read, running it again could repeat its writes")]
+    CheckpointUnreadable,
+}
+
+impl From<OperandError> for Error {
+    fn from(e: OperandError) -> Self {
+        match e {
+            OperandError::LengthMismatch => Error::OperandLengthMismatch,
+            OperandError::EmptyCombination => Error::EmptyCombination,
+        }
+    }
+}
+
+/// Error that happened during program execution
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+#[error("{error}")]
+pub struct Failure {
+    /// Index of the failed instruction, `None` if results could not be saved
+    pub instruction: Option<usize>,
+    pub error: Error,
+}
+
+impl Failure {
+    pub fn at(instruction: usize, error: Error) -> Self {
+        Self {
+            instruction: Some(instruction),
+            error,
+        }
+    }
+}
+
+/// Values of the program data, grouped by shard id. Data is loaded when an
+/// instruction uses it for the first time and is released after the last
+/// use, so only values needed later are kept in memory.
+struct Workspace {
+    contexts: HashMap<Sid, HashMap<Vid, Shard>>,
+    /// Data ids written since the last checkpoint, by shard id
+    written: HashMap<Sid, HashSet<Vid>>,
+    /// Data read by the program, not loaded from the storage yet
+    to_load: HashSet<Vid>,
+    /// Data ids not used after each step
+    released_after: Vec<Vec<Vid>>,
+}
+
+impl Workspace {
+    /// `steps` are groups of instructions executed one after another
+    fn new<'a, S>(steps: impl IntoIterator<Item = S>) -> Self
+    where
+        S: IntoIterator<Item = &'a Instruction<Vid, Vid>>,
+    {
+        let mut to_load = HashSet::new();
+        let mut last_use = HashMap::new();
+        let mut steps_count = 0;
+        for (step, instructions) in steps.into_iter().enumerate() {
+            for instruction in instructions {
+                let operands = instruction.operation.args_as_list();
+                to_load.extend(operands.iter().map(|data_id| (*data_id).clone()));
+                for data_id in operands.into_iter().chain([&instruction.result]) {
+                    last_use.insert(data_id.clone(), step);
+                }
+            }
+            steps_count = step + 1;
+        }
+        let mut released_after = vec![Vec::new(); steps_count];
+        for (data_id, step) in last_use {
+            released_after[step].push(data_id);
+        }
+        Self {
+            contexts: HashMap::new(),
+            written: HashMap::new(),
+            to_load,
+            released_after,
+        }
+    }
 }
 
 impl ShardProcessor {
-    /// pre-fetch operands
-    async fn prepare_context(&self, program: &Instructions) -> HashMap<Vid, Shard> {
-        let mut context = HashMap::new();
-        let needed_ids: HashSet<Vid> = program
-            .iter()
-            .flat_map(|ins| ins.operation.args_as_list())
-            .cloned()
-            .collect();
-        for id in needed_ids {
-            if let Ok(Some(val)) = self.retrieve_operand(id.clone(), None).await {
-                context.insert(id, val);
+    /// Load data used by the instructions for the first time (if the program
+    /// reads it at all), grouped by shard id
+    async fn load(
+        &self,
+        workspace: &mut Workspace,
+        instructions: &[&Instruction<Vid, Vid>],
+    ) {
+        let used = instructions.iter().flat_map(|instruction| {
+            instruction
+                .operation
+                .args_as_list()
+                .into_iter()
+                .chain([&instruction.result])
+        });
+        for data_id in used {
+            if !workspace.to_load.remove(data_id) {
+                continue;
+            }
+            if let Ok(shards) = self.memory_access.retrieve_local_shards(data_id.clone()).await {
+                for (shard_id, shard) in shards {
+                    workspace
+                        .contexts
+                        .entry(shard_id)
+                        .or_default()
+                        .insert(data_id.clone(), shard);
+                }
             }
         }
-        context
     }
 
-    async fn execute(
+    /// Drop data not used after the step. Results of the program are stored
+    /// right away or, if checkpoints are saved, at the next checkpoint (see
+    /// [`super::checkpoint`]).
+    async fn release(
         &self,
-        program: Instructions,
-        id: ProgramIdentifier,
-    ) -> Vec<Result<Vid, Error>> {
-        let mut context = self.prepare_context(&program).await;
-        let mut results = Vec::with_capacity(program.len());
-        debug!(target: Targets::ProgramExecution.into_str(), "Starting execution of program {:?}", id);
-        for instruction in program {
-            let Instruction {
-                operation,
-                result: result_id,
-            } = instruction;
-            let operation = match self.retrieve_operands(operation, &context).await {
-                Ok(o) => o,
-                Err(e) => {
-                    warn!("did not execute operation: {}", e);
-                    results.push(Err(e));
+        workspace: &mut Workspace,
+        step: usize,
+        checkpoint: &mut Checkpoint,
+    ) {
+        for data_id in std::mem::take(&mut workspace.released_after[step]) {
+            for (shard_id, context) in workspace.contexts.iter_mut() {
+                let Some(shard) = context.remove(&data_id) else {
+                    continue;
+                };
+                let is_written = workspace
+                    .written
+                    .get_mut(shard_id)
+                    .is_some_and(|written| written.remove(&data_id));
+                if !is_written {
+                    continue;
+                }
+                let full_shard_id = (data_id.clone(), shard_id.clone());
+                let checksum = shard.checksum();
+                if self.checkpoints.is_some() {
+                    checkpoint.pending.insert(full_shard_id.clone(), shard);
+                } else if let Err(e) = self
+                    .memory_access
+                    .store_local_shard(full_shard_id.clone(), shard)
+                    .await
+                {
+                    checkpoint.failures.push(Failure {
+                        instruction: None,
+                        error: e,
+                    });
                     continue;
                 }
+                checkpoint.stored.insert(full_shard_id, checksum);
+            }
+        }
+    }
+
+    /// Run the program on each shard id stored locally, continuing from the
+    /// checkpoint. Linear operations combine shards with the same id into the
+    /// resulting shard with this id. Non-linear ones are computed on assembled
+    /// data, this peer gets its shards of the result.
+    ///
+    /// Instructions are executed in steps given by `strategy`, data is loaded
+    /// and released step by step and checkpoints are saved between steps.
+    async fn execute<S: ComputeStrategy>(
+        &self,
+        strategy: &S,
+        mut checkpoint: Checkpoint,
+    ) -> Vec<Result<(FullShardId, Hash), Failure>> {
+        self.store_pending(&mut checkpoint).await;
+        let id = checkpoint.program.identifier().clone();
+        // instructions left after the checkpoint depend only on each other
+        // and on the stored data
+        let (indices, program): (Vec<usize>, Instructions) = checkpoint
+            .program
+            .instructions()
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(index, _)| !checkpoint.executed[*index])
+            .unzip();
+        let steps = strategy.steps(&program);
+        let mut workspace =
+            Workspace::new(steps.iter().map(|step| step.iter().map(|i| &program[*i])));
+        let mut since_checkpoint = 0;
+        debug!(target: Targets::ProgramExecution.into_str(), "Starting execution of program {:?}, {} instruction(s) left in {} step(s)", id, program.len(), steps.len());
+        for (step, step_instructions) in steps.into_iter().enumerate() {
+            let instructions: Vec<_> = step_instructions.iter().map(|i| &program[*i]).collect();
+            self.load(&mut workspace, &instructions).await;
+            let (linear, non_linear): (Vec<_>, Vec<_>) = step_instructions
+                .into_iter()
+                .map(|i| (indices[i], program[i].clone()))
+                .partition(|(_, instruction)| instruction.operation.is_linear());
+            let step_indices: Vec<usize> = linear
+                .iter()
+                .chain(&non_linear)
+                .map(|(index, _)| *index)
+                .collect();
+            let outputs = strategy
+                .compute_linear(linear, &mut workspace.contexts)
+                .await;
+            // operands of non-linear instructions are not written in this
+            // step, so it's fine to compute them before saving the outputs
+            for (index, instruction) in non_linear {
+                let execution_step = ExecutionStep {
+                    program: id.clone(),
+                    index: index.try_into().unwrap(),
+                };
+                match self
+                    .execute_on_plaintext(execution_step, instruction, &mut workspace.contexts)
+                    .await
+                {
+                    Ok(computed) => {
+                        for (shard_id, result_id) in computed {
+                            workspace
+                                .written
+                                .entry(shard_id)
+                                .or_default()
+                                .insert(result_id);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("did not execute operation: {}", e);
+                        checkpoint.failures.push(Failure::at(index, e));
+                    }
+                }
+            }
+            for (index, shard_id, result_id, output) in outputs {
+                match output {
+                    Ok(output) => {
+                        let context = workspace
+                            .contexts
+                            .get_mut(&shard_id)
+                            .expect("computed for this context");
+                        context.insert(result_id.clone(), output);
+                        workspace
+                            .written
+                            .entry(shard_id)
+                            .or_default()
+                            .insert(result_id);
+                    }
+                    Err(e) => {
+                        warn!("did not execute operation: {}", e);
+                        checkpoint.failures.push(Failure::at(index, e));
+                    }
+                }
+            }
+            self.release(&mut workspace, step, &mut checkpoint).await;
+            since_checkpoint += step_indices.len();
+            for index in step_indices {
+                checkpoint.executed[index] = true;
+            }
+            if self.checkpoint_due(since_checkpoint) {
+                since_checkpoint = 0;
+                self.checkpoint(&mut checkpoint, &mut workspace).await;
+            }
+        }
+        self.checkpoint(&mut checkpoint, &mut workspace).await;
+        self.finish(checkpoint)
+    }
+
+    fn checkpoint_due(&self, executed_since_checkpoint: usize) -> bool {
+        self.checkpoints.is_some() && executed_since_checkpoint >= CHECKPOINT_INTERVAL
+    }
+
+    /// Save the checkpoint with shards written since the previous one, then
+    /// store them (see [`super::checkpoint`])
+    async fn checkpoint(&self, checkpoint: &mut Checkpoint, workspace: &mut Workspace) {
+        for (shard_id, result_ids) in workspace.written.drain() {
+            let Some(context) = workspace.contexts.get(&shard_id) else {
+                continue;
             };
-            let output = Self::calculate(&operation);
-            context.insert(result_id.clone(), output.clone());
-            results.push(Ok(result_id));
+            for result_id in result_ids {
+                let Some(shard) = context.get(&result_id) else {
+                    continue;
+                };
+                let full_shard_id = (result_id, shard_id.clone());
+                checkpoint
+                    .stored
+                    .insert(full_shard_id.clone(), shard.checksum());
+                checkpoint.pending.insert(full_shard_id, shard.clone());
+            }
+        }
+        if let Some(file) = &self.checkpoints {
+            debug!(target: Targets::ProgramExecution.into_str(), "Saving checkpoint of program {:?} with {} instruction(s) executed", checkpoint.program.identifier(), checkpoint.executed_count());
+            if let Err(e) = file.save(checkpoint) {
+                // the previous checkpoint doesn't match the storage after the writes
+                error!("could not save checkpoint, the program won't be resumed after restart: {}", e);
+                if let Err(e) = file.clear() {
+                    error!("could not remove outdated checkpoint: {}", e);
+                }
+            }
+        }
+        self.store_pending(checkpoint).await;
+    }
+
+    /// Store shards written at the checkpoint and wait until they are in the
+    /// storage
+    async fn store_pending(&self, checkpoint: &mut Checkpoint) {
+        if checkpoint.pending.is_empty() {
+            return;
         }
-        debug!(target: Targets::ProgramExecution.into_str(), "Saving results of execution of program {:?}", id);
-        for (result_id, output) in context {
+        for (full_shard_id, shard) in std::mem::take(&mut checkpoint.pending) {
             if let Err(e) = self
                 .memory_access
-                .store_local_shard(result_id.clone(), output)
+                .store_local_shard(full_shard_id.clone(), shard)
                 .await
             {
-                results.push(Err(e));
-                continue;
-            };
+                checkpoint.stored.remove(&full_shard_id);
+                checkpoint.failures.push(Failure {
+                    instruction: None,
+                    error: e,
+                });
+            }
         }
-        results
+        if let Err(e) = self.memory_access.sync().await {
+            checkpoint.failures.push(Failure {
+                instruction: None,
+                error: e,
+            });
+        }
+    }
+
+    /// Stored results of the finished program with their checksums, along
+    /// with the failures. The final checkpoint is kept until the next
+    /// program, so the results are reported again if the program is given
+    /// after restart.
+    fn finish(&self, checkpoint: Checkpoint) -> Vec<Result<(FullShardId, Hash), Failure>> {
+        if let Some(file) = &self.checkpoints {
+            if let Err(e) = file.save(&checkpoint) {
+                error!("could not save checkpoint of finished program, it may be executed again after restart: {}", e);
+                if let Err(e) = file.clear() {
+                    error!("could not remove outdated checkpoint: {}", e);
+                }
+            }
+        }
+        checkpoint
+            .stored
+            .into_iter()
+            .map(Ok)
+            .chain(checkpoint.failures.into_iter().map(Err))
+            .collect()
+    }
+
+    /// Checkpoint of the program executed before restart, if any
+    fn restored_checkpoint(&self) -> Result<Option<Checkpoint>, checkpoint::Error> {
+        let Some(file) = &self.checkpoints else {
+            return Ok(None);
+        };
+        match file.load() {
+            Ok(Some(checkpoint)) => {
+                info!(
+                    "Restored checkpoint of program {:?}, {} of {} instruction(s) are executed",
+                    checkpoint.program.identifier(),
+                    checkpoint.executed_count(),
+                    checkpoint.executed.len()
+                );
+                Ok(Some(checkpoint))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                error!("could not load checkpoint, the program given after restart will be failed: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Fail the program instead of running it from the start, its progress
+    /// before restart is unknown
+    fn fail_unresumable(
+        &self,
+        program_id: &ProgramIdentifier,
+    ) -> Vec<Result<(FullShardId, Hash), Failure>> {
+        warn!("failing program {:?} since its checkpoint is unreadable", program_id);
+        if let Some(file) = &self.checkpoints {
+            if let Err(e) = file.clear() {
+                error!("could not remove unreadable checkpoint: {}", e);
+            }
+        }
+        vec![Err(Failure {
+            instruction: None,
+            error: Error::CheckpointUnreadable,
+        })]
+    }
+
+    /// Send local operand shards of the non-linear instruction to be computed
+    /// on assembled data and put the received result shards into contexts.
+    /// Returns ids of the shards computed.
+    async fn execute_on_plaintext(
+        &self,
+        step: ExecutionStep,
+        instruction: Instruction<Vid, Vid>,
+        contexts: &mut HashMap<Sid, HashMap<Vid, Shard>>,
+    ) -> Result<Vec<(Sid, Vid)>, Error> {
+        let operands: Vec<_> = contexts
+            .iter()
+            .flat_map(|(shard_id, context)| {
+                instruction
+                    .operation
+                    .args_as_list()
+                    .into_iter()
+                    .filter_map(|data_id| {
+                        let shard = context.get(data_id)?.clone();
+                        Some(((data_id.clone(), shard_id.clone()), shard))
+                    })
+            })
+            .collect();
+        if operands.is_empty() {
+            return Err(Error::NoShardsAssigned);
+        }
+        debug!(target: Targets::ProgramExecution.into_str(), "Computing non-linear instruction {} of program {:?} on assembled data", step.index, step.program);
+        let result_id = instruction.result.clone();
+        let shards = self
+            .memory_access
+            .compute_on_plaintext(step, instruction, operands)
+            .await?;
+        let mut computed = Vec::with_capacity(shards.len());
+        for (shard_id, shard) in shards {
+            contexts
+                .entry(shard_id.clone())
+                .or_default()
+                .insert(result_id.clone(), shard);
+            computed.push((shard_id, result_id.clone()));
+        }
+        Ok(computed)
     }
 }
 
 impl ShardProcessor {
-    pub fn new(bus: MemoryBus) -> Self {
-        Self { memory_access: bus }
+    pub fn new(bus: MemoryBus, checkpoints: Option<CheckpointFile>) -> Self {
+        Self {
+            memory_access: bus,
+            checkpoints,
+        }
     }
 
-    pub async fn run(self, mut connection: ModuleChannelServer<Module>) {
+    pub async fn run(self, connection: ModuleChannelServer<Module>) {
+        self.serve(Sequential, connection).await
+    }
+
+    /// Execute programs received through `connection` computing them with
+    /// `strategy`. If the program saved in the checkpoint is received again
+    /// (instruction memory gives the started one after restart), it's
+    /// continued from the checkpoint, or only its results are reported if
+    /// it's finished already.
+    pub(super) async fn serve<S: ComputeStrategy>(
+        self,
+        strategy: S,
+        mut connection: ModuleChannelServer<Module>,
+    ) {
+        let mut restored = self.restored_checkpoint();
         loop {
             tokio::select! {
                 in_event = connection.input.recv() => {
@@ -330,8 +816,15 @@ impl ShardProcessor {
                     match in_event {
                         InEvent::Execute(program) => {
                             connection.set_state(ModuleState::Executing);
-                            let (instructions, program_id) = program.into_parts();
-                            let results = self.execute(instructions, program_id.clone()).await;
+                            let program_id = program.identifier().clone();
+                            let results = match std::mem::replace(&mut restored, Ok(None)) {
+                                Ok(Some(checkpoint)) if checkpoint.program.identifier() == &program_id => {
+                                    info!("Continuing program {:?} from checkpoint", program_id);
+                                    self.execute(&strategy, checkpoint).await
+                                }
+                                Err(_) => self.fail_unresumable(&program_id),
+                                _ => self.execute(&strategy, Checkpoint::start(program)).await,
+                            };
                             if (connection
                                 .output
                                 .send(OutEvent::FinishedExecution {
@@ -356,3 +849,72 @@ impl ShardProcessor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Instruction, ShardProcessor, Workspace};
+    use crate::{
+        encoding::{
+            reed_solomon::{ReedSolomonWrapper, Settings},
+            DataEncoding,
+        },
+        processor::{mock::MockProcessor, Program},
+        types::{Data, Hash, Vid},
+    };
+
+    #[test]
+    fn linear_operations_commute_with_encoding() {
+        let encoding = ReedSolomonWrapper::new(Settings {
+            data_shards_total: 3,
+            data_shards_sufficient: 2,
+            max_shard_size: 5_000,
+        });
+        let first = Data((0..100).map(|i| (i * 7 % 256) as u8).collect());
+        let second = Data((0..100).map(|i| (i * 13 % 256) as u8).collect());
+        let instructions = vec![
+            Instruction::scalar_mul(3, Vid(1), Vid(3)),
+            Instruction::lin_comb(vec![(2, Vid(1)), (7, Vid(2)), (1, Vid(3))], Vid(4)),
+        ];
+
+        let mut data_storage = HashMap::from([(Vid(1), first.clone()), (Vid(2), second.clone())]);
+        let program = Program::new(instructions.clone(), Hash::from_array([0; 64])).unwrap();
+        MockProcessor::execute_on(program, &mut data_storage).unwrap();
+
+        let first = encoding.encode(first).unwrap();
+        let second = encoding.encode(second).unwrap();
+        let mut results = HashMap::new();
+        for (shard_id, first_shard) in first {
+            let mut context =
+                HashMap::from([(Vid(1), first_shard), (Vid(2), second[&shard_id].clone())]);
+            for Instruction { operation, result } in instructions.clone() {
+                let operation = ShardProcessor::retrieve_operands(operation, &context).unwrap();
+                let value = ShardProcessor::calculate(&operation).unwrap();
+                context.insert(result, value);
+            }
+            results.insert(shard_id, context.remove(&Vid(4)).unwrap());
+        }
+        assert_eq!(encoding.decode(results).unwrap(), data_storage[&Vid(4)]);
+    }
+
+    #[test]
+    fn data_is_released_after_last_use() {
+        let program = [
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::scalar_mul(2, Vid(3), Vid(4)),
+            Instruction::plus(Vid(4), Vid(1), Vid(1)),
+            Instruction::inv(Vid(5), Vid(2)),
+        ];
+        let workspace = Workspace::new(program.iter().map(std::iter::once));
+        let mut released = workspace.released_after;
+        released.iter_mut().for_each(|step| step.sort_by_key(|data_id| data_id.0));
+        assert_eq!(
+            released,
+            vec![vec![], vec![Vid(3)], vec![Vid(1), Vid(4)], vec![Vid(2), Vid(5)]]
+        );
+        let mut to_load: Vec<_> = workspace.to_load.into_iter().collect();
+        to_load.sort_by_key(|data_id| data_id.0);
+        assert_eq!(to_load, vec![Vid(1), Vid(2), Vid(3), Vid(4), Vid(5)]);
+    }
+}
@@ -0,0 +1,142 @@
+//! Programs with formal data id parameters.
+//!
+//! A template is published in consensus once and then invoked with actual
+//! data ids bound to its parameters, so the same instructions don't have to
+//! be sent for each set of data. Data ids of the template that are not
+//! parameters are used as they are.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::types::{Hash, Vid};
+
+use super::{Instructions, Program};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("Template has {expected} parameter(s), but {got} binding(s) are given")]
+    WrongBindingCount { expected: usize, got: usize },
+    #[error("Parameter {0:?} is declared more than once")]
+    DuplicateParameter(Vid),
+    #[error("Could not serialize the template: {0}")]
+    Serialization(String),
+}
+
+impl From<bincode::Error> for Error {
+    fn from(value: bincode::Error) -> Self {
+        Error::Serialization(value.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
+pub struct Template {
+    /// Formal parameters, bound to actual data ids in this order
+    parameters: Vec<Vid>,
+    instructions: Instructions,
+}
+
+impl Template {
+    pub fn new(parameters: Vec<Vid>, instructions: Instructions) -> Result<Self, Error> {
+        let mut declared = HashSet::new();
+        if let Some(duplicate) = parameters.iter().find(|p| !declared.insert(*p)) {
+            return Err(Error::DuplicateParameter(duplicate.clone()));
+        }
+        Ok(Self {
+            parameters,
+            instructions,
+        })
+    }
+
+    pub fn parameters(&self) -> &[Vid] {
+        &self.parameters
+    }
+
+    pub fn instructions(&self) -> &Instructions {
+        &self.instructions
+    }
+
+    pub fn hash(&self) -> bincode::Result<Hash> {
+        Ok(Program::digest(&[&bincode::serialize(self)?]))
+    }
+
+    /// Instructions with parameters replaced by `bindings`
+    pub fn instantiate(&self, bindings: &[Vid]) -> Result<Instructions, Error> {
+        if bindings.len() != self.parameters.len() {
+            return Err(Error::WrongBindingCount {
+                expected: self.parameters.len(),
+                got: bindings.len(),
+            });
+        }
+        let bind = |data_id: Vid| match self.parameters.iter().position(|p| p == &data_id) {
+            Some(i) => bindings[i].clone(),
+            None => data_id,
+        };
+        Ok(self
+            .instructions
+            .iter()
+            .cloned()
+            .map(|instruction| {
+                let mut instruction = instruction.map_operands(bind);
+                instruction.result = bind(instruction.result);
+                instruction
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Template};
+    use crate::{
+        processor::{Instruction, Program},
+        types::{Hash, Vid},
+    };
+
+    #[test]
+    fn template_is_instantiated_with_bindings() {
+        let template = Template::new(
+            vec![Vid(100), Vid(101)],
+            vec![
+                Instruction::plus(Vid(100), Vid(1), Vid(101)),
+                Instruction::scalar_mul(3, Vid(101), Vid(100)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            template.instantiate(&[Vid(5), Vid(6)]),
+            Ok(vec![
+                Instruction::plus(Vid(5), Vid(1), Vid(6)),
+                Instruction::scalar_mul(3, Vid(6), Vid(5)),
+            ])
+        );
+        assert_eq!(
+            template.instantiate(&[Vid(5)]),
+            Err(Error::WrongBindingCount {
+                expected: 2,
+                got: 1
+            })
+        );
+
+        // invocations differ by bindings, templates by parameters
+        let event_hash = Hash::from_array([0; 64]);
+        let first = Program::from_template(&template, &[Vid(5), Vid(6)], event_hash.clone());
+        let second = Program::from_template(&template, &[Vid(6), Vid(5)], event_hash.clone());
+        assert_ne!(first.unwrap().identifier(), second.unwrap().identifier());
+        let same_body = Template::new(
+            vec![Vid(100)],
+            vec![
+                Instruction::plus(Vid(100), Vid(1), Vid(101)),
+                Instruction::scalar_mul(3, Vid(101), Vid(100)),
+            ],
+        )
+        .unwrap();
+        assert_ne!(same_body.hash().unwrap(), template.hash().unwrap());
+
+        assert_eq!(
+            Template::new(vec![Vid(1), Vid(1)], vec![]),
+            Err(Error::DuplicateParameter(Vid(1)))
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Static checks of programs before they are scheduled.
+//!
+//! Once `Execute` transaction is finalized, every peer executes the program,
+//! so mistakes should be found before it is published. A program is rejected
+//! if it reads data that is neither stored nor computed by an earlier
+//! instruction, if it overwrites a result of its own before anything reads
+//! it, or if it is too large to be sent in consensus.
+
+use std::collections::{HashMap, HashSet};
+
+use thiserror::Error;
+
+use crate::types::Vid;
+
+use super::Instructions;
+
+/// Largest serialized program, so that it fits into a gossip message
+/// together with the rest of the event
+pub const MAX_PROGRAM_SIZE: u64 = 768 * 1024;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("Program takes {size} bytes, at most {max} bytes are allowed")]
+    TooLarge { size: u64, max: u64 },
+    #[error("Instruction {index} reads {data_id:?}, which is neither stored nor computed earlier")]
+    UndefinedOperand { index: usize, data_id: Vid },
+    #[error("Result of instruction {index} is overwritten by instruction {by} before it is read")]
+    LostResult { index: usize, by: usize },
+    #[error("Could not serialize the program: {0}")]
+    Serialization(String),
+}
+
+/// Check that the value fits into a consensus transaction
+pub fn check_size<T: serde::Serialize>(value: &T) -> Result<(), Error> {
+    let size = bincode::serialized_size(value).map_err(|e| Error::Serialization(e.to_string()))?;
+    if size > MAX_PROGRAM_SIZE {
+        return Err(Error::TooLarge {
+            size,
+            max: MAX_PROGRAM_SIZE,
+        });
+    }
+    Ok(())
+}
+
+/// Check the program against data ids stored in the system
+pub fn validate(program: &Instructions, stored: &HashSet<Vid>) -> Result<(), Error> {
+    check_size(program)?;
+    // results of earlier instructions, not read yet
+    let mut unread: HashMap<&Vid, usize> = HashMap::new();
+    let mut defined: HashSet<&Vid> = stored.iter().collect();
+    for (index, instruction) in program.iter().enumerate() {
+        for data_id in instruction.operation.args_as_list() {
+            if !defined.contains(data_id) {
+                return Err(Error::UndefinedOperand {
+                    index,
+                    data_id: data_id.clone(),
+                });
+            }
+            unread.remove(data_id);
+        }
+        if let Some(overwritten) = unread.insert(&instruction.result, index) {
+            return Err(Error::LostResult {
+                index: overwritten,
+                by: index,
+            });
+        }
+        defined.insert(&instruction.result);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{validate, Error, MAX_PROGRAM_SIZE};
+    use crate::{processor::Instruction, types::Vid};
+
+    #[test]
+    fn bad_programs_are_rejected() {
+        let stored = HashSet::from([Vid(1), Vid(2)]);
+        let program = vec![
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::inv(Vid(3), Vid(3)),
+            Instruction::plus(Vid(1), Vid(3), Vid(1)),
+        ];
+        assert_eq!(validate(&program, &stored), Ok(()));
+
+        let program = vec![
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::sub(Vid(3), Vid(4), Vid(5)),
+        ];
+        assert_eq!(
+            validate(&program, &stored),
+            Err(Error::UndefinedOperand {
+                index: 1,
+                data_id: Vid(4)
+            })
+        );
+
+        let program = vec![
+            Instruction::plus(Vid(1), Vid(2), Vid(3)),
+            Instruction::sub(Vid(1), Vid(2), Vid(3)),
+        ];
+        assert_eq!(
+            validate(&program, &stored),
+            Err(Error::LostResult { index: 0, by: 1 })
+        );
+
+        let program = vec![Instruction::plus(Vid(1), Vid(2), Vid(1)); 50_000];
+        assert!(matches!(
+            validate(&program, &stored),
+            Err(Error::TooLarge {
+                max: MAX_PROGRAM_SIZE,
+                ..
+            })
+        ));
+    }
+}
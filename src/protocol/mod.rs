@@ -11,7 +11,12 @@
 // use futures::io;
 use serde::{Deserialize, Serialize};
 
-use crate::types::{GraphSync, Shard, Sid, Vid};
+use crate::{
+    behaviour::snapshot::SignedSnapshot,
+    consensus::ConsensusSync,
+    data_memory::{audit::Challenge, plaintext::ExecutionStep},
+    types::{Hash, Shard, Sid, Vid},
+};
 
 pub mod one_shot;
 pub mod request_response;
@@ -24,6 +29,11 @@ pub enum Request {
     GetShard((Vid, Sid)),
     /// "I want to store this shard that you distribute, please serve it to me".
     ServeShard((Vid, Sid)),
+    /// "Prove that you still store shard `Sid` for data `Vid`".
+    /// For storage audits.
+    Audit((Vid, Sid), Challenge),
+    /// "Give me your current state". For fast sync of peers joining later.
+    Snapshot,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -32,12 +42,24 @@ pub enum Response {
     GetShard(Option<Shard>),
     /// Shard that was requested (or `None` if shard `Vid, Sid` is not currently in distribution)
     ServeShard(Option<Shard>),
+    /// Answer to the challenge (or `None` if the shard is not stored)
+    Audit(Option<Hash>),
+    /// State of the peer (or `None` if it can't provide one now)
+    Snapshot(Option<SignedSnapshot>),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum Simple {
-    /// Graph state update according to consensus
-    GossipGraph(GraphSync),
+    /// Consensus state update (graph sync in case of hashgraph)
+    GossipGraph(ConsensusSync),
+    /// The receiver is more than a generation behind the sender, so it
+    /// can't be synced by gossip and has to fast-sync
+    TooFarBehind { generation: u64 },
+    /// Operand shards of a non-linear instruction, sent to the peer
+    /// computing it
+    PlaintextOperands(ExecutionStep, Vec<((Vid, Sid), Shard)>),
+    /// Result shards of a non-linear instruction, sent to their holder
+    PlaintextResult(ExecutionStep, Vec<((Vid, Sid), Shard)>),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -5,6 +5,9 @@ use thiserror::Error;
 
 use super::{versions::RequestResponseVersion, Request, Response};
 
+/// Maximum size of a serialized request or response
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub struct SwarmRequestResponse;
 
@@ -27,7 +30,7 @@ impl libp2p_request_response::Codec for SwarmRequestResponse {
     where
         T: AsyncRead + Send + Unpin,
     {
-        let bytes = upgrade::read_length_prefixed(io, 1024 * 1024).await?;
+        let bytes = upgrade::read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
         let request = bincode::deserialize(&bytes)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         Ok(request)
@@ -41,7 +44,7 @@ impl libp2p_request_response::Codec for SwarmRequestResponse {
     where
         T: AsyncRead + Send + Unpin,
     {
-        let bytes = upgrade::read_length_prefixed(io, 1024 * 1024).await?;
+        let bytes = upgrade::read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
         let response = bincode::deserialize(&bytes)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         Ok(response)
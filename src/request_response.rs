@@ -28,6 +28,8 @@ pub enum OutEvent {
         request: protocol::Request,
         channel: ResponseChannel<protocol::Response>,
     },
+    /// Could not reach the peer to make the request
+    PeerUnreachable { request_id: RequestId, peer: PeerId },
 }
 
 #[derive(Debug)]
@@ -88,6 +90,22 @@ pub async fn handle_request_response_event(
                 }
             }
         },
+        libp2p_request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error: libp2p_request_response::OutboundFailure::DialFailure,
+        } => {
+            warn!("{:?}", event);
+            if (request_response_bus
+                .output
+                .send(OutEvent::PeerUnreachable { request_id, peer })
+                .await)
+                .is_err()
+            {
+                error!("other half of `request_response_bus.output` was closed. no reason to operate without main behaviour.");
+                return Err(SendError(()));
+            }
+        }
         libp2p_request_response::Event::OutboundFailure {
             peer: _,
             request_id: _,
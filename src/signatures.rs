@@ -5,6 +5,15 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, PartialEq, Eq, std::hash::Hash, Debug, Clone)]
 pub struct EncodedEd25519Pubkey([u8; 32]);
 
+impl EncodedEd25519Pubkey {
+    /// Decode the key received from an untrusted peer
+    pub fn try_decode(
+        &self,
+    ) -> Result<libp2p::identity::ed25519::PublicKey, libp2p::identity::DecodingError> {
+        libp2p::identity::ed25519::PublicKey::decode(&self.0)
+    }
+}
+
 impl From<EncodedEd25519Pubkey> for libp2p::identity::ed25519::PublicKey {
     fn from(val: EncodedEd25519Pubkey) -> Self {
         libp2p::identity::ed25519::PublicKey::decode(&val.0).expect("signature parse failure")
@@ -33,7 +42,8 @@ impl TryFrom<libp2p::identity::PublicKey> for EncodedEd25519Pubkey {
         // TODO: use `From<ed25519::PublicKey> for PublicKey` when released
         // https://github.com/libp2p/rust-libp2p/pull/3866
         #[allow(deprecated, irrefutable_let_patterns)]
-        let libp2p::identity::PublicKey::Ed25519(key) = value else {
+        let libp2p::identity::PublicKey::Ed25519(key) = value
+        else {
             return Err(());
         };
         Ok(Self::from(key))
@@ -1,3 +1,4 @@
+use blake2::{Blake2b512, Digest};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
@@ -11,53 +12,56 @@ pub struct Vid(pub u64);
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub struct Sid(pub u64);
 
-#[cfg(all(feature = "big-array", feature = "medium-array"))]
-compile_error!("'big-array' and 'medium-array' features are mutually exclusive");
-
-#[cfg(feature = "big-array")]
-pub const SHARD_BYTES_NUMBER: u64 = 2u64.pow(14);
-#[cfg(feature = "medium-array")]
-pub const SHARD_BYTES_NUMBER: u64 = 64;
-#[cfg(all(not(feature = "big-array"), not(feature = "medium-array")))]
-pub const SHARD_BYTES_NUMBER: u64 = 4;
-// parity shards are configured dynamically
-pub const DATA_SHARDS_COUNT: u64 = 2;
-
 /// Type/struct that represents unit of data stored on nodes.
-/// Should be actual data shard (erasure coded) in the future, but
-/// right now for demonstration purposes, represents vector(array) of size 4.
+///
+/// Shards of the same data unit have equal length; the data is zero-padded
+/// to fit. `data_len` records the length of the original (unpadded) data, so
+/// it can be restored on decoding.
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
-pub struct Shard(#[serde(with = "BigArray")] pub [u8; SHARD_BYTES_NUMBER as usize]);
+pub struct Shard {
+    data_len: u64,
+    bytes: Vec<u8>,
+}
 
 impl Shard {
-    pub fn as_inner(&self) -> &[u8; SHARD_BYTES_NUMBER as usize] {
-        &self.0
+    pub fn new(data_len: u64, bytes: Vec<u8>) -> Self {
+        Self { data_len, bytes }
+    }
+
+    /// Length of the original data this shard was produced from.
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Hash of the shard contents. Announced in consensus to detect corrupted
+    /// shards when they are received.
+    pub fn checksum(&self) -> Hash {
+        let mut hasher = Blake2b512::new();
+        hasher.update(self.data_len.to_be_bytes());
+        hasher.update(&self.bytes);
+        Hash::from_array(hasher.finalize().into())
     }
 }
 
 impl AsRef<[u8]> for Shard {
     fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+        self.bytes.as_ref()
     }
 }
 
 impl AsMut<[u8]> for Shard {
     fn as_mut(&mut self) -> &mut [u8] {
-        self.0.as_mut()
+        self.bytes.as_mut()
     }
 }
 
-/// for now just splits into three parts
+/// Data unit of arbitrary length, split into shards for storage.
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
-pub struct Data(
-    #[serde(with = "BigArray")] pub [u8; (SHARD_BYTES_NUMBER * DATA_SHARDS_COUNT) as usize],
-);
-
-impl Data {
-    pub fn as_inner(&self) -> &[u8; (SHARD_BYTES_NUMBER * DATA_SHARDS_COUNT) as usize] {
-        &self.0
-    }
-}
+pub struct Data(pub Vec<u8>);
 
 impl AsRef<[u8]> for Data {
     fn as_ref(&self) -> &[u8] {
@@ -130,6 +134,13 @@ impl Hash {
     pub const fn from_array(inner: [u8; 64]) -> Self {
         Hash { inner }
     }
+
+    pub fn to_hex(&self) -> String {
+        self.inner
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
 }
 
 impl From<rust_hashgraph::algorithm::event::Hash> for Hash {
@@ -9,9 +9,10 @@ use tracing::{info, warn};
 
 use crate::{
     behaviour::{self, metrics::Metrics, InEvent},
-    io::{read_input, InputData, InputProgram},
+    instruction_storage::ProgramStatus,
+    io::{assembly, read_input, read_program, read_template, InputData},
     module::ModuleChannelClient,
-    processor::{mock::MockProcessor, Program},
+    processor::{mock::MockProcessor, template::Template, Program, ProgramIdentifier},
     types::{Data, Hash, Sid, Vid},
 };
 
@@ -26,6 +27,48 @@ fn print_all_stored(list: Vec<(Vid, HashMap<Sid, PeerId>)>) {
     }
 }
 
+fn print_programs(programs: Vec<(ProgramIdentifier, ProgramStatus)>) {
+    if programs.is_empty() {
+        println!("No such programs");
+        return;
+    }
+    for (id, status) in programs {
+        println!(
+            "{} - {:?}, executed by {}/{} peers",
+            id.short(),
+            status.state,
+            status.confirmations,
+            status.threshold
+        );
+        for failure in status.failures {
+            match failure.instruction {
+                Some(index) => println!("\tinstruction {}: {}", index, failure.error),
+                None => println!("\tsaving results: {}", failure.error),
+            }
+        }
+    }
+}
+
+fn print_templates(templates: Vec<(Hash, Template)>) {
+    if templates.is_empty() {
+        println!("No such templates");
+        return;
+    }
+    for (hash, template) in templates {
+        let parameters: Vec<_> = template
+            .parameters()
+            .iter()
+            .map(|p| format!("v{}", p.0))
+            .collect();
+        println!(
+            "{} - parameters ({}), {} instruction(s)",
+            &hash.to_hex()[..16],
+            parameters.join(", "),
+            template.instructions().len()
+        );
+    }
+}
+
 fn print_metrics_field(name: String, data_points: Vec<(f32, f32)>) {
     println!("{}", name);
     let Some((t_last, _)) = data_points.last() else {
@@ -50,14 +93,55 @@ fn print_metrics(metrics: Metrics) {
         .map(|(t, size)| (t, size as f32))
         .collect();
     print_metrics_field("Consensus input queue".to_string(), data);
+    println!("Storage audits (passed/failed):");
+    for (peer, results) in metrics.audits {
+        println!("\t{:?} - {}/{}", peer, results.passed, results.failed);
+    }
+    println!("Program result checks (agreed/disagreed):");
+    for (peer, results) in metrics.result_checks {
+        println!("\t{:?} - {}/{}", peer, results.agreed, results.disagreed);
+    }
+    println!("Rejected transactions:");
+    for (peer, misbehaviour) in metrics.misbehaviour {
+        println!("\t{:?} - {}", peer, misbehaviour.rejected);
+        if let Some(latest) = misbehaviour.evidence.back() {
+            println!(
+                "\t\tlatest: {} in event {}: {}",
+                latest.tx,
+                &latest.event_hash.to_hex()[..16],
+                latest.reason
+            );
+        }
+    }
 }
 
 async fn handle_responses(mut output: Receiver<behaviour::OutEvent>) {
     while let Some(next) = output.recv().await {
         match next {
             behaviour::OutEvent::ScheduleOk => println!("Program scheduled successfully"),
+            behaviour::OutEvent::ScheduleRejected(e) => println!("Program was not scheduled: {}", e),
             behaviour::OutEvent::ProgramExecuted(id) => {
-                println!("Program {:?} finished execution", id)
+                println!("Program {} finished execution", id.short())
+            }
+            behaviour::OutEvent::Programs(programs) => print_programs(programs),
+            behaviour::OutEvent::CancelScheduled(id) => {
+                println!("Cancellation of program {} is scheduled", id.short())
+            }
+            behaviour::OutEvent::CancelRejected { prefix, reason } => {
+                println!("Could not cancel program {}: {}", prefix, reason)
+            }
+            behaviour::OutEvent::TemplateRegistrationScheduled(hash) => {
+                println!(
+                    "Template {} is scheduled for registration",
+                    &hash.to_hex()[..16]
+                )
+            }
+            behaviour::OutEvent::TemplateRegistrationRejected(e) => {
+                println!("Template was not registered: {}", e)
+            }
+            behaviour::OutEvent::Templates(templates) => print_templates(templates),
+            behaviour::OutEvent::TemplateRejected { prefix, reason } => {
+                println!("Could not schedule template {}: {}", prefix, reason)
             }
             behaviour::OutEvent::GetResponse(Ok(data)) => {
                 println!("Retrieved data with id {:?}: {:?}", data.0, data.1)
@@ -68,7 +152,26 @@ async fn handle_responses(mut output: Receiver<behaviour::OutEvent>) {
                 id
             ),
             behaviour::OutEvent::ListStoredResponse(list) => print_all_stored(list),
+            behaviour::OutEvent::PutRejected(id, e) => {
+                println!("Could not distribute data with id {:?}: {}", id, e)
+            }
+            behaviour::OutEvent::Deleted(id) => println!("Data with id {:?} was deleted", id),
             behaviour::OutEvent::StorageInitialized => println!("Storage initialized"),
+            behaviour::OutEvent::StorageInitializationRejected { local, cluster } => println!(
+                "Storage was initialized with encoding settings {:?}, but this node uses {:?}. \
+                Not joining the storage; restart with matching settings.",
+                cluster, local
+            ),
+            behaviour::OutEvent::DistributionChanged(epoch) => {
+                println!("Storage members changed, now at epoch {}", epoch)
+            }
+            behaviour::OutEvent::FastSyncStarted { generation } => println!(
+                "This node is too far behind to catch up by gossip, continuing from generation {} with a snapshot of the state",
+                generation
+            ),
+            behaviour::OutEvent::SnapshotInstalled => {
+                println!("Installed state snapshot from another peer, catching up")
+            }
             behaviour::OutEvent::GetMetricsResponse(metrics) => print_metrics(metrics),
         }
     }
@@ -88,19 +191,29 @@ async fn handle_get(data_id: Vid, input: &Sender<InEvent>) -> anyhow::Result<()>
 }
 
 async fn handle_schedule_exec(filename: &str, input: &Sender<InEvent>) -> anyhow::Result<()> {
-    let program = read_input::<_, InputProgram>(filename).await?;
-    input
-        .send(InEvent::ScheduleProgram(program.instructions))
-        .await?;
+    let instructions = read_program(filename).await?;
+    input.send(InEvent::ScheduleProgram(instructions)).await?;
+    Ok(())
+}
+
+async fn handle_register_template(filename: &str, input: &Sender<InEvent>) -> anyhow::Result<()> {
+    let template = read_template(filename).await?;
+    input.send(InEvent::RegisterTemplate(template)).await?;
+    Ok(())
+}
+
+async fn handle_print_program(filename: &str) -> anyhow::Result<()> {
+    let instructions = read_program(filename).await?;
+    print!("{}", assembly::print(&instructions));
     Ok(())
 }
 
 async fn handle_expected_output(data_filename: &str, program_filename: &str) -> anyhow::Result<()> {
     println!("Reading program...");
-    let program = read_input::<_, InputProgram>(program_filename).await?;
+    let instructions = read_program(program_filename).await?;
     println!("Reading data...");
     let data = read_input::<_, InputData>(data_filename).await?;
-    let program = Program::new(program.instructions, Hash::from_array([0; 64]))?;
+    let program = Program::new(instructions, Hash::from_array([0; 64]))?;
     let mut data_storage: HashMap<Vid, Data> = data.data.into_iter().collect();
     println!("Starting mock program execution...");
     MockProcessor::execute_on(program, &mut data_storage)?;
@@ -146,6 +259,48 @@ pub fn run_repl(
                 }),
             },
         )
+        .add(
+            "join",
+            easy_repl::Command {
+                description: "Become a member of already initialized storage".into(),
+                args_info: vec![],
+                handler: Box::new(|_| {
+                    if let Err(e) = rt.block_on(input.send(InEvent::Join)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "leave",
+            easy_repl::Command {
+                description: "Leave the storage, handing the shards over to other members".into(),
+                args_info: vec![],
+                handler: Box::new(|_| {
+                    if let Err(e) = rt.block_on(input.send(InEvent::Leave)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "evict",
+            easy_repl::Command {
+                description: "Remove unavailable peer from the storage".into(),
+                args_info: vec!["peer id".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(String);
+                    validator(args)?;
+                    let peer = args[0].parse::<PeerId>()?;
+                    if let Err(e) = rt.block_on(input.send(InEvent::Evict(peer))) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
         .add(
             "readall",
             easy_repl::Command {
@@ -191,6 +346,22 @@ pub fn run_repl(
                 }),
             },
         )
+        .add(
+            "delete",
+            easy_repl::Command {
+                description: "Delete data stored in the distributed system".into(),
+                args_info: vec!["data id".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(u64);
+                    validator(args)?;
+                    let data_id = Vid(args[0].parse::<u64>()?);
+                    if let Err(e) = rt.block_on(input.send(InEvent::Delete(data_id))) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
         .add(
             "schedule",
             easy_repl::Command {
@@ -207,6 +378,122 @@ pub fn run_repl(
                 }),
             },
         )
+        .add(
+            "programs",
+            easy_repl::Command {
+                description: "List scheduled programs with their status".into(),
+                args_info: vec![],
+                handler: Box::new(|_| {
+                    let event = InEvent::ListPrograms(String::new());
+                    if let Err(e) = rt.block_on(input.send(event)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "status",
+            easy_repl::Command {
+                description: "Show status of the program and errors of its instructions".into(),
+                args_info: vec!["program id (or its beginning)".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(String);
+                    validator(args)?;
+                    let event = InEvent::ListPrograms(args[0].to_lowercase());
+                    if let Err(e) = rt.block_on(input.send(event)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "cancel",
+            easy_repl::Command {
+                description: "Cancel the program scheduled by this peer if its execution is not announced yet"
+                    .into(),
+                args_info: vec!["program id (or its beginning)".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(String);
+                    validator(args)?;
+                    let event = InEvent::Cancel(args[0].to_lowercase());
+                    if let Err(e) = rt.block_on(input.send(event)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "register_template",
+            easy_repl::Command {
+                description: "Register the program template from the file".into(),
+                args_info: vec!["filename".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(String);
+                    validator(args)?;
+                    if let Err(e) = rt.block_on(handle_register_template(args[0], &input)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "templates",
+            easy_repl::Command {
+                description: "List registered templates".into(),
+                args_info: vec![],
+                handler: Box::new(|_| {
+                    let event = InEvent::ListTemplates(String::new());
+                    if let Err(e) = rt.block_on(input.send(event)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "schedule_template",
+            easy_repl::Command {
+                description: "Schedule the template with data ids bound to its parameters".into(),
+                args_info: vec!["template hash (or its beginning)".into(), "data ids".into()],
+                handler: Box::new(|args| {
+                    if args.is_empty() {
+                        println!("Expected template hash and data ids");
+                        return Ok(CommandStatus::Done);
+                    }
+                    let bindings = args[1..]
+                        .iter()
+                        .map(|arg| arg.parse::<u64>().map(Vid))
+                        .collect::<Result<_, _>>()?;
+                    let event = InEvent::ScheduleTemplate {
+                        prefix: args[0].to_lowercase(),
+                        bindings,
+                    };
+                    if let Err(e) = rt.block_on(input.send(event)) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
+        .add(
+            "print_program",
+            easy_repl::Command {
+                description: "Print the program from the file in text format".into(),
+                args_info: vec!["filename".into()],
+                handler: Box::new(|args| {
+                    let validator = validator!(String);
+                    validator(args)?;
+                    if let Err(e) = rt.block_on(handle_print_program(args[0])) {
+                        warn!("could not proceed with request: {}", e)
+                    }
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        )
         .add(
             "metrics_print",
             easy_repl::Command {